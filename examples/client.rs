@@ -20,6 +20,14 @@ enum CliCommand {
     Build {
         /// Url to a git repo containing the program to build
         git_url: String,
+        /// A branch, tag or commit hash to build, if not the default branch
+        #[arg(long = "ref")]
+        git_ref: Option<String>,
+    },
+    /// Create a new program project, ready to build, in a directory of the given name
+    New {
+        /// Package name of the program
+        name: String,
     },
     /// List hashes of all programs in the db
     List,
@@ -40,43 +48,136 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     });
 
     match cli.command {
-        CliCommand::Build { git_url } => {
+        CliCommand::Build { git_url, git_ref } => {
             let client = reqwest::Client::new();
+            let body = serde_json::json!({ "url": git_url, "ref": git_ref });
             let res = client
                 .post(format!("{}/add-program-git", endpoint_addr))
-                .body(git_url)
+                .body(body.to_string())
                 .send()
                 .await?;
 
             if res.status() == 200 {
                 let mut bytes_stream = res.bytes_stream();
-                let mut chunks = Vec::new();
+                let mut buffer = Vec::new();
                 while let Some(Ok(chunk)) = bytes_stream.next().await {
-                    // println!("chunk {}", String::from_utf8(chunk.to_vec()).unwrap());
-
-                    chunks.extend_from_slice(&chunk);
-                    let result_response: Result<BuildResponse, serde_json::Error> =
-                        serde_json::from_slice(&chunks[..]);
-
-                    if let Ok(response) = result_response {
-                        chunks.clear();
+                    buffer.extend_from_slice(&chunk);
+                    // Each response is on its own line, which may be split across chunks
+                    while let Some(newline) = buffer.iter().position(|byte| *byte == b'\n') {
+                        let line: Vec<u8> = buffer.drain(..=newline).collect();
+                        let response: BuildResponse = serde_json::from_slice(&line)?;
                         match response {
+                            BuildResponse::Queued {
+                                position,
+                                estimated_start,
+                            } => {
+                                let estimate = estimated_start
+                                    .map(|start| format!(", expected to start at {}", start))
+                                    .unwrap_or_default();
+                                eprintln!("Waiting in queue at position {}{}", position, estimate);
+                            }
+                            BuildResponse::Phase { phase, started } => {
+                                eprintln!("{:?} started at {}", phase, started);
+                            }
+                            BuildResponse::KeepAlive => {}
                             BuildResponse::StdOut(output) => {
                                 print!("{}", output);
                             }
                             BuildResponse::StdErr(output) => {
                                 eprint!("{}", output);
                             }
+                            BuildResponse::Warning(warning) => {
+                                eprintln!("Warning: {}", warning);
+                            }
+                            BuildResponse::Resource {
+                                memory,
+                                memory_limit,
+                                cpu_percent,
+                            } => {
+                                let limit = memory_limit
+                                    .map(|limit| format!(" of {} MB", limit / (1024 * 1024)))
+                                    .unwrap_or_default();
+                                eprintln!(
+                                    "Using {} MB{} of memory and {:.0}% CPU",
+                                    memory / (1024 * 1024),
+                                    limit,
+                                    cpu_percent
+                                );
+                            }
                             BuildResponse::Success {
                                 hash,
-                                binary,
                                 binary_filename,
+                                ..
                             } => {
                                 println!("Success! Program hash is {:?}", hash);
+                                let binary = client
+                                    .get(format!("{}/binary/{}", endpoint_addr, hex::encode(hash)))
+                                    .send()
+                                    .await?
+                                    .error_for_status()?
+                                    .bytes()
+                                    .await?;
                                 let mut file = File::create(&binary_filename)?;
                                 file.write_all(&binary)?;
                                 println!("Writen {} bytes to {}", binary.len(), binary_filename);
                             }
+                            BuildResponse::TestResult { passed, error } => {
+                                if passed {
+                                    println!("Test run passed");
+                                } else {
+                                    println!("Test run failed: {}", error.unwrap_or_default());
+                                }
+                            }
+                            BuildResponse::Benchmark(benchmark) => {
+                                println!(
+                                    "Evaluation used {} fuel and took {} microseconds",
+                                    benchmark.fuel, benchmark.execution_time_micros
+                                );
+                            }
+                            BuildResponse::Comparison(comparison) => {
+                                if comparison.identical {
+                                    println!("Built binary is identical to the given binary");
+                                } else {
+                                    println!(
+                                        "Built binary differs from the given binary in {} bytes",
+                                        comparison.differing_bytes
+                                    );
+                                }
+                            }
+                            BuildResponse::Verification {
+                                expected,
+                                built,
+                                matches,
+                            } => {
+                                if matches {
+                                    println!("Rebuilt program has the expected hash {:?}", built);
+                                } else {
+                                    println!(
+                                        "Rebuilt program has hash {:?} rather than {:?}",
+                                        built, expected
+                                    );
+                                }
+                            }
+                            BuildResponse::Audit(report) => {
+                                for vulnerability in report.vulnerabilities {
+                                    println!(
+                                        "Vulnerable dependency {} {}: {} ({})",
+                                        vulnerability.package,
+                                        vulnerability.version,
+                                        vulnerability.title,
+                                        vulnerability.advisory_id
+                                    );
+                                }
+                            }
+                            BuildResponse::Deployed(deployment) => {
+                                println!(
+                                    "Registered on chain in extrinsic {:?}",
+                                    deployment.extrinsic_hash
+                                );
+                            }
+                            BuildResponse::Failure { kind, error } => {
+                                println!("Build failed ({:?}): {}", kind, error);
+                            }
                         }
                     }
                 }
@@ -84,6 +185,22 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 println!("Failed to build {}", res.text().await?);
             }
         }
+        CliCommand::New { name } => {
+            let client = reqwest::Client::new();
+            let res = client
+                .post(format!("{}/scaffold", endpoint_addr))
+                .query(&[("name", &name)])
+                .send()
+                .await?;
+
+            if res.status() == 200 {
+                let archive = res.bytes().await?;
+                tar::Archive::new(&archive[..]).unpack(&name)?;
+                println!("Created program {} in ./{}", name, name);
+            } else {
+                println!("Failed to create program {}", res.text().await?);
+            }
+        }
         CliCommand::List => {
             let body = reqwest::get(format!("{}/programs", endpoint_addr))
                 .await?
@@ -0,0 +1,144 @@
+//! Cold storage of the large artifacts of programs which have not been accessed for a long time.
+//! Program metadata always stays in the db, but artifacts such as binaries are moved to an
+//! archive directory, which may be on cheaper storage such as a mounted network or object store,
+//! and moved back when they are next needed.
+use serde::{Deserialize, Serialize};
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    binary::BINARY_TREE, build::Error, build_log::BUILD_LOG_TREE, lockfile::LOCKFILE_TREE,
+    record::unix_time, source::SOURCE_TREE,
+};
+
+/// Names of the sled trees holding artifacts keyed by program hash, which may be archived
+pub const ARTIFACT_TREES: &[&str] = &[BINARY_TREE, BUILD_LOG_TREE, SOURCE_TREE, LOCKFILE_TREE];
+
+/// Name of the sled tree holding the unix time at which each program was last accessed
+const LAST_ACCESS_TREE: &str = "last_access";
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Settings for archiving artifacts of programs
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ArchiveConfig {
+    /// Directory to move archived artifacts to
+    pub path: PathBuf,
+    /// Number of days after which artifacts of programs which have not been accessed are
+    /// archived
+    pub after_days: u64,
+}
+
+/// The outcome of archiving artifacts
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ArchiveReport {
+    /// Number of programs which had artifacts archived
+    pub programs: usize,
+    /// Number of artifacts archived
+    pub artifacts: usize,
+    /// Total size of the artifacts archived in bytes
+    pub bytes: u64,
+}
+
+/// Record that a program has just been accessed, so that its artifacts are kept in the db
+pub fn record_access(db: &sled::Db, hash: &[u8]) -> Result<(), Error> {
+    db.open_tree(LAST_ACCESS_TREE)?
+        .insert(hash, &unix_time().to_be_bytes())?;
+    Ok(())
+}
+
+/// Move the artifacts of programs which have not been accessed recently to the archive. This is
+/// done on a blocking thread, as the archive may be slow to write to.
+pub async fn archive_untouched(
+    db: &sled::Db,
+    config: &ArchiveConfig,
+) -> Result<ArchiveReport, Error> {
+    let db = db.clone();
+    let config = config.clone();
+    tokio::task::spawn_blocking(move || archive_untouched_blocking(&db, &config))
+        .await
+        .map_err(|error| Error::Archive(format!("archiving panicked: {}", error)))?
+}
+
+fn archive_untouched_blocking(
+    db: &sled::Db,
+    config: &ArchiveConfig,
+) -> Result<ArchiveReport, Error> {
+    let last_access_tree = db.open_tree(LAST_ACCESS_TREE)?;
+    let cutoff = unix_time().saturating_sub(config.after_days * SECONDS_PER_DAY);
+    let mut report = ArchiveReport::default();
+    for res in db.iter() {
+        let (hash, _value) = res?;
+        let last_access = match last_access_tree.get(&hash)? {
+            Some(time) => u64::from_be_bytes(time.as_ref().try_into().unwrap_or_default()),
+            None => {
+                // Programs from before access was recorded are counted as accessed now
+                record_access(db, &hash)?;
+                continue;
+            }
+        };
+        if last_access >= cutoff {
+            continue;
+        }
+
+        let mut archived_any = false;
+        for tree_name in ARTIFACT_TREES {
+            let tree = db.open_tree(tree_name)?;
+            if let Some(artifact) = tree.get(&hash)? {
+                let path = archive_path(&config.path, tree_name, &hash);
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::write(&path, &artifact)?;
+                tree.remove(&hash)?;
+                report.artifacts += 1;
+                report.bytes += artifact.len() as u64;
+                archived_any = true;
+            }
+        }
+        if archived_any {
+            report.programs += 1;
+        }
+    }
+    log::info!("Archived artifacts: {:?}", report);
+    Ok(report)
+}
+
+/// Get an artifact of a program, moving it back from the archive if it was archived
+pub async fn get_artifact(
+    db: &sled::Db,
+    archive: Option<&ArchiveConfig>,
+    tree_name: &str,
+    hash: &[u8],
+) -> Result<Option<Vec<u8>>, Error> {
+    let tree = db.open_tree(tree_name)?;
+    if let Some(artifact) = tree.get(hash)? {
+        return Ok(Some(artifact.to_vec()));
+    }
+    let Some(archive) = archive else {
+        return Ok(None);
+    };
+    let path = archive_path(&archive.path, tree_name, hash);
+    let artifact = match tokio::fs::read(&path).await {
+        Ok(artifact) => artifact,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error.into()),
+    };
+    tree.insert(hash, artifact.as_slice())?;
+    record_access(db, hash)?;
+    tokio::fs::remove_file(&path).await?;
+    log::info!(
+        "Restored {} of {} from archive",
+        tree_name,
+        hex::encode(hash)
+    );
+    Ok(Some(artifact))
+}
+
+/// Archived artifacts are stored in a directory for each tree, named by program hash
+fn archive_path(archive_dir: &Path, tree_name: &str, hash: &[u8]) -> PathBuf {
+    archive_dir.join(tree_name).join(hex::encode(hash))
+}
@@ -0,0 +1,115 @@
+//! Attestations that a program was built by this service, signed with the server's key so that
+//! consumers can check that a build came from a builder they trust
+use parity_scale_codec::Encode;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sp_core::{sr25519, Pair, H256};
+
+use crate::build::Error;
+
+/// Name of the sled tree in which attestations are stored, keyed by program hash
+const ATTESTATION_TREE: &str = "attestations";
+
+/// Name of the sled tree holding the seed of the signing key, when it was generated here
+const SIGNING_KEY_TREE: &str = "signing_key";
+
+/// Key under which the seed is stored in the signing key tree
+const SEED_KEY: &[u8] = b"seed";
+
+/// A signed statement of how a program was built
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Attestation {
+    #[schemars(with = "String")]
+    pub hash: H256,
+    /// The commit built, for programs built from git
+    pub commit: Option<String>,
+    /// The docker image used, as recorded for the build
+    pub docker_image: Option<String>,
+    /// Unix time in seconds when the program was built
+    pub timestamp: u64,
+    /// Hex encoded sr25519 public key of the builder
+    pub public_key: String,
+    /// Hex encoded sr25519 signature of the [message](Attestation::message)
+    pub signature: String,
+}
+
+impl Attestation {
+    /// The message which is signed, which is the SCALE encoded tuple of the program hash, commit,
+    /// docker image and timestamp
+    pub fn message(
+        hash: H256,
+        commit: &Option<String>,
+        docker_image: &Option<String>,
+        timestamp: u64,
+    ) -> Vec<u8> {
+        (hash.0, commit, docker_image, timestamp).encode()
+    }
+}
+
+/// Signs attestations of builds with the server's key
+#[derive(Clone)]
+pub struct BuildSigner(sr25519::Pair);
+
+impl BuildSigner {
+    /// Use the given key, which is a secret URI such as a hex encoded seed or a mnemonic phrase.
+    /// If no key is given, one is generated the first time and kept in the db.
+    pub fn load(db: &sled::Db, secret_uri: Option<&str>) -> Result<Self, Error> {
+        if let Some(secret_uri) = secret_uri {
+            return sr25519::Pair::from_string(secret_uri, None)
+                .map(Self)
+                .map_err(|error| Error::InvalidSigningKey(format!("{:?}", error)));
+        }
+        let tree = db.open_tree(SIGNING_KEY_TREE)?;
+        if let Some(seed) = tree.get(SEED_KEY)? {
+            return sr25519::Pair::from_seed_slice(&seed)
+                .map(Self)
+                .map_err(|error| Error::InvalidSigningKey(format!("{:?}", error)));
+        }
+        let (pair, seed) = sr25519::Pair::generate();
+        tree.insert(SEED_KEY, seed.as_ref())?;
+        Ok(Self(pair))
+    }
+
+    /// The hex encoded public key, with which attestations can be checked
+    pub fn public_key(&self) -> String {
+        hex::encode(self.0.public())
+    }
+
+    /// Sign an attestation of a build
+    pub fn attest(
+        &self,
+        hash: H256,
+        commit: Option<String>,
+        docker_image: Option<String>,
+        timestamp: u64,
+    ) -> Attestation {
+        let message = Attestation::message(hash, &commit, &docker_image, timestamp);
+        Attestation {
+            hash,
+            commit,
+            docker_image,
+            timestamp,
+            public_key: self.public_key(),
+            signature: hex::encode(self.0.sign(&message)),
+        }
+    }
+}
+
+/// Store the attestation of a program's build, unless it already has one. Any build of the
+/// program gives the same hash, so the attestation of the first is kept.
+pub fn store_attestation(db: &sled::Db, attestation: &Attestation) -> Result<(), Error> {
+    let _ = db.open_tree(ATTESTATION_TREE)?.compare_and_swap(
+        attestation.hash,
+        None as Option<&[u8]>,
+        Some(serde_json::to_vec(attestation)?),
+    )?;
+    Ok(())
+}
+
+/// Get the attestation of a program's build, if it was built here
+pub fn get_attestation(db: &sled::Db, hash: &[u8]) -> Result<Option<Attestation>, Error> {
+    match db.open_tree(ATTESTATION_TREE)?.get(hash)? {
+        Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,150 @@
+//! Checks of the dependencies of programs against the RustSec advisory database with
+//! `cargo audit`, so that programs depending on crates with known vulnerabilities can be spotted
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sp_core::H256;
+use std::{io::ErrorKind, path::Path, process::Command};
+
+use crate::build::Error;
+
+/// Name of the sled tree in which the audit reports of programs are stored, keyed by program hash
+const AUDIT_TREE: &str = "audits";
+
+/// The known vulnerabilities found in a program's dependencies
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct AuditReport {
+    /// Number of crates in the lockfile which was checked
+    pub dependency_count: usize,
+    pub vulnerabilities: Vec<Vulnerability>,
+}
+
+/// A dependency with a known vulnerability
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Vulnerability {
+    /// RustSec advisory ID, such as `RUSTSEC-2023-0001`
+    pub advisory_id: String,
+    pub title: String,
+    pub url: Option<String>,
+    /// Name of the vulnerable crate
+    pub package: String,
+    /// Version of the vulnerable crate which the program depends on
+    pub version: String,
+    /// Version requirements of releases of the crate which fix the vulnerability
+    pub patched_versions: Vec<String>,
+}
+
+/// The parts of the JSON report given by `cargo audit --json` which are kept
+#[derive(Deserialize)]
+struct CargoAuditOutput {
+    lockfile: CargoAuditLockfile,
+    vulnerabilities: CargoAuditVulnerabilities,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct CargoAuditLockfile {
+    dependency_count: usize,
+}
+
+#[derive(Deserialize)]
+struct CargoAuditVulnerabilities {
+    list: Vec<CargoAuditVulnerability>,
+}
+
+#[derive(Deserialize)]
+struct CargoAuditVulnerability {
+    advisory: CargoAuditAdvisory,
+    versions: CargoAuditVersions,
+    package: CargoAuditPackage,
+}
+
+#[derive(Deserialize)]
+struct CargoAuditAdvisory {
+    id: String,
+    title: String,
+    url: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CargoAuditVersions {
+    patched: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct CargoAuditPackage {
+    name: String,
+    version: String,
+}
+
+impl From<CargoAuditOutput> for AuditReport {
+    fn from(output: CargoAuditOutput) -> Self {
+        Self {
+            dependency_count: output.lockfile.dependency_count,
+            vulnerabilities: output
+                .vulnerabilities
+                .list
+                .into_iter()
+                .map(|vulnerability| Vulnerability {
+                    advisory_id: vulnerability.advisory.id,
+                    title: vulnerability.advisory.title,
+                    url: vulnerability.advisory.url,
+                    package: vulnerability.package.name,
+                    version: vulnerability.package.version,
+                    patched_versions: vulnerability.versions.patched,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Check the crates in a lockfile against the advisory database
+pub fn audit_lockfile(lockfile_path: &Path) -> Result<AuditReport, AuditError> {
+    let output = Command::new("cargo")
+        .args(["audit", "--json", "--file"])
+        .arg(lockfile_path)
+        .output()
+        .map_err(|error| match error.kind() {
+            ErrorKind::NotFound => AuditError::Unavailable("cargo is not installed".to_string()),
+            _ => AuditError::Io(error),
+        })?;
+    // The report is given on standard output whether or not vulnerabilities were found, which
+    // is what the exit code says
+    match serde_json::from_slice::<CargoAuditOutput>(&output.stdout) {
+        Ok(report) => Ok(report.into()),
+        Err(_) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(AuditError::Unavailable(
+                if stderr.contains("no such command") {
+                    "cargo-audit is not installed on this server".to_string()
+                } else {
+                    stderr.trim().to_string()
+                },
+            ))
+        }
+    }
+}
+
+/// An error auditing the dependencies of a program. This does not fail the build.
+#[derive(Debug, thiserror::Error)]
+pub enum AuditError {
+    #[error("Could not audit dependencies: {0}")]
+    Unavailable(String),
+    #[error("Could not run cargo audit: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Store the audit report of a program, replacing any earlier one, as later audits may know of
+/// more advisories
+pub fn store_audit(db: &sled::Db, hash: H256, report: &AuditReport) -> Result<(), Error> {
+    db.open_tree(AUDIT_TREE)?
+        .insert(hash, serde_json::to_vec(report)?)?;
+    Ok(())
+}
+
+/// Get the audit report of a program, if it was audited
+pub fn get_audit(db: &sled::Db, hash: &[u8]) -> Result<Option<AuditReport>, Error> {
+    match db.open_tree(AUDIT_TREE)?.get(hash)? {
+        Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,125 @@
+//! Storage of program binaries, and registration of pre-built program binaries so that programs
+//! which are already deployed can be catalogued even when their source code is not available.
+//! Registered binaries cannot be verified, and are marked as such.
+use serde_json::json;
+use sp_core::H256;
+use wasmparser::{Parser, Payload, Validator};
+
+use crate::{
+    archive::{get_artifact, ArchiveConfig},
+    build::{EntropyProgramMetadata, Error},
+    digest::store_digests,
+    entropy_metadata::store_entropy_metadata,
+    index::{add_name_version, add_search_terms},
+};
+
+/// Name of the sled tree in which program binaries are stored, keyed by program hash
+pub const BINARY_TREE: &str = "binaries";
+
+/// How programs registered without source code are marked in their metadata
+pub const UNVERIFIED_NO_SOURCE: &str = "unverified (no source)";
+
+/// The magic number and version at the start of a wasm component binary, which is what programs
+/// are compiled to
+const WASM_COMPONENT_HEADER: [u8; 8] = [0x00, 0x61, 0x73, 0x6d, 0x0d, 0x00, 0x01, 0x00];
+
+/// Functions which a program component must export to be evaluated by the Entropy programs
+/// runtime
+const PROGRAM_EXPORTS: [&str; 2] = ["evaluate", "custom-hash"];
+
+/// Store the metadata of a pre-built program binary, returning its hash. If the program is
+/// already present, for example because it was built from source, the existing metadata is kept.
+pub fn register_binary(
+    db: &sled::Db,
+    binary: &[u8],
+    name: &str,
+    version: &str,
+    entropy_metadata: &EntropyProgramMetadata,
+) -> Result<H256, Error> {
+    validate_wasm(binary)?;
+    let mut hasher = entropy_metadata.program_hasher();
+    hasher.update(binary);
+    let digests = hasher.finalize_digests();
+    let hash = digests.blake2_256;
+    store_binary(db, hash, binary)?;
+    // Programs stored before digests were recorded gain them when registered again
+    store_digests(db, &digests)?;
+    if db.contains_key(hash)? {
+        return Ok(hash);
+    }
+
+    // Stored in the shape of the package metadata cargo gives, as programs' metadata is read as
+    // that, with no targets or dependencies as the source is not known
+    let metadata = json!({
+        "id": format!("{} {}", name, version),
+        "name": name,
+        "version": version,
+        "dependencies": [],
+        "targets": [],
+        "features": {},
+        "manifest_path": "",
+        "metadata": { "entropy-program": entropy_metadata },
+        "verification": UNVERIFIED_NO_SOURCE,
+    });
+    db.insert(hash, serde_json::to_vec(&metadata)?)?;
+    store_entropy_metadata(db, hash, entropy_metadata)?;
+    add_name_version(db, name, version, hash)?;
+    add_search_terms(db, hash, &metadata)?;
+    Ok(hash)
+}
+
+/// Store the binary of a program
+pub fn store_binary(db: &sled::Db, hash: H256, binary: &[u8]) -> Result<(), Error> {
+    db.open_tree(BINARY_TREE)?.insert(hash, binary)?;
+    Ok(())
+}
+
+/// Get the binary of a program
+pub async fn get_binary(
+    db: &sled::Db,
+    archive: Option<&ArchiveConfig>,
+    hash: &[u8],
+) -> Result<Option<Vec<u8>>, Error> {
+    get_artifact(db, archive, BINARY_TREE, hash).await
+}
+
+/// Check that a binary is a valid wasm component which exports the functions of an Entropy
+/// program
+pub fn validate_wasm(binary: &[u8]) -> Result<(), Error> {
+    if !binary.starts_with(&WASM_COMPONENT_HEADER) {
+        return Err(Error::InvalidWasm(
+            "binary does not start with a wasm component header".to_string(),
+        ));
+    }
+    Validator::new()
+        .validate_all(binary)
+        .map_err(|error| Error::InvalidWasm(error.to_string()))?;
+
+    // Only exports of the outer component count, not those of the modules within it
+    let mut depth = 0;
+    let mut exports = Vec::new();
+    for payload in Parser::new(0).parse_all(binary) {
+        match payload.map_err(|error| Error::InvalidWasm(error.to_string()))? {
+            Payload::ModuleSection { .. } | Payload::ComponentSection { .. } => depth += 1,
+            Payload::End(_) => depth -= 1,
+            Payload::ComponentExportSection(reader) if depth == 0 => {
+                for export in reader {
+                    let export = export.map_err(|error| Error::InvalidWasm(error.to_string()))?;
+                    exports.push(export.name.0.to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+    let missing: Vec<&str> = PROGRAM_EXPORTS
+        .into_iter()
+        .filter(|name| !exports.iter().any(|export| export == name))
+        .collect();
+    if !missing.is_empty() {
+        return Err(Error::InvalidWasm(format!(
+            "component does not export {}",
+            missing.join(", ")
+        )));
+    }
+    Ok(())
+}
@@ -1,275 +1,2696 @@
 use cargo_metadata::{CargoOpt, MetadataCommand};
-use futures::channel::mpsc::{self as futures_mpsc, TrySendError};
+use futures::{
+    channel::mpsc::{self as futures_mpsc, TrySendError},
+    StreamExt,
+};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use sp_core::Hasher;
+use sha2::{Digest, Sha256};
+use sha3::Keccak256;
 use sp_core::H256;
-use sp_runtime::traits::BlakeTwo256;
 use std::{
-    io::Read,
-    path::{Path, PathBuf},
+    collections::{BTreeMap, VecDeque},
+    path::{Component, Path, PathBuf},
     process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
 };
-use tar::Archive;
-use temp_dir::TempDir;
 use thiserror::Error;
 use tokio::fs::{read_dir, File};
-use tokio::{io::AsyncReadExt, sync::mpsc::Receiver};
+use tokio::{
+    io::AsyncReadExt,
+    process::{Child, Command as AsyncCommand},
+    sync::{mpsc::Receiver, Mutex as AsyncMutex},
+};
+
+use crate::{
+    archive::record_access,
+    attestation::{get_attestation, store_attestation, Attestation, BuildSigner},
+    audit::{audit_lockfile, store_audit, AuditReport},
+    binary::{get_binary, store_binary, validate_wasm},
+    build_cache::{get_commit_build, record_commit_build},
+    build_log::{store_build_log, MAX_BUILD_LOG_SIZE},
+    build_queue::{InFlight, QueueEntry},
+    builder_pool::{BuilderMessage, BuilderPool, RemoteBuild},
+    cargo_cache::CargoCacheConfig,
+    chain::{
+        deploy_program, get_deployment_status, store_deployment_status, ChainConfig, Deployment,
+        DeploymentError, DeploymentStatus,
+    },
+    changelog::{collect_changelog, head_commit, store_changelog},
+    compare::BinaryComparison,
+    config::Config,
+    container::{qualify_image_name, ContainerBackend, ContainerEngine},
+    dependencies::{resolve_dependencies, store_dependencies},
+    digest::{store_digests, ProgramDigests},
+    disk::{build_temp_dir, PruneTrigger},
+    entropy_metadata::{get_entropy_metadata, store_entropy_metadata},
+    git_auth::{strip_credentials, GitAuth},
+    index::{add_name_version, add_search_terms},
+    ipfs::pin_program,
+    job::{update_job, JobEvents, JobStatus},
+    kubernetes::{JobOutcome, JobTask, KubernetesConfig, KubernetesJob},
+    lockfile::store_lockfile,
+    namespace::add_to_namespace,
+    native_build::NativeBuildConfig,
+    notify::Notifier,
+    optimize::{optimize_binary, store_optimization},
+    peer_comparison::compare_with_peers,
+    provenance::{head_tag, store_provenance, GitProvenance},
+    record::{insert_build_record, last_built_commit, unix_time, BuildSource, BuildStatus},
+    reproduction::{record_local_reproduction, ANONYMOUS_REQUESTER},
+    resource_monitor::ResourceMonitor,
+    sbom::{generate_sbom, store_sbom},
+    scaffold::{fetch_source_file, ProgramTemplate},
+    sccache::SccacheConfig,
+    source::{snapshot_source, store_source, MAX_SOURCE_SNAPSHOT_SIZE},
+    test_run::{store_benchmark, Benchmark, TestRun},
+    test_suite::TestSummary,
+    unpack::{unpack_tar, unpack_zip, SpooledArchive, DEFAULT_MAX_UNPACKED_SIZE},
+    visibility::{get_visibility, set_initial_visibility, set_visibility, Visibility},
+};
+
+/// Size of the reads of a build process's output, and the most output which is held back waiting
+/// for the end of a line
+const OUTPUT_BUFFER_SIZE: usize = 10_000;
+
+/// Size of the chunks in which a binary is read and hashed
+const HASH_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Length in bytes of the hash used for programs, which is blake2b-256 as with `BlakeTwo256`
+const BLAKE2_256_LENGTH: usize = 32;
+
+/// How many lines of build output to keep for the build record
+const LOG_TAIL_LINES: usize = 100;
+
+/// How long a build may run before it is killed
+const BUILD_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// The image used to build programs which do not specify one, matching the default given in the
+/// Dockerfile of the example program
+pub(crate) const DEFAULT_DOCKER_IMAGE: &str = "peg997/build-entropy-programs:version0.1";
+
+/// Where program source code is mounted when running commands in a container
+const CONTAINER_SOURCE_DIR: &str = "/usr/src/programs";
+
+/// Exit code given by docker when a container is killed, typically by the OOM killer
+const KILLED_EXIT_CODE: i32 = 137;
+
+/// Scheduling period used to express a CPU limit as a quota, in microseconds
+const CPU_PERIOD_MICROS: u64 = 100_000;
+
+/// How long a builder of the pool may go without sending anything before its build is given up
+const BUILDER_SILENCE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How often a build waiting on a builder checks whether it has been cancelled
+const REMOTE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often a running build process is checked to still be wanted
+const WANTED_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a build may give no responses before a keep-alive is sent, which is well within the
+/// idle timeouts of common proxies
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// A request to build a program
+pub struct BuildRequest {
+    request_type: BuildRequestType,
+    responder: BuildResponder,
+    /// The tenant namespace to add the program to, if any
+    namespace: Option<String>,
+    /// Email address to notify when the build finishes, if any
+    notify_email: Option<String>,
+    /// The job to update with the progress of the build, if any
+    job_id: Option<u64>,
+    options: BuildOptions,
+    /// The build's place in the queue, while it waits for a worker
+    queue_entry: Option<QueueEntry>,
+    /// Lets identical requests follow this build until it is dropped
+    in_flight: Option<InFlight>,
+}
+
+impl BuildRequest {
+    /// A new build request with a git url. Credentials in the URL are taken out of it, so that
+    /// they are not stored with the URL.
+    pub fn new_git(url: String, responder: BuildResponder) -> Self {
+        let (url, token) = strip_credentials(&url);
+        Self {
+            request_type: BuildRequestType::Git {
+                url,
+                git_ref: None,
+                auth: token.map(GitAuth::token).unwrap_or_default(),
+            },
+            responder,
+            namespace: None,
+            notify_email: None,
+            job_id: None,
+            options: BuildOptions::default(),
+            queue_entry: None,
+            in_flight: None,
+        }
+    }
+
+    /// A new build request with the contents of a tar archive
+    pub fn new_tar(archive: SpooledArchive, responder: BuildResponder) -> Self {
+        Self {
+            request_type: BuildRequestType::Tar { archive },
+            responder,
+            namespace: None,
+            notify_email: None,
+            job_id: None,
+            options: BuildOptions::default(),
+            queue_entry: None,
+            in_flight: None,
+        }
+    }
+
+    /// A new build request with the contents of a zip archive
+    pub fn new_zip(raw_archive: Vec<u8>, responder: BuildResponder) -> Self {
+        Self {
+            request_type: BuildRequestType::Zip { raw_archive },
+            responder,
+            namespace: None,
+            notify_email: None,
+            job_id: None,
+            options: BuildOptions::default(),
+            queue_entry: None,
+            in_flight: None,
+        }
+    }
+
+    /// A new build request with the URL of a single source file
+    pub fn new_source_url(url: String, responder: BuildResponder) -> Self {
+        Self {
+            request_type: BuildRequestType::SourceUrl { url },
+            responder,
+            namespace: None,
+            notify_email: None,
+            job_id: None,
+            options: BuildOptions::default(),
+            queue_entry: None,
+            in_flight: None,
+        }
+    }
+
+    /// Build the given branch, tag or commit of a git repository rather than the default branch.
+    /// This has no effect on other kinds of request.
+    pub fn with_git_ref(mut self, git_ref: String) -> Self {
+        if let BuildRequestType::Git { git_ref: r, .. } = &mut self.request_type {
+            *r = Some(git_ref);
+        }
+        self
+    }
+
+    /// Clone a private git repository with the given access token. This has no effect on other
+    /// kinds of request.
+    pub fn with_git_token(mut self, token: String) -> Self {
+        if let BuildRequestType::Git { auth, .. } = &mut self.request_type {
+            *auth = GitAuth::token(token);
+        }
+        self
+    }
+
+    /// Build with the given cargo features rather than the program's default features
+    pub fn with_features(mut self, features: FeatureSelection) -> Self {
+        self.options.features = features;
+        self
+    }
+
+    /// Set options affecting how the program is built and checked. Features already chosen with
+    /// [Self::with_features] are kept if the options choose none.
+    pub fn with_options(mut self, options: BuildOptions) -> Self {
+        let features = std::mem::take(&mut self.options.features);
+        self.options = options;
+        if self.options.features.is_default() {
+            self.options.features = features;
+        }
+        self
+    }
+
+    /// Add the program to the given tenant namespace once it is built
+    pub fn in_namespace(mut self, namespace: String) -> Self {
+        self.namespace = Some(namespace);
+        self
+    }
+
+    /// The cargo features the program will be built with
+    pub fn features(&self) -> &FeatureSelection {
+        &self.options.features
+    }
+
+    /// The tenant namespace the program will be added to, if any
+    pub fn namespace(&self) -> Option<&str> {
+        self.namespace.as_deref()
+    }
+
+    /// Send an email to the given address when the build finishes
+    pub fn with_notification(mut self, email: String) -> Self {
+        self.notify_email = Some(email);
+        self
+    }
+
+    /// Record the progress of the build in the given job
+    pub fn with_job(mut self, job_id: u64) -> Self {
+        self.job_id = Some(job_id);
+        self
+    }
+
+    /// A flag which cancels the build when set
+    pub fn cancellation_flag(&self) -> Arc<AtomicBool> {
+        self.responder.cancellation_flag()
+    }
+
+    /// A responder sending to the same client as the build's own
+    pub(crate) fn responder(&self) -> BuildResponder {
+        self.responder.clone()
+    }
+
+    /// Identifies requests which would build the same program in the same way, so that one
+    /// arriving while another is queued or being built can follow that build rather than
+    /// repeat it. Requests which update a job, notify someone or give their own git credentials
+    /// are always built, as following another build would skip what they ask for.
+    pub(crate) fn dedup_key(&self) -> Option<String> {
+        if self.job_id.is_some() || self.notify_email.is_some() {
+            return None;
+        }
+        let source = match &self.request_type {
+            BuildRequestType::Git { url, git_ref, auth } if !auth.has_token() => {
+                format!("git {} {:?}", url, git_ref)
+            }
+            BuildRequestType::Git { .. } => return None,
+            BuildRequestType::Tar { archive } => format!("tar {}", hex::encode(archive.sha256())),
+            BuildRequestType::Zip { raw_archive } => {
+                format!("zip {}", hex::encode(Sha256::digest(raw_archive)))
+            }
+            BuildRequestType::SourceUrl { url } => format!("source-url {}", url),
+        };
+        // Options are compared by their debug output, which includes every one of them
+        let options = Sha256::digest(format!("{:?} {:?}", self.namespace, self.options));
+        Some(format!("{} {}", source, hex::encode(options)))
+    }
+
+    pub(crate) fn enter_queue(&mut self, queue_entry: QueueEntry, in_flight: Option<InFlight>) {
+        self.queue_entry = Some(queue_entry);
+        self.in_flight = in_flight;
+    }
+
+    /// A description of the source code to be stored in the build record
+    pub fn source(&self) -> BuildSource {
+        self.request_type.source()
+    }
+}
+
+/// Options affecting how a program is built and checked
+#[derive(Debug, Clone, Default)]
+pub struct BuildOptions {
+    /// Evaluate the program with sample input once it is built
+    pub test_run: Option<TestRun>,
+    /// Measure the fuel used to evaluate the program with the test run input
+    pub benchmark: bool,
+    /// Run the program's test suite before building it
+    pub run_tests: bool,
+    /// Fail the build if the program's test suite fails
+    pub require_tests_pass: bool,
+    /// A binary to compare with the one built
+    pub compare_with: Option<Vec<u8>>,
+    /// Who can see the program once it is built, if not public
+    pub visibility: Option<Visibility>,
+    /// Directory of the program within the repository or archive, if not the root
+    pub path: Option<PathBuf>,
+    /// Build the program even if the same commit has already been built
+    pub no_cache: bool,
+    /// Give the binary in the success message, as well as making it available to download
+    pub include_binary: bool,
+    /// The hash the program is expected to have, which the built program is checked against
+    pub verify: Option<H256>,
+    /// Check that the binary given to compare with has the same hash as the one built
+    pub verify_given: bool,
+    /// Image to build with instead of the one given in the program's metadata, such as one
+    /// pinned by digest by an earlier build
+    pub docker_image: Option<String>,
+    /// Images to build the program with one after another, instead of those given in the
+    /// program's metadata
+    pub docker_images: Vec<String>,
+    /// Optimize the binary with `wasm-opt`, whether or not the program's metadata asks for it
+    pub optimize: bool,
+    /// Register the program on chain once it is built
+    pub deploy: bool,
+    /// The cargo features to build the program with, if not its default features
+    pub features: FeatureSelection,
+    /// Limits on the resources the build's containers may use, which are taken from the
+    /// configuration when the build starts
+    pub resource_limits: ResourceLimits,
+    /// The docker images the program may be built with, which are also taken from the
+    /// configuration when the build starts
+    pub allowed_docker_images: Vec<String>,
+    /// How `docker build` is run, also taken from the configuration
+    pub docker: DockerSettings,
+    /// Whether to build with docker or podman, also taken from the configuration
+    pub container_engine: ContainerEngine,
+    /// Build with cargo on the host rather than in a container, if configured to
+    pub native_build: Option<NativeBuildConfig>,
+    /// Build as jobs in a Kubernetes cluster rather than with a container engine here, if
+    /// configured to
+    pub kubernetes: Option<KubernetesConfig>,
+    /// Volumes holding the cargo caches which test containers mount, if configured
+    pub cargo_cache: Option<CargoCacheConfig>,
+    /// Compile with sccache, if configured to
+    pub sccache: Option<SccacheConfig>,
+    /// Have a builder of the pool run the tests and compile the program, rather than doing so
+    /// here. This is set when a pool is configured.
+    pub remote_build: bool,
+}
+
+/// Settings for running `docker build`, so that the toolchain programs are built with can be
+/// changed without changing the programs
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DockerSettings {
+    /// Image to build programs with which do not name one, given to their Dockerfile as the
+    /// `IMAGE` build argument. Otherwise the Dockerfile's own default is used.
+    pub default_image: Option<String>,
+    /// Further build arguments given to every build. `IMAGE`, `FEATURES` and
+    /// `NO_DEFAULT_FEATURES` are set for each build, so cannot be given here.
+    #[serde(default)]
+    pub build_args: BTreeMap<String, String>,
+    /// Whether to build with BuildKit, if not docker's default
+    pub buildkit: Option<bool>,
+    /// Further options for `docker build`, such as `--network=none`
+    #[serde(default)]
+    pub build_options: Vec<String>,
+    /// Further images to pull when the server starts, besides the default image
+    #[serde(default)]
+    pub pull_images: Vec<String>,
+    /// Number of recent successful builds whose images are also pulled when the server starts
+    pub pull_recent_images: Option<usize>,
+}
+
+impl DockerSettings {
+    /// Apply the settings to a `build` command
+    fn apply(&self, backend: &dyn ContainerBackend, command: &mut Command) {
+        if let Some(buildkit) = self.buildkit {
+            backend.set_buildkit(command, buildkit);
+        }
+        command.args(&self.build_options);
+        for (name, value) in &self.build_args {
+            if !matches!(name.as_str(), "IMAGE" | "FEATURES" | "NO_DEFAULT_FEATURES") {
+                command
+                    .arg("--build-arg")
+                    .arg(format!("{}={}", name, value));
+            }
+        }
+    }
+}
+
+/// Limits on the resources a build may use
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ResourceLimits {
+    /// Memory limit, such as `900m`
+    pub memory: Option<String>,
+    /// Limit on memory and swap together. Setting this to the same as `memory` disables swap.
+    pub memory_swap: Option<String>,
+    /// Number of CPUs, which may be fractional
+    pub cpus: Option<f64>,
+    /// The most bytes an uploaded archive may unpack to, 1GiB if not given
+    pub max_unpacked_size: Option<u64>,
+    /// How often to send the client the memory and CPU a build's containers are using, in
+    /// seconds. Builds are only monitored if this is given.
+    pub monitor_interval_seconds: Option<u64>,
+    /// Percentage of the memory limit at which a monitored build is stopped, 95 if not given
+    pub stop_at_percent: Option<u64>,
+    /// Where the cgroup v2 filesystem is mounted, if not `/sys/fs/cgroup`
+    pub cgroup_root: Option<PathBuf>,
+}
+
+impl ResourceLimits {
+    /// The most bytes an uploaded archive may unpack to
+    pub fn max_unpacked_size(&self) -> u64 {
+        self.max_unpacked_size.unwrap_or(DEFAULT_MAX_UNPACKED_SIZE)
+    }
+
+    /// Arguments limiting the resources of `docker build`, which has no `--cpus` option
+    fn docker_build_args(&self) -> Vec<String> {
+        let mut args = self.memory_args();
+        if let Some(cpus) = self.cpus {
+            args.push(format!("--cpu-period={}", CPU_PERIOD_MICROS));
+            args.push(format!(
+                "--cpu-quota={}",
+                (cpus * CPU_PERIOD_MICROS as f64) as u64
+            ));
+        }
+        args
+    }
+
+    /// Arguments limiting the resources of `docker run`
+    fn docker_run_args(&self) -> Vec<String> {
+        let mut args = self.memory_args();
+        if let Some(cpus) = self.cpus {
+            args.push(format!("--cpus={}", cpus));
+        }
+        args
+    }
+
+    fn memory_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(memory) = &self.memory {
+            args.push(format!("--memory={}", memory));
+        }
+        if let Some(memory_swap) = &self.memory_swap {
+            args.push(format!("--memory-swap={}", memory_swap));
+        }
+        args
+    }
+
+    /// The error to give when a container is killed, which is most likely because it ran out of
+    /// memory
+    fn killed_error(&self) -> Error {
+        match &self.memory {
+            Some(memory) => Error::ResourceLimitExceeded(memory.clone()),
+            None => Error::OutOfMemory,
+        }
+    }
+}
+
+/// A choice of cargo features to build a program with. The features are given to the program's
+/// Dockerfile as the `FEATURES` and `NO_DEFAULT_FEATURES` build arguments.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub struct FeatureSelection {
+    /// Features to enable
+    #[serde(default)]
+    pub features: Vec<String>,
+    /// Whether to disable the default features
+    #[serde(default)]
+    pub no_default_features: bool,
+}
+
+impl FeatureSelection {
+    /// Check the feature names, and sort them so that the same selection always gives the same
+    /// hash
+    pub fn normalize(mut self) -> Result<Self, Error> {
+        for feature in &self.features {
+            // Names may refer to a dependency's feature as `dependency/feature`
+            if feature.is_empty()
+                || !feature
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '+' | '.' | '/'))
+            {
+                return Err(Error::InvalidFeature(feature.clone()));
+            }
+        }
+        self.features.sort();
+        self.features.dedup();
+        Ok(self)
+    }
+
+    /// Whether the program's default features are used
+    pub fn is_default(&self) -> bool {
+        self.features.is_empty() && !self.no_default_features
+    }
+
+    /// Arguments giving the selection to `docker build`
+    fn docker_build_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if !self.features.is_empty() {
+            args.push("--build-arg".to_string());
+            args.push(format!("FEATURES={}", self.features.join(",")));
+        }
+        if self.no_default_features {
+            args.push("--build-arg".to_string());
+            args.push("NO_DEFAULT_FEATURES=--no-default-features".to_string());
+        }
+        args
+    }
+
+    /// Arguments giving the selection to `cargo`
+    pub(crate) fn cargo_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if !self.features.is_empty() {
+            args.push(format!("--features={}", self.features.join(",")));
+        }
+        if self.no_default_features {
+            args.push("--no-default-features".to_string());
+        }
+        args
+    }
+}
+
+impl std::fmt::Display for FeatureSelection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.cargo_args().join(" "))
+    }
+}
+
+impl BuildOptions {
+    /// Whether a program previously built from the same source may be given instead of building
+    /// it again. This is not done when the build is wanted for more than the binary, or with an
+    /// image other than those the cached build may have used.
+    fn can_use_cache(&self) -> bool {
+        !self.no_cache
+            && self.test_run.is_none()
+            && !self.run_tests
+            && self.compare_with.is_none()
+            && self.verify.is_none()
+            && !self.optimize
+            && self.docker_image.is_none()
+            && self.docker_images.is_empty()
+    }
+}
+
+/// Check that a path to a program within a repository or archive is relative and does not
+/// lead outside of it
+pub fn validate_subdirectory(path: &str) -> Result<PathBuf, Error> {
+    let path = PathBuf::from(path);
+    if !path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(Error::InvalidPath);
+    }
+    Ok(path)
+}
+
+/// The directory of a program within a repository or archive once it is on disk. A subdirectory
+/// which passed [validate_subdirectory] may still lead elsewhere through a link in the
+/// repository, so where it leads must be within the repository too.
+fn program_directory(repo_path: &Path, subdirectory: Option<&Path>) -> Result<PathBuf, Error> {
+    let Some(subdirectory) = subdirectory else {
+        return Ok(repo_path.to_path_buf());
+    };
+    let program_path = repo_path.join(subdirectory);
+    let resolved = program_path
+        .canonicalize()
+        .map_err(|_| Error::InvalidPath)?;
+    if !resolved.starts_with(repo_path.canonicalize()?) {
+        return Err(Error::InvalidPath);
+    }
+    Ok(program_path)
+}
+
+/// A program which was successfully built and stored
+#[derive(Debug, Clone)]
+pub struct BuiltProgram {
+    pub hash: H256,
+    /// Results of the program's test suite, if it was run
+    pub test_summary: Option<TestSummary>,
+    /// The commit built, for programs built from git
+    pub commit: Option<String>,
+    /// Whether the program was given from an earlier build rather than being built
+    pub cached: bool,
+    /// The docker image the program was built with, pinned by digest if it could be resolved
+    pub docker_image: Option<String>,
+    /// Every program built, if it was built with several images
+    pub matrix: Vec<MatrixBuild>,
+    /// The programs built which had not been stored before this build
+    pub first_stored: Vec<H256>,
+}
+
+impl BuiltProgram {
+    /// The hashes of the programs built, with the images they were built with
+    pub fn builds(&self) -> Vec<MatrixBuild> {
+        if self.matrix.is_empty() {
+            vec![MatrixBuild {
+                hash: self.hash,
+                docker_image: self.docker_image.clone(),
+            }]
+        } else {
+            self.matrix.clone()
+        }
+    }
+}
+
+/// A program built with one of several docker images from the same source
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct MatrixBuild {
+    #[schemars(with = "String")]
+    pub hash: H256,
+    /// The image the program was built with, pinned by digest if it could be resolved
+    pub docker_image: Option<String>,
+}
+
+/// Input parameters for a build request
+pub enum BuildRequestType {
+    Git {
+        url: String,
+        git_ref: Option<String>,
+        auth: GitAuth,
+    },
+    Tar {
+        archive: SpooledArchive,
+    },
+    Zip {
+        raw_archive: Vec<u8>,
+    },
+    SourceUrl {
+        url: String,
+    },
+}
+
+impl BuildRequestType {
+    /// A description of the source code to be stored in the build record
+    fn source(&self) -> BuildSource {
+        match self {
+            BuildRequestType::Git { url, git_ref, .. } => BuildSource::Git {
+                url: url.clone(),
+                git_ref: git_ref.clone(),
+            },
+            BuildRequestType::Tar { archive } => BuildSource::Tar {
+                size: archive.size() as usize,
+            },
+            BuildRequestType::Zip { raw_archive } => BuildSource::Zip {
+                size: raw_archive.len(),
+            },
+            BuildRequestType::SourceUrl { url } => BuildSource::SourceUrl { url: url.clone() },
+        }
+    }
+}
+
+/// An item in the response stream for a program being built
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub enum BuildResponse {
+    /// The build is waiting for a worker, sent when it is queued and each time it moves up
+    Queued {
+        /// Place in the queue, where 1 is the next build to start
+        position: usize,
+        /// When the build is expected to start as a unix timestamp, judging by how long recent
+        /// builds took, if any have finished since the server started
+        estimated_start: Option<u64>,
+    },
+    /// The build has moved on to another step, sent as each step starts
+    Phase {
+        phase: BuildPhase,
+        /// When the step started, as a unix timestamp
+        started: u64,
+    },
+    /// A message from building on standard output
+    StdOut(String),
+    /// A message from building on standard error
+    StdErr(String),
+    /// Something the client should be made aware of which does not cause the build to fail
+    Warning(String),
+    /// Sent when a build has given nothing else for a while, such as while linking or pulling an
+    /// image, so that proxies do not close the connection for being idle
+    KeepAlive,
+    /// The final message on a successful build. The binary can be fetched from `/binary/:hash`,
+    /// and is only included here if requested.
+    Success {
+        #[schemars(with = "String")]
+        hash: H256,
+        /// Size of the binary in bytes
+        size: usize,
+        binary_filename: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        binary: Option<Vec<u8>>,
+        /// Signed statement of how the program was built, for programs built here
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        attestation: Option<Attestation>,
+        /// Every program built, when the program was built with several docker images. The
+        /// first is the one described by the other fields.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        matrix: Vec<MatrixBuild>,
+    },
+    /// The outcome of evaluating the built program with the sample input given in the request
+    TestResult { passed: bool, error: Option<String> },
+    /// The cost of evaluating the built program with the sample input given in the request
+    Benchmark(Benchmark),
+    /// How the built program differs from the binary given in the request
+    Comparison(BinaryComparison),
+    /// Whether the program built has the hash of the program or binary being verified
+    Verification {
+        #[schemars(with = "String")]
+        expected: H256,
+        #[schemars(with = "String")]
+        built: H256,
+        matches: bool,
+    },
+    /// Known vulnerabilities in the program's dependencies
+    Audit(AuditReport),
+    /// The memory and CPU the build's containers are using, sent periodically if the server
+    /// monitors builds
+    Resource {
+        /// Memory in use in bytes
+        memory: u64,
+        /// The build's memory limit in bytes, if it has one
+        memory_limit: Option<u64>,
+        /// CPU use since the last sample, where 100 is one CPU
+        cpu_percent: f64,
+    },
+    /// The program was registered on chain, as requested. This follows the success message.
+    Deployed(Deployment),
+    /// The final message on a failed build
+    Failure { kind: FailureKind, error: String },
+}
+
+/// For serializing and sending [BuildResponse]s to the client
+#[derive(Debug, Clone)]
+pub struct BuildResponder {
+    sender: futures_mpsc::Sender<Result<String, Error>>,
+    /// The last lines of output sent, shared between clones of this responder
+    log_tail: Arc<Mutex<VecDeque<String>>>,
+    /// All output sent, up to a limit, shared between clones of this responder
+    log: Arc<Mutex<String>>,
+    /// Set when the build should be abandoned
+    cancelled: Arc<AtomicBool>,
+    /// Also given every response, for builds submitted as jobs
+    events: Option<JobEvents>,
+    /// Responders of identical requests which arrived before this build finished, shared
+    /// between clones of this responder
+    followers: Arc<Mutex<Followers>>,
+    /// Carry on building once the client has disconnected, rather than stopping the build
+    finish_when_disconnected: bool,
+    /// When a response was last sent, shared between clones of this responder
+    last_sent: Arc<Mutex<Instant>>,
+}
+
+/// The responders following a build, which are given every response from when they joined
+#[derive(Debug, Default)]
+struct Followers {
+    responders: Vec<BuildResponder>,
+    /// Set once the final response has been sent, after which no more may join
+    finished: bool,
+}
+
+impl BuildResponder {
+    pub fn new(sender: futures_mpsc::Sender<Result<String, Error>>) -> Self {
+        Self {
+            sender,
+            log_tail: Default::default(),
+            log: Default::default(),
+            cancelled: Default::default(),
+            events: None,
+            followers: Default::default(),
+            finish_when_disconnected: false,
+            last_sent: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Also give every response to the subscribers of a job
+    pub fn with_events(mut self, events: JobEvents) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Tell the client the build has moved on to the given step
+    pub fn phase(&mut self, phase: BuildPhase) {
+        let _ = self.try_send(BuildResponse::Phase {
+            phase,
+            started: unix_time(),
+        });
+    }
+
+    /// Send a keep-alive if nothing has been sent for a while
+    pub fn keep_alive(&mut self) {
+        let quiet = self
+            .last_sent
+            .lock()
+            .is_ok_and(|last_sent| last_sent.elapsed() >= KEEP_ALIVE_INTERVAL);
+        if quiet {
+            let _ = self.try_send(BuildResponse::KeepAlive);
+        }
+    }
+
+    /// Carry on building once the client has disconnected, whatever the configuration says
+    pub fn finishing_when_disconnected(mut self) -> Self {
+        self.finish_when_disconnected = true;
+        self
+    }
+
+    /// A responder for a build which no client is waiting on, such as one requested at startup.
+    /// Responses are logged rather than sent anywhere.
+    pub fn detached() -> Self {
+        let (sender, mut receiver) = futures_mpsc::channel(1000);
+        tokio::spawn(async move {
+            while let Some(response) = receiver.next().await {
+                match response {
+                    Ok(response) => log::debug!("{}", response),
+                    Err(error) => log::error!("{}", error),
+                }
+            }
+        });
+        Self::new(sender)
+    }
+
+    /// Attempt to serialize and send a [BuildResponse] to the client, as a line of JSON
+    pub(crate) fn try_send(
+        &mut self,
+        build_response: BuildResponse,
+    ) -> Result<(), TrySendError<Result<String, Error>>> {
+        // Output is recorded while holding the followers, so that one which joins part way
+        // through is given each line exactly once
+        let followers = self.followers.lock();
+        if let BuildResponse::StdOut(output) | BuildResponse::StdErr(output) = &build_response {
+            self.record_output(output);
+        }
+        if let Ok(mut followers) = followers {
+            for follower in followers.responders.iter_mut() {
+                // A follower which has gone away does not affect the build
+                let _ = follower.try_send(build_response.clone());
+            }
+            if let BuildResponse::Success { .. } | BuildResponse::Failure { .. } = &build_response {
+                followers.finished = true;
+            }
+        }
+        // Subscribers to a job's events are kept alive by the event stream itself
+        if let Some(events) = &self.events {
+            if !matches!(build_response, BuildResponse::KeepAlive) {
+                events.send(&build_response);
+            }
+        }
+        if let Ok(mut last_sent) = self.last_sent.lock() {
+            *last_sent = Instant::now();
+        }
+        self.sender.try_send(
+            serde_json::to_string(&build_response)
+                .map(|json| json + "\n")
+                .map_err(Error::Json),
+        )
+    }
+
+    /// Give the responses of this build to the responder of an identical request from now on,
+    /// after the output so far. Gives false if the build has already finished.
+    pub(crate) fn add_follower(&self, mut follower: BuildResponder) -> bool {
+        let Ok(mut followers) = self.followers.lock() else {
+            return false;
+        };
+        if followers.finished {
+            return false;
+        }
+        let _ = follower.try_send(BuildResponse::Warning(
+            "An identical build was already queued or running, so its output is given instead"
+                .to_string(),
+        ));
+        let log = self.log();
+        if !log.is_empty() {
+            let _ = follower.try_send(BuildResponse::StdOut(log));
+        }
+        followers.responders.push(follower);
+        true
+    }
+
+    /// Attempt to send an [Error] to the client as the final [BuildResponse]
+    fn try_send_error(&mut self, error: &Error) {
+        let failure = BuildResponse::Failure {
+            kind: error.kind(),
+            error: error.to_string(),
+        };
+        if self.try_send(failure).is_err() {
+            log::error!("Client dropped connection while attempting to send error reponse");
+        }
+    }
+
+    /// Keep the last lines of build output so they can be stored with the build record, and
+    /// the full output so it can be stored with the program
+    fn record_output(&self, output: &str) {
+        if let Ok(mut log_tail) = self.log_tail.lock() {
+            log_tail.extend(output.lines().map(|line| line.to_string()));
+            while log_tail.len() > LOG_TAIL_LINES {
+                log_tail.pop_front();
+            }
+        }
+        if let Ok(mut log) = self.log.lock() {
+            if log.len() + output.len() <= MAX_BUILD_LOG_SIZE {
+                log.push_str(output);
+            }
+        }
+    }
+
+    /// A flag which cancels the build when set
+    pub fn cancellation_flag(&self) -> Arc<AtomicBool> {
+        self.cancelled.clone()
+    }
+
+    /// Whether the build has been cancelled
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Whether no one is waiting for the build's responses any more, which is the case once the
+    /// client and any followers have disconnected
+    fn is_disconnected(&self) -> bool {
+        self.sender.is_closed()
+            && self
+                .followers
+                .lock()
+                .map(|followers| {
+                    followers
+                        .responders
+                        .iter()
+                        .all(BuildResponder::is_disconnected)
+                })
+                .unwrap_or(true)
+    }
+
+    /// Check whether the build should carry on, giving an error if it has been cancelled or its
+    /// client has disconnected, unless it is to be finished regardless
+    fn check_wanted(&self) -> Result<(), Error> {
+        if self.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        if !self.finish_when_disconnected && self.is_disconnected() {
+            return Err(Error::ClientDisconnected);
+        }
+        Ok(())
+    }
+
+    /// Get all build output
+    fn log(&self) -> String {
+        self.log.lock().map(|log| log.clone()).unwrap_or_default()
+    }
+
+    /// Get the last lines of build output
+    fn log_tail(&self) -> Vec<String> {
+        self.log_tail
+            .lock()
+            .map(|log_tail| log_tail.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Handle incoming requests to build a program from the client, building up to the given number
+/// of programs at a time
+pub async fn handle_build_requests(
+    build_requests_rx: Receiver<BuildRequest>,
+    db: sled::Db,
+    notifier: Arc<RwLock<Option<Notifier>>>,
+    config: Arc<RwLock<Arc<Config>>>,
+    signer: BuildSigner,
+    builder_pool: BuilderPool,
+) {
+    let workers = config
+        .read()
+        .map(|config| config.build_workers())
+        .unwrap_or(1);
+    let build_requests_rx = Arc::new(AsyncMutex::new(build_requests_rx));
+    let prune_trigger = PruneTrigger::default();
+    let worker_handles: Vec<_> = (0..workers)
+        .map(|worker| {
+            let build_requests_rx = build_requests_rx.clone();
+            let db = db.clone();
+            let notifier = notifier.clone();
+            let config = config.clone();
+            let signer = signer.clone();
+            let builder_pool = builder_pool.clone();
+            let prune_trigger = prune_trigger.clone();
+            tokio::spawn(async move {
+                let program_builder = ProgramBuilder {
+                    db: db.clone(),
+                    signer: signer.clone(),
+                    builder_pool,
+                    prune_trigger,
+                };
+                loop {
+                    // The lock is released as soon as a request is taken, so that other workers
+                    // can take the next one
+                    let Some(build_request) = build_requests_rx.lock().await.recv().await else {
+                        break;
+                    };
+                    log::debug!("Build worker {} took a build request", worker);
+                    handle_build_request(&program_builder, build_request, &db, &notifier, &config)
+                        .await;
+                }
+            })
+        })
+        .collect();
+    for handle in worker_handles {
+        if let Err(error) = handle.await {
+            log::error!("Build worker stopped: {}", error);
+        }
+    }
+}
+
+/// Build a single program, storing a record of the build and notifying the submitter
+async fn handle_build_request(
+    program_builder: &ProgramBuilder,
+    build_request: BuildRequest,
+    db: &sled::Db,
+    notifier: &RwLock<Option<Notifier>>,
+    config: &RwLock<Arc<Config>>,
+) {
+    let mut build_request = build_request;
+    let build_timer = build_request.queue_entry.take().map(QueueEntry::start);
+    if let Ok(config) = config.read() {
+        build_request.options.resource_limits = config.build_limits.clone();
+        build_request.options.allowed_docker_images = config.allowed_docker_images.clone();
+        build_request.options.docker = config.docker.clone();
+        build_request.options.container_engine = config.container_engine;
+        build_request.options.remote_build = config.builder_pool.is_some();
+        build_request.responder.finish_when_disconnected |= config.finish_disconnected_builds;
+        // Builders of a pool decide for themselves how to build
+        if !build_request.options.remote_build {
+            build_request.options.native_build = config.native_build.clone();
+            build_request.options.kubernetes = config.kubernetes.clone();
+            build_request.options.cargo_cache = config.cargo_cache.clone();
+            build_request.options.sccache = config.sccache.clone();
+        }
+    }
+    let mut responder = build_request.responder;
+    let source = build_request.request_type.source();
+    // Jobs may be cancelled, and clients may disconnect, while they are queued
+    let wanted = responder.check_wanted();
+    let cancelled = wanted.is_err();
+    if let (Some(job_id), false) = (build_request.job_id, cancelled) {
+        if let Err(error) = update_job(db, job_id, JobStatus::Building, None) {
+            log::error!("Could not update job {}: {}", job_id, error);
+        }
+    }
+    let result = if let Err(error) = wanted {
+        Err(error)
+    } else {
+        match build_request.request_type {
+            BuildRequestType::Git { url, git_ref, auth } => {
+                let credentials = config
+                    .read()
+                    .map(|config| config.git_credentials.clone())
+                    .unwrap_or_default();
+                let auth =
+                    auth.or_configured(&credentials, &url, build_request.namespace.as_deref());
+                // The server's credentials are only for its tenants' own private programs
+                if auth.uses_configured()
+                    && build_request.options.visibility != Some(Visibility::Private)
+                {
+                    build_request.options.visibility = Some(Visibility::Private);
+                    let _ = responder.try_send(BuildResponse::Warning(
+                        "Building as a private program, as the repository is cloned with credentials configured on the server".to_string(),
+                    ));
+                }
+                program_builder
+                    .add_program_git(
+                        url,
+                        git_ref.as_deref(),
+                        &auth,
+                        &build_request.options,
+                        responder.clone(),
+                    )
+                    .await
+            }
+            BuildRequestType::Tar { archive } => {
+                program_builder
+                    .add_program_tar(archive, &build_request.options, responder.clone())
+                    .await
+            }
+            BuildRequestType::Zip { raw_archive } => {
+                program_builder
+                    .add_program_zip(raw_archive, &build_request.options, responder.clone())
+                    .await
+            }
+            BuildRequestType::SourceUrl { url } => {
+                program_builder
+                    .add_program_source_url(&url, &build_request.options, responder.clone())
+                    .await
+            }
+        }
+    };
+    if let (Some(build_timer), false) = (build_timer, cancelled) {
+        build_timer.finish();
+    }
+
+    // A cached build has no output, so keep the log of the build which produced it
+    let result = result.and_then(|built| {
+        if built.cached {
+            return Ok(built);
+        }
+        store_build_log(db, built.hash, &responder.log()).map(|()| built)
+    });
+
+    // A verifying rebuild which got the expected hash counts once for whoever asked for it
+    if let (Ok(built), Some(expected)) = (&result, build_request.options.verify) {
+        if built.hash == expected {
+            let requester = build_request
+                .namespace
+                .as_deref()
+                .unwrap_or(ANONYMOUS_REQUESTER);
+            if let Err(error) = record_local_reproduction(db, expected, requester) {
+                log::error!("Could not record reproduction of {:?}: {}", expected, error);
+            }
+        }
+    }
+
+    // Every program of a matrix belongs to the namespace and has the visibility requested
+    let result = match (result, &build_request.namespace) {
+        (Ok(built), Some(namespace)) => built
+            .builds()
+            .iter()
+            .try_for_each(|matrix_build| add_to_namespace(db, namespace, matrix_build.hash))
+            .map(|()| built),
+        (result, _) => result,
+    };
+
+    // A tenant owns the programs it is the first to store, and only the owner may later change
+    // their visibility
+    let visibility = build_request.options.visibility;
+    let result = match (result, &build_request.namespace) {
+        (Ok(built), namespace) => built
+            .builds()
+            .iter()
+            .try_for_each(|matrix_build| {
+                let set = match (visibility, namespace) {
+                    _ if built.first_stored.contains(&matrix_build.hash) => set_initial_visibility(
+                        db,
+                        matrix_build.hash,
+                        visibility.unwrap_or_default(),
+                        namespace.as_deref(),
+                    )?,
+                    (Some(visibility), Some(namespace)) => {
+                        set_visibility(db, matrix_build.hash, visibility, namespace)?
+                    }
+                    (Some(_), None) => false,
+                    (None, _) => true,
+                };
+                // The program was built anyway, so this does not fail the build
+                if !set {
+                    let _ = responder.try_send(BuildResponse::Warning(format!(
+                        "Program {} was first stored by another build, so its visibility was not changed",
+                        hex::encode(matrix_build.hash)
+                    )));
+                }
+                Ok::<(), Error>(())
+            })
+            .map(|()| built),
+        (result, _) => result,
+    };
+
+    // Registering the program is not part of the build, so failing to do so is only a warning
+    if let (Ok(built), true) = (&result, build_request.options.deploy) {
+        let chain = config.read().ok().and_then(|config| config.chain.clone());
+        let deployment = match chain {
+            Some(chain) => deploy_stored_program(db, &chain, built.hash).await,
+            None => Err(DeploymentError::NoChain),
+        };
+        let response = match deployment {
+            Ok(deployment) => BuildResponse::Deployed(deployment),
+            Err(error) => BuildResponse::Warning(error.to_string()),
+        };
+        let _ = responder.try_send(response);
+    }
+
+    // Ask peers to check the hash and publish the program to IPFS in the background, as these
+    // may take a while. Private programs are not shared.
+    let builds = result
+        .as_ref()
+        .map(BuiltProgram::builds)
+        .unwrap_or_default();
+    for matrix_build in &builds {
+        let current_config = config.read().map(|config| config.clone()).ok();
+        let cached = result.as_ref().is_ok_and(|built| built.cached);
+        let listed = get_visibility(db, matrix_build.hash.as_ref())
+            .map(|visibility| visibility.is_listed())
+            .unwrap_or_default();
+        if let (Some(current_config), false, true) = (current_config, cached, listed) {
+            let hash = matrix_build.hash;
+            if !current_config.peers.is_empty() {
+                let db = db.clone();
+                let peers = current_config.peers.clone();
+                tokio::spawn(async move {
+                    if let Err(error) = compare_with_peers(&db, &peers, hash).await {
+                        log::error!("Could not compare {:?} with peers: {}", hash, error);
+                    }
+                });
+            }
+            if let Some(ipfs) = current_config.ipfs.clone() {
+                let db = db.clone();
+                tokio::spawn(async move {
+                    let archive = current_config.archive.as_ref();
+                    match pin_program(&db, archive, &ipfs, hash).await {
+                        Ok(pins) => log::info!("Pinned {:?} to IPFS as {}", hash, pins.binary),
+                        Err(error) => log::error!("Could not pin {:?} to IPFS: {}", hash, error),
+                    }
+                });
+            }
+        }
+    }
+
+    let test_summary = match &result {
+        Ok(built) => built.test_summary.clone(),
+        Err(Error::TestsFailed(summary)) => Some(summary.clone()),
+        Err(_) => None,
+    };
+    let commit = result.as_ref().ok().and_then(|built| built.commit.clone());
+    let docker_image = result
+        .as_ref()
+        .ok()
+        .and_then(|built| built.docker_image.clone());
+
+    let status = match result {
+        Ok(built) => BuildStatus::Succeeded { hash: built.hash },
+        Err(error) => {
+            responder.try_send_error(&error);
+            BuildStatus::Failed {
+                kind: error.kind(),
+                error: error.to_string(),
+            }
+        }
+    };
+
+    // The other programs of a matrix each get a record of their own
+    for matrix_build in builds.iter().skip(1) {
+        if let Err(error) = insert_build_record(
+            db,
+            source.clone(),
+            BuildStatus::Succeeded {
+                hash: matrix_build.hash,
+            },
+            responder.log_tail(),
+            test_summary.clone(),
+            commit.clone(),
+            matrix_build.docker_image.clone(),
+            build_request.namespace.clone(),
+        ) {
+            log::error!("Could not store build record: {}", error);
+        }
+    }
+
+    let job_status = JobStatus::from(&status);
+    let record = insert_build_record(
+        db,
+        source,
+        status,
+        responder.log_tail(),
+        test_summary,
+        commit,
+        docker_image,
+        build_request.namespace.clone(),
+    );
+    if let Some(job_id) = build_request.job_id {
+        let build_id = record.as_ref().ok().map(|record| record.id);
+        if let Err(error) = update_job(db, job_id, job_status, build_id) {
+            log::error!("Could not update job {}: {}", job_id, error);
+        }
+    }
+
+    if let Ok(config) = config.read() {
+        program_builder
+            .prune_trigger
+            .build_finished(&config, Some(db));
+    }
+
+    let record = match record {
+        Ok(record) => record,
+        Err(error) => {
+            log::error!("Could not store build record: {}", error);
+            return;
+        }
+    };
+
+    // Send notifications in the background so as not to hold up the next build
+    let current_notifier = notifier.read().ok().and_then(|notifier| notifier.clone());
+    if let (Some(notifier), Some(email)) = (current_notifier, build_request.notify_email) {
+        tokio::spawn(async move {
+            if let Err(error) = notifier.notify(&email, &record).await {
+                log::error!("Could not send notification to {}: {}", email, error);
+            }
+        });
+    }
+}
+
+/// Register a program which has been stored on chain, unless it is known to be registered
+/// already, as registering it again would only spend the deployer's funds
+async fn deploy_stored_program(
+    db: &sled::Db,
+    chain: &ChainConfig,
+    hash: H256,
+) -> Result<Deployment, DeploymentError> {
+    if let Some(status) = get_deployment_status(db, hash.as_ref())?.filter(|status| status.deployed)
+    {
+        return Err(DeploymentError::AlreadyDeployed(status.deployer));
+    }
+    let binary = get_binary(db, None, hash.as_ref())
+        .await?
+        .ok_or(Error::BinaryNotFound)?;
+    let entropy_metadata = get_entropy_metadata(db, hash.as_ref())?.unwrap_or_default();
+    let deployment = deploy_program(chain, &binary, &entropy_metadata).await?;
+    store_deployment_status(
+        db,
+        hash.as_ref(),
+        &DeploymentStatus {
+            deployed: true,
+            deployer: Some(deployment.deployer.clone()),
+            checked_at: unix_time(),
+        },
+    )?;
+    Ok(deployment)
+}
+
+/// Builds programs and stores metadata
+struct ProgramBuilder {
+    db: sled::Db,
+    /// Signs an attestation of each program built
+    signer: BuildSigner,
+    /// Builders which builds are sent to, if a pool is configured
+    builder_pool: BuilderPool,
+    /// Counts builds towards pruning unused images, shared by the build workers
+    prune_trigger: PruneTrigger,
+}
+
+impl ProgramBuilder {
+    /// Add a program given as a location of a git repo
+    pub async fn add_program_git(
+        &self,
+        git_url: String,
+        git_ref: Option<&str>,
+        auth: &GitAuth,
+        options: &BuildOptions,
+        mut response_tx: BuildResponder,
+    ) -> Result<BuiltProgram, Error> {
+        // Git would take these as options, such as `--upload-pack` which runs a command
+        if !is_git_argument(&git_url) || !git_ref.is_none_or(is_git_argument) {
+            return Err(Error::GitClone(INVALID_GIT_ARGUMENT.to_string()));
+        }
+        let temp_dir = build_temp_dir()?;
+        response_tx.phase(BuildPhase::Clone);
+        let (commit, tag) = {
+            let (repo_path, git_url) = (temp_dir.path().to_path_buf(), git_url.clone());
+            let (git_ref, auth) = (git_ref.map(str::to_string), auth.clone());
+            run_blocking(move || {
+                clone_repo(&repo_path, &git_url, git_ref.as_deref(), &auth)?;
+                Ok((head_commit(&repo_path)?, head_tag(&repo_path)?))
+            })
+            .await?
+        };
+        let mut provenance = GitProvenance {
+            url: git_url.clone(),
+            commit: commit.clone(),
+            tag,
+            path: options.path.as_ref().map(|path| path.display().to_string()),
+            docker_image: None,
+        };
+        if options.can_use_cache() {
+            if let Some(built) = self
+                .cached_build(&git_url, &commit, options, response_tx.clone())
+                .await?
+            {
+                store_provenance(&self.db, built.hash, &provenance)?;
+                return Ok(built);
+            }
+        }
+
+        let built = self
+            .add_program(temp_dir.path(), Some(&commit), options, response_tx)
+            .await?;
+        // Submitting the commit again builds the whole matrix again
+        if built.matrix.is_empty() {
+            record_commit_build(
+                &self.db,
+                &git_url,
+                &commit,
+                options.path.as_deref(),
+                &options.features,
+                built.hash,
+            )?;
+        }
+        for matrix_build in built.builds() {
+            provenance.docker_image = matrix_build.docker_image;
+            store_provenance(&self.db, matrix_build.hash, &provenance)?;
+        }
+
+        // Changelogs are a nicety, so failing to make one does not fail the build
+        let changelog = {
+            let (db, repo_path) = (self.db.clone(), temp_dir.path().to_path_buf());
+            let git_ref = git_ref.map(str::to_string);
+            let hashes: Vec<H256> = built.builds().iter().map(|build| build.hash).collect();
+            run_blocking(move || {
+                add_changelog(
+                    &db,
+                    &repo_path,
+                    &git_url,
+                    git_ref.as_deref(),
+                    &commit,
+                    &hashes,
+                )
+            })
+            .await
+        };
+        if let Err(error) = changelog {
+            log::warn!("Could not add changelog: {}", error);
+        }
+        Ok(built)
+    }
+
+    /// Give the program previously built from the same commit of a git repository, if there is
+    /// one and its binary is still stored
+    async fn cached_build(
+        &self,
+        git_url: &str,
+        commit: &str,
+        options: &BuildOptions,
+        mut response_tx: BuildResponder,
+    ) -> Result<Option<BuiltProgram>, Error> {
+        let Some(hash) = get_commit_build(
+            &self.db,
+            git_url,
+            commit,
+            options.path.as_deref(),
+            &options.features,
+        )?
+        else {
+            return Ok(None);
+        };
+        let (Some(metadata), Some(binary)) = (
+            self.db.get(hash)?,
+            get_binary(&self.db, None, hash.as_ref()).await?,
+        ) else {
+            return Ok(None);
+        };
+        let metadata: serde_json::Value = serde_json::from_slice(&metadata)?;
+        // Cargo names the binary after the package, with dashes replaced by underscores
+        let binary_filename = format!(
+            "{}.wasm",
+            metadata["name"]
+                .as_str()
+                .unwrap_or("program")
+                .replace('-', "_")
+        );
+        log::info!("Using cached build {:?} of commit {}", hash, commit);
+        record_access(&self.db, hash.as_ref())?;
+
+        let _ = response_tx.try_send(BuildResponse::Warning(format!(
+            "Commit {} has already been built, so the stored program is given. Use no-cache=true to build it again.",
+            commit
+        )));
+        response_tx
+            .try_send(BuildResponse::Success {
+                hash,
+                size: binary.len(),
+                binary_filename,
+                binary: options.include_binary.then_some(binary),
+                attestation: get_attestation(&self.db, hash.as_ref())?,
+                matrix: Vec::new(),
+            })
+            .map_err(|_| Error::Mpsc)?;
+        Ok(Some(BuiltProgram {
+            hash,
+            test_summary: None,
+            commit: Some(commit.to_string()),
+            cached: true,
+            docker_image: None,
+            matrix: Vec::new(),
+            first_stored: Vec::new(),
+        }))
+    }
+
+    /// Add a program given as a tar achive
+    async fn add_program_tar(
+        &self,
+        archive: SpooledArchive,
+        options: &BuildOptions,
+        mut response_tx: BuildResponder,
+    ) -> Result<BuiltProgram, Error> {
+        let temp_dir = build_temp_dir()?;
+        response_tx.phase(BuildPhase::Unpack);
+        let destination = temp_dir.path().to_path_buf();
+        let max_size = options.resource_limits.max_unpacked_size();
+        run_blocking(move || unpack_tar(&archive, &destination, max_size)).await?;
+
+        self.add_program(temp_dir.path(), None, options, response_tx)
+            .await
+    }
+
+    /// Add a program given as a zip archive. Archives downloaded from git hosting services have
+    /// the project in a single directory, so if there is no manifest at the root of the archive
+    /// but a single directory, that is used.
+    async fn add_program_zip(
+        &self,
+        input: Vec<u8>,
+        options: &BuildOptions,
+        mut response_tx: BuildResponder,
+    ) -> Result<BuiltProgram, Error> {
+        let temp_dir = build_temp_dir()?;
+        response_tx.phase(BuildPhase::Unpack);
+        let destination = temp_dir.path().to_path_buf();
+        let max_size = options.resource_limits.max_unpacked_size();
+        run_blocking(move || unpack_zip(&input, &destination, max_size)).await?;
+
+        let mut project_path = temp_dir.path().to_path_buf();
+        if !project_path.join("Cargo.toml").exists() {
+            let entries = std::fs::read_dir(&project_path)?.collect::<Result<Vec<_>, _>>()?;
+            if let [entry] = entries.as_slice() {
+                if entry.file_type()?.is_dir() {
+                    project_path = entry.path();
+                }
+            }
+        }
+
+        self.add_program(&project_path, None, options, response_tx)
+            .await
+    }
+
+    /// Add a program given as the URL of a single source file, which is built in a project
+    /// generated from the standard template
+    async fn add_program_source_url(
+        &self,
+        url: &str,
+        options: &BuildOptions,
+        mut response_tx: BuildResponder,
+    ) -> Result<BuiltProgram, Error> {
+        response_tx.phase(BuildPhase::Unpack);
+        let source = fetch_source_file(url).await?;
+        let raw_archive = ProgramTemplate::default().with_source(source).to_tar()?;
+        let archive = SpooledArchive::from_bytes(&raw_archive)?;
+        self.add_program_tar(archive, options, response_tx).await
+    }
+
+    /// Build a program, and save metadata under the hash of its binary
+    async fn add_program(
+        &self,
+        repo_path: &Path,
+        commit: Option<&str>,
+        options: &BuildOptions,
+        mut response_tx: BuildResponder,
+    ) -> Result<BuiltProgram, Error> {
+        let (repo_dir, subdirectory) = (repo_path.to_path_buf(), options.path.clone());
+        let program_path =
+            run_blocking(move || program_directory(&repo_dir, subdirectory.as_deref())).await?;
+        let manifest_path = program_path.join("Cargo.toml");
+        response_tx.phase(BuildPhase::Metadata);
+
+        // Get metadata from Cargo.toml file
+        let metadata = run_blocking(move || {
+            Ok(MetadataCommand::new()
+                .manifest_path(manifest_path)
+                .features(CargoOpt::AllFeatures)
+                .exec()?)
+        })
+        .await?;
+
+        let root_package_metadata = metadata
+            .root_package()
+            .ok_or(Error::MetadataMissingRootPackage)?;
+
+        let dependencies = resolve_dependencies(&metadata);
+
+        // Check the dependencies for known vulnerabilities. This does not fail the build, as the
+        // advisory database may not be reachable.
+        // Cargo writes a lockfile when getting the metadata if the program has none
+        let lockfile_path = metadata.workspace_root.join("Cargo.lock");
+        let lockfile = std::fs::read_to_string(&lockfile_path)?;
+        let audit_path = lockfile_path.clone().into_std_path_buf();
+        let audit = match run_blocking(move || Ok(audit_lockfile(&audit_path))).await? {
+            Ok(report) => {
+                let _ = response_tx.try_send(BuildResponse::Audit(report.clone()));
+                Some(report)
+            }
+            Err(error) => {
+                let _ = response_tx.try_send(BuildResponse::Warning(error.to_string()));
+                None
+            }
+        };
+
+        // Get the docker image name from Cargo.toml, if there is one
+        let mut entropy_metadata = extract_metadata(&root_package_metadata.metadata);
+        entropy_metadata.features = options.features.clone();
+        // Broken schemas cannot be fixed once the program is deployed under this hash
+        entropy_metadata.validate_schemas()?;
+
+        let deadline = Instant::now() + BUILD_TIMEOUT;
+
+        // An image given to rebuild the program with takes precedence over a matrix of images.
+        // Native builds use no image.
+        let image_names = match &options.docker_image {
+            _ if options.native_build.is_some() => vec![None],
+            Some(docker_image) => vec![Some(docker_image.clone())],
+            None if !options.docker_images.is_empty() => {
+                options.docker_images.iter().cloned().map(Some).collect()
+            }
+            None => match entropy_metadata.docker_images.take() {
+                Some(docker_images) if !docker_images.is_empty() => {
+                    docker_images.into_iter().map(Some).collect()
+                }
+                _ => vec![entropy_metadata
+                    .docker_image
+                    .clone()
+                    .or_else(|| options.docker.default_image.clone())],
+            },
+        };
+        let is_matrix = image_names.len() > 1;
+        // Images are checked before they are pulled
+        for image_name in image_names.iter().flatten() {
+            check_docker_image(&options.allowed_docker_images, image_name)?;
+        }
+
+        // Pin the images by digest, so that the program can later be rebuilt with exactly the
+        // same image even if its tag has moved. Builders of a pool pin the images they pull, and
+        // a Kubernetes cluster pulls its own.
+        let backend = options.container_engine.backend();
+        let docker_images: Vec<Option<String>> = if options.remote_build
+            || (options.kubernetes.is_some() && options.native_build.is_none())
+        {
+            image_names.clone()
+        } else {
+            let image_names = image_names.clone();
+            run_blocking(move || {
+                Ok(image_names
+                    .iter()
+                    .map(|image_name| {
+                        image_name.as_deref().map(|image_name| {
+                            let image_name = backend.image_name(image_name);
+                            pin_image(backend, &image_name).unwrap_or(image_name)
+                        })
+                    })
+                    .collect())
+            })
+            .await?
+        };
+
+        // Snapshot the source before anything is written into it by the tests or build
+        let source_snapshot = {
+            let program_path = program_path.clone();
+            run_blocking(move || snapshot_source(&program_path)).await?
+        };
+
+        // Run the program's own tests in the same image it is built with, which is the first
+        // image of a matrix. A builder of the pool runs them along with its first build.
+        let mut test_summary = if options.run_tests && !options.remote_build {
+            response_tx.phase(BuildPhase::Tests);
+            let summary = test_program(
+                options,
+                &program_path,
+                docker_images[0].as_deref(),
+                &mut response_tx,
+                deadline,
+            )
+            .await?;
+            check_test_summary(&summary, options, &mut response_tx)?;
+            Some(summary)
+        } else {
+            None
+        };
+
+        let binary_dir = match &options.native_build {
+            Some(native) => native.binary_dir(&program_path),
+            None => program_path.join("binary_dir"),
+        };
+        let mut matrix: Vec<MatrixBuild> = Vec::new();
+        let mut first_stored = Vec::new();
+        let mut success = None;
+        for (image_name, docker_image) in image_names.into_iter().zip(docker_images) {
+            if is_matrix {
+                let _ = response_tx.try_send(BuildResponse::StdOut(format!(
+                    "Building with {}\n",
+                    docker_image.as_deref().unwrap_or(DEFAULT_DOCKER_IMAGE)
+                )));
+                // The binary built with the previous image must not be taken for this one's
+                if binary_dir.exists() {
+                    std::fs::remove_dir_all(&binary_dir)?;
+                }
+                // Each program of a matrix is stored with the image it was built with
+                entropy_metadata.docker_image = image_name;
+            }
+
+            // Build the program, or have a builder of the pool build it and write the binary it
+            // gives where it would have been built here
+            response_tx.phase(BuildPhase::Compile);
+            let docker_image = if options.remote_build {
+                let built = build_remotely(
+                    &self.builder_pool,
+                    RemoteBuild {
+                        id: 0,
+                        docker_image: docker_image.clone(),
+                        features: options.features.clone(),
+                        run_tests: options.run_tests && matrix.is_empty(),
+                        require_tests_pass: options.require_tests_pass,
+                    },
+                    source_snapshot.clone(),
+                    options,
+                    &mut response_tx,
+                    deadline,
+                )
+                .await?;
+                if built.test_summary.is_some() {
+                    test_summary = built.test_summary;
+                }
+                std::fs::create_dir_all(&binary_dir)?;
+                std::fs::write(binary_dir.join(built.binary_filename), built.binary)?;
+                built.docker_image
+            } else {
+                compile_program(
+                    options,
+                    &program_path,
+                    docker_image.as_deref(),
+                    &binary_dir,
+                    &mut response_tx,
+                    deadline,
+                )
+                .await?;
+                docker_image
+            };
+
+            response_tx.phase(BuildPhase::Collect);
+            let binary_filename = get_binary_filename(binary_dir.clone()).await?;
+
+            // Optimize before hashing, as the optimized binary is the one which will be deployed
+            let optimization = if options.optimize || entropy_metadata.optimize.unwrap_or_default()
+            {
+                let binary_filename = binary_filename.clone();
+                let optimization = run_blocking(move || optimize_binary(&binary_filename)).await?;
+                log::info!("Optimized binary with {}", optimization.wasm_opt_version);
+                Some(optimization)
+            } else {
+                None
+            };
+
+            let binary_filename_string = binary_filename
+                .file_name()
+                .and_then(|o| o.to_str())
+                .map(|o| o.to_string())
+                .unwrap_or_else(|| "program.wasm".to_string());
+
+            // Read the wasm binary, hashing it with the metadata as it is read. The binary is
+            // still kept whole, as sled stores a value as one buffer and the validator parses
+            // the component as a whole.
+            response_tx.phase(BuildPhase::Hash);
+            let mut hasher = entropy_metadata.program_hasher();
+            let mut binary = vec![];
+            {
+                let mut file = File::open(binary_filename).await?;
+                let mut chunk = vec![0; HASH_CHUNK_SIZE];
+                loop {
+                    let read_bytes = file.read(&mut chunk).await?;
+                    if read_bytes == 0 {
+                        break;
+                    }
+                    hasher.update(&chunk[..read_bytes]);
+                    binary.extend_from_slice(&chunk[..read_bytes]);
+                }
+            }
+            // A broken binary would be stored under a hash which can never be evaluated
+            validate_wasm(&binary)?;
+            let digests = hasher.finalize_digests();
+            let hash = digests.blake2_256;
+            log::info!("Hashed binary {:?}", hash);
+
+            // Only a rebuild asked for to verify the program counts as a reproduction of it
+            if self.db.contains_key(hash)? {
+                log::info!("Reproduced existing program {:?}", hash);
+            } else {
+                first_stored.push(hash);
+            }
 
-const OUTPUT_BUFFER_SIZE: usize = 10_000;
+            // Write metadata to db
+            response_tx.phase(BuildPhase::Store);
+            let root_package_metadata_json = serde_json::to_string(&root_package_metadata)?;
+            self.db
+                .insert(hash, root_package_metadata_json.as_bytes())?;
+            store_binary(&self.db, hash, &binary)?;
+            store_entropy_metadata(&self.db, hash, &entropy_metadata)?;
+            store_digests(&self.db, &digests)?;
+            store_dependencies(&self.db, hash, &dependencies)?;
+            store_lockfile(&self.db, hash, &lockfile)?;
+            store_sbom(
+                &self.db,
+                hash,
+                &generate_sbom(&metadata, &dependencies, &digests)?,
+            )?;
+            if let Some(audit) = &audit {
+                store_audit(&self.db, hash, audit)?;
+            }
+            if let Some(optimization) = &optimization {
+                store_optimization(&self.db, hash, optimization)?;
+            }
+            if source_snapshot.len() <= MAX_SOURCE_SNAPSHOT_SIZE {
+                store_source(&self.db, hash, &source_snapshot)?;
+            } else {
+                log::warn!(
+                    "Not keeping source of {:?}, as its snapshot is {} bytes",
+                    hash,
+                    source_snapshot.len()
+                );
+            }
+            record_access(&self.db, hash.as_ref())?;
+            add_search_terms(
+                &self.db,
+                hash,
+                &serde_json::to_value(root_package_metadata)?,
+            )?;
 
-/// A request to build a program
-pub struct BuildRequest {
-    request_type: BuildRequestType,
-    responder: BuildResponder,
+            // Warn if a different binary was previously stored under the same name and version,
+            // other than one built with another image of this matrix
+            let mut collisions = add_name_version(
+                &self.db,
+                &root_package_metadata.name,
+                &root_package_metadata.version.to_string(),
+                hash,
+            )?;
+            collisions.retain(|collision| !matrix.iter().any(|built| built.hash == *collision));
+            if !collisions.is_empty() {
+                let warning = format!(
+                    "{} version {} was already stored with a different hash: {:?}",
+                    root_package_metadata.name, root_package_metadata.version, collisions
+                );
+                log::warn!("{}", warning);
+                // This is not fatal, so if the client has gone we still carry on
+                let _ = response_tx.try_send(BuildResponse::Warning(warning));
+            }
+
+            if let Some(test_run) = options.test_run.clone() {
+                let result = test_run.clone().run(binary.clone()).await;
+                let passed = result.is_ok();
+                if let Err(error) = &result {
+                    log::warn!("Test run of {:?} failed: {}", hash, error);
+                }
+                response_tx
+                    .try_send(BuildResponse::TestResult {
+                        passed,
+                        error: result.err(),
+                    })
+                    .map_err(|_| Error::Mpsc)?;
+
+                if options.benchmark && passed {
+                    match test_run.benchmark(binary.clone()).await {
+                        Ok(benchmark) => {
+                            store_benchmark(&self.db, hash, &benchmark)?;
+                            response_tx
+                                .try_send(BuildResponse::Benchmark(benchmark))
+                                .map_err(|_| Error::Mpsc)?;
+                        }
+                        Err(error) => {
+                            let _ = response_tx.try_send(BuildResponse::Warning(format!(
+                                "Benchmark failed: {}",
+                                error
+                            )));
+                        }
+                    }
+                }
+            }
+
+            if let Some(given) = &options.compare_with {
+                let comparison = BinaryComparison::new(given, &binary, &entropy_metadata);
+                let given_hash = comparison.given_hash;
+                response_tx
+                    .try_send(BuildResponse::Comparison(comparison))
+                    .map_err(|_| Error::Mpsc)?;
+                if options.verify_given {
+                    response_tx
+                        .try_send(BuildResponse::Verification {
+                            expected: given_hash,
+                            built: hash,
+                            matches: given_hash == hash,
+                        })
+                        .map_err(|_| Error::Mpsc)?;
+                }
+            }
+
+            if let Some(expected) = options.verify {
+                response_tx
+                    .try_send(BuildResponse::Verification {
+                        expected,
+                        built: hash,
+                        matches: hash == expected,
+                    })
+                    .map_err(|_| Error::Mpsc)?;
+            }
+
+            let attestation = self.signer.attest(
+                hash,
+                commit.map(str::to_string),
+                docker_image.clone(),
+                unix_time(),
+            );
+            store_attestation(&self.db, &attestation)?;
+
+            // The success message is given for the first image, listing the whole matrix
+            if success.is_none() {
+                success = Some((binary, binary_filename_string, attestation));
+            }
+            matrix.push(MatrixBuild { hash, docker_image });
+        }
+
+        let (binary, binary_filename, attestation) = success.ok_or(Error::BinaryNotFound)?;
+        let first = matrix[0].clone();
+        if !is_matrix {
+            matrix.clear();
+        }
+        response_tx
+            .try_send(BuildResponse::Success {
+                hash: first.hash,
+                size: binary.len(),
+                binary_filename,
+                binary: options.include_binary.then_some(binary),
+                attestation: Some(attestation),
+                matrix: matrix.clone(),
+            })
+            .map_err(|_| Error::Mpsc)?;
+        Ok(BuiltProgram {
+            hash: first.hash,
+            test_summary,
+            commit: commit.map(str::to_string),
+            cached: false,
+            docker_image: first.docker_image,
+            matrix,
+            first_stored,
+        })
+    }
 }
 
-impl BuildRequest {
-    /// A new build request with a git url
-    pub fn new_git(url: String, responder: BuildResponder) -> Self {
-        Self {
-            request_type: BuildRequestType::Git { url },
-            responder,
+/// Why a git URL or ref was refused
+pub const INVALID_GIT_ARGUMENT: &str = "Git URLs and refs may not start with a dash";
+
+/// Whether a git URL or ref given by a client can be passed to git, which would take one
+/// starting with a dash as an option
+pub fn is_git_argument(value: &str) -> bool {
+    !value.starts_with('-')
+}
+
+/// Run work which blocks, such as running git or `wasm-opt` or unpacking an archive, on a thread
+/// for blocking work, so that the async runtime's worker threads keep serving requests
+pub async fn run_blocking<T, F>(work: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+{
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(|error| Error::Blocking(error.to_string()))?
+}
+
+/// Clone a git repository into an empty directory, checking out the given branch, tag or commit,
+/// or the default branch
+fn clone_repo(
+    repo_path: &Path,
+    git_url: &str,
+    git_ref: Option<&str>,
+    auth: &GitAuth,
+) -> Result<(), Error> {
+    match git_ref {
+        // `git clone --branch` does not accept commit hashes, so fetch just the ref given
+        Some(git_ref) => {
+            run_git(repo_path, &["init", "--quiet"])?;
+            run_git(repo_path, &["remote", "add", "--", "origin", git_url])?;
+            run_git_with_auth(
+                repo_path,
+                &["fetch", "--depth=1", "--", "origin", git_ref],
+                auth,
+            )?;
+            run_git(repo_path, &["checkout", "--quiet", "FETCH_HEAD"])?;
+        }
+        None => {
+            let mut command = Command::new("git");
+            auth.apply(&mut command);
+            let output = command
+                .arg("clone")
+                .arg("--depth=1")
+                .arg("--")
+                .arg(git_url)
+                .arg(repo_path)
+                .stderr(Stdio::inherit())
+                .stdout(Stdio::inherit())
+                .output()?;
+
+            if !output.status.success() {
+                return Err(Error::GitClone(
+                    String::from_utf8_lossy(&output.stderr).to_string(),
+                ));
+            }
         }
     }
+    Ok(())
+}
 
-    /// A new build request with the contents of a tar archive
-    pub fn new_tar(raw_archive: Vec<u8>, responder: BuildResponder) -> Self {
-        Self {
-            request_type: BuildRequestType::Tar { raw_archive },
-            responder,
+/// Store the commits since the last build of the same branch or tag of a repository as the
+/// changelog of the programs built
+fn add_changelog(
+    db: &sled::Db,
+    repo_path: &Path,
+    git_url: &str,
+    git_ref: Option<&str>,
+    commit: &str,
+    hashes: &[H256],
+) -> Result<(), Error> {
+    let Some(previous_commit) = last_built_commit(db, git_url, git_ref)? else {
+        return Ok(());
+    };
+    if previous_commit == commit {
+        return Ok(());
+    }
+    match collect_changelog(repo_path, &previous_commit)? {
+        Some(changelog) => {
+            for hash in hashes {
+                store_changelog(db, *hash, &changelog)?;
+            }
         }
+        None => log::info!(
+            "Previous commit {} is not an ancestor of {}",
+            previous_commit,
+            commit
+        ),
     }
+    Ok(())
 }
 
-/// Input parameters for a build request
-pub enum BuildRequestType {
-    Git { url: String },
-    Tar { raw_archive: Vec<u8> },
+/// Run a git command in a repository, failing with its error output if it does not succeed
+fn run_git(repo_path: &Path, args: &[&str]) -> Result<(), Error> {
+    run_git_with_auth(repo_path, args, &GitAuth::default())
 }
 
-/// An item in the response stream for a program being built
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum BuildResponse {
-    /// A message from building on standard output
-    StdOut(String),
-    /// A message from building on standard error
-    StdErr(String),
-    /// The final message on a successful build, with the hash and binary blob
-    Success {
-        hash: H256,
-        binary: Vec<u8>,
-        binary_filename: String,
-    },
+/// Run a git command which may need credentials to reach the remote repository
+fn run_git_with_auth(repo_path: &Path, args: &[&str], auth: &GitAuth) -> Result<(), Error> {
+    let mut command = Command::new("git");
+    auth.apply(&mut command);
+    let output = command.arg("-C").arg(repo_path).args(args).output()?;
+    if !output.status.success() {
+        return Err(Error::GitClone(
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        ));
+    }
+    Ok(())
 }
 
-/// For serializing and sending [BuildResponse]s to the client
-#[derive(Debug, Clone)]
-pub struct BuildResponder(pub futures_mpsc::Sender<Result<String, Error>>);
+/// Check that a docker image is allowed by the configured list of images. An entry ending with
+/// `/` allows any image from that registry or namespace, an entry with a tag or digest allows
+/// exactly that image, and any other entry allows the image by name with any tag or digest.
+/// Names are compared as docker resolves them, so `peg997/image` is the same as
+/// `docker.io/peg997/image`. If the list is empty, any image is allowed.
+pub fn check_docker_image(allowed: &[String], image_name: &str) -> Result<(), Error> {
+    if allowed.is_empty() {
+        return Ok(());
+    }
+    let image = qualify_image_name(image_name);
+    let allowed = allowed.iter().any(|entry| {
+        let entry = qualify_image_name(entry);
+        if entry.ends_with('/') {
+            image.starts_with(&entry)
+        } else {
+            image == entry || image_repository(&image) == entry
+        }
+    });
+    if !allowed {
+        return Err(Error::UntrustedImage(image_name.to_string()));
+    }
+    Ok(())
+}
 
-impl BuildResponder {
-    /// Attempt to serialize and send a [BuildResponse] to the client
-    fn try_send(
-        &mut self,
-        build_response: BuildResponse,
-    ) -> Result<(), TrySendError<Result<String, Error>>> {
-        self.0
-            .try_send(serde_json::to_string(&build_response).map_err(|e| Error::Json(e)))
+/// The name of an image without its tag or digest
+fn image_repository(image_name: &str) -> &str {
+    let name = image_name
+        .split_once('@')
+        .map_or(image_name, |(name, _)| name);
+    // A colon after the last slash starts a tag, rather than giving a registry's port
+    match name.rsplit_once(':') {
+        Some((repository, tag)) if !tag.contains('/') => repository,
+        _ => name,
     }
+}
 
-    /// Attempt to serialize and send an [Error] to the client
-    fn try_send_error(&mut self, error: Error) {
-        if self.0.try_send(Err(error)).is_err() {
-            log::error!("Client dropped connection while attempting to send error reponse");
+/// Pull a docker image and get a reference to it pinned by digest, such as `name@sha256:...`.
+/// Gives `None` if the image cannot be pulled or was built locally, and so has no digest.
+fn pin_image(backend: &dyn ContainerBackend, image_name: &str) -> Option<String> {
+    if image_name.contains('@') {
+        return Some(image_name.to_string());
+    }
+    let pull = backend
+        .command()
+        .args(["pull", "--quiet", image_name])
+        .output()
+        .ok()?;
+    if !pull.status.success() {
+        log::warn!(
+            "Could not pull image {}: {}",
+            image_name,
+            String::from_utf8_lossy(&pull.stderr).trim()
+        );
+    }
+    let output = backend
+        .command()
+        .args(["image", "inspect", "--format", "{{index .RepoDigests 0}}"])
+        .arg(image_name)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        log::warn!(
+            "Could not find digest of image {}: {}",
+            image_name,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        return None;
+    }
+    let pinned = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (!pinned.is_empty()).then_some(pinned)
+}
+
+/// Run a program's tests with cargo on the host if configured to, otherwise in a container of
+/// the given image
+async fn test_program(
+    options: &BuildOptions,
+    program_path: &Path,
+    docker_image: Option<&str>,
+    response_tx: &mut BuildResponder,
+    deadline: Instant,
+) -> Result<TestSummary, Error> {
+    let mut monitor = None;
+    let command = match (&options.native_build, &options.kubernetes) {
+        (Some(native), _) => {
+            let mut command = native.test_command(program_path, &options.features);
+            if let Some(sccache) = &options.sccache {
+                sccache.apply(&mut command);
+            }
+            command
+        }
+        (None, Some(kubernetes)) => {
+            let job = kubernetes
+                .submit(
+                    program_path,
+                    docker_image.unwrap_or(DEFAULT_DOCKER_IMAGE),
+                    JobTask::Test,
+                    options,
+                    deadline.saturating_duration_since(Instant::now()),
+                )
+                .await?;
+            let mut output = String::new();
+            let success = run_kubernetes_job(
+                &job,
+                &options.resource_limits,
+                response_tx,
+                deadline,
+                |stdout| output.push_str(stdout),
+            )
+            .await?;
+            return Ok(TestSummary::from_output(&output, success));
+        }
+        (None, None) => {
+            let backend = options.container_engine.backend();
+            let image_name = docker_image
+                .map(str::to_string)
+                .unwrap_or_else(|| backend.image_name(DEFAULT_DOCKER_IMAGE));
+            monitor = ResourceMonitor::new(&options.resource_limits);
+            container_test_command(
+                backend,
+                program_path,
+                &image_name,
+                options,
+                monitor.as_ref(),
+            )
+        }
+    };
+    run_test_suite(
+        command,
+        &options.resource_limits,
+        monitor.as_ref(),
+        response_tx,
+        deadline,
+    )
+    .await
+}
+
+/// Fail the build if the program's tests failed and are required to pass, otherwise warn the
+/// client that they failed
+fn check_test_summary(
+    summary: &TestSummary,
+    options: &BuildOptions,
+    response_tx: &mut BuildResponder,
+) -> Result<(), Error> {
+    if !summary.success {
+        if options.require_tests_pass {
+            return Err(Error::TestsFailed(summary.clone()));
         }
+        let _ = response_tx.try_send(BuildResponse::Warning(format!(
+            "Tests failed: {} passed, {} failed",
+            summary.passed, summary.failed
+        )));
     }
+    Ok(())
 }
 
-/// Handle incoming requests to build a program from the client
-pub async fn handle_build_requests(mut build_requests_rx: Receiver<BuildRequest>, db: sled::Db) {
-    let program_builder = ProgramBuilder(db);
-    while let Some(build_request) = build_requests_rx.recv().await {
-        let mut responder = build_request.responder;
-        match build_request.request_type {
-            BuildRequestType::Git { url } => {
-                if let Err(error) = program_builder
-                    .add_program_git(url, responder.clone())
-                    .await
-                {
-                    responder.try_send_error(error)
-                }
+/// Compile a program into the given directory, with cargo on the host if configured to,
+/// otherwise with `docker build` and the given image
+async fn compile_program(
+    options: &BuildOptions,
+    program_path: &Path,
+    docker_image: Option<&str>,
+    binary_dir: &Path,
+    response_tx: &mut BuildResponder,
+    deadline: Instant,
+) -> Result<(), Error> {
+    let mut monitor = None;
+    let command = match (&options.native_build, &options.kubernetes) {
+        (Some(native), _) => {
+            let mut command = native.build_command(program_path, &options.features);
+            if let Some(sccache) = &options.sccache {
+                sccache.apply(&mut command);
             }
-            BuildRequestType::Tar { raw_archive } => {
-                if let Err(error) = program_builder
-                    .add_program_tar(raw_archive, responder.clone())
-                    .await
-                {
-                    responder.try_send_error(error)
-                }
+            command
+        }
+        (None, Some(kubernetes)) => {
+            let job = kubernetes
+                .submit(
+                    program_path,
+                    docker_image.unwrap_or(DEFAULT_DOCKER_IMAGE),
+                    JobTask::Build,
+                    options,
+                    deadline.saturating_duration_since(Instant::now()),
+                )
+                .await?;
+            if !run_kubernetes_job(
+                &job,
+                &options.resource_limits,
+                response_tx,
+                deadline,
+                |_| {},
+            )
+            .await?
+            {
+                return Err(Error::CompilationFailed("Unknown".to_string()));
             }
+            return job.copy_binaries(binary_dir).await;
+        }
+        (None, None) => {
+            let backend = options.container_engine.backend();
+            monitor = ResourceMonitor::new(&options.resource_limits);
+            let mut command = backend.command();
+            command
+                .arg("build")
+                .args(options.resource_limits.docker_build_args())
+                .args(monitor.as_ref().map(ResourceMonitor::cgroup_parent_arg));
+            options.docker.apply(backend, &mut command);
+            if let Some(image_name) = docker_image {
+                command
+                    .arg("--build-arg")
+                    .arg(format!("IMAGE={}", image_name));
+            }
+            command
+                .args(options.features.docker_build_args())
+                .args(
+                    options
+                        .sccache
+                        .iter()
+                        .flat_map(SccacheConfig::docker_build_args),
+                )
+                .args(backend.output_args(binary_dir))
+                .arg(program_path);
+            command
+        }
+    };
+    let mut process = spawn_build_process(command)?;
+    let watch = monitor.map(|monitor| monitor.start(response_tx.clone()));
+
+    stream_output(&mut process, response_tx, deadline, |_| {}).await?;
+    let exit_status = process.wait().await?;
+    if let Some(watch) = watch {
+        watch.finish()?;
+    }
+    if !exit_status.success() {
+        // Docker reports a step killed by the OOM killer in its output rather than its own exit
+        // code
+        let killed_message = format!("exit code: {}", KILLED_EXIT_CODE);
+        if exit_status.code() == Some(KILLED_EXIT_CODE)
+            || response_tx
+                .log_tail()
+                .iter()
+                .any(|line| line.contains(&killed_message))
+        {
+            return Err(options.resource_limits.killed_error());
         }
+        return Err(Error::CompilationFailed("Unknown".to_string()));
     }
+    Ok(())
 }
 
-/// Builds programs and stores metadata
-struct ProgramBuilder(sled::Db);
+/// Follow the output of a Kubernetes job until it finishes, giving whether it succeeded
+async fn run_kubernetes_job(
+    job: &KubernetesJob,
+    resource_limits: &ResourceLimits,
+    response_tx: &mut BuildResponder,
+    deadline: Instant,
+    on_stdout: impl FnMut(&str),
+) -> Result<bool, Error> {
+    let mut process = spawn_build_process(job.logs_command())?;
+    stream_output(&mut process, response_tx, deadline, on_stdout).await?;
+    process.wait().await?;
+    match job.wait(deadline).await? {
+        JobOutcome::Succeeded => Ok(true),
+        JobOutcome::OutOfMemory => Err(resource_limits.killed_error()),
+        JobOutcome::Failed => Ok(false),
+    }
+}
 
-impl ProgramBuilder {
-    /// Add a program given as a location of a git repo
-    pub async fn add_program_git(
-        &self,
-        git_url: String,
-        response_tx: BuildResponder,
-    ) -> Result<(), Error> {
-        let temp_dir = TempDir::new()?;
-        let output = Command::new("git")
-            .arg("clone")
-            .arg("--depth=1")
-            .arg(git_url)
-            .arg(temp_dir.path())
-            .stderr(Stdio::inherit())
-            .stdout(Stdio::inherit())
-            .output()?;
-
-        if !output.status.success() {
-            return Err(Error::GitClone(
-                String::from_utf8_lossy(&output.stderr).to_string(),
-            ));
-        }
+/// A binary built by a builder of the pool
+struct RemoteBinary {
+    /// The outcome of the program's tests, if the builder ran them
+    test_summary: Option<TestSummary>,
+    binary_filename: String,
+    binary: Vec<u8>,
+    docker_image: Option<String>,
+}
 
-        self.add_program(temp_dir.path(), response_tx).await
+/// Queue a build for the builders of the pool, passing its output on to the client until a
+/// builder gives the binary
+async fn build_remotely(
+    builder_pool: &BuilderPool,
+    build: RemoteBuild,
+    source: Vec<u8>,
+    options: &BuildOptions,
+    response_tx: &mut BuildResponder,
+    deadline: Instant,
+) -> Result<RemoteBinary, Error> {
+    let (id, mut messages) = builder_pool.submit(build, source);
+    let result = receive_remote_build(&mut messages, options, response_tx, deadline).await;
+    builder_pool.finish(id);
+    result
+}
+
+/// Handle the messages from the builder carrying out a build until it ends
+async fn receive_remote_build(
+    messages: &mut tokio::sync::mpsc::Receiver<BuilderMessage>,
+    options: &BuildOptions,
+    response_tx: &mut BuildResponder,
+    deadline: Instant,
+) -> Result<RemoteBinary, Error> {
+    let mut builder = None;
+    let mut last_heard = Instant::now();
+    let mut test_summary = None;
+    loop {
+        if Instant::now() > deadline {
+            return Err(Error::Timeout);
+        }
+        response_tx.check_wanted()?;
+        // Nothing may be heard while the build waits for a builder to take it
+        response_tx.keep_alive();
+        let message = match tokio::time::timeout(REMOTE_POLL_INTERVAL, messages.recv()).await {
+            Ok(Some(message)) => message,
+            Ok(None) => return Err(Error::Mpsc),
+            // Time spent waiting in the queue for a builder only counts towards the deadline
+            Err(_) => match &builder {
+                Some(builder) if last_heard.elapsed() > BUILDER_SILENCE_TIMEOUT => {
+                    return Err(Error::Builder(
+                        FailureKind::Internal,
+                        format!("Builder {} stopped responding", builder),
+                    ));
+                }
+                _ => continue,
+            },
+        };
+        last_heard = Instant::now();
+        match message {
+            BuilderMessage::Started(name) => {
+                let _ =
+                    response_tx.try_send(BuildResponse::StdOut(format!("Building on {}\n", name)));
+                builder = Some(name);
+            }
+            BuilderMessage::Response(response) => {
+                let _ = response_tx.try_send(response);
+            }
+            BuilderMessage::Heartbeat => {}
+            BuilderMessage::Tests(summary) => {
+                check_test_summary(&summary, options, response_tx)?;
+                test_summary = Some(summary);
+            }
+            BuilderMessage::Built {
+                binary_filename,
+                binary,
+                docker_image,
+            } => {
+                // The binary is written into the program's directory, so its name must not be
+                // a path
+                let binary_filename = Path::new(&binary_filename)
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .ok_or(Error::BinaryNotFound)?
+                    .to_string();
+                return Ok(RemoteBinary {
+                    test_summary,
+                    binary_filename,
+                    binary,
+                    docker_image,
+                });
+            }
+            BuilderMessage::Failed { kind, error } => return Err(Error::Builder(kind, error)),
+        }
     }
+}
 
-    /// Add a program given as a tar achive
-    async fn add_program_tar(
-        &self,
-        input: Vec<u8>,
-        response_tx: BuildResponder,
-    ) -> Result<(), Error> {
-        let mut archive = Archive::new(&input[..]);
-        let temp_dir = TempDir::new()?;
-        archive.unpack(temp_dir.path())?;
+/// Carry out a build sent by the service this process is a builder for, in the source it sent,
+/// giving the messages which end the build: the outcome of the tests if they were run, then the
+/// binary or why there is none
+pub async fn build_for_pool(
+    build: &RemoteBuild,
+    program_path: &Path,
+    options: &BuildOptions,
+    mut response_tx: BuildResponder,
+) -> Vec<BuilderMessage> {
+    let mut messages = Vec::new();
+    let result = carry_out_remote_build(
+        build,
+        program_path,
+        options,
+        &mut response_tx,
+        &mut messages,
+    )
+    .await;
+    messages.push(result.unwrap_or_else(|error| BuilderMessage::failed(&error)));
+    messages
+}
+
+/// The steps of [build_for_pool], adding the outcome of the tests to the given messages and
+/// giving the message with the binary
+async fn carry_out_remote_build(
+    build: &RemoteBuild,
+    program_path: &Path,
+    options: &BuildOptions,
+    response_tx: &mut BuildResponder,
+    messages: &mut Vec<BuilderMessage>,
+) -> Result<BuilderMessage, Error> {
+    let deadline = Instant::now() + BUILD_TIMEOUT;
+    let backend = options.container_engine.backend();
+    // Builders may restrict the images they build with as the service does
+    let docker_image = match (&build.docker_image, &options.native_build) {
+        (Some(image_name), None) => {
+            check_docker_image(&options.allowed_docker_images, image_name)?;
+            if options.kubernetes.is_some() {
+                Some(image_name.clone())
+            } else {
+                let image_name = backend.image_name(image_name);
+                Some(
+                    run_blocking(move || Ok(pin_image(backend, &image_name).unwrap_or(image_name)))
+                        .await?,
+                )
+            }
+        }
+        _ => None,
+    };
 
-        self.add_program(temp_dir.path(), response_tx).await
+    if build.run_tests {
+        let summary = test_program(
+            options,
+            program_path,
+            docker_image.as_deref(),
+            response_tx,
+            deadline,
+        )
+        .await?;
+        let failed = !summary.success;
+        messages.push(BuilderMessage::Tests(summary.clone()));
+        if failed && build.require_tests_pass {
+            return Err(Error::TestsFailed(summary));
+        }
     }
 
-    /// Build a program, and save metadata under the hash of its binary
-    async fn add_program(
-        &self,
-        repo_path: &Path,
-        mut response_tx: BuildResponder,
-    ) -> Result<(), Error> {
-        let manifest_path: PathBuf = [repo_path, Path::new("Cargo.toml")].iter().collect();
+    let binary_dir = match &options.native_build {
+        Some(native) => native.binary_dir(program_path),
+        None => program_path.join("binary_dir"),
+    };
+    compile_program(
+        options,
+        program_path,
+        docker_image.as_deref(),
+        &binary_dir,
+        response_tx,
+        deadline,
+    )
+    .await?;
+    let binary_filename = get_binary_filename(binary_dir).await?;
+    Ok(BuilderMessage::Built {
+        binary: tokio::fs::read(&binary_filename).await?,
+        binary_filename: binary_filename
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("program.wasm")
+            .to_string(),
+        docker_image,
+    })
+}
 
-        // Get metadata from Cargo.toml file
-        let metadata = MetadataCommand::new()
-            .manifest_path(manifest_path)
-            .features(CargoOpt::AllFeatures)
-            .exec()?;
+/// The command which runs `cargo test` on a program inside a container
+fn container_test_command(
+    backend: &dyn ContainerBackend,
+    repo_path: &Path,
+    image_name: &str,
+    options: &BuildOptions,
+    monitor: Option<&ResourceMonitor>,
+) -> Command {
+    let mut command = backend.command();
+    command
+        .arg("run")
+        .arg("--rm")
+        .args(options.resource_limits.docker_run_args())
+        .args(monitor.map(ResourceMonitor::cgroup_parent_arg))
+        .args(
+            options
+                .cargo_cache
+                .iter()
+                .flat_map(CargoCacheConfig::docker_run_args),
+        )
+        .args(
+            options
+                .sccache
+                .iter()
+                .flat_map(SccacheConfig::docker_run_args),
+        )
+        .arg(backend.volume_arg(repo_path, CONTAINER_SOURCE_DIR))
+        .arg(format!("--workdir={}", CONTAINER_SOURCE_DIR))
+        .arg(image_name)
+        .arg("cargo")
+        .arg("test")
+        .args(options.features.cargo_args());
+    command
+}
 
-        let root_package_metadata = metadata
-            .root_package()
-            .ok_or(Error::MetadataMissingRootPackage)?;
+/// Run a program's tests with the given command, streaming the output to the client
+async fn run_test_suite(
+    command: Command,
+    resource_limits: &ResourceLimits,
+    monitor: Option<&ResourceMonitor>,
+    response_tx: &mut BuildResponder,
+    deadline: Instant,
+) -> Result<TestSummary, Error> {
+    let mut process = spawn_build_process(command)?;
+    let watch = monitor.map(|monitor| monitor.start(response_tx.clone()));
 
-        // Get the docker image name from Cargo.toml, if there is one
-        let entropy_metadata = extract_metadata(&root_package_metadata.metadata);
+    let mut output = String::new();
+    stream_output(&mut process, response_tx, deadline, |stdout| {
+        output.push_str(stdout)
+    })
+    .await?;
+    let exit_status = process.wait().await?;
+    if let Some(watch) = watch {
+        watch.finish()?;
+    }
+    if exit_status.code() == Some(KILLED_EXIT_CODE) {
+        return Err(resource_limits.killed_error());
+    }
+    Ok(TestSummary::from_output(&output, exit_status.success()))
+}
 
-        let binary_dir: PathBuf = [repo_path, Path::new("binary_dir")].iter().collect();
+/// Start a build process whose output is read with [stream_output]. The process is killed if
+/// the build is abandoned before it has finished.
+fn spawn_build_process(command: Command) -> Result<Child, Error> {
+    Ok(AsyncCommand::from(command)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?)
+}
 
-        // Build the program
-        let mut command = Command::new("docker");
-        command.arg("build");
-        if let Some(image_name) = entropy_metadata.docker_image.clone() {
-            command
-                .arg("--build-arg")
-                .arg(format!("IMAGE={}", image_name));
-        }
-        let mut process = command
-            .arg(format!("--output={}", binary_dir.display()))
-            .arg(repo_path)
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()?;
-
-        let mut stdout = process.stdout.take().ok_or(Error::NoStdOut)?;
-        let mut stderr = process.stderr.take().ok_or(Error::NoStdErr)?;
-        let mut buf: [u8; OUTPUT_BUFFER_SIZE] = [0; OUTPUT_BUFFER_SIZE];
-        loop {
-            let read_bytes_stdout = stdout.read(&mut buf)?;
-            if read_bytes_stdout > 0 {
-                match std::str::from_utf8(&buf[..read_bytes_stdout]) {
-                    Ok(output) => {
-                        println!("{}", output);
-                        if response_tx
-                            .try_send(BuildResponse::StdOut(output.to_string()))
-                            .is_err()
-                        {
-                            break;
-                        };
-                    }
-                    Err(error) => log::error!("Bad UTF8 found on stdout {}", error),
-                }
-            };
+/// Forward the standard output and standard error of a child process to the client until the
+/// process closes them, passing standard output also to the given function. Both are read at
+/// once, so that a process filling one pipe is never left waiting while the other is read.
+async fn stream_output(
+    process: &mut Child,
+    response_tx: &mut BuildResponder,
+    deadline: Instant,
+    mut on_stdout: impl FnMut(&str),
+) -> Result<(), Error> {
+    let mut stdout = process.stdout.take().ok_or(Error::NoStdOut)?;
+    let mut stderr = process.stderr.take().ok_or(Error::NoStdErr)?;
+    let mut stdout_buf = [0; OUTPUT_BUFFER_SIZE];
+    let mut stderr_buf = [0; OUTPUT_BUFFER_SIZE];
+    let mut stdout_lines = OutputLines::default();
+    let mut stderr_lines = OutputLines::default();
+    let (mut stdout_open, mut stderr_open) = (true, true);
+    let mut wanted_check = tokio::time::interval(WANTED_CHECK_INTERVAL);
+    let timeout = tokio::time::sleep_until(deadline.into());
+    tokio::pin!(timeout);
 
-            let read_bytes_stderr = stderr.read(&mut buf)?;
-            if read_bytes_stderr > 0 {
-                match std::str::from_utf8(&buf[..read_bytes_stderr]) {
-                    Ok(output) => {
-                        println!("{}", output);
-                        if response_tx
-                            .try_send(BuildResponse::StdErr(output.to_string()))
-                            .is_err()
-                        {
-                            break;
-                        };
-                    }
-                    Err(error) => log::error!("Bad UTF8 found on stderr {}", error),
+    // Output which a slow or disconnected client misses is still kept in the build log
+    while stdout_open || stderr_open {
+        tokio::select! {
+            read = stdout.read(&mut stdout_buf), if stdout_open => {
+                let read = read?;
+                stdout_open = read > 0;
+                if let Some(output) = stdout_lines.push(&stdout_buf[..read]) {
+                    log::debug!("{}", output.trim_end());
+                    on_stdout(&output);
+                    let _ = response_tx.try_send(BuildResponse::StdOut(output));
                 }
-            };
-            if read_bytes_stderr == 0 && read_bytes_stdout == 0 {
-                break;
+            }
+            read = stderr.read(&mut stderr_buf), if stderr_open => {
+                let read = read?;
+                stderr_open = read > 0;
+                if let Some(output) = stderr_lines.push(&stderr_buf[..read]) {
+                    log::debug!("{}", output.trim_end());
+                    let _ = response_tx.try_send(BuildResponse::StdErr(output));
+                }
+            }
+            _ = wanted_check.tick() => {
+                if response_tx.check_wanted().is_err() {
+                    let _ = process.kill().await;
+                    break;
+                }
+                response_tx.keep_alive();
+            }
+            _ = &mut timeout => {
+                process.kill().await?;
+                return Err(Error::Timeout);
             }
         }
-        if !process.wait()?.success() {
-            return Err(Error::CompilationFailed("Unknown".to_string()));
-        }
-
-        let binary_filename = get_binary_filename(binary_dir).await?;
+    }
+    // The client may also have gone while the process was quiet, in which case what it built is
+    // not kept. The files of the build are removed as they are dropped.
+    if let Err(error) = response_tx.check_wanted() {
+        process.wait().await?;
+        return Err(error);
+    }
+    Ok(())
+}
 
-        let binary_filename_string = binary_filename
-            .file_name()
-            .and_then(|o| o.to_str())
-            .map(|o| o.to_string())
-            .unwrap_or_else(|| "program.wasm".to_string());
+/// Output of a build process which has been read but not yet sent, so that only whole lines are
+/// sent unless a line is too long to hold back. A character is never split between two sends, as
+/// a read may end part way through one. Bytes which are not UTF-8 are replaced rather than lost.
+#[derive(Default)]
+struct OutputLines(Vec<u8>);
 
-        // Read the wasm binary
-        let binary = {
-            let mut file = File::open(binary_filename).await?;
-            let mut binary = vec![];
-            file.read_to_end(&mut binary).await?;
-            binary
+impl OutputLines {
+    /// Add output which has been read, giving the lines it completes, if any. Reading nothing
+    /// means the output has ended, so whatever is left is given.
+    fn push(&mut self, read: &[u8]) -> Option<String> {
+        self.0.extend_from_slice(read);
+        let end = match self.0.iter().rposition(|byte| *byte == b'\n') {
+            _ if read.is_empty() => self.0.len(),
+            Some(newline) => newline + 1,
+            None if self.0.len() >= OUTPUT_BUFFER_SIZE => whole_characters_len(&self.0),
+            None => return None,
         };
+        if end == 0 {
+            return None;
+        }
+        let rest = self.0.split_off(end);
+        let lines = std::mem::replace(&mut self.0, rest);
+        Some(String::from_utf8_lossy(&lines).into_owned())
+    }
+}
 
-        // Hash the binary with metadata
-        let mut hash_input: Vec<u8> = vec![];
-        hash_input.extend(&binary);
-        hash_input.extend(&entropy_metadata.to_bytes());
-        // TODO #6 this wont let us hash chunks which means we need to read the whole binary into memory
-        let hash = BlakeTwo256::hash(&hash_input);
-        log::info!("Hashed binary {:?}", hash);
-
-        // Write metadata to db
-        let root_package_metadata_json = serde_json::to_string(&root_package_metadata)?;
-        self.0.insert(hash, root_package_metadata_json.as_bytes())?;
-
-        response_tx
-            .try_send(BuildResponse::Success {
-                hash,
-                binary,
-                binary_filename: binary_filename_string,
-            })
-            .map_err(|_| Error::Mpsc)?;
-        Ok(())
+/// Length of the given output without a character cut off at its end, if one is
+fn whole_characters_len(output: &[u8]) -> usize {
+    // A character is at most four bytes, so only the last three can start one which is cut off
+    for from_end in 1..=output.len().min(3) {
+        let byte = output[output.len() - from_end];
+        let is_continuation = byte & 0b1100_0000 == 0b1000_0000;
+        if is_continuation {
+            continue;
+        }
+        let width = match byte {
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => 1,
+        };
+        return if width > from_end {
+            output.len() - from_end
+        } else {
+            output.len()
+        };
     }
+    output.len()
 }
 
 /// Get the name of the first .wasm file we find in the target directory
@@ -282,37 +2703,140 @@ async fn get_binary_filename(binary_dir: PathBuf) -> Result<PathBuf, Error> {
             }
         }
     }
-    Err(Error::CompilationFailed(
-        "Cannot find binary after compiling".to_string(),
-    ))
+    Err(Error::BinaryNotFound)
 }
 
 /// Metadata extracted from the `Cargo.toml` file which is specific to Entropy programs
-#[derive(Default)]
-struct EntropyProgramMetadata {
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub struct EntropyProgramMetadata {
     /// The name of the docker image used to build the program
-    docker_image: Option<String>,
+    pub docker_image: Option<String>,
+    /// Images to build the program with one after another, each giving its own program. This is
+    /// only given in `Cargo.toml`, as each program is stored with its own image.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub docker_images: Option<Vec<String>>,
     /// Configuration schema (typically given as JSON schema)
-    configuration_schema: Option<String>,
+    pub configuration_schema: Option<String>,
     /// auxiliary_data_schema (typically given as JSON schema)
-    auxiliary_data_schema: Option<String>,
+    pub auxiliary_data_schema: Option<String>,
     /// Oracle data pointer
-    oracle_data_pointer: Option<String>,
+    pub oracle_data_pointer: Option<String>,
     /// Program version number
-    version_number: Option<u8>,
+    pub version_number: Option<u8>,
+    /// Whether to optimize the binary for size with `wasm-opt`. This is not itself part of the
+    /// hash, but changes the binary.
+    pub optimize: Option<bool>,
+    /// The cargo features the program was built with, if not its default features
+    #[serde(default, flatten, skip_serializing_if = "FeatureSelection::is_default")]
+    pub features: FeatureSelection,
 }
 
 impl EntropyProgramMetadata {
     /// Get all the values which are used in the on-chain program hash in the same format they are
     /// added to the hash in the programs pallet
-    fn to_bytes(self) -> Vec<u8> {
+    fn to_bytes(&self) -> Vec<u8> {
         let mut bytes: Vec<u8> = vec![];
-        bytes.extend(self.configuration_schema.unwrap_or_default().as_bytes());
-        bytes.extend(self.auxiliary_data_schema.unwrap_or_default().as_bytes());
-        bytes.extend(self.oracle_data_pointer.unwrap_or_default().as_bytes());
+        bytes.extend(
+            self.configuration_schema
+                .as_deref()
+                .unwrap_or_default()
+                .as_bytes(),
+        );
+        bytes.extend(
+            self.auxiliary_data_schema
+                .as_deref()
+                .unwrap_or_default()
+                .as_bytes(),
+        );
+        bytes.extend(
+            self.oracle_data_pointer
+                .as_deref()
+                .unwrap_or_default()
+                .as_bytes(),
+        );
         bytes.extend(&vec![self.version_number.unwrap_or_default()]);
+        // Programs built with their default features keep the hash the programs pallet gives
+        if !self.features.is_default() {
+            bytes.extend(self.features.features.join(",").as_bytes());
+            bytes.push(self.features.no_default_features.into());
+        }
         bytes
     }
+
+    /// Check that the configuration and auxiliary data schemas, if given, are valid JSON Schema
+    pub fn validate_schemas(&self) -> Result<(), Error> {
+        let schemas = [
+            ("configuration-schema", &self.configuration_schema),
+            ("auxiliary-data-schema", &self.auxiliary_data_schema),
+        ];
+        for (field, schema) in schemas {
+            if let Some(schema) = schema {
+                let value: serde_json::Value = serde_json::from_str(schema)
+                    .map_err(|error| Error::InvalidSchema(field, error.to_string()))?;
+                jsonschema::JSONSchema::compile(&value)
+                    .map_err(|error| Error::InvalidSchema(field, error.to_string()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Hash a program binary together with this metadata, giving the hash by which the program
+    /// is referred to on-chain
+    pub fn program_hash(&self, binary: &[u8]) -> H256 {
+        let mut hasher = self.program_hasher();
+        hasher.update(binary);
+        hasher.finalize()
+    }
+
+    /// Get a hasher which can be given a program binary in chunks, so that the binary does not
+    /// need to be copied into one buffer with the metadata
+    pub fn program_hasher(&self) -> ProgramHasher {
+        ProgramHasher {
+            state: blake2b_simd::Params::new()
+                .hash_length(BLAKE2_256_LENGTH)
+                .to_state(),
+            sha256: Sha256::new(),
+            keccak256: Keccak256::new(),
+            metadata: self.to_bytes(),
+        }
+    }
+}
+
+/// Incrementally hashes a program binary followed by its metadata, giving the same hash as
+/// [EntropyProgramMetadata::program_hash]
+pub struct ProgramHasher {
+    state: blake2b_simd::State,
+    /// Other digests are made of the same input, for tooling which expects them
+    sha256: Sha256,
+    keccak256: Keccak256,
+    metadata: Vec<u8>,
+}
+
+impl ProgramHasher {
+    /// Add the next chunk of the binary
+    pub fn update(&mut self, chunk: &[u8]) {
+        self.state.update(chunk);
+        self.sha256.update(chunk);
+        self.keccak256.update(chunk);
+    }
+
+    /// Add the metadata and give the hash, which is the same as `BlakeTwo256` of the binary
+    /// followed by the metadata
+    pub fn finalize(self) -> H256 {
+        self.finalize_digests().blake2_256
+    }
+
+    /// Add the metadata and give the program hash together with the other digests
+    pub fn finalize_digests(mut self) -> ProgramDigests {
+        let metadata = std::mem::take(&mut self.metadata);
+        self.update(&metadata);
+        ProgramDigests {
+            blake2_256: H256::from_slice(self.state.finalize().as_bytes()),
+            sha256: H256::from_slice(&self.sha256.finalize()),
+            keccak256: H256::from_slice(&self.keccak256.finalize()),
+        }
+    }
 }
 
 /// We expect there to be program-related metadata given in the Cargo.toml file like so:
@@ -320,13 +2844,22 @@ impl EntropyProgramMetadata {
 /// [package.metadata.entropy-program]
 /// docker-image = "peg997/build-entropy-programs:version0.1"
 /// ```
-fn extract_metadata(metadata: &serde_json::value::Value) -> EntropyProgramMetadata {
+pub fn extract_metadata(metadata: &serde_json::value::Value) -> EntropyProgramMetadata {
     let mut entropy_metadata = EntropyProgramMetadata::default();
     if let serde_json::value::Value::Object(m) = metadata {
         if let Some(serde_json::value::Value::Object(p)) = m.get("entropy-program") {
             if let Some(serde_json::value::Value::String(docker_image)) = p.get("docker-image") {
                 entropy_metadata.docker_image = Some(docker_image.clone());
             };
+            if let Some(serde_json::value::Value::Array(docker_images)) = p.get("docker-images") {
+                entropy_metadata.docker_images = Some(
+                    docker_images
+                        .iter()
+                        .filter_map(|docker_image| docker_image.as_str())
+                        .map(str::to_string)
+                        .collect(),
+                );
+            };
             if let Some(serde_json::value::Value::String(configuration_schema)) =
                 p.get("configuration-schema")
             {
@@ -349,6 +2882,9 @@ fn extract_metadata(metadata: &serde_json::value::Value) -> EntropyProgramMetada
                     .map(|v| v.try_into().ok())
                     .unwrap_or(None);
             };
+            if let Some(serde_json::value::Value::Bool(optimize)) = p.get("optimize") {
+                entropy_metadata.optimize = Some(*optimize);
+            };
         }
     }
     entropy_metadata
@@ -373,10 +2909,302 @@ pub enum Error {
     Io(#[from] std::io::Error),
     #[error("Compilation failed: {0}")]
     CompilationFailed(String),
+    #[error("Cannot find binary after compiling")]
+    BinaryNotFound,
+    #[error("Invalid signing key: {0}")]
+    InvalidSigningKey(String),
+    #[error("Archive cannot be safely unpacked: {0}")]
+    UnsafeArchive(String),
+    #[error("Invalid {0} in Cargo.toml, which must be JSON Schema: {1}")]
+    InvalidSchema(&'static str, String),
+    #[error("Cannot read zip archive: {0}")]
+    Zip(#[from] zip::result::ZipError),
+    #[error("Build was cancelled")]
+    Cancelled,
+    #[error("Build was stopped as the client disconnected")]
+    ClientDisconnected,
+    #[error("Build was killed - probably ran out of memory")]
+    OutOfMemory,
+    #[error("Build was killed for exceeding its memory limit of {0}")]
+    ResourceLimitExceeded(String),
+    #[error("The build was stopped as it was using {0} MB of its {1} MB memory limit")]
+    MemoryLimitNeared(u64, u64),
+    #[error("Invalid wasm binary: {0}")]
+    InvalidWasm(String),
+    #[error("Could not optimize binary: {0}")]
+    Optimization(String),
+    #[error("Could not query the chain: {0}")]
+    Chain(String),
+    #[error("Could not sync with peer: {0}")]
+    Peer(String),
+    #[error("IPFS error: {0}")]
+    Ipfs(String),
+    #[error("Not an IPFS CID: {0}")]
+    InvalidCid(String),
+    #[error("Archives can only be fetched from https URLs")]
+    InsecureUrl,
+    #[error("Archive has SHA-256 checksum {actual}, but {expected} was expected")]
+    ChecksumMismatch { expected: String, actual: String },
+    #[error("The program path must be a relative path within the repository")]
+    InvalidPath,
+    #[error("Invalid cargo feature name: {0}")]
+    InvalidFeature(String),
+    #[error("Docker image {0} is not allowed by this server")]
+    UntrustedImage(String),
+    #[error("Could not pull docker image {0}: {1}")]
+    ImagePull(String, String),
+    #[error("Could not fetch source: {0}")]
+    Fetch(String),
+    #[error("{1}")]
+    Builder(FailureKind, String),
+    #[error("Could not reach the service being built for: {0}")]
+    PoolConnection(String),
+    #[error("Kubernetes error: {0}")]
+    Kubernetes(String),
+    #[error("Could not prune cargo caches: {0}")]
+    CargoCache(String),
+    #[error("Not enough free disk space: {0} has {1} MB free")]
+    LowDiskSpace(String, u64),
+    #[error("Disk space error: {0}")]
+    Disk(String),
+    #[error("Could not archive artifacts: {0}")]
+    Archive(String),
+    #[error("Blocking task failed: {0}")]
+    Blocking(String),
+    #[error("Tests failed: {} passed, {} failed", .0.passed, .0.failed)]
+    TestsFailed(TestSummary),
+    #[error("Build did not finish within {} seconds", BUILD_TIMEOUT.as_secs())]
+    Timeout,
     #[error("Failed to get standard output of child process")]
     NoStdOut,
     #[error("Failed to get standard error of child process")]
     NoStdErr,
     #[error("Could not send response - client disconnected")]
     Mpsc,
+    #[error("Expected a program hash followed by a git URL, but got: {0}")]
+    InvalidChainSource(String),
+}
+
+impl Error {
+    /// Which category of build failure this error belongs to
+    pub fn kind(&self) -> FailureKind {
+        match self {
+            Error::GitClone(_) | Error::Fetch(_) | Error::Ipfs(_) => FailureKind::Clone,
+            Error::MetadataMissingRootPackage | Error::Metadata(_) => FailureKind::Metadata,
+            Error::CompilationFailed(_) | Error::InvalidWasm(_) | Error::Optimization(_) => {
+                FailureKind::Compile
+            }
+            Error::OutOfMemory | Error::ResourceLimitExceeded(_) | Error::MemoryLimitNeared(..) => {
+                FailureKind::OutOfMemory
+            }
+            Error::Timeout => FailureKind::Timeout,
+            Error::Cancelled | Error::ClientDisconnected => FailureKind::Cancelled,
+            Error::TestsFailed(_) => FailureKind::Tests,
+            Error::BinaryNotFound => FailureKind::BinaryMissing,
+            Error::Builder(kind, _) => *kind,
+            Error::Json(_)
+            | Error::Db(_)
+            | Error::Hex(_)
+            | Error::Io(_)
+            | Error::NoStdOut
+            | Error::NoStdErr
+            | Error::Mpsc
+            | Error::InvalidChainSource(_)
+            | Error::InvalidCid(_)
+            | Error::InsecureUrl
+            | Error::InvalidSigningKey(_)
+            | Error::Chain(_)
+            | Error::ImagePull(..)
+            | Error::PoolConnection(_)
+            | Error::Kubernetes(_)
+            | Error::CargoCache(_)
+            | Error::LowDiskSpace(..)
+            | Error::Disk(_)
+            | Error::Archive(_)
+            | Error::Blocking(_)
+            | Error::Peer(_) => FailureKind::Internal,
+            Error::InvalidPath
+            | Error::InvalidFeature(_)
+            | Error::UntrustedImage(_)
+            | Error::Zip(_)
+            | Error::UnsafeArchive(_)
+            | Error::InvalidSchema(..)
+            | Error::ChecksumMismatch { .. } => FailureKind::Metadata,
+        }
+    }
+}
+
+/// The steps of a build, in the order they are taken. Building with several images repeats the
+/// steps from compiling onwards for each image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BuildPhase {
+    /// Cloning the git repository
+    Clone,
+    /// Unpacking an archive, or fetching a source file
+    Unpack,
+    /// Reading the program's metadata, auditing its dependencies and pinning its images
+    Metadata,
+    /// Running the program's test suite
+    Tests,
+    /// Compiling the program, here or on a builder of the pool
+    Compile,
+    /// Finding the binary built, and optimizing it if asked to
+    Collect,
+    /// Hashing and checking the binary
+    Hash,
+    /// Storing the program and what is known about it
+    Store,
+}
+
+/// Broad categories of build failure
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureKind {
+    /// The git repository could not be cloned
+    Clone,
+    /// The Cargo.toml file could not be read
+    Metadata,
+    /// The program failed to compile
+    Compile,
+    /// The build was killed, probably by the OOM killer
+    OutOfMemory,
+    /// The build took too long
+    Timeout,
+    /// The build was cancelled by its submitter
+    Cancelled,
+    /// The program's test suite failed
+    Tests,
+    /// The build succeeded but no wasm binary was produced
+    BinaryMissing,
+    /// Something went wrong on the server side
+    Internal,
+    /// The service stopped before the build finished
+    Interrupted,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sp_core::Hasher;
+    use sp_runtime::traits::BlakeTwo256;
+    use temp_dir::TempDir;
+
+    #[test]
+    fn whole_lines_are_given() {
+        let mut lines = OutputLines::default();
+        assert_eq!(lines.push(b"one\ntw"), Some("one\n".to_string()));
+        assert_eq!(lines.push(b"o"), None);
+        assert_eq!(lines.push(b"\nthree\n"), Some("two\nthree\n".to_string()));
+    }
+
+    #[test]
+    fn characters_split_at_buffer_size_are_kept_whole() {
+        for character in ["é", "€", "😀"] {
+            let bytes = character.as_bytes();
+            for split in 1..bytes.len() {
+                let padding = "a".repeat(OUTPUT_BUFFER_SIZE - split);
+                let mut lines = OutputLines::default();
+                let mut first = padding.as_bytes().to_vec();
+                first.extend_from_slice(&bytes[..split]);
+                assert_eq!(first.len(), OUTPUT_BUFFER_SIZE);
+                assert_eq!(whole_characters_len(&first), padding.len());
+
+                // The start of the character is held back until the rest of it is read
+                assert_eq!(lines.push(&first), Some(padding.clone()));
+                let mut rest = bytes[split..].to_vec();
+                rest.push(b'\n');
+                assert_eq!(lines.push(&rest), Some(format!("{}\n", character)));
+            }
+        }
+    }
+
+    #[test]
+    fn whole_characters_are_not_held_back() {
+        assert_eq!(whole_characters_len(b""), 0);
+        assert_eq!(whole_characters_len(b"abc"), 3);
+        assert_eq!(whole_characters_len("abé".as_bytes()), 4);
+        assert_eq!(whole_characters_len("ab😀".as_bytes()), 6);
+    }
+
+    #[test]
+    fn invalid_bytes_are_replaced() {
+        // Bytes which cannot start a character are not held back
+        assert_eq!(whole_characters_len(b"abc\xff"), 4);
+        assert_eq!(whole_characters_len(b"abc\x80\x80\x80"), 6);
+
+        let mut lines = OutputLines::default();
+        assert_eq!(
+            lines.push(b"ok \xff\xfe\n"),
+            Some("ok \u{fffd}\u{fffd}\n".to_string())
+        );
+        let mut long = vec![b'a'; OUTPUT_BUFFER_SIZE - 1];
+        long.push(0xff);
+        let output = lines.push(&long).unwrap();
+        assert_eq!(output.len(), OUTPUT_BUFFER_SIZE - 1 + '\u{fffd}'.len_utf8());
+        assert!(output.ends_with('\u{fffd}'));
+    }
+
+    #[test]
+    fn rest_of_output_is_given_when_it_ends() {
+        let mut lines = OutputLines::default();
+        assert_eq!(lines.push(b"no newline"), None);
+        assert_eq!(lines.push(b""), Some("no newline".to_string()));
+        assert_eq!(lines.push(b""), None);
+
+        // A character cut off by the end of the output is replaced rather than lost
+        assert_eq!(lines.push(&"€".as_bytes()[..2]), None);
+        assert_eq!(lines.push(b""), Some("\u{fffd}".to_string()));
+    }
+
+    #[test]
+    fn program_hash_is_blake_two_256_of_binary_and_metadata() {
+        let metadata = EntropyProgramMetadata {
+            configuration_schema: Some(r#"{"type": "object"}"#.to_string()),
+            auxiliary_data_schema: Some(r#"{"type": "string"}"#.to_string()),
+            oracle_data_pointer: Some("block-number".to_string()),
+            version_number: Some(1),
+            ..Default::default()
+        };
+        let binary = (0..=255)
+            .cycle()
+            .take(3 * HASH_CHUNK_SIZE + 7)
+            .collect::<Vec<u8>>();
+        let expected = BlakeTwo256::hash(&[binary.as_slice(), &metadata.to_bytes()].concat());
+        assert_eq!(metadata.program_hash(&binary), expected);
+
+        // Hashing in chunks, as a built binary is read, gives the same hash
+        let mut hasher = metadata.program_hasher();
+        for chunk in binary.chunks(HASH_CHUNK_SIZE) {
+            hasher.update(chunk);
+        }
+        assert_eq!(hasher.finalize_digests().blake2_256, expected);
+    }
+
+    #[test]
+    fn program_directory_within_repository_is_accepted() {
+        let repo = TempDir::new().unwrap();
+        std::fs::create_dir_all(repo.path().join("programs/example")).unwrap();
+        std::os::unix::fs::symlink("programs", repo.path().join("linked")).unwrap();
+        for subdirectory in ["programs/example", "linked/example"] {
+            assert_eq!(
+                program_directory(repo.path(), Some(Path::new(subdirectory))).unwrap(),
+                repo.path().join(subdirectory)
+            );
+        }
+    }
+
+    #[test]
+    fn program_directory_through_link_out_of_repository_is_rejected() {
+        let repo = TempDir::new().unwrap();
+        std::os::unix::fs::symlink("/", repo.path().join("sub")).unwrap();
+        std::os::unix::fs::symlink("..", repo.path().join("up")).unwrap();
+        for subdirectory in ["sub", "sub/tmp", "up"] {
+            let subdirectory = validate_subdirectory(subdirectory).unwrap();
+            assert!(matches!(
+                program_directory(repo.path(), Some(&subdirectory)),
+                Err(Error::InvalidPath)
+            ));
+        }
+    }
 }
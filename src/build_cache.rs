@@ -0,0 +1,59 @@
+//! Programs built from each commit of a git repository, so that submitting the same commit again
+//! does not need another build
+use sp_core::H256;
+use std::path::Path;
+
+use crate::build::{Error, FeatureSelection};
+
+/// Name of the sled tree mapping repository, commit and program path to program hashes
+const COMMIT_BUILDS_TREE: &str = "commit_builds";
+
+/// Record that a program was built from a commit of a git repository
+pub fn record_commit_build(
+    db: &sled::Db,
+    git_url: &str,
+    commit: &str,
+    path: Option<&Path>,
+    features: &FeatureSelection,
+    hash: H256,
+) -> Result<(), Error> {
+    db.open_tree(COMMIT_BUILDS_TREE)?
+        .insert(commit_key(git_url, commit, path, features), hash.as_ref())?;
+    Ok(())
+}
+
+/// Get the hash of the program built from a commit of a git repository, if it is still present
+pub fn get_commit_build(
+    db: &sled::Db,
+    git_url: &str,
+    commit: &str,
+    path: Option<&Path>,
+    features: &FeatureSelection,
+) -> Result<Option<H256>, Error> {
+    match db
+        .open_tree(COMMIT_BUILDS_TREE)?
+        .get(commit_key(git_url, commit, path, features))?
+    {
+        Some(hash) if db.contains_key(&hash)? => Ok(Some(H256::from_slice(&hash))),
+        _ => Ok(None),
+    }
+}
+
+/// Commit hashes cannot contain spaces, so one separates the commit from the rest of the key.
+/// A choice of features follows on a new line, so that keys of builds with the default features
+/// are unchanged.
+fn commit_key(
+    git_url: &str,
+    commit: &str,
+    path: Option<&Path>,
+    features: &FeatureSelection,
+) -> Vec<u8> {
+    let path = path
+        .map(|path| path.display().to_string())
+        .unwrap_or_default();
+    let mut key = format!("{} {} {}", commit, git_url, path);
+    if !features.is_default() {
+        key.push_str(&format!("\n{}", features));
+    }
+    key.into_bytes()
+}
@@ -0,0 +1,31 @@
+//! Full output of the builds which produced programs, so that how a binary was produced can be
+//! audited after the response stream has gone away
+use sp_core::H256;
+
+use crate::{
+    archive::{get_artifact, ArchiveConfig},
+    build::Error,
+};
+
+/// Name of the sled tree in which build logs are stored, keyed by program hash
+pub const BUILD_LOG_TREE: &str = "build_logs";
+
+/// The most build output kept for a program, beyond which the start of the output is kept
+pub const MAX_BUILD_LOG_SIZE: usize = 10 * 1024 * 1024;
+
+/// Store the output of the build which produced a program, replacing that of any earlier build
+pub fn store_build_log(db: &sled::Db, hash: H256, log: &str) -> Result<(), Error> {
+    db.open_tree(BUILD_LOG_TREE)?.insert(hash, log.as_bytes())?;
+    Ok(())
+}
+
+/// Get the output of the build which produced a program
+pub async fn get_build_log(
+    db: &sled::Db,
+    archive: Option<&ArchiveConfig>,
+    hash: &[u8],
+) -> Result<Option<String>, Error> {
+    Ok(get_artifact(db, archive, BUILD_LOG_TREE, hash)
+        .await?
+        .map(|log| String::from_utf8_lossy(&log).to_string()))
+}
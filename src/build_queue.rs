@@ -0,0 +1,247 @@
+//! The queue of builds waiting for a worker. Clients are told their place in the queue when their
+//! build is queued and again each time a build ahead of it starts, along with when it is expected
+//! to start, judging by how long recent builds took. A request identical to one which is queued
+//! or being built follows that build instead of being queued.
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc::{
+    channel,
+    error::{SendError, TrySendError},
+    Receiver, Sender,
+};
+
+use crate::{
+    build::{BuildRequest, BuildResponder, BuildResponse},
+    record::unix_time,
+};
+
+/// Number of recent builds whose durations are averaged to estimate when queued builds start
+const RECENT_BUILDS: usize = 20;
+
+/// Sends build requests to the build workers, keeping track of those waiting
+#[derive(Clone)]
+pub struct BuildQueue {
+    sender: Sender<BuildRequest>,
+    waiting: Arc<Mutex<Waiting>>,
+    in_flight: Arc<Mutex<InFlightBuilds>>,
+}
+
+/// The responders of builds which are queued or being built, by what identifies identical
+/// requests, along with the ticket of each build
+type InFlightBuilds = HashMap<String, (u64, BuildResponder)>;
+
+/// The builds waiting for a worker, in the order they will start
+#[derive(Default)]
+struct Waiting {
+    next_ticket: u64,
+    builds: VecDeque<(u64, BuildResponder)>,
+    recent_durations: VecDeque<Duration>,
+    workers: usize,
+}
+
+/// A build's place in the queue, which it leaves when this is dropped, whether because a worker
+/// took it or because it could not be queued
+pub struct QueueEntry {
+    ticket: u64,
+    waiting: Arc<Mutex<Waiting>>,
+}
+
+/// Lets identical requests follow a build until it is dropped, once the build has finished
+pub struct InFlight {
+    key: String,
+    ticket: u64,
+    in_flight: Arc<Mutex<InFlightBuilds>>,
+}
+
+/// Times a build once a worker has taken it, so that its duration informs later estimates
+pub struct BuildTimer {
+    started: Instant,
+    waiting: Arc<Mutex<Waiting>>,
+}
+
+/// Make a queue holding up to the given number of builds, for the given number of workers
+pub fn build_queue(size: usize, workers: usize) -> (BuildQueue, Receiver<BuildRequest>) {
+    let (sender, receiver) = channel(size);
+    let waiting = Waiting {
+        workers: workers.max(1),
+        ..Default::default()
+    };
+    let queue = BuildQueue {
+        sender,
+        waiting: Arc::new(Mutex::new(waiting)),
+        in_flight: Default::default(),
+    };
+    (queue, receiver)
+}
+
+impl BuildQueue {
+    /// Queue a build, failing if the queue is full. A build which cannot be queued is dropped.
+    pub fn try_send(&self, build_request: BuildRequest) -> Result<(), TrySendError<()>> {
+        let Some(build_request) = self.enter(build_request) else {
+            return Ok(());
+        };
+        self.sender
+            .try_send(build_request)
+            .map_err(|error| match error {
+                TrySendError::Full(_) => TrySendError::Full(()),
+                TrySendError::Closed(_) => TrySendError::Closed(()),
+            })
+    }
+
+    /// Queue a build, waiting for room if the queue is full
+    pub async fn send(&self, build_request: BuildRequest) -> Result<(), SendError<()>> {
+        let Some(build_request) = self.enter(build_request) else {
+            return Ok(());
+        };
+        self.sender
+            .send(build_request)
+            .await
+            .map_err(|_| SendError(()))
+    }
+
+    /// Number of builds waiting for a worker
+    pub fn len(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of builds which may wait before the queue is full
+    pub fn max_len(&self) -> usize {
+        self.sender.max_capacity()
+    }
+
+    /// Put a build at the back of the queue, telling its client where it is, unless it follows
+    /// an identical build, in which case it is not given back
+    fn enter(&self, mut build_request: BuildRequest) -> Option<BuildRequest> {
+        let Ok(mut waiting) = self.waiting.lock() else {
+            return Some(build_request);
+        };
+        let ticket = waiting.next_ticket;
+        waiting.next_ticket += 1;
+
+        let in_flight = match (build_request.dedup_key(), self.in_flight.lock()) {
+            (Some(key), Ok(mut in_flight)) => {
+                if let Some((_, leader)) = in_flight.get(&key) {
+                    if leader.add_follower(build_request.responder()) {
+                        log::info!("Build request follows an identical build");
+                        return None;
+                    }
+                }
+                // Any build of the same key has finished, so this one takes its place
+                in_flight.insert(key.clone(), (ticket, build_request.responder()));
+                Some(InFlight {
+                    key,
+                    ticket,
+                    in_flight: self.in_flight.clone(),
+                })
+            }
+            _ => None,
+        };
+
+        waiting
+            .builds
+            .push_back((ticket, build_request.responder()));
+        let position = waiting.builds.len() - 1;
+        waiting.notify_from(position);
+        let queue_entry = QueueEntry {
+            ticket,
+            waiting: self.waiting.clone(),
+        };
+        build_request.enter_queue(queue_entry, in_flight);
+        Some(build_request)
+    }
+}
+
+impl Waiting {
+    /// When a build at the given position is expected to start, as a unix timestamp, if any
+    /// builds have finished to judge by
+    fn estimated_start(&self, position: usize) -> Option<u64> {
+        let count = self.recent_durations.len() as u32;
+        if count == 0 {
+            return None;
+        }
+        let average = self.recent_durations.iter().sum::<Duration>() / count;
+        // Every worker is busy while builds are waiting, so each round of builds ahead of this
+        // one takes about as long as an average build
+        let rounds = position.div_ceil(self.workers) as u32;
+        Some(unix_time() + (average * rounds).as_secs())
+    }
+
+    /// Tell the clients of builds from the given index onwards their place in the queue
+    fn notify_from(&mut self, index: usize) {
+        let estimates: Vec<_> = (index..self.builds.len())
+            .map(|index| self.estimated_start(index + 1))
+            .collect();
+        for ((position, (_, responder)), estimated_start) in self
+            .builds
+            .iter_mut()
+            .enumerate()
+            .skip(index)
+            .zip(estimates)
+        {
+            let _ = responder.try_send(BuildResponse::Queued {
+                position: position + 1,
+                estimated_start,
+            });
+        }
+    }
+}
+
+impl QueueEntry {
+    /// Leave the queue as a worker takes the build, starting its timer
+    pub fn start(self) -> BuildTimer {
+        BuildTimer {
+            started: Instant::now(),
+            waiting: self.waiting.clone(),
+        }
+    }
+}
+
+impl Drop for QueueEntry {
+    fn drop(&mut self) {
+        let Ok(mut waiting) = self.waiting.lock() else {
+            return;
+        };
+        let index = waiting
+            .builds
+            .iter()
+            .position(|(ticket, _)| *ticket == self.ticket);
+        if let Some(index) = index {
+            waiting.builds.remove(index);
+            // Everything behind the build moves up a place
+            waiting.notify_from(index);
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if let Ok(mut in_flight) = self.in_flight.lock() {
+            // A later build may have taken the key once this one finished
+            if in_flight
+                .get(&self.key)
+                .is_some_and(|(ticket, _)| *ticket == self.ticket)
+            {
+                in_flight.remove(&self.key);
+            }
+        }
+    }
+}
+
+impl BuildTimer {
+    /// Record how long the build took
+    pub fn finish(self) {
+        if let Ok(mut waiting) = self.waiting.lock() {
+            waiting.recent_durations.push_back(self.started.elapsed());
+            while waiting.recent_durations.len() > RECENT_BUILDS {
+                waiting.recent_durations.pop_front();
+            }
+        }
+    }
+}
@@ -0,0 +1,265 @@
+//! A pool of builders on other machines which programs are built on, so that the host serving
+//! requests need not have the memory builds take. Builders are this service run with
+//! `--builder`, which ask it for builds over HTTP, carry out the tests and compilation, and send
+//! back their output and the binary. Everything else about a build still happens here.
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::sync::{mpsc, Notify};
+
+use crate::{
+    build::{BuildResponse, Error, FailureKind, FeatureSelection},
+    record::unix_time,
+    test_suite::TestSummary,
+};
+
+/// Longest time a request from a builder for a build is held open waiting for one to be queued
+pub const MAX_BUILD_WAIT: Duration = Duration::from_secs(30);
+
+/// Number of messages from a builder which may be waiting to be passed on to the client
+const MESSAGE_BUFFER_SIZE: usize = 1000;
+
+/// Settings for building programs on a pool of builders, given both to the service and to the
+/// builders
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BuilderPoolConfig {
+    /// Secret key builders give as a bearer token
+    pub key: String,
+    /// Name this process registers with when run as a builder, if not a random one
+    pub name: Option<String>,
+}
+
+/// A build sent to a builder. Its source is fetched separately.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RemoteBuild {
+    pub id: u64,
+    /// The image chosen for the program, which the builder pins by digest when it pulls it
+    pub docker_image: Option<String>,
+    pub features: FeatureSelection,
+    /// Run the program's tests before building it
+    pub run_tests: bool,
+    /// Give up without building if the tests fail
+    pub require_tests_pass: bool,
+}
+
+/// A message about a build, sent from its builder as a line of JSON
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum BuilderMessage {
+    /// A builder took the build. This is given by the pool itself rather than by builders.
+    Started(String),
+    /// Output or a warning, which is passed on to the client
+    Response(BuildResponse),
+    /// Sent while a build gives no output, so that it is not taken to have stopped
+    Heartbeat,
+    /// The outcome of the program's tests, if they were run
+    Tests(TestSummary),
+    /// The binary, which ends the build
+    Built {
+        binary_filename: String,
+        binary: Vec<u8>,
+        /// The image the program was built with, pinned by digest if it could be resolved
+        docker_image: Option<String>,
+    },
+    /// Why the build failed, which also ends it
+    Failed { kind: FailureKind, error: String },
+}
+
+impl BuilderMessage {
+    /// The message ending a build which failed with the given error
+    pub fn failed(error: &Error) -> Self {
+        Self::Failed {
+            kind: error.kind(),
+            error: error.to_string(),
+        }
+    }
+}
+
+/// A builder which has asked for builds
+#[derive(Debug, Clone, Serialize)]
+pub struct BuilderStatus {
+    /// Unix time in seconds when the builder registered
+    pub registered_at: u64,
+    /// Unix time in seconds when the builder last asked for a build or sent output
+    pub last_seen: u64,
+    /// The build it is carrying out, if any
+    pub building: Option<u64>,
+}
+
+impl BuilderStatus {
+    fn new() -> Self {
+        let now = unix_time();
+        Self {
+            registered_at: now,
+            last_seen: now,
+            building: None,
+        }
+    }
+}
+
+/// A build waiting for or being carried out by a builder
+struct PendingBuild {
+    build: RemoteBuild,
+    /// Gzipped tarball of the program's source
+    source: Arc<Vec<u8>>,
+    messages: mpsc::Sender<BuilderMessage>,
+    /// The builder carrying it out, once one has taken it
+    builder: Option<String>,
+}
+
+#[derive(Default)]
+struct PoolState {
+    /// IDs of builds which no builder has taken yet, oldest first
+    queue: VecDeque<u64>,
+    builds: HashMap<u64, PendingBuild>,
+    builders: BTreeMap<String, BuilderStatus>,
+}
+
+/// Builds waiting for builders, and the builders which have registered
+#[derive(Clone, Default)]
+pub struct BuilderPool {
+    state: Arc<Mutex<PoolState>>,
+    /// Woken when a build is queued
+    queued: Arc<Notify>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl BuilderPool {
+    /// Record that a builder has started. A builder which registers again has restarted, and
+    /// abandoned anything it was building.
+    pub fn register(&self, name: &str) {
+        if let Ok(mut state) = self.state.lock() {
+            state
+                .builders
+                .insert(name.to_string(), BuilderStatus::new());
+        }
+        log::info!("Builder {} registered", name);
+    }
+
+    /// Queue a build for the next builder which asks for one, giving the build's ID and the
+    /// channel on which its messages arrive
+    pub fn submit(
+        &self,
+        mut build: RemoteBuild,
+        source: Vec<u8>,
+    ) -> (u64, mpsc::Receiver<BuilderMessage>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        build.id = id;
+        let (sender, receiver) = mpsc::channel(MESSAGE_BUFFER_SIZE);
+        if let Ok(mut state) = self.state.lock() {
+            state.queue.push_back(id);
+            state.builds.insert(
+                id,
+                PendingBuild {
+                    build,
+                    source: Arc::new(source),
+                    messages: sender,
+                    builder: None,
+                },
+            );
+        }
+        self.queued.notify_one();
+        (id, receiver)
+    }
+
+    /// Give a builder the oldest queued build, waiting up to the given time for one if there
+    /// are none
+    pub async fn next(&self, builder: &str, wait: Duration) -> Option<RemoteBuild> {
+        let deadline = tokio::time::Instant::now() + wait;
+        loop {
+            if let Some(build) = self.take(builder) {
+                return Some(build);
+            }
+            tokio::time::timeout_at(deadline, self.queued.notified())
+                .await
+                .ok()?;
+        }
+    }
+
+    /// Take the oldest queued build for a builder, if there is one
+    fn take(&self, builder: &str) -> Option<RemoteBuild> {
+        let mut guard = self.state.lock().ok()?;
+        let state = &mut *guard;
+        let status = state
+            .builders
+            .entry(builder.to_string())
+            .or_insert_with(BuilderStatus::new);
+        status.last_seen = unix_time();
+        let id = state.queue.pop_front()?;
+        let pending = state.builds.get_mut(&id)?;
+        status.building = Some(id);
+        pending.builder = Some(builder.to_string());
+        let _ = pending
+            .messages
+            .try_send(BuilderMessage::Started(builder.to_string()));
+        Some(pending.build.clone())
+    }
+
+    /// The source of a build, as a gzipped tarball
+    pub fn source(&self, id: u64) -> Option<Arc<Vec<u8>>> {
+        let state = self.state.lock().ok()?;
+        state.builds.get(&id).map(|pending| pending.source.clone())
+    }
+
+    /// Pass messages from a builder on to the build they are about. Gives false if the build is
+    /// no longer wanted, as it has been given up on.
+    pub async fn deliver(&self, id: u64, messages: Vec<BuilderMessage>) -> bool {
+        let sender = {
+            let Ok(mut guard) = self.state.lock() else {
+                return false;
+            };
+            let state = &mut *guard;
+            let Some(pending) = state.builds.get(&id) else {
+                return false;
+            };
+            if let Some(status) = pending
+                .builder
+                .as_ref()
+                .and_then(|builder| state.builders.get_mut(builder))
+            {
+                status.last_seen = unix_time();
+            }
+            pending.messages.clone()
+        };
+        for message in messages {
+            if sender.send(message).await.is_err() {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Forget a build once it has ended or been given up on
+    pub fn finish(&self, id: u64) {
+        let Ok(mut guard) = self.state.lock() else {
+            return;
+        };
+        let state = &mut *guard;
+        state.queue.retain(|queued| *queued != id);
+        if let Some(status) = state
+            .builds
+            .remove(&id)
+            .and_then(|pending| pending.builder)
+            .and_then(|builder| state.builders.get_mut(&builder))
+        {
+            if status.building == Some(id) {
+                status.building = None;
+            }
+        }
+    }
+
+    /// The builders which have registered or asked for builds, by name
+    pub fn builders(&self) -> BTreeMap<String, BuilderStatus> {
+        self.state
+            .lock()
+            .map(|state| state.builders.clone())
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,156 @@
+//! Cargo caches kept between builds, so that each build does not download and compile every
+//! dependency from scratch. Programs built with the template's Dockerfile keep the cargo
+//! registry and target directory in BuildKit cache mounts, and test containers mount named
+//! volumes for them. Both only grow, so are pruned periodically or on request.
+use serde::{Deserialize, Serialize};
+use std::{process::Command, time::Duration};
+
+use crate::{
+    build::Error,
+    container::{ContainerBackend, ContainerEngine},
+};
+
+/// Cargo's home directory in build images, as in the official rust images
+const CONTAINER_CARGO_HOME: &str = "/usr/local/cargo";
+
+/// Where the target directory volume is mounted in test containers
+const CONTAINER_TARGET_DIR: &str = "/var/cache/cargo-target";
+
+const DEFAULT_REGISTRY_VOLUME: &str = "program-metadata-cargo-registry";
+
+const DEFAULT_TARGET_VOLUME: &str = "program-metadata-cargo-target";
+
+/// Cache mounts unused for this long are removed when pruning, if not configured
+const DEFAULT_MAX_AGE_HOURS: u64 = 7 * 24;
+
+/// Settings for the cargo caches shared between builds
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CargoCacheConfig {
+    /// Named volume holding the cargo registry for test containers
+    pub registry_volume: Option<String>,
+    /// Named volume holding the target directory for test containers
+    pub target_volume: Option<String>,
+    /// How often to prune the caches, in hours. If not given, they are only pruned on request.
+    pub cleanup_interval_hours: Option<u64>,
+    /// Build cache mounts not used for this many hours are removed when pruning, a week if not
+    /// given
+    pub max_age_hours: Option<u64>,
+    /// Amount of build cache to keep when pruning, such as `20GB`. If not given, all build cache
+    /// older than the maximum age is removed.
+    pub max_size: Option<String>,
+}
+
+/// The outcome of pruning the caches
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CargoCacheReport {
+    /// What the container engine reported removing from its build cache
+    pub build_cache: Option<String>,
+    /// The volumes which were removed, and are created again empty by the next test run
+    pub removed_volumes: Vec<String>,
+    /// Volumes which could not be removed, such as those in use by a running test
+    pub kept_volumes: Vec<String>,
+}
+
+impl CargoCacheConfig {
+    fn registry_volume(&self) -> &str {
+        self.registry_volume
+            .as_deref()
+            .unwrap_or(DEFAULT_REGISTRY_VOLUME)
+    }
+
+    fn target_volume(&self) -> &str {
+        self.target_volume
+            .as_deref()
+            .unwrap_or(DEFAULT_TARGET_VOLUME)
+    }
+
+    /// How often to prune the caches, if periodically
+    pub fn cleanup_interval(&self) -> Option<Duration> {
+        self.cleanup_interval_hours
+            .map(|hours| Duration::from_secs(hours.max(1) * 60 * 60))
+    }
+
+    /// Arguments to `run` which mount the cache volumes in a test container
+    pub fn docker_run_args(&self) -> Vec<String> {
+        vec![
+            format!(
+                "--volume={}:{}/registry",
+                self.registry_volume(),
+                CONTAINER_CARGO_HOME
+            ),
+            format!("--volume={}:{}", self.target_volume(), CONTAINER_TARGET_DIR),
+            format!("--env=CARGO_TARGET_DIR={}", CONTAINER_TARGET_DIR),
+        ]
+    }
+
+    /// Remove build cache mounts which have not been used recently, and the test volumes
+    pub fn prune(&self, container_engine: ContainerEngine) -> Result<CargoCacheReport, Error> {
+        let backend = container_engine.backend();
+        let mut report = CargoCacheReport::default();
+        // Podman has no separate build cache to prune
+        if container_engine == ContainerEngine::Docker {
+            let mut command = backend.command();
+            command
+                .args([
+                    "builder",
+                    "prune",
+                    "--force",
+                    "--filter=type=exec.cachemount",
+                ])
+                .arg(format!(
+                    "--filter=until={}h",
+                    self.max_age_hours.unwrap_or(DEFAULT_MAX_AGE_HOURS)
+                ));
+            if let Some(max_size) = &self.max_size {
+                command.arg(format!("--keep-storage={}", max_size));
+            }
+            let output = run(command)?;
+            report.build_cache = output.lines().last().map(str::to_string);
+        }
+        for volume in [self.registry_volume(), self.target_volume()] {
+            if remove_volume(backend, volume) {
+                report.removed_volumes.push(volume.to_string());
+            } else {
+                report.kept_volumes.push(volume.to_string());
+            }
+        }
+        log::info!("Pruned cargo caches: {:?}", report);
+        Ok(report)
+    }
+}
+
+/// Prune the caches without blocking the async runtime
+pub async fn prune_cargo_cache(
+    config: CargoCacheConfig,
+    container_engine: ContainerEngine,
+) -> Result<CargoCacheReport, Error> {
+    tokio::task::spawn_blocking(move || config.prune(container_engine))
+        .await
+        .map_err(|error| Error::CargoCache(format!("pruning panicked: {}", error)))?
+}
+
+/// Remove a volume, giving whether it is gone. A volume which does not exist yet is gone.
+fn remove_volume(backend: &dyn ContainerBackend, volume: &str) -> bool {
+    let mut command = backend.command();
+    command.args(["volume", "rm", volume]);
+    match run(command) {
+        Ok(_) => true,
+        Err(Error::CargoCache(error)) if error.to_lowercase().contains("no such volume") => true,
+        Err(error) => {
+            log::warn!("Could not remove volume {}: {}", volume, error);
+            false
+        }
+    }
+}
+
+/// Run a command of the container engine, giving its standard output
+fn run(mut command: Command) -> Result<String, Error> {
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(Error::CargoCache(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
@@ -0,0 +1,357 @@
+//! Interaction with the Entropy chain, such as registering built programs with the programs
+//! pallet. Calls are made through subxt's dynamic interface, so that the service does not need
+//! the chain's metadata when it is compiled.
+use futures::StreamExt;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sp_core::H256;
+use std::str::FromStr;
+use subxt::{
+    dynamic::Value,
+    ext::scale_value::{scale::TypeId, At, Primitive, ValueDef},
+    utils::AccountId32,
+    OnlineClient, PolkadotConfig,
+};
+use subxt_signer::{sr25519::Keypair, SecretUri};
+
+use crate::{
+    binary::store_binary,
+    build::{EntropyProgramMetadata, Error},
+    digest::store_digests,
+    entropy_metadata::store_entropy_metadata,
+    index::add_search_terms,
+    record::unix_time,
+};
+
+/// Name of the sled tree in which the last known deployment status of programs is stored, keyed
+/// by program hash
+const DEPLOYMENT_STATUS_TREE: &str = "deployment_status";
+
+/// How often to check which programs are on chain, if not configured
+const DEFAULT_STATUS_INTERVAL_MINUTES: u64 = 60;
+
+/// How programs which were found on chain, rather than built or registered here, are marked in
+/// their metadata
+pub const UNVERIFIED_ON_CHAIN: &str = "unverified (deployed on chain)";
+
+/// Number of programs fetched at a time when importing those already on chain
+const IMPORT_PAGE_SIZE: u32 = 100;
+
+/// Settings for connecting to the Entropy chain
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ChainConfig {
+    /// Websocket RPC endpoint of a chain node, such as `ws://localhost:9944`
+    pub endpoint: String,
+    /// Secret URI of a funded sr25519 account with which to register programs on request. If
+    /// not given, programs are not registered.
+    pub deployer_key: Option<String>,
+    /// How often to check which stored programs are on chain, in minutes
+    pub status_interval_minutes: Option<u64>,
+    /// Whether to import every program registered on chain, following the chain as new ones are
+    /// registered, so that programs which were not built here can also be looked up
+    pub index_programs: Option<bool>,
+}
+
+impl ChainConfig {
+    /// How often to check which stored programs are on chain, in minutes
+    pub fn status_interval_minutes(&self) -> u64 {
+        self.status_interval_minutes
+            .unwrap_or(DEFAULT_STATUS_INTERVAL_MINUTES)
+    }
+}
+
+/// Whether a program is registered on chain, as last checked
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct DeploymentStatus {
+    pub deployed: bool,
+    /// SS58 address of the account which registered the program, if it is on chain
+    pub deployer: Option<String>,
+    /// Unix time in seconds when the chain was checked
+    pub checked_at: u64,
+}
+
+/// The transaction with which a program was registered on chain
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Deployment {
+    #[schemars(with = "String")]
+    pub extrinsic_hash: H256,
+    /// The finalized block the transaction was included in
+    #[schemars(with = "String")]
+    pub block_hash: H256,
+    /// SS58 address of the account which registered the program
+    pub deployer: String,
+}
+
+/// An error registering a program on chain. This does not fail the build of the program.
+#[derive(Debug, thiserror::Error)]
+pub enum DeploymentError {
+    #[error("Could not register program on chain: no chain is configured")]
+    NoChain,
+    #[error("Could not register program on chain: no deployer key is configured")]
+    NoDeployerKey,
+    #[error("Invalid deployer key: {0}")]
+    InvalidSigningKey(String),
+    #[error("Could not register program on chain: {0}")]
+    Chain(String),
+    #[error("Program is already registered on chain by {}", .0.as_deref().unwrap_or("an unknown account"))]
+    AlreadyDeployed(Option<String>),
+    #[error("Could not read program to register on chain: {0}")]
+    Program(#[from] Error),
+}
+
+/// Register a program with the programs pallet, waiting for the transaction to be finalized
+pub async fn deploy_program(
+    config: &ChainConfig,
+    binary: &[u8],
+    entropy_metadata: &EntropyProgramMetadata,
+) -> Result<Deployment, DeploymentError> {
+    let deployer_key = config
+        .deployer_key
+        .as_deref()
+        .ok_or(DeploymentError::NoDeployerKey)?;
+    let secret_uri = SecretUri::from_str(deployer_key)
+        .map_err(|error| DeploymentError::InvalidSigningKey(error.to_string()))?;
+    let signer = Keypair::from_uri(&secret_uri)
+        .map_err(|error| DeploymentError::InvalidSigningKey(error.to_string()))?;
+
+    let chain_error = |error: subxt::Error| DeploymentError::Chain(error.to_string());
+    let api = OnlineClient::<PolkadotConfig>::from_url(&config.endpoint)
+        .await
+        .map_err(chain_error)?;
+    let bytes = |value: &Option<String>| Value::from_bytes(value.as_deref().unwrap_or_default());
+    let set_program = subxt::dynamic::tx(
+        "Programs",
+        "set_program",
+        vec![
+            Value::from_bytes(binary),
+            bytes(&entropy_metadata.configuration_schema),
+            bytes(&entropy_metadata.auxiliary_data_schema),
+            bytes(&entropy_metadata.oracle_data_pointer),
+            Value::u128(entropy_metadata.version_number.unwrap_or_default().into()),
+        ],
+    );
+    let events = api
+        .tx()
+        .sign_and_submit_then_watch_default(&set_program, &signer)
+        .await
+        .map_err(chain_error)?
+        .wait_for_finalized_success()
+        .await
+        .map_err(chain_error)?;
+    Ok(Deployment {
+        extrinsic_hash: H256::from_slice(events.extrinsic_hash().as_ref()),
+        block_hash: H256::from_slice(events.block_hash().as_ref()),
+        deployer: AccountId32::from(signer.public_key().0).to_string(),
+    })
+}
+
+/// Check which stored programs are registered with the programs pallet, storing the result for
+/// each. Gives the number of programs found on chain.
+pub async fn refresh_deployment_status(
+    db: &sled::Db,
+    config: &ChainConfig,
+) -> Result<usize, Error> {
+    let chain_error = |error: subxt::Error| Error::Chain(error.to_string());
+    let api = OnlineClient::<PolkadotConfig>::from_url(&config.endpoint)
+        .await
+        .map_err(chain_error)?;
+    let storage = api.storage().at_latest().await.map_err(chain_error)?;
+    let mut deployed = 0;
+    for key in db.iter().keys() {
+        let hash = key?;
+        let address =
+            subxt::dynamic::storage("Programs", "Programs", vec![Value::from_bytes(&hash)]);
+        let program_info = storage.fetch(&address).await.map_err(chain_error)?;
+        let deployer = match &program_info {
+            Some(program_info) => program_info
+                .to_value()
+                .map_err(chain_error)?
+                .at("deployer")
+                .map(account_address),
+            None => None,
+        };
+        let status = DeploymentStatus {
+            deployed: program_info.is_some(),
+            deployer,
+            checked_at: unix_time(),
+        };
+        if status.deployed {
+            deployed += 1;
+        }
+        store_deployment_status(db, &hash, &status)?;
+    }
+    Ok(deployed)
+}
+
+/// Import every program registered with the programs pallet, first those already on chain and
+/// then those registered in each block as it is finalized. This runs until the connection to the
+/// chain is lost.
+pub async fn index_chain_programs(db: &sled::Db, config: &ChainConfig) -> Result<(), Error> {
+    let chain_error = |error: subxt::Error| Error::Chain(error.to_string());
+    let api = OnlineClient::<PolkadotConfig>::from_url(&config.endpoint)
+        .await
+        .map_err(chain_error)?;
+    // Subscribing first means programs registered while catching up are not missed
+    let mut blocks = api
+        .blocks()
+        .subscribe_finalized()
+        .await
+        .map_err(chain_error)?;
+
+    let storage = api.storage().at_latest().await.map_err(chain_error)?;
+    let mut programs = storage
+        .iter(
+            subxt::dynamic::storage_root("Programs", "Programs"),
+            IMPORT_PAGE_SIZE,
+        )
+        .await
+        .map_err(chain_error)?;
+    let mut imported = 0;
+    while let Some((key, program_info)) = programs.next().await.map_err(chain_error)? {
+        // Storage keys end with the map key, which is the program hash
+        let Some(hash) = key.0.len().checked_sub(32).map(|start| &key.0[start..]) else {
+            continue;
+        };
+        let program_info = program_info.to_value().map_err(chain_error)?;
+        if import_chain_program(db, H256::from_slice(hash), &program_info)? {
+            imported += 1;
+        }
+    }
+    log::info!("Imported {} programs which were already on chain", imported);
+
+    while let Some(block) = blocks.next().await {
+        let block = block.map_err(chain_error)?;
+        let events = block.events().await.map_err(chain_error)?;
+        for event in events.iter() {
+            let event = event.map_err(chain_error)?;
+            if event.pallet_name() != "Programs" || event.variant_name() != "ProgramCreated" {
+                continue;
+            }
+            let fields = event.field_values().map_err(chain_error)?;
+            let hash = fields
+                .at("program_hash")
+                .map(value_bytes)
+                .unwrap_or_default();
+            if hash.len() != 32 {
+                log::warn!("Program registered in block {} has no hash", block.number());
+                continue;
+            }
+            let address =
+                subxt::dynamic::storage("Programs", "Programs", vec![Value::from_bytes(&hash)]);
+            let program_info = api
+                .storage()
+                .at(block.hash())
+                .fetch(&address)
+                .await
+                .map_err(chain_error)?;
+            if let Some(program_info) = program_info {
+                let program_info = program_info.to_value().map_err(chain_error)?;
+                import_chain_program(db, H256::from_slice(&hash), &program_info)?;
+            }
+        }
+    }
+    Err(Error::Chain(
+        "subscription to finalized blocks ended".to_string(),
+    ))
+}
+
+/// Store a program found on chain, marking it as unverified unless it is already present.
+/// Returns whether it was newly added.
+fn import_chain_program(
+    db: &sled::Db,
+    hash: H256,
+    program_info: &subxt::ext::scale_value::Value<TypeId>,
+) -> Result<bool, Error> {
+    let field = |name: &str| program_info.at(name).map(value_bytes).unwrap_or_default();
+    let text = |name: &str| {
+        let bytes = field(name);
+        (!bytes.is_empty()).then(|| String::from_utf8_lossy(&bytes).to_string())
+    };
+    let entropy_metadata = EntropyProgramMetadata {
+        configuration_schema: text("configuration_schema"),
+        auxiliary_data_schema: text("auxiliary_data_schema"),
+        oracle_data_pointer: text("oracle_data_pointer"),
+        version_number: program_info
+            .at("version_number")
+            .and_then(|version_number| version_number.as_u128())
+            .and_then(|version_number| u8::try_from(version_number).ok()),
+        ..Default::default()
+    };
+    store_deployment_status(
+        db,
+        hash.as_bytes(),
+        &DeploymentStatus {
+            deployed: true,
+            deployer: program_info.at("deployer").map(account_address),
+            checked_at: unix_time(),
+        },
+    )?;
+    if db.contains_key(hash)? {
+        return Ok(false);
+    }
+
+    let bytecode = field("bytecode");
+    let mut hasher = entropy_metadata.program_hasher();
+    hasher.update(&bytecode);
+    let digests = hasher.finalize_digests();
+    // The metadata is read back from its on-chain form, so check nothing was lost on the way
+    if digests.blake2_256 != hash {
+        log::warn!(
+            "Program {} on chain does not match its hash, so was not imported",
+            hex::encode(hash)
+        );
+        return Ok(false);
+    }
+    store_binary(db, hash, &bytecode)?;
+    store_digests(db, &digests)?;
+    let metadata = json!({
+        "metadata": { "entropy-program": entropy_metadata },
+        "verification": UNVERIFIED_ON_CHAIN,
+    });
+    db.insert(hash, serde_json::to_vec(&metadata)?)?;
+    store_entropy_metadata(db, hash, &entropy_metadata)?;
+    add_search_terms(db, hash, &metadata)?;
+    Ok(true)
+}
+
+/// Flatten a decoded value made of bytes, such as an account ID, into those bytes
+fn value_bytes<T>(value: &subxt::ext::scale_value::Value<T>) -> Vec<u8> {
+    match &value.value {
+        ValueDef::Composite(composite) => composite.values().flat_map(value_bytes).collect(),
+        ValueDef::Primitive(Primitive::U128(byte)) => vec![*byte as u8],
+        _ => Vec::new(),
+    }
+}
+
+/// The SS58 address of a decoded account ID
+fn account_address<T>(value: &subxt::ext::scale_value::Value<T>) -> String {
+    let mut account = [0; 32];
+    let bytes = value_bytes(value);
+    let length = bytes.len().min(32);
+    account[..length].copy_from_slice(&bytes[..length]);
+    AccountId32::from(account).to_string()
+}
+
+/// Store whether a program is registered on chain
+pub fn store_deployment_status(
+    db: &sled::Db,
+    hash: &[u8],
+    status: &DeploymentStatus,
+) -> Result<(), Error> {
+    db.open_tree(DEPLOYMENT_STATUS_TREE)?
+        .insert(hash, serde_json::to_vec(status)?)?;
+    Ok(())
+}
+
+/// Get whether a program is registered on chain, if this has been checked
+pub fn get_deployment_status(
+    db: &sled::Db,
+    hash: &[u8],
+) -> Result<Option<DeploymentStatus>, Error> {
+    match db.open_tree(DEPLOYMENT_STATUS_TREE)?.get(hash)? {
+        Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,222 @@
+//! Verification of programs found on chain by rebuilding them from the source they claim, so that
+//! programs which were deployed without being built here can be checked against their source
+use futures::{channel::mpsc as futures_mpsc, StreamExt};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sp_core::H256;
+
+use crate::{
+    build::{BuildOptions, BuildRequest, BuildResponder, BuildResponse, Error},
+    build_queue::BuildQueue,
+    chain::get_deployment_status,
+    record::unix_time,
+    seed::Seed,
+};
+
+/// Name of the sled tree in which the outcomes of verifying on-chain programs are stored, keyed
+/// by program hash
+const CHAIN_VERIFICATION_TREE: &str = "chain_verification";
+
+/// How far verifying an on-chain program against its source has got
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ChainVerificationStatus {
+    /// The rebuild is waiting for or in progress
+    Pending,
+    /// The rebuild gave the same hash as the program on chain
+    Verified,
+    /// The rebuild gave a different hash
+    Mismatch,
+    /// The program could not be rebuilt
+    Failed,
+}
+
+/// The outcome of rebuilding an on-chain program from the source it claims
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ChainVerification {
+    pub status: ChainVerificationStatus,
+    /// The git repository the program was rebuilt from
+    pub repository: String,
+    /// The branch, tag or commit rebuilt, if not the default branch
+    pub git_ref: Option<String>,
+    /// The hash the rebuild gave, if it succeeded
+    #[schemars(with = "Option<String>")]
+    pub built: Option<H256>,
+    /// Why the rebuild failed, if it did
+    pub error: Option<String>,
+    /// Unix time in seconds when the status was last changed
+    pub updated_at: u64,
+}
+
+/// The outcome of requesting rebuilds of on-chain programs
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ChainVerificationImport {
+    /// Number of rebuilds requested
+    pub queued: usize,
+    /// Number of programs skipped, because they are not known to be on chain or have already
+    /// been verified
+    pub skipped: usize,
+}
+
+/// Parse a list of programs to verify, which has a program hash and git URL on each line,
+/// optionally followed by a branch or tag. Blank lines and lines starting with `#` are ignored.
+pub fn parse_chain_source_list(input: &str) -> Result<Vec<(H256, Seed)>, Error> {
+    input
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let mut words = line.split_whitespace();
+            let hash = words.next().unwrap_or_default();
+            let hash = hex::decode(hash.trim_start_matches("0x"))
+                .ok()
+                .filter(|hash| hash.len() == 32)
+                .ok_or_else(|| Error::InvalidChainSource(line.to_string()))?;
+            let url = words
+                .next()
+                .ok_or_else(|| Error::InvalidChainSource(line.to_string()))?;
+            Ok((
+                H256::from_slice(&hash),
+                Seed {
+                    url: url.to_string(),
+                    git_ref: words.next().map(|git_ref| git_ref.to_string()),
+                },
+            ))
+        })
+        .collect()
+}
+
+/// Find the programs on chain which have not been verified but whose stored metadata gives the
+/// repository they were built from
+pub fn find_chain_sources(db: &sled::Db) -> Result<Vec<(H256, Seed)>, Error> {
+    let mut sources = Vec::new();
+    for entry in db.iter() {
+        let (hash, value) = entry?;
+        if hash.len() != 32 {
+            continue;
+        }
+        let metadata: serde_json::Value = serde_json::from_slice(&value)?;
+        // Programs built from source here are already verified
+        if metadata["verification"].is_null() {
+            continue;
+        }
+        if let Some(repository) = metadata["repository"].as_str() {
+            sources.push((
+                H256::from_slice(&hash),
+                Seed {
+                    url: repository.to_string(),
+                    git_ref: None,
+                },
+            ));
+        }
+    }
+    Ok(sources)
+}
+
+/// Request rebuilds of programs on chain from their claimed source, storing the outcome of each
+/// once it is known
+pub async fn enqueue_chain_verifications(
+    db: &sled::Db,
+    build_queue: &BuildQueue,
+    sources: Vec<(H256, Seed)>,
+) -> Result<ChainVerificationImport, Error> {
+    let mut import = ChainVerificationImport::default();
+    for (hash, seed) in sources {
+        let on_chain = get_deployment_status(db, hash.as_bytes())?
+            .map(|status| status.deployed)
+            .unwrap_or_default();
+        let verified = get_chain_verification(db, hash.as_bytes())?
+            .map(|verification| verification.status == ChainVerificationStatus::Verified)
+            .unwrap_or_default();
+        if !on_chain || verified {
+            import.skipped += 1;
+            continue;
+        }
+
+        let mut verification = ChainVerification {
+            status: ChainVerificationStatus::Pending,
+            repository: seed.url.clone(),
+            git_ref: seed.git_ref.clone(),
+            built: None,
+            error: None,
+            updated_at: unix_time(),
+        };
+        store_chain_verification(db, hash, &verification)?;
+
+        let (sender, mut receiver) = futures_mpsc::channel(1000);
+        let mut build_request = BuildRequest::new_git(seed.url, BuildResponder::new(sender))
+            .with_options(BuildOptions {
+                verify: Some(hash),
+                ..Default::default()
+            });
+        if let Some(git_ref) = seed.git_ref {
+            build_request = build_request.with_git_ref(git_ref);
+        }
+        build_queue
+            .send(build_request)
+            .await
+            .map_err(|_| Error::Mpsc)?;
+        log::info!("Queued verification of on-chain program {:?}", hash);
+        import.queued += 1;
+
+        let db = db.clone();
+        tokio::spawn(async move {
+            while let Some(response) = receiver.next().await {
+                let response = response
+                    .ok()
+                    .and_then(|line| serde_json::from_str::<BuildResponse>(&line).ok());
+                match response {
+                    Some(BuildResponse::Verification { built, matches, .. }) => {
+                        verification.status = if matches {
+                            ChainVerificationStatus::Verified
+                        } else {
+                            ChainVerificationStatus::Mismatch
+                        };
+                        verification.built = Some(built);
+                    }
+                    Some(BuildResponse::Failure { error, .. }) => {
+                        verification.status = ChainVerificationStatus::Failed;
+                        verification.error = Some(error);
+                    }
+                    _ => {}
+                }
+            }
+            if verification.status == ChainVerificationStatus::Pending {
+                verification.status = ChainVerificationStatus::Failed;
+                verification.error = Some("the build ended without giving a hash".to_string());
+            }
+            verification.updated_at = unix_time();
+            log::info!(
+                "Verification of on-chain program {:?}: {:?}",
+                hash,
+                verification.status
+            );
+            if let Err(error) = store_chain_verification(&db, hash, &verification) {
+                log::error!("Could not store verification of {:?}: {}", hash, error);
+            }
+        });
+    }
+    Ok(import)
+}
+
+/// Store the outcome of verifying an on-chain program, replacing any earlier attempt
+pub fn store_chain_verification(
+    db: &sled::Db,
+    hash: H256,
+    verification: &ChainVerification,
+) -> Result<(), Error> {
+    db.open_tree(CHAIN_VERIFICATION_TREE)?
+        .insert(hash, serde_json::to_vec(verification)?)?;
+    Ok(())
+}
+
+/// Get the outcome of verifying an on-chain program, if it has been attempted
+pub fn get_chain_verification(
+    db: &sled::Db,
+    hash: &[u8],
+) -> Result<Option<ChainVerification>, Error> {
+    match db.open_tree(CHAIN_VERIFICATION_TREE)?.get(hash)? {
+        Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+        None => Ok(None),
+    }
+}
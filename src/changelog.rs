@@ -0,0 +1,105 @@
+//! Changelogs of programs built from git, made from the messages of the commits since the
+//! previous build of the same repository
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sp_core::H256;
+use std::{path::Path, process::Command};
+
+use crate::build::Error;
+
+/// Name of the sled tree in which changelogs are stored, keyed by program hash
+const CHANGELOG_TREE: &str = "changelogs";
+
+/// The most commits to fetch beyond the shallow clone when looking for the previous build
+const MAX_CHANGELOG_COMMITS: usize = 500;
+
+/// Changes to a program since the previous build of the same repository
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Changelog {
+    /// The commit of the previous build
+    pub previous_commit: String,
+    /// The commit this program was built from
+    pub commit: String,
+    /// Commits between the two, newest first
+    pub entries: Vec<ChangelogEntry>,
+}
+
+/// A single commit in a changelog
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ChangelogEntry {
+    pub commit: String,
+    /// The first line of the commit message
+    pub summary: String,
+}
+
+/// Get the commit hash checked out in a git repository
+pub fn head_commit(repo_path: &Path) -> Result<String, Error> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repo_path)
+        .arg("rev-parse")
+        .arg("HEAD")
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::GitClone(
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Collect the commits from a previous commit up to the one checked out in a shallow clone.
+/// Gives `None` if the previous commit is not among the most recent ancestors, for example
+/// because history was rewritten.
+pub fn collect_changelog(
+    repo_path: &Path,
+    previous_commit: &str,
+) -> Result<Option<Changelog>, Error> {
+    let commit = head_commit(repo_path)?;
+    // The history of a shallow clone must be deepened to reach the previous commit
+    Command::new("git")
+        .arg("-C")
+        .arg(repo_path)
+        .arg("fetch")
+        .arg("--quiet")
+        .arg(format!("--deepen={}", MAX_CHANGELOG_COMMITS))
+        .output()?;
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repo_path)
+        .arg("log")
+        .arg("--format=%H %s")
+        .arg(format!("{}..{}", previous_commit, commit))
+        .output()?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    let entries = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .map(|(commit, summary)| ChangelogEntry {
+            commit: commit.to_string(),
+            summary: summary.to_string(),
+        })
+        .collect();
+    Ok(Some(Changelog {
+        previous_commit: previous_commit.to_string(),
+        commit,
+        entries,
+    }))
+}
+
+/// Store the changelog of a program
+pub fn store_changelog(db: &sled::Db, hash: H256, changelog: &Changelog) -> Result<(), Error> {
+    db.open_tree(CHANGELOG_TREE)?
+        .insert(hash, serde_json::to_vec(changelog)?)?;
+    Ok(())
+}
+
+/// Get the changelog of a program, if it has one
+pub fn get_changelog(db: &sled::Db, hash: &[u8]) -> Result<Option<Changelog>, Error> {
+    match db.open_tree(CHANGELOG_TREE)?.get(hash)? {
+        Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,88 @@
+//! Release channels such as `stable` or `beta`, which point at a particular program hash within
+//! a family of programs with the same package name
+use serde::{Deserialize, Serialize};
+use sp_core::H256;
+
+use crate::build::Error;
+
+/// Name of the sled tree in which channels are stored, keyed by package name and channel name
+const CHANNELS_TREE: &str = "channels";
+
+/// A channel of a program family
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Channel {
+    /// The program the channel currently points at
+    pub hash: H256,
+    /// The namespace of the tenant who owns the program family's channels
+    pub namespace: String,
+}
+
+/// Point a channel, whose name has been checked with [is_valid_channel_name], at a program. The
+/// first tenant to tag a program family owns its channels, and others may not change them, so
+/// this gives false without changing the channel if another tenant owns them.
+pub fn set_channel(
+    db: &sled::Db,
+    name: &str,
+    channel: &str,
+    hash: H256,
+    namespace: &str,
+) -> Result<bool, Error> {
+    let tree = db.open_tree(CHANNELS_TREE)?;
+    for res in tree.scan_prefix(family_prefix(name)) {
+        let (_key, value) = res?;
+        let existing: Channel = serde_json::from_slice(&value)?;
+        if existing.namespace != namespace {
+            return Ok(false);
+        }
+    }
+    let channel_record = Channel {
+        hash,
+        namespace: namespace.to_string(),
+    };
+    tree.insert(
+        channel_key(name, channel),
+        serde_json::to_vec(&channel_record)?,
+    )?;
+    Ok(true)
+}
+
+/// Get the program a channel points at
+pub fn get_channel(db: &sled::Db, name: &str, channel: &str) -> Result<Option<Channel>, Error> {
+    match db
+        .open_tree(CHANNELS_TREE)?
+        .get(channel_key(name, channel))?
+    {
+        Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+        None => Ok(None),
+    }
+}
+
+/// Get the names of all channels of a program family, together with the hash each points at
+pub fn get_channels(db: &sled::Db, name: &str) -> Result<Vec<(String, H256)>, Error> {
+    let prefix = family_prefix(name);
+    let mut channels = Vec::new();
+    for res in db.open_tree(CHANNELS_TREE)?.scan_prefix(&prefix) {
+        let (key, value) = res?;
+        let channel: Channel = serde_json::from_slice(&value)?;
+        let channel_name = String::from_utf8_lossy(&key[prefix.len()..]).to_string();
+        channels.push((channel_name, channel.hash));
+    }
+    Ok(channels)
+}
+
+/// Channel names are restricted to lowercase letters, digits and dashes
+pub fn is_valid_channel_name(channel: &str) -> bool {
+    !channel.is_empty()
+        && channel
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+/// Package names cannot contain spaces, so a space terminates the name in keys
+fn family_prefix(name: &str) -> Vec<u8> {
+    format!("{} ", name).into_bytes()
+}
+
+fn channel_key(name: &str, channel: &str) -> Vec<u8> {
+    format!("{} {}", name, channel).into_bytes()
+}
@@ -0,0 +1,37 @@
+//! Maintenance of the db: rebuilding its secondary indexes, which drops stale entries from them,
+//! and flushing it to disk. Sled reclaims the space of overwritten and removed values itself, as
+//! it rewrites its segments in the background, so the size on disk may not change straight away.
+use serde::Serialize;
+use std::time::Instant;
+
+use crate::{build::Error, index::rebuild_indexes};
+
+/// The outcome of compacting the db
+#[derive(Debug, Clone, Serialize)]
+pub struct CompactionReport {
+    /// Size of the db on disk in bytes before compacting
+    pub size_before: u64,
+    /// Size of the db on disk in bytes after compacting
+    pub size_after: u64,
+    /// Number of programs added to the rebuilt secondary indexes
+    pub indexed_programs: usize,
+    /// How long compacting took, in milliseconds
+    pub duration_millis: u64,
+}
+
+/// Rebuild the secondary indexes, replacing the old ones once complete, and flush the db to disk
+pub async fn compact_db(db: &sled::Db) -> Result<CompactionReport, Error> {
+    let start = Instant::now();
+    let size_before = db.size_on_disk()?;
+    let indexed_programs = rebuild_indexes(db)?;
+    db.flush_async().await?;
+    let size_after = db.size_on_disk()?;
+    let report = CompactionReport {
+        size_before,
+        size_after,
+        indexed_programs,
+        duration_millis: start.elapsed().as_millis() as u64,
+    };
+    log::info!("Compacted db: {:?}", report);
+    Ok(report)
+}
@@ -0,0 +1,57 @@
+//! Comparison of a given program binary with one freshly built from source, to check whether a
+//! deployed binary really corresponds to some source code
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sp_core::H256;
+
+use crate::build::EntropyProgramMetadata;
+
+/// The outcome of comparing a given binary with one built from source
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct BinaryComparison {
+    /// Whether the binaries are byte for byte identical
+    pub identical: bool,
+    /// Hash of the given binary, with the metadata of the program built from source
+    #[schemars(with = "String")]
+    pub given_hash: H256,
+    /// Hash of the binary built from source
+    #[schemars(with = "String")]
+    pub built_hash: H256,
+    /// Length in bytes of the given binary
+    pub given_size: usize,
+    /// Length in bytes of the binary built from source
+    pub built_size: usize,
+    /// Offset of the first byte at which the binaries differ, if they differ
+    pub first_difference: Option<usize>,
+    /// Number of bytes which differ, counting any bytes beyond the end of the shorter binary
+    pub differing_bytes: usize,
+}
+
+impl BinaryComparison {
+    /// Compare a given binary with one built from source
+    pub fn new(given: &[u8], built: &[u8], entropy_metadata: &EntropyProgramMetadata) -> Self {
+        let mismatched_offsets = given
+            .iter()
+            .zip(built)
+            .enumerate()
+            .filter(|(_, (given_byte, built_byte))| given_byte != built_byte)
+            .map(|(offset, _)| offset);
+        let common_length = given.len().min(built.len());
+        let length_difference = given.len().abs_diff(built.len());
+
+        let mut first_difference = mismatched_offsets.clone().next();
+        if first_difference.is_none() && length_difference > 0 {
+            first_difference = Some(common_length);
+        }
+
+        Self {
+            identical: given == built,
+            given_hash: entropy_metadata.program_hash(given),
+            built_hash: entropy_metadata.program_hash(built),
+            given_size: given.len(),
+            built_size: built.len(),
+            first_difference,
+            differing_bytes: mismatched_offsets.count() + length_difference,
+        }
+    }
+}
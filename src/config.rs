@@ -0,0 +1,247 @@
+//! Server configuration, read from an optional TOML file
+use serde::Deserialize;
+use std::path::Path;
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+
+use crate::{
+    archive::ArchiveConfig,
+    build::{DockerSettings, ResourceLimits},
+    builder_pool::BuilderPoolConfig,
+    cargo_cache::CargoCacheConfig,
+    chain::ChainConfig,
+    container::ContainerEngine,
+    digest::HashAlgorithm,
+    disk::DiskConfig,
+    git_auth::GitCredential,
+    ipfs::IpfsConfig,
+    kubernetes::KubernetesConfig,
+    native_build::NativeBuildConfig,
+    sccache::SccacheConfig,
+    seed::Seed,
+};
+
+/// Environment variable giving the path to the configuration file
+pub const CONFIG_PATH_ENV_VAR: &str = "PROGRAM_METADATA_SERVICE_CONFIG";
+
+/// Number of reproductions needed for a program to be shown as verified, if not configured
+const DEFAULT_VERIFIED_THRESHOLD: u64 = 2;
+
+/// How often to sync with peers, in minutes, if not configured
+const DEFAULT_PEER_SYNC_INTERVAL_MINUTES: u64 = 60;
+
+/// The largest request body accepted, in bytes, if not configured
+const DEFAULT_MAX_REQUEST_BODY_SIZE: usize = 50 * 1024 * 1024;
+
+/// Number of builds which may wait to start, if not configured
+const DEFAULT_MAX_QUEUED_BUILDS: usize = 1000;
+
+/// Server configuration
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Config {
+    /// Tenants who may build and manage programs in their own namespace
+    pub tenants: Vec<Tenant>,
+    /// The URL at which this service is publicly reachable, used to give links in notifications
+    pub public_url: Option<String>,
+    /// Mail server used to notify submitters when their build finishes
+    pub smtp: Option<SmtpConfig>,
+    /// Number of independent rebuilds which must reproduce a program's hash for it to be shown
+    /// as verified
+    pub verified_threshold: Option<u64>,
+    /// Fuel given to the runtime when test running programs, if not the runtime's default
+    pub test_run_fuel: Option<u64>,
+    /// Secret key given as a bearer token to use `/admin` routes, which are disabled if not set
+    pub admin_api_key: Option<String>,
+    /// The most verbose level of log messages to show, overriding `RUST_LOG`
+    pub log_level: Option<log::LevelFilter>,
+    /// How often to compact the db, in hours. If not given, it is only compacted on request.
+    pub compaction_interval_hours: Option<u64>,
+    /// Settings for moving artifacts of programs which are not being used to an archive
+    pub archive: Option<ArchiveConfig>,
+    /// Whether to start in read-only mode, in which builds and changes are rejected
+    pub read_only: bool,
+    /// Programs which should always be present, and are built at startup if they are missing
+    pub programs: Vec<Seed>,
+    /// Number of programs which may be built at the same time, 1 if not given. Each build uses
+    /// a lot of memory, so this should only be raised on larger hosts.
+    pub build_workers: Option<usize>,
+    /// Number of builds which may wait for a worker, 1000 if not given. Further builds are
+    /// rejected until the queue drains.
+    pub max_queued_builds: Option<usize>,
+    /// Queue build jobs which were interrupted by the service stopping again when it starts,
+    /// rather than leaving them failed
+    pub requeue_interrupted_jobs: bool,
+    /// Carry on with builds whose client has disconnected, storing the program if it builds,
+    /// rather than stopping them
+    pub finish_disconnected_builds: bool,
+    /// Limits on the memory and CPU each build may use
+    pub build_limits: ResourceLimits,
+    /// Docker images which programs may be built with, given by name or as a registry or
+    /// namespace ending with `/`. Any image may be used if none are given.
+    pub allowed_docker_images: Vec<String>,
+    /// The default image, build arguments and other settings with which programs are built
+    pub docker: DockerSettings,
+    /// The container tool programs are built with, which is docker if not given
+    pub container_engine: ContainerEngine,
+    /// Build programs with cargo on the host rather than in containers. This is meant for
+    /// development and CI, as such builds are not reproducible.
+    pub native_build: Option<NativeBuildConfig>,
+    /// Build programs as jobs in a Kubernetes cluster rather than with a container engine here
+    pub kubernetes: Option<KubernetesConfig>,
+    /// Cargo caches shared between builds, and how often they are pruned
+    pub cargo_cache: Option<CargoCacheConfig>,
+    /// Cache compiled crates with sccache, locally or in a bucket shared between hosts
+    pub sccache: Option<SccacheConfig>,
+    /// Free disk space required to accept builds, and how often disk space is reclaimed
+    pub disk: Option<DiskConfig>,
+    /// Send builds to builders which register with this service, rather than building here. A
+    /// process run with `--builder` reads its key and name from here too.
+    pub builder_pool: Option<BuilderPoolConfig>,
+    /// The largest request body accepted, in bytes, such as an uploaded archive. Bodies are held
+    /// in memory, so this should be well below the memory of the host.
+    pub max_request_body_size: Option<usize>,
+    /// Secret URI of the sr25519 key with which build attestations are signed, such as a hex
+    /// encoded seed or a mnemonic phrase. If not given, a key is generated and kept in the db.
+    pub signing_key: Option<String>,
+    /// The digest given as the hash of a program by `/program/:program_hash`. Programs can be
+    /// looked up by any of their digests, but are referred to on-chain by their `blake2-256`
+    /// hash, which is the default.
+    pub primary_hash: HashAlgorithm,
+    /// Connection to the Entropy chain, with which programs can be registered
+    pub chain: Option<ChainConfig>,
+    /// Base URLs of other instances of this service, whose programs are mirrored here
+    pub peers: Vec<String>,
+    /// How often to mirror programs from peers, in minutes
+    pub peer_sync_interval_minutes: Option<u64>,
+    /// IPFS node to publish programs to, and fetch archives to build from
+    pub ipfs: Option<IpfsConfig>,
+    /// Credentials for cloning private repositories, chosen by the start of the repository URL
+    pub git_credentials: Vec<GitCredential>,
+}
+
+/// A tenant with their own namespace, authenticated with an API key
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Tenant {
+    /// The name of the namespace, as used in `/ns/:namespace` routes
+    pub namespace: String,
+    /// Secret key given as a bearer token to authenticate as this tenant
+    pub api_key: String,
+    /// The maximum number of programs this tenant may have in their namespace
+    pub max_programs: Option<usize>,
+    /// The maximum number of builds this tenant may request per day
+    pub max_builds_per_day: Option<u64>,
+    /// The maximum number of builds this tenant may request in total
+    pub max_builds: Option<u64>,
+    /// The maximum number of bytes which may be stored for programs in this tenant's namespace
+    pub max_stored_bytes: Option<u64>,
+    /// Address to notify about this tenant's builds, when none is given with the request
+    pub email: Option<String>,
+    /// Other addresses which may be given as `notify` with this tenant's builds
+    #[serde(default)]
+    pub notify_addresses: Vec<String>,
+}
+
+impl Tenant {
+    /// Whether builds may be made to notify the given address, which must be one configured for
+    /// the tenant, so that the server cannot be used to send email to anyone
+    pub fn may_notify(&self, address: &str) -> bool {
+        self.email.as_deref() == Some(address)
+            || self
+                .notify_addresses
+                .iter()
+                .any(|allowed| allowed == address)
+    }
+}
+
+/// Settings for sending email notifications
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SmtpConfig {
+    /// Hostname of the SMTP server, which must support TLS
+    pub host: String,
+    /// Port to connect to, if not the default submission port
+    pub port: Option<u16>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// The sender address, for example `Program metadata <noreply@example.com>`
+    pub from: String,
+}
+
+impl Config {
+    /// Read configuration from the file given by the environment variable, or use defaults if
+    /// it is not set
+    pub fn from_env() -> Result<Self, ConfigError> {
+        match std::env::var(CONFIG_PATH_ENV_VAR) {
+            Ok(path) => Self::from_file(path),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// Read configuration from a TOML file
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&contents)?)
+    }
+
+    /// Number of reproductions needed for a program to be shown as verified
+    pub fn verified_threshold(&self) -> u64 {
+        self.verified_threshold
+            .unwrap_or(DEFAULT_VERIFIED_THRESHOLD)
+    }
+
+    /// The largest request body accepted, in bytes
+    pub fn max_request_body_size(&self) -> usize {
+        self.max_request_body_size
+            .unwrap_or(DEFAULT_MAX_REQUEST_BODY_SIZE)
+    }
+
+    /// How often to mirror programs from peers, in minutes
+    pub fn peer_sync_interval_minutes(&self) -> u64 {
+        self.peer_sync_interval_minutes
+            .unwrap_or(DEFAULT_PEER_SYNC_INTERVAL_MINUTES)
+            .max(1)
+    }
+
+    /// Number of programs which may be built at the same time
+    pub fn build_workers(&self) -> usize {
+        self.build_workers.unwrap_or(1).max(1)
+    }
+
+    /// Number of builds which may wait for a worker
+    pub fn max_queued_builds(&self) -> usize {
+        self.max_queued_builds
+            .unwrap_or(DEFAULT_MAX_QUEUED_BUILDS)
+            .max(1)
+    }
+
+    /// Get the tenant who owns the given namespace
+    pub fn tenant(&self, namespace: &str) -> Option<&Tenant> {
+        self.tenants
+            .iter()
+            .find(|tenant| tenant.namespace == namespace)
+    }
+
+    /// Get the tenant with the given API key. Every tenant's key is compared, in constant time,
+    /// so that timing does not reveal which key a guess is close to.
+    pub fn tenant_with_api_key(&self, api_key: &str) -> Option<&Tenant> {
+        self.tenants.iter().fold(None, |found, tenant| {
+            let matches = bool::from(tenant.api_key.as_bytes().ct_eq(api_key.as_bytes()));
+            if matches && found.is_none() {
+                Some(tenant)
+            } else {
+                found
+            }
+        })
+    }
+}
+
+/// An error when reading the configuration file
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Cannot read config file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Cannot parse config file: {0}")]
+    Toml(#[from] toml::de::Error),
+}
@@ -0,0 +1,114 @@
+//! The tools with which programs are built in containers. Docker is used unless podman is
+//! configured, which suits hosts which cannot give access to the docker socket, such as those
+//! running builds rootless.
+use serde::Deserialize;
+use std::{path::Path, process::Command};
+
+/// Which container tool to build programs with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ContainerEngine {
+    #[default]
+    Docker,
+    Podman,
+}
+
+impl ContainerEngine {
+    /// The backend which runs this tool
+    pub fn backend(self) -> &'static dyn ContainerBackend {
+        match self {
+            ContainerEngine::Docker => &Docker,
+            ContainerEngine::Podman => &Podman,
+        }
+    }
+}
+
+/// Builds images and runs containers with a docker compatible command line tool, allowing for
+/// where the tools differ
+pub trait ContainerBackend: Send + Sync {
+    /// A command running the tool, to which a subcommand such as `build` is added
+    fn command(&self) -> Command;
+
+    /// Arguments to `build` which write the files of the final stage to a directory, rather than
+    /// keeping an image
+    fn output_args(&self, output_dir: &Path) -> Vec<String>;
+
+    /// Argument to `run` which mounts a directory of the host in the container
+    fn volume_arg(&self, host_dir: &Path, container_dir: &str) -> String;
+
+    /// The name by which the tool should be given an image
+    fn image_name(&self, image_name: &str) -> String;
+
+    /// Set whether `build` uses BuildKit, where the tool has a choice
+    fn set_buildkit(&self, command: &mut Command, buildkit: bool);
+}
+
+/// Docker, whose daemon builds and runs containers
+pub struct Docker;
+
+impl ContainerBackend for Docker {
+    fn command(&self) -> Command {
+        Command::new("docker")
+    }
+
+    fn output_args(&self, output_dir: &Path) -> Vec<String> {
+        vec![format!("--output={}", output_dir.display())]
+    }
+
+    fn volume_arg(&self, host_dir: &Path, container_dir: &str) -> String {
+        format!("--volume={}:{}", host_dir.display(), container_dir)
+    }
+
+    fn image_name(&self, image_name: &str) -> String {
+        image_name.to_string()
+    }
+
+    fn set_buildkit(&self, command: &mut Command, buildkit: bool) {
+        command.env("DOCKER_BUILDKIT", if buildkit { "1" } else { "0" });
+    }
+}
+
+/// Podman, which needs no daemon and can run without root
+pub struct Podman;
+
+impl ContainerBackend for Podman {
+    fn command(&self) -> Command {
+        Command::new("podman")
+    }
+
+    /// Podman only accepts a bare directory for `--output` in recent versions, so the exporter
+    /// is named
+    fn output_args(&self, output_dir: &Path) -> Vec<String> {
+        vec![format!("--output=type=local,dest={}", output_dir.display())]
+    }
+
+    /// The source is relabelled, so that it can be read on hosts using SELinux
+    fn volume_arg(&self, host_dir: &Path, container_dir: &str) -> String {
+        format!("--volume={}:{}:Z", host_dir.display(), container_dir)
+    }
+
+    /// Podman will not guess the registry of a short name without asking, so names are given
+    /// with the registry docker would use
+    fn image_name(&self, image_name: &str) -> String {
+        qualify_image_name(image_name)
+    }
+
+    /// Podman always builds with buildah
+    fn set_buildkit(&self, _command: &mut Command, _buildkit: bool) {}
+}
+
+/// Give an image name with the registry docker would use for it, such as
+/// `docker.io/library/rust:1.78` for `rust:1.78`
+pub(crate) fn qualify_image_name(image_name: &str) -> String {
+    let image_name = image_name.trim();
+    match image_name.split_once('/') {
+        // The first part of a name is a registry if it looks like a host name
+        Some((registry, _))
+            if registry.contains('.') || registry.contains(':') || registry == "localhost" =>
+        {
+            image_name.to_string()
+        }
+        Some(_) => format!("docker.io/{}", image_name),
+        None => format!("docker.io/library/{}", image_name),
+    }
+}
@@ -0,0 +1,97 @@
+//! The resolved dependency graph of programs, taken from `cargo metadata`, so that what code went
+//! into a signing program can be audited
+use cargo_metadata::{DependencyKind, Metadata, Node, Package, PackageId};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sp_core::H256;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::build::Error;
+
+/// Name of the sled tree in which the dependencies of programs are stored, keyed by program hash
+const DEPENDENCIES_TREE: &str = "dependencies";
+
+/// A crate which went into the build of a program
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Dependency {
+    pub name: String,
+    pub version: String,
+    /// Where the crate came from, such as a registry or git repository. Crates within the
+    /// program's own repository have none.
+    pub source: Option<String>,
+    /// The crates this one directly depends on, as `name version`
+    pub dependencies: Vec<String>,
+}
+
+/// Get the crates which are built along with the root package, including the package itself.
+/// Dependencies only used by its tests are left out, as they do not go into the binary.
+pub fn resolve_dependencies(metadata: &Metadata) -> Vec<Dependency> {
+    let Some(resolve) = &metadata.resolve else {
+        return Vec::new();
+    };
+    let Some(root) = &resolve.root else {
+        return Vec::new();
+    };
+    let packages: HashMap<&PackageId, &Package> = metadata
+        .packages
+        .iter()
+        .map(|package| (&package.id, package))
+        .collect();
+    let nodes: HashMap<&PackageId, &Node> =
+        resolve.nodes.iter().map(|node| (&node.id, node)).collect();
+
+    let mut dependencies = Vec::new();
+    let mut seen = HashSet::from([root]);
+    let mut queue = VecDeque::from([root]);
+    while let Some(id) = queue.pop_front() {
+        let (Some(package), Some(node)) = (packages.get(id), nodes.get(id)) else {
+            continue;
+        };
+        let mut direct = Vec::new();
+        for dep in &node.deps {
+            // Cargo older than 1.41 does not give the kinds, so the dependency is kept
+            if !dep.dep_kinds.is_empty()
+                && dep
+                    .dep_kinds
+                    .iter()
+                    .all(|info| info.kind == DependencyKind::Development)
+            {
+                continue;
+            }
+            if let Some(dep_package) = packages.get(&dep.pkg) {
+                direct.push(format!("{} {}", dep_package.name, dep_package.version));
+            }
+            if seen.insert(&dep.pkg) {
+                queue.push_back(&dep.pkg);
+            }
+        }
+        direct.sort();
+        dependencies.push(Dependency {
+            name: package.name.clone(),
+            version: package.version.to_string(),
+            source: package.source.as_ref().map(|source| source.repr.clone()),
+            dependencies: direct,
+        });
+    }
+    dependencies.sort_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version)));
+    dependencies
+}
+
+/// Store the dependencies a program was built with
+pub fn store_dependencies(
+    db: &sled::Db,
+    hash: H256,
+    dependencies: &[Dependency],
+) -> Result<(), Error> {
+    db.open_tree(DEPENDENCIES_TREE)?
+        .insert(hash, serde_json::to_vec(dependencies)?)?;
+    Ok(())
+}
+
+/// Get the dependencies of a program, if it was built here
+pub fn get_dependencies(db: &sled::Db, hash: &[u8]) -> Result<Option<Vec<Dependency>>, Error> {
+    match db.open_tree(DEPENDENCIES_TREE)?.get(hash)? {
+        Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,80 @@
+//! Digests of programs made with hash algorithms other than the one used on-chain, for tooling
+//! which expects them. Programs are stored under their `BlakeTwo256` hash, and the other digests
+//! are mapped to it so that a program can be looked up by any of them.
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sp_core::H256;
+
+use crate::build::Error;
+
+/// Name of the sled tree in which the digests of each program are stored, keyed by program hash
+const DIGESTS_TREE: &str = "digests";
+
+/// Name of the sled tree mapping other digests to program hashes
+const DIGEST_ALIASES_TREE: &str = "digest_aliases";
+
+/// A hash algorithm with which programs are digested
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum HashAlgorithm {
+    /// The algorithm used on-chain, by which programs are stored
+    #[default]
+    #[serde(rename = "blake2-256")]
+    Blake2_256,
+    Sha256,
+    Keccak256,
+}
+
+/// Digests of a program binary followed by its Entropy metadata, as with the program hash
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+pub struct ProgramDigests {
+    /// The program hash
+    #[schemars(with = "String")]
+    pub blake2_256: H256,
+    #[schemars(with = "String")]
+    pub sha256: H256,
+    #[schemars(with = "String")]
+    pub keccak256: H256,
+}
+
+impl ProgramDigests {
+    /// Get the digest made with the given algorithm
+    pub fn get(&self, algorithm: HashAlgorithm) -> H256 {
+        match algorithm {
+            HashAlgorithm::Blake2_256 => self.blake2_256,
+            HashAlgorithm::Sha256 => self.sha256,
+            HashAlgorithm::Keccak256 => self.keccak256,
+        }
+    }
+}
+
+/// Store the digests of a program, so that it can be looked up by any of them
+pub fn store_digests(db: &sled::Db, digests: &ProgramDigests) -> Result<(), Error> {
+    let hash = digests.blake2_256;
+    db.open_tree(DIGESTS_TREE)?
+        .insert(hash, serde_json::to_vec(digests)?)?;
+    let aliases = db.open_tree(DIGEST_ALIASES_TREE)?;
+    aliases.insert(digests.sha256, hash.as_bytes())?;
+    aliases.insert(digests.keccak256, hash.as_bytes())?;
+    Ok(())
+}
+
+/// Get the digests of a program, if they were made when it was stored
+pub fn get_digests(db: &sled::Db, hash: &[u8]) -> Result<Option<ProgramDigests>, Error> {
+    match db.open_tree(DIGESTS_TREE)?.get(hash)? {
+        Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+        None => Ok(None),
+    }
+}
+
+/// Get the hash under which a program is stored from any of its digests. Gives the digest
+/// itself if no program has it as another digest.
+pub fn resolve_digest(db: &sled::Db, digest: Vec<u8>) -> Result<Vec<u8>, Error> {
+    if db.contains_key(&digest)? {
+        return Ok(digest);
+    }
+    Ok(match db.open_tree(DIGEST_ALIASES_TREE)?.get(&digest)? {
+        Some(hash) => hash.to_vec(),
+        None => digest,
+    })
+}
@@ -0,0 +1,396 @@
+//! Keeping the host from running out of disk. Builds leave behind dangling images and build
+//! cache, and builds interrupted by a crash leave their temporary directories, so these are
+//! removed periodically. Builds are refused while free space is low, rather than failing part
+//! way through with the host full.
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+use temp_dir::TempDir;
+
+use crate::{
+    build::{check_docker_image, Error},
+    config::Config,
+    container::ContainerEngine,
+    image_pull::images_to_pull,
+};
+
+/// Where the db is kept, relative to the working directory
+pub const DB_PATH: &str = "./program-metadata-http-service-db";
+
+/// Start of the names of temporary directories made for builds, by which stale ones are found
+const TEMP_DIR_PREFIX: &str = "program-metadata-";
+
+/// Temporary directories of other processes are removed once they are this old, if not
+/// configured
+const DEFAULT_STALE_TEMP_DIR_HOURS: u64 = 24;
+
+/// Settings for checking and reclaiming disk space
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DiskConfig {
+    /// Builds are refused while a checked filesystem has less than this many megabytes free
+    pub min_free_mb: Option<u64>,
+    /// Further directories whose filesystems are checked, such as docker's data root. Those of
+    /// the temporary directory and the db are always checked.
+    #[serde(default)]
+    pub paths: Vec<PathBuf>,
+    /// How often to remove dangling images and build cache and stale temporary directories, in
+    /// hours. If not given, this is only done on request.
+    pub cleanup_interval_hours: Option<u64>,
+    /// Age in hours after which temporary directories left by other processes are removed, a
+    /// day if not given. Builds time out well before this.
+    pub stale_temp_dir_hours: Option<u64>,
+    /// Also remove unused images after this many builds
+    pub prune_after_builds: Option<u64>,
+    /// Also remove unused images after a build which leaves a checked filesystem fuller than
+    /// this percentage
+    pub prune_above_percent: Option<u64>,
+}
+
+/// Space on the filesystem holding a directory
+#[derive(Debug, Clone, Serialize)]
+pub struct FilesystemUsage {
+    pub path: PathBuf,
+    pub total_bytes: u64,
+    pub used_bytes: u64,
+    pub available_bytes: u64,
+}
+
+/// Disk usage of the host and the container engine
+#[derive(Debug, Clone, Serialize)]
+pub struct DiskUsage {
+    pub filesystems: Vec<FilesystemUsage>,
+    /// Space used by images, containers, volumes and build cache, as the container engine's
+    /// `system df` reports it
+    pub container_engine: Option<Vec<serde_json::Value>>,
+    /// Temporary directories of builds, including those still running
+    pub temp_dirs: usize,
+}
+
+/// What was removed by a cleanup
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DiskCleanupReport {
+    /// What the container engine reported reclaiming by removing dangling images
+    pub images: Option<String>,
+    /// What the container engine reported reclaiming by removing dangling build cache
+    pub build_cache: Option<String>,
+    pub removed_temp_dirs: Vec<PathBuf>,
+    /// Images which were removed as no longer used. These are only removed by automatic
+    /// pruning.
+    pub removed_images: Vec<String>,
+}
+
+/// Counts builds towards the next automatic prune, which removes images other than those builds
+/// are expected to use as well as what a cleanup does
+#[derive(Clone, Default)]
+pub struct PruneTrigger {
+    builds: Arc<AtomicU64>,
+    /// Whether a prune is running, so that another is not started alongside it
+    running: Arc<AtomicBool>,
+}
+
+/// Make a temporary directory for a build, which is removed when dropped, or by a later cleanup
+/// if the process stops first
+pub fn build_temp_dir() -> std::io::Result<TempDir> {
+    TempDir::with_prefix(TEMP_DIR_PREFIX)
+}
+
+impl DiskConfig {
+    /// The directories whose filesystems are checked
+    fn checked_paths(&self) -> Vec<PathBuf> {
+        let mut paths = vec![std::env::temp_dir(), PathBuf::from(DB_PATH)];
+        paths.extend(self.paths.iter().cloned());
+        paths
+    }
+
+    /// Check that each checked filesystem has the minimum free space, if one is configured
+    pub fn check_free_space(&self) -> Result<(), Error> {
+        let Some(min_free_mb) = self.min_free_mb else {
+            return Ok(());
+        };
+        for path in self.checked_paths() {
+            let available_mb = filesystem_usage(&path)?.available_bytes / (1024 * 1024);
+            if available_mb < min_free_mb {
+                return Err(Error::LowDiskSpace(
+                    path.display().to_string(),
+                    available_mb,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// How often to clean up, if periodically
+    pub fn cleanup_interval(&self) -> Option<Duration> {
+        self.cleanup_interval_hours
+            .map(|hours| Duration::from_secs(hours.max(1) * 60 * 60))
+    }
+
+    /// Report the space on each checked filesystem and used by the container engine
+    pub fn usage(&self, container_engine: ContainerEngine) -> Result<DiskUsage, Error> {
+        let filesystems = self
+            .checked_paths()
+            .iter()
+            .map(|path| filesystem_usage(path))
+            .collect::<Result<_, _>>()?;
+        let mut command = container_engine.backend().command();
+        command.args(["system", "df", "--format={{json .}}"]);
+        let container_engine = match run(command) {
+            Ok(output) => Some(
+                output
+                    .lines()
+                    .filter_map(|line| serde_json::from_str(line).ok())
+                    .collect(),
+            ),
+            Err(error) => {
+                log::warn!(
+                    "Could not get disk usage of the container engine: {}",
+                    error
+                );
+                None
+            }
+        };
+        Ok(DiskUsage {
+            filesystems,
+            container_engine,
+            temp_dirs: build_temp_dirs()?.len(),
+        })
+    }
+
+    /// Remove dangling images and build cache, and temporary directories which other processes
+    /// left behind
+    pub fn clean_up(&self, container_engine: ContainerEngine) -> Result<DiskCleanupReport, Error> {
+        let backend = container_engine.backend();
+        let mut report = DiskCleanupReport::default();
+
+        let mut command = backend.command();
+        command.args(["image", "prune", "--force"]);
+        report.images = last_line(run(command));
+        // Podman keeps build cache as images, so pruning images is enough
+        if container_engine == ContainerEngine::Docker {
+            let mut command = backend.command();
+            command.args(["builder", "prune", "--force"]);
+            report.build_cache = last_line(run(command));
+        }
+
+        let stale_age = Duration::from_secs(
+            self.stale_temp_dir_hours
+                .unwrap_or(DEFAULT_STALE_TEMP_DIR_HOURS)
+                * 60
+                * 60,
+        );
+        let own_prefix = format!("{}{:x}-", TEMP_DIR_PREFIX, std::process::id());
+        for path in build_temp_dirs()? {
+            let own = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(&own_prefix));
+            let age = std::fs::metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+            if own || age.is_none_or(|age| age < stale_age) {
+                continue;
+            }
+            match std::fs::remove_dir_all(&path) {
+                Ok(()) => report.removed_temp_dirs.push(path),
+                Err(error) => log::warn!("Could not remove {}: {}", path.display(), error),
+            }
+        }
+        log::info!("Cleaned up disk: {:?}", report);
+        Ok(report)
+    }
+
+    /// Whether a checked filesystem is fuller than the configured percentage
+    fn above_threshold(&self) -> bool {
+        let Some(percent) = self.prune_above_percent else {
+            return false;
+        };
+        self.checked_paths()
+            .iter()
+            .filter_map(|path| filesystem_usage(path).ok())
+            .any(|usage| usage.used_bytes * 100 > usage.total_bytes * percent)
+    }
+
+    /// Clean up, and remove images other than those matching the given names, which are
+    /// compared as allowed images are. If no names are given, every image is kept.
+    fn prune(
+        &self,
+        container_engine: ContainerEngine,
+        keep: &[String],
+    ) -> Result<DiskCleanupReport, Error> {
+        let mut report = self.clean_up(container_engine)?;
+        let backend = container_engine.backend();
+        let mut command = backend.command();
+        command.args([
+            "image",
+            "ls",
+            "--digests",
+            "--format={{.ID}} {{.Repository}} {{.Tag}} {{.Digest}}",
+        ]);
+        // The names of each image, by ID
+        let mut images: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for line in run(command)?.lines() {
+            let [id, repository, tag, digest] = line.split_whitespace().collect::<Vec<_>>()[..]
+            else {
+                continue;
+            };
+            if repository == "<none>" {
+                continue;
+            }
+            let names = images.entry(id.to_string()).or_default();
+            if tag != "<none>" {
+                names.push(format!("{}:{}", repository, tag));
+            }
+            if digest != "<none>" {
+                names.push(format!("{}@{}", repository, digest));
+            }
+        }
+        for names in images.into_values() {
+            let kept = names
+                .iter()
+                .any(|name| check_docker_image(keep, name).is_ok());
+            if kept || names.is_empty() {
+                continue;
+            }
+            // Images used by running containers are not removed
+            let mut command = backend.command();
+            command.args(["image", "rm"]).args(&names);
+            match run(command) {
+                Ok(_) => report.removed_images.extend(names),
+                Err(error) => log::debug!("Could not remove {:?}: {}", names, error),
+            }
+        }
+        log::info!("Removed unused images: {:?}", report.removed_images);
+        Ok(report)
+    }
+}
+
+impl PruneTrigger {
+    /// Count a finished build, and prune in the background if the configured policy calls for
+    /// it. Images which are allowed or pulled at startup are kept, so that builds do not pull
+    /// them again.
+    pub fn build_finished(&self, config: &Config, db: Option<&sled::Db>) {
+        let Some(disk) = &config.disk else {
+            return;
+        };
+        let builds = self.builds.fetch_add(1, Ordering::Relaxed) + 1;
+        let due = disk
+            .prune_after_builds
+            .is_some_and(|after| builds >= after.max(1))
+            || disk.above_threshold();
+        if !due || self.running.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        self.builds.store(0, Ordering::Relaxed);
+
+        let mut keep = config.allowed_docker_images.clone();
+        match db {
+            Some(db) => match images_to_pull(db, &config.docker) {
+                Ok(images) => keep.extend(images),
+                Err(error) => log::warn!("Could not find recently used images: {}", error),
+            },
+            None => keep.extend(
+                config
+                    .docker
+                    .default_image
+                    .iter()
+                    .chain(&config.docker.pull_images)
+                    .cloned(),
+            ),
+        }
+        let disk = disk.clone();
+        let container_engine = config.container_engine;
+        let running = self.running.clone();
+        tokio::task::spawn_blocking(move || {
+            if let Err(error) = disk.prune(container_engine, &keep) {
+                log::error!("Could not prune images: {}", error);
+            }
+            running.store(false, Ordering::Relaxed);
+        });
+    }
+}
+
+/// Clean up without blocking the async runtime
+pub async fn clean_up_disk(
+    config: DiskConfig,
+    container_engine: ContainerEngine,
+) -> Result<DiskCleanupReport, Error> {
+    tokio::task::spawn_blocking(move || config.clean_up(container_engine))
+        .await
+        .map_err(|error| Error::Disk(format!("cleanup panicked: {}", error)))?
+}
+
+/// The temporary directories made for builds, by this process or others
+fn build_temp_dirs() -> Result<Vec<PathBuf>, Error> {
+    let mut dirs = Vec::new();
+    for entry in std::fs::read_dir(std::env::temp_dir())? {
+        let entry = entry?;
+        let is_build_dir = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.starts_with(TEMP_DIR_PREFIX));
+        if is_build_dir && entry.file_type()?.is_dir() {
+            dirs.push(entry.path());
+        }
+    }
+    Ok(dirs)
+}
+
+/// Get the space on the filesystem holding a directory with `df`, whose portable output gives
+/// sizes in kilobytes
+fn filesystem_usage(path: &Path) -> Result<FilesystemUsage, Error> {
+    let mut command = Command::new("df");
+    command.arg("-Pk").arg(path);
+    let output = run(command)?;
+    let sizes: Vec<u64> = output
+        .lines()
+        .nth(1)
+        .into_iter()
+        .flat_map(|line| line.split_whitespace().skip(1).take(3))
+        .filter_map(|size| size.parse().ok())
+        .collect();
+    let [total, used, available] = sizes[..] else {
+        return Err(Error::Disk(format!(
+            "could not read the output of df for {}",
+            path.display()
+        )));
+    };
+    Ok(FilesystemUsage {
+        path: path.to_path_buf(),
+        total_bytes: total * 1024,
+        used_bytes: used * 1024,
+        available_bytes: available * 1024,
+    })
+}
+
+/// The last line of a command's output, which is where prune commands report the space
+/// reclaimed. Failures are logged, as the rest of a cleanup can go ahead without it.
+fn last_line(output: Result<String, Error>) -> Option<String> {
+    match output {
+        Ok(output) => output.lines().last().map(str::to_string),
+        Err(error) => {
+            log::warn!("{}", error);
+            None
+        }
+    }
+}
+
+/// Run a command, giving its standard output
+fn run(mut command: Command) -> Result<String, Error> {
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(Error::Disk(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
@@ -0,0 +1,37 @@
+//! The Entropy specific metadata of programs, which is part of the program hash and so is stored
+//! as it was hashed rather than only as part of the package metadata
+use sp_core::H256;
+
+use crate::build::{extract_metadata, EntropyProgramMetadata, Error};
+
+/// Name of the sled tree in which Entropy program metadata is stored, keyed by program hash
+const ENTROPY_METADATA_TREE: &str = "entropy_metadata";
+
+/// Store the Entropy metadata with which a program was hashed
+pub fn store_entropy_metadata(
+    db: &sled::Db,
+    hash: H256,
+    entropy_metadata: &EntropyProgramMetadata,
+) -> Result<(), Error> {
+    db.open_tree(ENTROPY_METADATA_TREE)?
+        .insert(hash, serde_json::to_vec(entropy_metadata)?)?;
+    Ok(())
+}
+
+/// Get the Entropy metadata of a program, if the program is present. For programs stored before
+/// this was stored separately, it is read from the package metadata.
+pub fn get_entropy_metadata(
+    db: &sled::Db,
+    hash: &[u8],
+) -> Result<Option<EntropyProgramMetadata>, Error> {
+    if let Some(value) = db.open_tree(ENTROPY_METADATA_TREE)?.get(hash)? {
+        return Ok(Some(serde_json::from_slice(&value)?));
+    }
+    match db.get(hash)? {
+        Some(value) => {
+            let metadata: serde_json::Value = serde_json::from_slice(&value)?;
+            Ok(Some(extract_metadata(&metadata["metadata"])))
+        }
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,238 @@
+//! Mirroring of programs from other instances of this service, so that several instances can
+//! serve as redundant registries. Mirrored programs are checked against their hash, and where
+//! each came from is recorded.
+use cargo_metadata::semver::Version;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sp_core::H256;
+
+use crate::{
+    binary::store_binary,
+    build::{EntropyProgramMetadata, Error},
+    digest::store_digests,
+    entropy_metadata::store_entropy_metadata,
+    index::{add_name_version, add_search_terms},
+    record::unix_time,
+    scaffold::is_valid_package_name,
+};
+
+/// Name of the sled tree in which the peer each mirrored program came from is stored, keyed by
+/// program hash
+const ORIGINS_TREE: &str = "origins";
+
+/// Number of program hashes requested from a peer at a time
+const PEER_PAGE_SIZE: usize = 500;
+
+/// The most bytes of a program's metadata which are read from a peer
+const MAX_PEER_PROGRAM_SIZE: usize = 4 * 1024 * 1024;
+
+/// The most bytes of a program's binary which are read from a peer
+const MAX_PEER_BINARY_SIZE: usize = 50 * 1024 * 1024;
+
+/// Where a program which was not added here was mirrored from
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Origin {
+    /// Base URL of the instance the program was mirrored from
+    pub peer: String,
+    /// Unix time in seconds when the program was mirrored
+    pub mirrored_at: u64,
+    /// How the peer said the program was verified, such as it being registered without source
+    /// code. This is only the peer's claim, so is not given as the program's own verification.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verification: Option<String>,
+}
+
+/// The outcome of syncing with a peer
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PeerSync {
+    /// Number of programs mirrored
+    pub mirrored: usize,
+    /// Number of programs which were not mirrored, because their binary did not match their hash
+    /// or they could not be fetched or stored
+    pub rejected: usize,
+}
+
+/// The parts of a peer's program metadata which are mirrored
+#[derive(Deserialize)]
+struct PeerProgram {
+    name: String,
+    version: String,
+    package: Option<serde_json::Value>,
+    entropy_metadata: EntropyProgramMetadata,
+    verification: Option<String>,
+}
+
+/// Mirror the listed programs of a peer which are not already present
+pub async fn sync_peer(db: &sled::Db, peer: &str) -> Result<PeerSync, Error> {
+    let peer = peer.trim_end_matches('/');
+    let client = reqwest::Client::new();
+    let mut sync = PeerSync::default();
+    let mut after: Option<String> = None;
+    loop {
+        let mut request = client
+            .get(format!("{}/programs", peer))
+            .query(&[("limit", PEER_PAGE_SIZE.to_string())]);
+        if let Some(after) = &after {
+            request = request.query(&[("after", after)]);
+        }
+        let response = request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(peer_error)?;
+        after = response
+            .headers()
+            .get("x-next-cursor")
+            .and_then(|next| next.to_str().ok())
+            .map(str::to_string);
+        let hashes: Vec<String> =
+            serde_json::from_slice(&response.bytes().await.map_err(peer_error)?)?;
+
+        for hash in hashes {
+            let hash = hex::decode(&hash)?;
+            if hash.len() != 32 || db.contains_key(&hash)? {
+                continue;
+            }
+            // One program which cannot be mirrored should not keep the others from being
+            match mirror_program(db, &client, peer, H256::from_slice(&hash)).await {
+                Ok(true) => sync.mirrored += 1,
+                Ok(false) => sync.rejected += 1,
+                Err(error) => {
+                    log::warn!(
+                        "Could not mirror program {} from {}: {}",
+                        hex::encode(&hash),
+                        peer,
+                        error
+                    );
+                    sync.rejected += 1;
+                }
+            }
+        }
+        if after.is_none() {
+            return Ok(sync);
+        }
+    }
+}
+
+/// Fetch a program from a peer and store it, unless its binary does not give its hash. Returns
+/// whether it was stored.
+async fn mirror_program(
+    db: &sled::Db,
+    client: &reqwest::Client,
+    peer: &str,
+    hash: H256,
+) -> Result<bool, Error> {
+    // A peer could otherwise send a response without end
+    let fetch = |path: String, max_size: usize| async move {
+        let mut response = client
+            .get(format!("{}{}", peer, path))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(peer_error)?;
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(peer_error)? {
+            body.extend_from_slice(&chunk);
+            if body.len() > max_size {
+                return Err(Error::Peer(format!(
+                    "{} is larger than {} bytes",
+                    path, max_size
+                )));
+            }
+        }
+        Ok(body)
+    };
+    let hex_hash = hex::encode(hash);
+    let program: PeerProgram = serde_json::from_slice(
+        &fetch(format!("/program/{}", hex_hash), MAX_PEER_PROGRAM_SIZE).await?,
+    )?;
+    // The name and version are indexed and shown on this service's pages, so must be a crate's.
+    // Programs the peer found on chain have no name.
+    let is_valid = |name: &str, version: &str| {
+        name.is_empty() || (is_valid_package_name(name) && Version::parse(version).is_ok())
+    };
+    let package_field = |field: &str| {
+        program
+            .package
+            .as_ref()
+            .map(|package| package[field].as_str().unwrap_or_default().to_string())
+    };
+    let (package_name, package_version) = (package_field("name"), package_field("version"));
+    if !is_valid(&program.name, &program.version)
+        || package_name.is_some_and(|name| name != program.name)
+        || package_version.is_some_and(|version| version != program.version)
+    {
+        log::warn!(
+            "Program {} from {} does not have a valid crate name and version, so was not mirrored",
+            hex_hash,
+            peer
+        );
+        return Ok(false);
+    }
+
+    let binary = fetch(format!("/binary/{}", hex_hash), MAX_PEER_BINARY_SIZE).await?;
+
+    // The peer's metadata is taken on trust, but the binary must be what the hash refers to
+    let mut hasher = program.entropy_metadata.program_hasher();
+    hasher.update(&binary);
+    let digests = hasher.finalize_digests();
+    if digests.blake2_256 != hash {
+        log::warn!(
+            "Program {} from {} does not match its hash, so was not mirrored",
+            hex_hash,
+            peer
+        );
+        return Ok(false);
+    }
+
+    let mut metadata = program.package.unwrap_or_else(|| {
+        json!({
+            "name": program.name,
+            "version": program.version,
+        })
+    });
+    // Whatever the peer says of how the program was verified is kept with its origin instead
+    if let Some(metadata) = metadata.as_object_mut() {
+        metadata.remove("verification");
+    }
+    db.insert(hash, serde_json::to_vec(&metadata)?)?;
+    store_binary(db, hash, &binary)?;
+    store_entropy_metadata(db, hash, &program.entropy_metadata)?;
+    store_digests(db, &digests)?;
+    // Programs the peer found on chain have no name
+    if !program.name.is_empty() {
+        add_name_version(db, &program.name, &program.version, hash)?;
+    }
+    add_search_terms(db, hash, &metadata)?;
+    store_origin(
+        db,
+        hash,
+        &Origin {
+            peer: peer.to_string(),
+            mirrored_at: unix_time(),
+            verification: program.verification,
+        },
+    )?;
+    Ok(true)
+}
+
+/// An error from a request to a peer
+pub(crate) fn peer_error(error: reqwest::Error) -> Error {
+    Error::Peer(error.to_string())
+}
+
+/// Store where a mirrored program came from
+pub fn store_origin(db: &sled::Db, hash: H256, origin: &Origin) -> Result<(), Error> {
+    db.open_tree(ORIGINS_TREE)?
+        .insert(hash, serde_json::to_vec(origin)?)?;
+    Ok(())
+}
+
+/// Get where a program was mirrored from, if it was
+pub fn get_origin(db: &sled::Db, hash: &[u8]) -> Result<Option<Origin>, Error> {
+    match db.open_tree(ORIGINS_TREE)?.get(hash)? {
+        Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+        None => Ok(None),
+    }
+}
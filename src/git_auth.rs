@@ -0,0 +1,157 @@
+//! Credentials for cloning private git repositories. Tokens are given to git through a
+//! credential helper reading them from the environment, and SSH keys through `GIT_SSH_COMMAND`,
+//! so that they never appear in repository URLs, which are stored and logged.
+use serde::Deserialize;
+use std::{fmt, path::PathBuf, process::Command};
+
+/// Environment variable from which the credential helper reads the token
+const TOKEN_ENV_VAR: &str = "PROGRAM_METADATA_GIT_TOKEN";
+
+/// Credential helper answering requests for credentials with the token. The username is
+/// ignored by GitHub and GitLab when a token is given as the password.
+const TOKEN_CREDENTIAL_HELPER: &str = "!f() { test \"$1\" = get && echo username=x-access-token \
+     && echo \"password=$PROGRAM_METADATA_GIT_TOKEN\"; }; f";
+
+/// Credentials configured on the server for repositories whose URL starts with a given prefix.
+/// These are only used for builds in the namespaces they are given to, as anyone could otherwise
+/// have the server clone its private repositories for them.
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct GitCredential {
+    /// Such as `https://github.com/myorg/` or `git@github.com:myorg/`
+    pub url_prefix: String,
+    /// Access token for cloning over https
+    pub token: Option<String>,
+    /// Path to a private deploy key for cloning over SSH
+    pub ssh_key: Option<PathBuf>,
+    /// Namespaces of the tenants whose builds may use these credentials
+    #[serde(default)]
+    pub namespaces: Vec<String>,
+}
+
+impl fmt::Debug for GitCredential {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GitCredential")
+            .field("url_prefix", &self.url_prefix)
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .field("ssh_key", &self.ssh_key)
+            .field("namespaces", &self.namespaces)
+            .finish()
+    }
+}
+
+/// The credentials to clone a particular repository with, if any
+#[derive(Clone, Default)]
+pub struct GitAuth {
+    token: Option<String>,
+    ssh_key: Option<PathBuf>,
+    /// Whether any of these credentials were configured on the server
+    configured: bool,
+}
+
+impl fmt::Debug for GitAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GitAuth")
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .field("ssh_key", &self.ssh_key)
+            .field("configured", &self.configured)
+            .finish()
+    }
+}
+
+impl GitAuth {
+    /// Credentials given with a single request
+    pub fn token(token: String) -> Self {
+        Self {
+            token: Some(token),
+            ssh_key: None,
+            configured: false,
+        }
+    }
+
+    /// Whether a token was given with the request, rather than only in the configuration
+    pub fn has_token(&self) -> bool {
+        self.token.is_some()
+    }
+
+    /// Whether any of these credentials were configured on the server, rather than given with
+    /// the request
+    pub fn uses_configured(&self) -> bool {
+        self.configured
+    }
+
+    /// Fill in anything not given with the request from the configured credentials with the
+    /// longest prefix matching the URL, of those given to the namespace the build is in
+    pub fn or_configured(
+        self,
+        credentials: &[GitCredential],
+        url: &str,
+        namespace: Option<&str>,
+    ) -> Self {
+        let Some(namespace) = namespace else {
+            return self;
+        };
+        let configured = credentials
+            .iter()
+            .filter(|credential| url.starts_with(&credential.url_prefix))
+            .filter(|credential| credential.namespaces.iter().any(|name| name == namespace))
+            .max_by_key(|credential| credential.url_prefix.len());
+        match configured {
+            Some(configured) => Self {
+                configured: (self.token.is_none() && configured.token.is_some())
+                    || (self.ssh_key.is_none() && configured.ssh_key.is_some()),
+                token: self.token.or_else(|| configured.token.clone()),
+                ssh_key: self.ssh_key.or_else(|| configured.ssh_key.clone()),
+            },
+            None => self,
+        }
+    }
+
+    /// Set up a git command to use these credentials. Git is also stopped from prompting for
+    /// credentials, which would otherwise hang the build.
+    pub fn apply(&self, command: &mut Command) {
+        command.env("GIT_TERMINAL_PROMPT", "0");
+        if let Some(token) = &self.token {
+            // An empty helper first clears any helpers configured on the host
+            command
+                .env("GIT_CONFIG_COUNT", "2")
+                .env("GIT_CONFIG_KEY_0", "credential.helper")
+                .env("GIT_CONFIG_VALUE_0", "")
+                .env("GIT_CONFIG_KEY_1", "credential.helper")
+                .env("GIT_CONFIG_VALUE_1", TOKEN_CREDENTIAL_HELPER)
+                .env(TOKEN_ENV_VAR, token);
+        }
+        if let Some(ssh_key) = &self.ssh_key {
+            command.env(
+                "GIT_SSH_COMMAND",
+                format!(
+                    "ssh -i '{}' -o IdentitiesOnly=yes -o BatchMode=yes",
+                    ssh_key.display()
+                ),
+            );
+        }
+    }
+}
+
+/// Remove a username or password from a repository URL, giving the URL without them and the
+/// password, or the username if there is no password, as a token
+pub fn strip_credentials(url: &str) -> (String, Option<String>) {
+    let Ok(mut parsed) = reqwest::Url::parse(url) else {
+        // Such as `git@github.com:myorg/repo.git`, where the username is not a secret
+        return (url.to_string(), None);
+    };
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return (url.to_string(), None);
+    }
+    let token = match (parsed.username(), parsed.password()) {
+        (_, Some(password)) => Some(password.to_string()),
+        ("", None) => None,
+        (username, None) => Some(username.to_string()),
+    };
+    if token.is_none() {
+        return (url.to_string(), None);
+    }
+    let _ = parsed.set_username("");
+    let _ = parsed.set_password(None);
+    (parsed.to_string(), token)
+}
@@ -0,0 +1,72 @@
+//! Pulling of docker images when the server starts, so that the first builds after a deploy do
+//! not spend minutes of their submitters' connections waiting for `docker pull`
+use std::{
+    io::{BufRead, BufReader},
+    process::Stdio,
+};
+
+use crate::{
+    build::{DockerSettings, Error},
+    container::{ContainerBackend, ContainerEngine},
+    record::recent_docker_images,
+};
+
+/// Find the images to pull: the configured default image and further images, then those used by
+/// recent builds, if configured to pull them
+pub fn images_to_pull(db: &sled::Db, docker: &DockerSettings) -> Result<Vec<String>, Error> {
+    let mut images: Vec<String> = docker
+        .default_image
+        .iter()
+        .chain(&docker.pull_images)
+        .cloned()
+        .collect();
+    if let Some(builds) = docker.pull_recent_images {
+        for image in recent_docker_images(db, builds)? {
+            if !images.contains(&image) {
+                images.push(image);
+            }
+        }
+    }
+    Ok(images)
+}
+
+/// Pull images one at a time, logging docker's progress. Images which cannot be pulled are
+/// logged and skipped, as builds will try to pull them again.
+pub async fn pull_images(container_engine: ContainerEngine, images: Vec<String>) {
+    let backend = container_engine.backend();
+    let pulls = tokio::task::spawn_blocking(move || {
+        for image in images {
+            let image = backend.image_name(&image);
+            log::info!("Pulling docker image {}", image);
+            match pull_image(backend, &image) {
+                Ok(()) => log::info!("Pulled docker image {}", image),
+                Err(error) => log::warn!("{}", error),
+            }
+        }
+    });
+    if let Err(error) = pulls.await {
+        log::error!("Pulling docker images stopped: {}", error);
+    }
+}
+
+/// Pull an image, logging each line of progress docker gives
+fn pull_image(backend: &dyn ContainerBackend, image: &str) -> Result<(), Error> {
+    let mut process = backend
+        .command()
+        .args(["pull", image])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let stdout = process.stdout.take().ok_or(Error::NoStdOut)?;
+    for line in BufReader::new(stdout).lines() {
+        log::info!("{}: {}", image, line?);
+    }
+    let output = process.wait_with_output()?;
+    if !output.status.success() {
+        return Err(Error::ImagePull(
+            image.to_string(),
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,277 @@
+//! Secondary indexes over the programs stored in the db
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sp_core::H256;
+use std::collections::BTreeSet;
+
+use crate::build::Error;
+
+/// Name of the sled tree mapping package name and version to program hashes
+const NAME_VERSION_TREE: &str = "name_versions";
+
+/// Name of the sled tree of words in program metadata, keyed by word followed by a zero byte
+/// and the program hash
+const SEARCH_TREE: &str = "search_terms";
+
+/// Name of the sled tree holding which generation of the index trees is in use. Each rebuild
+/// of the indexes writes a new generation, so that the one in use is never incomplete.
+const INDEX_GENERATION_TREE: &str = "index_generation";
+
+/// Key of the generation of the index trees in use, which is 0 if they were never rebuilt
+const INDEX_GENERATION_KEY: &[u8] = b"generation";
+
+/// Added to program metadata when other programs have the same name and version
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct CollisionWarning {
+    pub message: String,
+    /// Hashes of the other programs
+    #[schemars(with = "Vec<String>")]
+    pub hashes: Vec<H256>,
+}
+
+impl CollisionWarning {
+    pub fn new(hashes: Vec<H256>) -> Self {
+        Self {
+            message: "Other programs have the same name and version but a different hash"
+                .to_string(),
+            hashes,
+        }
+    }
+}
+
+/// Add a program to the name and version index, returning the hashes of any other programs
+/// with the same name and version
+pub fn add_name_version(
+    db: &sled::Db,
+    name: &str,
+    version: &str,
+    hash: H256,
+) -> Result<Vec<H256>, Error> {
+    insert_name_version(&index_tree(db, NAME_VERSION_TREE)?, name, version, hash)
+}
+
+/// Add a program to a name and version index tree, returning the hashes of any other programs
+/// with the same name and version
+fn insert_name_version(
+    tree: &sled::Tree,
+    name: &str,
+    version: &str,
+    hash: H256,
+) -> Result<Vec<H256>, Error> {
+    let key = name_version_key(name, version);
+    // Updated atomically, so that programs with the same name and version added at once are all
+    // kept. The closure may run more than once, so its outcome is reset each time.
+    let mut hashes = Vec::new();
+    let mut parse_error = None;
+    tree.update_and_fetch(&key, |value| {
+        parse_error = None;
+        hashes = match value.map(serde_json::from_slice::<Vec<H256>>).transpose() {
+            Ok(stored) => stored.unwrap_or_default(),
+            Err(error) => {
+                parse_error = Some(error);
+                return value.map(<[u8]>::to_vec);
+            }
+        };
+        if hashes.contains(&hash) {
+            return value.map(<[u8]>::to_vec);
+        }
+        hashes.push(hash);
+        serde_json::to_vec(&hashes).ok()
+    })?;
+    if let Some(error) = parse_error {
+        return Err(error.into());
+    }
+    Ok(hashes.into_iter().filter(|other| *other != hash).collect())
+}
+
+/// Get the hashes of other programs with the same name and version as the given program
+pub fn name_version_collisions(
+    db: &sled::Db,
+    name: &str,
+    version: &str,
+    hash: H256,
+) -> Result<Vec<H256>, Error> {
+    let tree = index_tree(db, NAME_VERSION_TREE)?;
+    let hashes = get_hashes(&tree, &name_version_key(name, version))?;
+    Ok(hashes.into_iter().filter(|other| *other != hash).collect())
+}
+
+/// Get the hashes of all programs with the given package name, of any version, in order
+pub fn programs_named(db: &sled::Db, name: &str) -> Result<Vec<H256>, Error> {
+    let tree = index_tree(db, NAME_VERSION_TREE)?;
+    let mut hashes = Vec::new();
+    for res in tree.scan_prefix(format!("{} ", name)) {
+        let (_key, value) = res?;
+        hashes.extend(serde_json::from_slice::<Vec<H256>>(&value)?);
+    }
+    hashes.sort();
+    hashes.dedup();
+    Ok(hashes)
+}
+
+/// A program matching a search, as given by `/search`
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct SearchResult {
+    #[schemars(with = "String")]
+    pub hash: H256,
+    pub name: String,
+    pub version: String,
+    pub description: Option<String>,
+}
+
+impl SearchResult {
+    pub fn new(hash: H256, metadata: &serde_json::Value) -> Self {
+        Self {
+            hash,
+            name: metadata["name"].as_str().unwrap_or_default().to_string(),
+            version: metadata["version"].as_str().unwrap_or_default().to_string(),
+            description: metadata["description"].as_str().map(str::to_string),
+        }
+    }
+}
+
+/// Add the words in a program's package name, description, keywords and authors to the search
+/// index
+pub fn add_search_terms(
+    db: &sled::Db,
+    hash: H256,
+    metadata: &serde_json::Value,
+) -> Result<(), Error> {
+    insert_search_terms(&index_tree(db, SEARCH_TREE)?, hash, metadata)
+}
+
+/// Add the words in a program's metadata to a search index tree
+fn insert_search_terms(
+    tree: &sled::Tree,
+    hash: H256,
+    metadata: &serde_json::Value,
+) -> Result<(), Error> {
+    let mut text = vec![
+        metadata["name"].as_str().unwrap_or_default(),
+        metadata["description"].as_str().unwrap_or_default(),
+    ];
+    for field in ["keywords", "authors"] {
+        if let Some(values) = metadata[field].as_array() {
+            text.extend(values.iter().filter_map(|value| value.as_str()));
+        }
+    }
+    let words: BTreeSet<String> = text.into_iter().flat_map(search_words).collect();
+    for word in words {
+        let mut key = word.into_bytes();
+        key.push(0);
+        key.extend_from_slice(hash.as_bytes());
+        tree.insert(key, &[])?;
+    }
+    Ok(())
+}
+
+/// Get the hashes of programs whose metadata contains a word starting with each word of the
+/// query, in order
+pub fn search_programs(db: &sled::Db, query: &str) -> Result<Vec<H256>, Error> {
+    let tree = index_tree(db, SEARCH_TREE)?;
+    let mut matches: Option<BTreeSet<H256>> = None;
+    for word in search_words(query) {
+        let mut hashes = BTreeSet::new();
+        for key in tree.scan_prefix(word.as_bytes()).keys() {
+            let key = key?;
+            if key.len() > H256::len_bytes() {
+                hashes.insert(H256::from_slice(&key[key.len() - H256::len_bytes()..]));
+            }
+        }
+        matches = Some(match matches {
+            Some(previous) => previous.intersection(&hashes).cloned().collect(),
+            None => hashes,
+        });
+    }
+    Ok(matches.unwrap_or_default().into_iter().collect())
+}
+
+/// Rebuild the secondary indexes from the programs in the db, returning the number of programs
+/// indexed. The indexes are written to fresh trees, which replace those in use in a single
+/// write once complete, so that lookups meanwhile use the old indexes rather than partial ones.
+pub fn rebuild_indexes(db: &sled::Db) -> Result<usize, Error> {
+    let generations = db.open_tree(INDEX_GENERATION_TREE)?;
+    let old_generation = get_generation(&generations)?;
+    let generation = old_generation + 1;
+    let name_versions = db.open_tree(generation_tree_name(NAME_VERSION_TREE, generation))?;
+    let search_terms = db.open_tree(generation_tree_name(SEARCH_TREE, generation))?;
+    // An earlier rebuild which failed part way through may have left these behind
+    name_versions.clear()?;
+    search_terms.clear()?;
+    let indexed = index_programs(db, &name_versions, &search_terms)?;
+
+    generations.insert(INDEX_GENERATION_KEY, &generation.to_be_bytes())?;
+    // Programs stored during the rebuild may have been indexed in the old trees instead, so
+    // index everything again, which leaves programs already indexed as they are
+    index_programs(db, &name_versions, &search_terms)?;
+    db.drop_tree(generation_tree_name(NAME_VERSION_TREE, old_generation))?;
+    db.drop_tree(generation_tree_name(SEARCH_TREE, old_generation))?;
+    Ok(indexed)
+}
+
+/// Add every program in the db to the given index trees, returning the number of programs
+/// added to the name and version index
+fn index_programs(
+    db: &sled::Db,
+    name_versions: &sled::Tree,
+    search_terms: &sled::Tree,
+) -> Result<usize, Error> {
+    let mut indexed = 0;
+    for res in db.iter() {
+        let (key, value) = res?;
+        let metadata: serde_json::Value = serde_json::from_slice(&value)?;
+        let hash = H256::from_slice(&key);
+        if let (Some(name), Some(version)) =
+            (metadata["name"].as_str(), metadata["version"].as_str())
+        {
+            insert_name_version(name_versions, name, version, hash)?;
+            indexed += 1;
+        }
+        insert_search_terms(search_terms, hash, &metadata)?;
+    }
+    Ok(indexed)
+}
+
+/// Open the generation of an index tree which is in use
+fn index_tree(db: &sled::Db, name: &str) -> Result<sled::Tree, Error> {
+    let generation = get_generation(&db.open_tree(INDEX_GENERATION_TREE)?)?;
+    Ok(db.open_tree(generation_tree_name(name, generation))?)
+}
+
+/// Get the generation of the index trees in use
+fn get_generation(generations: &sled::Tree) -> Result<u64, Error> {
+    Ok(match generations.get(INDEX_GENERATION_KEY)? {
+        Some(value) => u64::from_be_bytes(value.as_ref().try_into().unwrap_or_default()),
+        None => 0,
+    })
+}
+
+/// The first generation of each index tree keeps its original name, so that indexes stored
+/// before they were rebuilt like this are still used
+fn generation_tree_name(name: &str, generation: u64) -> String {
+    match generation {
+        0 => name.to_string(),
+        _ => format!("{}-{}", name, generation),
+    }
+}
+
+/// Split text into lowercase words, treating anything other than letters and digits as a
+/// separator
+fn search_words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// Name and version are separated by a space as neither can contain one
+fn name_version_key(name: &str, version: &str) -> Vec<u8> {
+    format!("{} {}", name, version).into_bytes()
+}
+
+/// Get the list of hashes stored under a key in an index tree
+fn get_hashes(tree: &sled::Tree, key: &[u8]) -> Result<Vec<H256>, Error> {
+    Ok(match tree.get(key)? {
+        Some(value) => serde_json::from_slice(&value)?,
+        None => Vec::new(),
+    })
+}
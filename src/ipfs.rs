@@ -0,0 +1,142 @@
+//! Publishing of program binaries and source to IPFS, through the RPC API of an IPFS node such as
+//! Kubo, so that they remain available independently of this service. Programs can also be built
+//! from archives stored on IPFS.
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sp_core::H256;
+
+use crate::{archive::ArchiveConfig, binary::get_binary, build::Error, source::get_source};
+
+/// Name of the sled tree in which the CIDs of pinned programs are stored, keyed by program hash
+const IPFS_PINS_TREE: &str = "ipfs_pins";
+
+/// Settings for connecting to an IPFS node
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct IpfsConfig {
+    /// Base URL of the node's RPC API, such as `http://127.0.0.1:5001`
+    pub api_url: String,
+}
+
+/// The CIDs under which a program's binary and source were pinned
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct IpfsPins {
+    pub binary: String,
+    /// The gzipped tarball of the source, if a snapshot of it was kept
+    pub source: Option<String>,
+}
+
+/// The response of the node to adding a file
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct AddResponse {
+    hash: String,
+}
+
+/// Take the CID from an `ipfs://` URL, or a bare CID
+pub fn parse_cid(input: &str) -> Result<String, Error> {
+    let input = input.trim();
+    let cid = input
+        .strip_prefix("ipfs://")
+        .unwrap_or(input)
+        .trim_end_matches('/');
+    // CIDs are base58 or base32 encoded, and may be followed by a path within a directory
+    if cid.is_empty()
+        || !cid
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '/' || c == '.' || c == '-' || c == '_')
+        || cid.split('/').any(|segment| segment == "..")
+    {
+        return Err(Error::InvalidCid(input.to_string()));
+    }
+    Ok(cid.to_string())
+}
+
+/// Add a file to the node, pinning it, and give its CID
+pub async fn add_file(config: &IpfsConfig, name: &str, contents: &[u8]) -> Result<String, Error> {
+    let boundary = format!("{:032x}", rand::random::<u128>());
+    let mut body = format!(
+        "--{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{name}\"\r\n\
+         Content-Type: application/octet-stream\r\n\r\n"
+    )
+    .into_bytes();
+    body.extend_from_slice(contents);
+    body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+
+    let response = reqwest::Client::new()
+        .post(format!(
+            "{}/api/v0/add",
+            config.api_url.trim_end_matches('/')
+        ))
+        .query(&[("pin", "true"), ("cid-version", "1")])
+        .header(
+            "content-type",
+            format!("multipart/form-data; boundary={}", boundary),
+        )
+        .body(body)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(ipfs_error)?;
+    let added: AddResponse = serde_json::from_slice(&response.bytes().await.map_err(ipfs_error)?)?;
+    Ok(added.hash)
+}
+
+/// Get a file from the node, failing if it is larger than the given size
+pub async fn fetch_file(config: &IpfsConfig, cid: &str, max_size: usize) -> Result<Vec<u8>, Error> {
+    let mut response = reqwest::Client::new()
+        .post(format!(
+            "{}/api/v0/cat",
+            config.api_url.trim_end_matches('/')
+        ))
+        .query(&[("arg", cid)])
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(ipfs_error)?;
+    let mut contents = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(ipfs_error)? {
+        contents.extend_from_slice(&chunk);
+        if contents.len() > max_size {
+            return Err(Error::Ipfs(format!(
+                "{} is larger than {} bytes",
+                cid, max_size
+            )));
+        }
+    }
+    Ok(contents)
+}
+
+/// Add a program's binary and source snapshot to the node, and store their CIDs
+pub async fn pin_program(
+    db: &sled::Db,
+    archive: Option<&ArchiveConfig>,
+    config: &IpfsConfig,
+    hash: H256,
+) -> Result<IpfsPins, Error> {
+    let name = hex::encode(hash);
+    let binary = get_binary(db, archive, hash.as_ref())
+        .await?
+        .ok_or(Error::BinaryNotFound)?;
+    let binary = add_file(config, &format!("{}.wasm", name), &binary).await?;
+    let source = match get_source(db, archive, hash.as_ref()).await? {
+        Some(source) => Some(add_file(config, &format!("{}-source.tar.gz", name), &source).await?),
+        None => None,
+    };
+    let pins = IpfsPins { binary, source };
+    db.open_tree(IPFS_PINS_TREE)?
+        .insert(hash, serde_json::to_vec(&pins)?)?;
+    Ok(pins)
+}
+
+fn ipfs_error(error: reqwest::Error) -> Error {
+    Error::Ipfs(error.to_string())
+}
+
+/// Get the CIDs of a program, if it was pinned
+pub fn get_ipfs_pins(db: &sled::Db, hash: &[u8]) -> Result<Option<IpfsPins>, Error> {
+    match db.open_tree(IPFS_PINS_TREE)?.get(hash)? {
+        Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+        None => Ok(None),
+    }
+}
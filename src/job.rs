@@ -0,0 +1,341 @@
+//! Build jobs which are submitted without waiting for the build output. The status of each job
+//! is persisted so that it can be polled, even if the client disconnects during a long build.
+use rand::{distributions::Alphanumeric, Rng};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sp_core::H256;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+};
+use subtle::ConstantTimeEq;
+use tokio::sync::broadcast;
+
+use crate::{
+    build::{BuildResponse, Error, FailureKind},
+    record::{BuildSource, BuildStatus},
+};
+
+/// Name of the sled tree in which jobs are stored, keyed by job id
+const JOBS_TREE: &str = "jobs";
+
+/// Name of the sled tree in which the requests which submitted pending jobs are stored, keyed by
+/// job id, so that they can be queued again after a restart
+const JOB_REQUESTS_TREE: &str = "job_requests";
+
+/// Length of the tokens given to submitters for cancelling their jobs
+const CANCEL_TOKEN_LENGTH: usize = 32;
+
+/// How many responses of a running job are kept to give to clients who subscribe late
+const MAX_EVENT_HISTORY: usize = 10_000;
+
+/// How many responses may be waiting for a slow subscriber before it misses some
+const EVENT_CHANNEL_CAPACITY: usize = 1000;
+
+/// A build job and its progress
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Job {
+    /// Identifier of this job, which is also its key in the db
+    pub id: u64,
+    /// Where the source code came from
+    pub source: BuildSource,
+    /// The progress of the job
+    pub status: JobStatus,
+    /// Identifier of the build record, once the job has finished
+    pub build_id: Option<u64>,
+    /// The namespace of the tenant the job builds for, if any, who alone may see the job
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+}
+
+/// The request which submitted a job, as given. Requests giving their own git credentials are
+/// not stored, so that the credentials are not kept on disk.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobRequest {
+    /// The query string, giving the build options
+    pub query: String,
+    /// The body, giving the repository
+    pub body: String,
+}
+
+/// The progress of a build job
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum JobStatus {
+    /// Waiting for earlier builds to finish
+    Queued,
+    Building,
+    Succeeded {
+        #[schemars(with = "String")]
+        hash: H256,
+    },
+    Failed {
+        kind: FailureKind,
+        error: String,
+    },
+    Cancelled,
+}
+
+impl JobStatus {
+    /// Whether the job has not yet finished
+    pub fn is_pending(&self) -> bool {
+        matches!(self, JobStatus::Queued | JobStatus::Building)
+    }
+}
+
+impl From<&BuildStatus> for JobStatus {
+    fn from(status: &BuildStatus) -> Self {
+        match status {
+            BuildStatus::Succeeded { hash } => JobStatus::Succeeded { hash: *hash },
+            BuildStatus::Failed {
+                kind: FailureKind::Cancelled,
+                ..
+            } => JobStatus::Cancelled,
+            BuildStatus::Failed { kind, error } => JobStatus::Failed {
+                kind: *kind,
+                error: error.clone(),
+            },
+        }
+    }
+}
+
+/// Store a new job, which is queued
+pub fn create_job(
+    db: &sled::Db,
+    source: BuildSource,
+    namespace: Option<String>,
+) -> Result<Job, Error> {
+    let job = Job {
+        id: db.generate_id()?,
+        source,
+        status: JobStatus::Queued,
+        build_id: None,
+        namespace,
+    };
+    store_job(db, &job)?;
+    Ok(job)
+}
+
+/// Get the job with the given id
+pub fn get_job(db: &sled::Db, id: u64) -> Result<Option<Job>, Error> {
+    match db.open_tree(JOBS_TREE)?.get(id.to_be_bytes())? {
+        Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+        None => Ok(None),
+    }
+}
+
+/// Keep the request which submitted a job until the job has finished
+pub fn store_job_request(db: &sled::Db, id: u64, request: &JobRequest) -> Result<(), Error> {
+    db.open_tree(JOB_REQUESTS_TREE)?
+        .insert(id.to_be_bytes(), serde_json::to_vec(request)?)?;
+    Ok(())
+}
+
+/// Update the progress of a job, and the build record once it has finished
+pub fn update_job(
+    db: &sled::Db,
+    id: u64,
+    status: JobStatus,
+    build_id: Option<u64>,
+) -> Result<(), Error> {
+    if !status.is_pending() {
+        db.open_tree(JOB_REQUESTS_TREE)?.remove(id.to_be_bytes())?;
+    }
+    db.open_tree(JOBS_TREE)?
+        .update_and_fetch(id.to_be_bytes(), |value| {
+            let mut job: Job = serde_json::from_slice(value?).ok()?;
+            job.status = status.clone();
+            job.build_id = build_id;
+            serde_json::to_vec(&job).ok()
+        })?;
+    Ok(())
+}
+
+/// Record that a job was cancelled, unless it has already finished. This is done atomically,
+/// so that a build which finishes meanwhile keeps its outcome. Gives whether the job was
+/// cancelled.
+pub fn cancel_pending_job(db: &sled::Db, id: u64) -> Result<bool, Error> {
+    let mut cancelled = false;
+    db.open_tree(JOBS_TREE)?
+        .update_and_fetch(id.to_be_bytes(), |value| {
+            let value = value?;
+            cancelled = false;
+            let mut job: Job = match serde_json::from_slice(value) {
+                Ok(job) => job,
+                Err(_) => return Some(value.to_vec()),
+            };
+            if job.status.is_pending() {
+                job.status = JobStatus::Cancelled;
+                cancelled = true;
+            }
+            serde_json::to_vec(&job).ok()
+        })?;
+    if cancelled {
+        db.open_tree(JOB_REQUESTS_TREE)?.remove(id.to_be_bytes())?;
+    }
+    Ok(cancelled)
+}
+
+/// Mark jobs which were queued or building when the service stopped as interrupted, as the
+/// build queue is not persisted. Gives each job marked, along with the request which submitted
+/// it if that was kept.
+pub fn fail_interrupted_jobs(db: &sled::Db) -> Result<Vec<(Job, Option<JobRequest>)>, Error> {
+    let requests = db.open_tree(JOB_REQUESTS_TREE)?;
+    let mut interrupted = Vec::new();
+    for res in db.open_tree(JOBS_TREE)?.iter() {
+        let (key, value) = res?;
+        let mut job: Job = serde_json::from_slice(&value)?;
+        if !job.status.is_pending() {
+            continue;
+        }
+        job.status = JobStatus::Failed {
+            kind: FailureKind::Interrupted,
+            error: "The service restarted before the build finished".to_string(),
+        };
+        store_job(db, &job)?;
+        let request = match requests.remove(key)? {
+            Some(request) => Some(serde_json::from_slice(&request)?),
+            None => None,
+        };
+        interrupted.push((job, request));
+    }
+    Ok(interrupted)
+}
+
+/// Jobs which are queued or building, with the means to cancel them. These are only kept in
+/// memory, as the build queue is not persisted.
+#[derive(Debug, Clone, Default)]
+pub struct RunningJobs(Arc<Mutex<HashMap<u64, RunningJob>>>);
+
+#[derive(Debug)]
+struct RunningJob {
+    /// Secret given to the submitter, who alone may cancel the job
+    cancel_token: String,
+    /// Set to cancel the build. This is dropped once the build has finished.
+    cancelled: Weak<AtomicBool>,
+    /// Responses given by the build, which are also dropped once it has finished
+    events: Weak<Mutex<JobEventsInner>>,
+}
+
+impl RunningJobs {
+    /// Keep track of a job so that it can be cancelled and its progress followed, returning the
+    /// token needed to cancel it
+    pub fn register(&self, id: u64, cancelled: &Arc<AtomicBool>, events: &JobEvents) -> String {
+        let cancel_token: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(CANCEL_TOKEN_LENGTH)
+            .map(char::from)
+            .collect();
+        if let Ok(mut jobs) = self.0.lock() {
+            // Forget jobs whose builds have finished
+            jobs.retain(|_id, job| job.cancelled.strong_count() > 0);
+            jobs.insert(
+                id,
+                RunningJob {
+                    cancel_token: cancel_token.clone(),
+                    cancelled: Arc::downgrade(cancelled),
+                    events: Arc::downgrade(&events.0),
+                },
+            );
+        }
+        cancel_token
+    }
+
+    /// Whether the given token is the one needed to cancel a job
+    pub fn check_cancel_token(&self, id: u64, cancel_token: &str) -> bool {
+        self.0
+            .lock()
+            .map(|jobs| {
+                // Compared in constant time, so that timing does not reveal how much of a
+                // guessed token is right
+                jobs.get(&id).is_some_and(|job| {
+                    bool::from(job.cancel_token.as_bytes().ct_eq(cancel_token.as_bytes()))
+                })
+            })
+            .unwrap_or_default()
+    }
+
+    /// Cancel a job, returning whether it was still running
+    pub fn cancel(&self, id: u64) -> bool {
+        let cancelled = self
+            .0
+            .lock()
+            .ok()
+            .and_then(|jobs| jobs.get(&id).and_then(|job| job.cancelled.upgrade()));
+        match cancelled {
+            Some(cancelled) => {
+                cancelled.store(true, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Subscribe to the responses of a job, getting those given so far and a receiver for those
+    /// which follow. Gives `None` if the job is not running.
+    pub fn subscribe(
+        &self,
+        id: u64,
+    ) -> Option<(Vec<BuildResponse>, broadcast::Receiver<BuildResponse>)> {
+        let events = self
+            .0
+            .lock()
+            .ok()
+            .and_then(|jobs| jobs.get(&id).and_then(|job| job.events.upgrade()))?;
+        JobEvents(events).subscribe()
+    }
+}
+
+/// The responses given while building a job, which are kept so that clients who subscribe part
+/// way through the build are also given the output so far
+#[derive(Debug, Clone)]
+pub struct JobEvents(Arc<Mutex<JobEventsInner>>);
+
+#[derive(Debug)]
+struct JobEventsInner {
+    history: VecDeque<BuildResponse>,
+    sender: broadcast::Sender<BuildResponse>,
+}
+
+impl Default for JobEvents {
+    fn default() -> Self {
+        let (sender, _receiver) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        Self(Arc::new(Mutex::new(JobEventsInner {
+            history: VecDeque::new(),
+            sender,
+        })))
+    }
+}
+
+impl JobEvents {
+    /// Keep a response and give it to current subscribers
+    pub fn send(&self, response: &BuildResponse) {
+        if let Ok(mut events) = self.0.lock() {
+            if events.history.len() >= MAX_EVENT_HISTORY {
+                events.history.pop_front();
+            }
+            events.history.push_back(response.clone());
+            // There being no subscribers is not an error
+            let _ = events.sender.send(response.clone());
+        }
+    }
+
+    /// Get the responses given so far, and a receiver for those which follow. Both are taken
+    /// under the same lock so that no response is missed or repeated.
+    fn subscribe(&self) -> Option<(Vec<BuildResponse>, broadcast::Receiver<BuildResponse>)> {
+        let events = self.0.lock().ok()?;
+        Some((
+            events.history.iter().cloned().collect(),
+            events.sender.subscribe(),
+        ))
+    }
+}
+
+fn store_job(db: &sled::Db, job: &Job) -> Result<(), Error> {
+    db.open_tree(JOBS_TREE)?
+        .insert(job.id.to_be_bytes(), serde_json::to_vec(job)?)?;
+    Ok(())
+}
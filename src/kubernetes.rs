@@ -0,0 +1,381 @@
+//! Building programs as Kubernetes jobs, for services running in a cluster without a container
+//! engine of their own. Each build is a job in the program's image, which compiles the source
+//! from a volume shared with this service as the program template's Dockerfile does, and leaves
+//! the binary there. Jobs are managed with `kubectl`.
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    build::{run_blocking, BuildOptions, Error, ResourceLimits},
+    native_build::WASM_TARGET,
+    sccache::SccacheConfig,
+    source::snapshot_source,
+};
+
+/// Where the shared volume is mounted in build pods, if not configured
+const DEFAULT_MOUNT_PATH: &str = "/builds";
+
+/// Where the source is unpacked within a build pod
+const POD_SOURCE_DIR: &str = "/usr/src/programs";
+
+/// How long to wait for a build pod to start, which includes pulling its image
+const POD_START_TIMEOUT: &str = "10m";
+
+/// How long a finished job is kept by the cluster if it could not be deleted
+const JOB_TTL_SECONDS: u64 = 60 * 60;
+
+/// How often to check whether a job has finished once its logs have ended
+const STATUS_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Settings for building programs as Kubernetes jobs
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct KubernetesConfig {
+    /// Namespace in which jobs are created, if not the default of the kubectl context
+    pub namespace: Option<String>,
+    /// The kubectl context to use, if not the current one
+    pub context: Option<String>,
+    /// Persistent volume claim mounted into build pods, through which sources and binaries are
+    /// passed
+    pub volume_claim: String,
+    /// Where the same volume is mounted on this host
+    pub shared_dir: PathBuf,
+    /// Where the volume is mounted in build pods, `/builds` if not given
+    pub mount_path: Option<String>,
+}
+
+/// What a job does with a program's source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobTask {
+    /// Compile the program, copying the binary to the shared volume
+    Build,
+    /// Run the program's tests
+    Test,
+}
+
+/// How a job ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobOutcome {
+    Succeeded,
+    /// The pod was killed for exceeding its memory limit
+    OutOfMemory,
+    Failed,
+}
+
+/// A job in the cluster, which is deleted along with its files on the shared volume when this is
+/// dropped
+pub struct KubernetesJob {
+    config: KubernetesConfig,
+    name: String,
+    /// Directory of the job on the shared volume, as mounted on this host
+    dir: PathBuf,
+}
+
+impl KubernetesConfig {
+    /// A kubectl command for the configured context and namespace
+    fn kubectl(&self) -> Command {
+        let mut command = Command::new("kubectl");
+        if let Some(context) = &self.context {
+            command.arg(format!("--context={}", context));
+        }
+        if let Some(namespace) = &self.namespace {
+            command.arg(format!("--namespace={}", namespace));
+        }
+        command
+    }
+
+    fn mount_path(&self) -> &str {
+        self.mount_path.as_deref().unwrap_or(DEFAULT_MOUNT_PATH)
+    }
+
+    /// Put a snapshot of a program's source on the shared volume, and create a job which carries
+    /// out the given task with it in the given image
+    pub async fn submit(
+        &self,
+        program_path: &Path,
+        image_name: &str,
+        task: JobTask,
+        options: &BuildOptions,
+        timeout: Duration,
+    ) -> Result<KubernetesJob, Error> {
+        let name = format!("program-build-{:016x}", rand::random::<u64>());
+        let job = KubernetesJob {
+            config: self.clone(),
+            dir: self.shared_dir.join(&name),
+            name,
+        };
+        let manifest = job.manifest(image_name, task, options, timeout).to_string();
+        let (config, dir, program_path) = (self.clone(), job.dir.clone(), program_path.to_owned());
+        run_blocking(move || {
+            std::fs::create_dir_all(&dir)?;
+            std::fs::write(dir.join("source.tar.gz"), snapshot_source(&program_path)?)?;
+            config.apply(&manifest)
+        })
+        .await?;
+        log::info!("Created build job {}", job.name);
+        Ok(job)
+    }
+
+    /// Create or update resources in the cluster from their JSON definition
+    fn apply(&self, manifest: &str) -> Result<(), Error> {
+        let mut process = self
+            .kubectl()
+            .args(["apply", "--filename=-"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        if let Some(mut stdin) = process.stdin.take() {
+            stdin.write_all(manifest.as_bytes())?;
+        }
+        let output = process.wait_with_output()?;
+        if !output.status.success() {
+            return Err(Error::Kubernetes(
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// How the given job ended, if it has
+    fn job_outcome(&self, name: &str) -> Result<Option<JobOutcome>, Error> {
+        let job = self.get(&["job", name])?;
+        if job["status"]["succeeded"].as_u64().unwrap_or_default() > 0 {
+            return Ok(Some(JobOutcome::Succeeded));
+        }
+        if job["status"]["failed"].as_u64().unwrap_or_default() > 0 {
+            return Ok(Some(if self.was_oom_killed(name)? {
+                JobOutcome::OutOfMemory
+            } else {
+                JobOutcome::Failed
+            }));
+        }
+        Ok(None)
+    }
+
+    /// Whether the pod of the given job was killed for exceeding its memory limit
+    fn was_oom_killed(&self, name: &str) -> Result<bool, Error> {
+        let pods = self.get(&["pods", &format!("--selector=job-name={}", name)])?;
+        Ok(pods["items"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .flat_map(|pod| pod["status"]["containerStatuses"].as_array())
+            .flatten()
+            .any(|status| status["state"]["terminated"]["reason"] == "OOMKilled"))
+    }
+
+    /// Get resources from the cluster as JSON
+    fn get(&self, args: &[&str]) -> Result<Value, Error> {
+        let output = self
+            .kubectl()
+            .arg("get")
+            .args(args)
+            .arg("--output=json")
+            .output()?;
+        if !output.status.success() {
+            return Err(Error::Kubernetes(
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ));
+        }
+        Ok(serde_json::from_slice(&output.stdout)?)
+    }
+
+    /// Delete a job and its files on the shared volume, logging rather than failing if either
+    /// cannot be deleted
+    fn delete_job(&self, name: &str, dir: &Path) {
+        let deleted = self
+            .kubectl()
+            .args(["delete", "job", name])
+            .args(["--ignore-not-found", "--wait=false", "--cascade=background"])
+            .output();
+        if !deleted.is_ok_and(|output| output.status.success()) {
+            log::warn!("Could not delete build job {}", name);
+        }
+        if let Err(error) = std::fs::remove_dir_all(dir) {
+            if error.kind() != ErrorKind::NotFound {
+                log::warn!("Could not remove {}: {}", dir.display(), error);
+            }
+        }
+    }
+}
+
+impl KubernetesJob {
+    /// The job's definition, whose pod unpacks the source, carries out the task and, for a
+    /// build, copies the binary back to the shared volume
+    fn manifest(
+        &self,
+        image_name: &str,
+        task: JobTask,
+        options: &BuildOptions,
+        timeout: Duration,
+    ) -> Value {
+        let job_path = format!("{}/{}", self.config.mount_path(), self.name);
+        // Feature names are checked to contain no characters special to the shell
+        let features = options.features.cargo_args().join(" ");
+        // A local sccache cache is kept on the shared volume, so that it outlives the pod
+        let env = options.sccache.as_ref().map_or_else(
+            || json!([]),
+            |sccache| sccache.kubernetes_env(&format!("{}/sccache", self.config.mount_path())),
+        );
+        let command = match task {
+            JobTask::Build => format!(
+                "cargo component build --release --target {target} {features} && \
+                 mkdir -p {job_path}/output && \
+                 cp target/{target}/release/*.wasm {job_path}/output/",
+                target = WASM_TARGET,
+            ),
+            JobTask::Test => format!("cargo test {}", features),
+        };
+        let script = format!(
+            "set -e; mkdir -p {source_dir}; tar xzf {job_path}/source.tar.gz -C {source_dir}; \
+             cd {source_dir}; {command}",
+            source_dir = POD_SOURCE_DIR,
+        );
+        // A pod only sees its own job's directory of the volume, and the local cache if it uses
+        // one, so that it cannot read or change the sources and binaries of other jobs
+        let mut volume_mounts = vec![json!({
+            "name": "builds",
+            "mountPath": job_path,
+            "subPath": self.name,
+        })];
+        if options
+            .sccache
+            .as_ref()
+            .is_some_and(SccacheConfig::is_local)
+        {
+            volume_mounts.push(json!({
+                "name": "builds",
+                "mountPath": format!("{}/sccache", self.config.mount_path()),
+                "subPath": "sccache",
+            }));
+        }
+        json!({
+            "apiVersion": "batch/v1",
+            "kind": "Job",
+            "metadata": {
+                "name": self.name,
+                "labels": { "app.kubernetes.io/managed-by": "program-metadata-http-service" },
+            },
+            "spec": {
+                "backoffLimit": 0,
+                "activeDeadlineSeconds": timeout.as_secs(),
+                "ttlSecondsAfterFinished": JOB_TTL_SECONDS,
+                "template": {
+                    "spec": {
+                        "restartPolicy": "Never",
+                        "containers": [{
+                            "name": "build",
+                            "image": image_name,
+                            "command": ["sh", "-c", script],
+                            "env": env,
+                            "resources": resources(&options.resource_limits),
+                            "volumeMounts": volume_mounts,
+                        }],
+                        "volumes": [{
+                            "name": "builds",
+                            "persistentVolumeClaim": { "claimName": self.config.volume_claim },
+                        }],
+                    },
+                },
+            },
+        })
+    }
+
+    /// The command which follows the output of the job's pod until it ends, once it has started
+    pub fn logs_command(&self) -> Command {
+        let mut command = self.config.kubectl();
+        command
+            .arg("logs")
+            .arg("--follow")
+            .arg(format!("--pod-running-timeout={}", POD_START_TIMEOUT))
+            .arg(format!("job/{}", self.name));
+        command
+    }
+
+    /// Wait for the job to finish, giving how it ended
+    pub async fn wait(&self, deadline: Instant) -> Result<JobOutcome, Error> {
+        loop {
+            let (config, name) = (self.config.clone(), self.name.clone());
+            if let Some(outcome) = run_blocking(move || config.job_outcome(&name)).await? {
+                return Ok(outcome);
+            }
+            if Instant::now() > deadline {
+                return Err(Error::Timeout);
+            }
+            tokio::time::sleep(STATUS_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Copy the binaries a build job left on the shared volume into the given directory. The
+    /// job's pod could write anything there, so only regular files are copied, rather than
+    /// following links out of the volume.
+    pub async fn copy_binaries(&self, binary_dir: &Path) -> Result<(), Error> {
+        let (output_dir, binary_dir) = (self.dir.join("output"), binary_dir.to_owned());
+        run_blocking(move || {
+            std::fs::create_dir_all(&binary_dir)?;
+            for entry in std::fs::read_dir(output_dir)? {
+                let path = entry?.path();
+                if !std::fs::symlink_metadata(&path)?.is_file() {
+                    return Err(Error::CompilationFailed(format!(
+                        "Build output {} is not a regular file",
+                        path.display()
+                    )));
+                }
+                if let Some(file_name) = path.file_name() {
+                    std::fs::copy(&path, binary_dir.join(file_name))?;
+                }
+            }
+            Ok(())
+        })
+        .await
+    }
+}
+
+impl Drop for KubernetesJob {
+    fn drop(&mut self) {
+        let (config, name, dir) = (self.config.clone(), self.name.clone(), self.dir.clone());
+        // Jobs are dropped by async tasks, which should not wait on kubectl or the volume
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn_blocking(move || config.delete_job(&name, &dir));
+            }
+            Err(_) => config.delete_job(&name, &dir),
+        }
+    }
+}
+
+/// The resources of a build pod, which are reserved for it as well as limiting it. Swap is not
+/// given to pods, so the memory and swap limit does not apply.
+fn resources(resource_limits: &ResourceLimits) -> Value {
+    let mut resources = serde_json::Map::new();
+    if let Some(memory) = &resource_limits.memory {
+        resources.insert("memory".to_string(), memory_quantity(memory).into());
+    }
+    if let Some(cpus) = resource_limits.cpus {
+        resources.insert("cpu".to_string(), cpus.to_string().into());
+    }
+    json!({ "limits": resources, "requests": resources })
+}
+
+/// Convert a memory size in docker's notation, such as `900m`, to a Kubernetes quantity such as
+/// `900Mi`. Docker's units are powers of 1024.
+fn memory_quantity(memory: &str) -> String {
+    let memory = memory.trim();
+    let Some(unit) = memory.chars().last().filter(char::is_ascii_alphabetic) else {
+        return memory.to_string();
+    };
+    let number = &memory[..memory.len() - 1];
+    match unit.to_ascii_lowercase() {
+        'k' => format!("{}Ki", number),
+        'm' => format!("{}Mi", number),
+        'g' => format!("{}Gi", number),
+        _ => number.to_string(),
+    }
+}
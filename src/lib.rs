@@ -1 +1,54 @@
+pub mod archive;
+pub mod attestation;
+pub mod audit;
+pub mod binary;
 pub mod build;
+pub mod build_cache;
+pub mod build_log;
+pub mod build_queue;
+pub mod builder_pool;
+pub mod cargo_cache;
+pub mod chain;
+pub mod chain_verification;
+pub mod changelog;
+pub mod channel;
+pub mod compaction;
+pub mod compare;
+pub mod config;
+pub mod container;
+pub mod dependencies;
+pub mod digest;
+pub mod disk;
+pub mod entropy_metadata;
+pub mod federation;
+pub mod git_auth;
+pub mod image_pull;
+pub mod index;
+pub mod ipfs;
+pub mod job;
+pub mod kubernetes;
+pub mod lockfile;
+pub mod namespace;
+pub mod native_build;
+pub mod notify;
+pub mod optimize;
+pub mod peer_comparison;
+pub mod program_details;
+pub mod program_info;
+pub mod provenance;
+pub mod quota;
+pub mod record;
+pub mod remote_builder;
+pub mod reproduction;
+pub mod resource_monitor;
+pub mod sbom;
+pub mod scaffold;
+pub mod sccache;
+pub mod schema;
+pub mod seed;
+pub mod source;
+pub mod test_run;
+pub mod test_suite;
+pub mod unpack;
+pub mod usage;
+pub mod visibility;
@@ -0,0 +1,29 @@
+//! The `Cargo.lock` files programs were built with, which give the exact versions of their
+//! dependencies needed to reproduce or audit a build
+use sp_core::H256;
+
+use crate::{
+    archive::{get_artifact, ArchiveConfig},
+    build::Error,
+};
+
+/// Name of the sled tree in which lockfiles are stored, keyed by program hash
+pub const LOCKFILE_TREE: &str = "lockfiles";
+
+/// Store the lockfile a program was built with, replacing that of any earlier build
+pub fn store_lockfile(db: &sled::Db, hash: H256, lockfile: &str) -> Result<(), Error> {
+    db.open_tree(LOCKFILE_TREE)?
+        .insert(hash, lockfile.as_bytes())?;
+    Ok(())
+}
+
+/// Get the lockfile a program was built with
+pub async fn get_lockfile(
+    db: &sled::Db,
+    archive: Option<&ArchiveConfig>,
+    hash: &[u8],
+) -> Result<Option<String>, Error> {
+    Ok(get_artifact(db, archive, LOCKFILE_TREE, hash)
+        .await?
+        .map(|lockfile| String::from_utf8_lossy(&lockfile).to_string()))
+}
@@ -1,135 +1,2884 @@
 //! An http service which builds programs and hosts related metadata
 use axum::{
     body::{Body, Bytes},
-    extract::{self, State},
-    http::StatusCode,
-    response::{Html, IntoResponse, Response},
-    routing::{get, post},
-    Router,
+    extract::{
+        self,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        DefaultBodyLimit, State,
+    },
+    http::{
+        header::{AUTHORIZATION, CONTENT_DISPOSITION, CONTENT_TYPE, RETRY_AFTER, USER_AGENT},
+        HeaderMap, HeaderName, HeaderValue, StatusCode,
+    },
+    middleware::map_response,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Response,
+    },
+    routing::{delete, get, post, put},
+    Json, Router,
 };
 use cargo_metadata::Package;
-use futures::channel::mpsc::{self as futures_mpsc};
+use futures::{
+    channel::mpsc::{self as futures_mpsc},
+    stream::{self, BoxStream},
+    SinkExt, StreamExt, TryStreamExt,
+};
 use http::Method;
+use http_body_util::LengthLimitError;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    ops::Bound,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
 use thiserror::Error;
-use tokio::sync::mpsc::{channel, Sender};
-use tower_http::cors::{Any, CorsLayer};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{broadcast::error::RecvError, mpsc::error::TrySendError};
+use tower_http::{
+    cors::{Any, CorsLayer},
+    limit::RequestBodyLimitLayer,
+};
+
+use program_metadata_http_service::{
+    archive::{archive_untouched, record_access},
+    attestation::{get_attestation, Attestation, BuildSigner},
+    audit::get_audit,
+    binary::{get_binary, register_binary, validate_wasm},
+    build::{
+        check_docker_image, handle_build_requests, is_git_argument, validate_subdirectory,
+        BuildOptions, BuildRequest, BuildResponder, BuildResponse, EntropyProgramMetadata,
+        Error as BuildError, FeatureSelection, INVALID_GIT_ARGUMENT,
+    },
+    build_log::get_build_log,
+    build_queue::{build_queue, BuildQueue},
+    builder_pool::{BuilderMessage, BuilderPool, BuilderStatus, MAX_BUILD_WAIT},
+    cargo_cache::prune_cargo_cache,
+    chain::{get_deployment_status, index_chain_programs, refresh_deployment_status},
+    chain_verification::{
+        enqueue_chain_verifications, find_chain_sources, get_chain_verification,
+        parse_chain_source_list,
+    },
+    changelog::get_changelog,
+    channel::{get_channel, get_channels, is_valid_channel_name, set_channel},
+    compaction::compact_db,
+    config::{Config, ConfigError, Tenant},
+    dependencies::{get_dependencies, Dependency},
+    digest::{get_digests, resolve_digest},
+    disk::{clean_up_disk, DB_PATH},
+    entropy_metadata::get_entropy_metadata,
+    federation::{get_origin, sync_peer},
+    git_auth::strip_credentials,
+    image_pull::{images_to_pull, pull_images},
+    index::{
+        name_version_collisions, programs_named, search_programs, CollisionWarning, SearchResult,
+    },
+    ipfs::{fetch_file, get_ipfs_pins, parse_cid},
+    job::{
+        cancel_pending_job, create_job, fail_interrupted_jobs, get_job, store_job_request,
+        update_job, Job, JobEvents, JobRequest, JobStatus, RunningJobs,
+    },
+    lockfile::get_lockfile,
+    namespace::{namespace_contains, namespace_programs, remove_from_namespace},
+    notify::{validate_address, Notifier},
+    optimize::get_optimization,
+    peer_comparison::get_peer_consensus,
+    program_details::ProgramDetails,
+    program_info::ProgramInfo,
+    provenance::get_provenance,
+    quota::{quota_status, release_build, reserve_build, QuotaStatus},
+    record::{first_build_of, get_build_record, get_build_records, BuildStatus},
+    remote_builder::run_builder,
+    reproduction::get_reproductions,
+    sbom::get_sbom,
+    scaffold::{
+        is_allowed_source_url, is_valid_package_name, InvalidPackageName, ProgramTemplate,
+        DEFAULT_PROGRAM_NAME, UNTRUSTED_SOURCE_URL,
+    },
+    schema::{api_schema, ErrorBody},
+    seed::{enqueue_seeds, parse_seed_list},
+    source::get_source,
+    test_run::{get_benchmark, TestRun},
+    unpack::{check_tar, check_zip, fetch_archive, SpooledArchive, ZIP_MAGIC},
+    usage::{get_usage, is_bot, record_fetch, FetchKind},
+    visibility::{get_visibility, set_visibility, Visibility},
+};
+use sp_core::H256;
+use subtle::ConstantTimeEq;
+
+/// Number of programs shown on each page of the front page
+const FRONT_PAGE_SIZE: usize = 50;
+
+/// How long to wait before reconnecting to the chain when importing programs from it fails
+const INDEXER_RETRY_SECONDS: u64 = 60;
+
+/// How long clients are asked to wait before submitting a build again when the queue is full
+const QUEUE_FULL_RETRY_AFTER_SECONDS: u64 = 30;
+
+/// State available to http route handlers
+#[derive(Clone)]
+struct AppState {
+    /// The key value store
+    db: sled::Db,
+    /// Queue of builds waiting for a worker
+    build_queue: BuildQueue,
+    /// Server configuration, which is replaced when it is reloaded
+    config: Arc<RwLock<Arc<Config>>>,
+    /// Sends email notifications, if enabled in the configuration
+    notifier: Arc<RwLock<Option<Notifier>>>,
+    /// Whether builds and changes are currently rejected
+    read_only: Arc<AtomicBool>,
+    /// Message explaining why new builds are rejected, while in maintenance mode
+    maintenance: Arc<RwLock<Option<String>>>,
+    /// Build jobs which may be cancelled
+    running_jobs: RunningJobs,
+    /// The largest request body accepted, which is fixed when the router is built
+    max_request_body_size: usize,
+    /// Builds waiting for and being carried out by builders, if a pool is configured
+    builder_pool: BuilderPool,
+}
+
+impl AppState {
+    /// Get the current configuration
+    fn config(&self) -> Arc<Config> {
+        self.config
+            .read()
+            .map(|config| config.clone())
+            .unwrap_or_default()
+    }
+
+    /// Re-read the configuration file, applying settings which can be changed while running.
+    /// Builds which are in progress are not affected.
+    fn reload_config(&self) -> Result<(), AppError> {
+        let config = Config::from_env()?;
+        let notifier = config
+            .smtp
+            .as_ref()
+            .map(|smtp| Notifier::new(smtp, config.public_url.clone()))
+            .transpose()?;
+        if let Some(log_level) = config.log_level {
+            log::set_max_level(log_level);
+        }
+        if let Ok(mut current) = self.notifier.write() {
+            *current = notifier;
+        }
+        if let Ok(mut current) = self.config.write() {
+            *current = Arc::new(config);
+        }
+        log::info!("Reloaded configuration");
+        Ok(())
+    }
+
+    /// Check that the service is not in read-only mode before making changes
+    fn check_writable(&self) -> Result<(), AppError> {
+        if self.read_only.load(Ordering::Relaxed) {
+            return Err(AppError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Check that new builds are being accepted, and that there is the disk space for them
+    fn check_accepting_builds(&self) -> Result<(), AppError> {
+        self.check_writable()?;
+        if let Some(message) = self.maintenance_message() {
+            return Err(AppError::Maintenance(message));
+        }
+        if let Some(disk) = &self.config().disk {
+            disk.check_free_space()?;
+        }
+        Ok(())
+    }
+
+    /// The maintenance message, if in maintenance mode
+    fn maintenance_message(&self) -> Option<String> {
+        self.maintenance
+            .read()
+            .map(|maintenance| maintenance.clone())
+            .unwrap_or_default()
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let config = Config::from_env()?;
+
+    // If a log level is configured, it is applied after all messages are let through the
+    // logger's own filter, so that reloading the configuration can change it
+    let mut logger = env_logger::Builder::from_default_env();
+    if config.log_level.is_some() {
+        logger.filter_level(log::LevelFilter::Trace);
+    }
+    logger.init();
+    if let Some(log_level) = config.log_level {
+        log::set_max_level(log_level);
+    }
+    let configured_programs = config.programs.clone();
+    let read_only = Arc::new(AtomicBool::new(config.read_only));
+
+    let mut port = "3000".to_string();
+    let mut seed_file = None;
+    let mut builder_url = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--seed-file" {
+            seed_file = Some(args.next().ok_or("--seed-file requires a path")?);
+        } else if arg == "--builder" {
+            builder_url = Some(
+                args.next()
+                    .ok_or("--builder requires the URL of a service")?,
+            );
+        } else {
+            port = arg;
+        }
+    }
+
+    // Prune the cargo caches periodically, if configured to. Builders of a pool do so too.
+    if let Some(cargo_cache) = config.cargo_cache.clone() {
+        if let Some(period) = cargo_cache.cleanup_interval() {
+            let container_engine = config.container_engine;
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(period);
+                // The first tick completes immediately, and pruning at startup would empty the
+                // caches just as builds are resumed
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    if let Err(error) =
+                        prune_cargo_cache(cargo_cache.clone(), container_engine).await
+                    {
+                        log::error!("{}", error);
+                    }
+                }
+            });
+        }
+    }
+
+    // Reclaim disk space periodically, if configured to, starting with whatever an earlier run
+    // left behind
+    if let Some(disk) = config.disk.clone() {
+        if let Some(period) = disk.cleanup_interval() {
+            let container_engine = config.container_engine;
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(period);
+                loop {
+                    interval.tick().await;
+                    if let Err(error) = clean_up_disk(disk.clone(), container_engine).await {
+                        log::error!("{}", error);
+                    }
+                }
+            });
+        }
+    }
+
+    // Build programs for another instance rather than serving requests
+    if let Some(builder_url) = builder_url {
+        let pool_config = config
+            .builder_pool
+            .clone()
+            .ok_or("--builder requires a builder-pool key in the configuration")?;
+        run_builder(builder_url, pool_config, config).await;
+        return Ok(());
+    }
+
+    let cors = CorsLayer::new()
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+        .allow_headers([AUTHORIZATION])
+        .allow_origin(Any);
+
+    // Changing this limit requires a restart, as it is applied when the router is built
+    let max_request_body_size = config.max_request_body_size();
+
+    // Changing the size of the build queue or the number of workers requires a restart too
+    let (build_queue, build_requests_rx) =
+        build_queue(config.max_queued_builds(), config.build_workers());
+
+    let notifier = config
+        .smtp
+        .as_ref()
+        .map(|smtp| Notifier::new(smtp, config.public_url.clone()))
+        .transpose()?;
+
+    let db = sled::open(DB_PATH)?;
+
+    // Changing the signing key also requires a restart
+    let signer = BuildSigner::load(&db, config.signing_key.as_deref())?;
+    log::info!(
+        "Signing build attestations with key {}",
+        signer.public_key()
+    );
+
+    let state = AppState {
+        db: db.clone(),
+        build_queue: build_queue.clone(),
+        config: Arc::new(RwLock::new(Arc::new(config))),
+        notifier: Arc::new(RwLock::new(notifier)),
+        read_only: read_only.clone(),
+        maintenance: Default::default(),
+        running_jobs: Default::default(),
+        max_request_body_size,
+        builder_pool: Default::default(),
+    };
+
+    let app = Router::new()
+        .route("/", get(front_page))
+        .route("/programs", get(list_programs))
+        .route("/search", get(search))
+        .route("/binary/:program_hash", get(get_program_binary))
+        .route("/stats", get(stats_page))
+        .route("/program/:program_hash", get(get_program))
+        .route("/program/compare", post(compare_program))
+        .route("/verify/:program_hash", post(verify_program))
+        .route("/verify-binary", post(verify_binary))
+        .route("/program/:program_hash/program-info", get(get_program_info))
+        .route(
+            "/program/:program_hash/entropy-metadata",
+            get(get_program_entropy_metadata),
+        )
+        .route(
+            "/program/:program_hash/configuration-schema",
+            get(get_program_configuration_schema),
+        )
+        .route(
+            "/program/:program_hash/auxiliary-data-schema",
+            get(get_program_auxiliary_data_schema),
+        )
+        .route(
+            "/program/:program_hash/changelog",
+            get(get_program_changelog),
+        )
+        .route("/program/:program_hash/log", get(get_program_build_log))
+        .route("/program/:program_hash/source", get(get_program_source))
+        .route(
+            "/program/:program_hash/attestation",
+            get(get_program_attestation),
+        )
+        .route(
+            "/program/:program_hash/dependencies",
+            get(get_program_dependencies),
+        )
+        .route("/program/:program_hash/lockfile", get(get_program_lockfile))
+        .route("/program/:program_hash/sbom", get(get_program_sbom))
+        .route("/program/by-name/:name/channels", get(list_channels))
+        .route(
+            "/program/by-name/:name/channel/:channel",
+            get(resolve_channel),
+        )
+        .route("/add-program-git", post(add_program_git))
+        .route("/add-program-tar", post(add_program_tar))
+        .route("/add-program-zip", post(add_program_zip))
+        .route("/add-program-source", post(add_program_source))
+        .route("/add-program-source-url", post(add_program_source_url))
+        .route("/add-program-ipfs", post(add_program_ipfs))
+        .route("/add-program-url", post(add_program_url))
+        .route("/add-program-binary", post(add_program_binary))
+        .route("/hash", post(compute_program_hash))
+        .route("/scaffold", post(scaffold_program))
+        .route("/schemas/api", get(get_api_schema))
+        .route("/builds", get(list_builds))
+        .route("/queue", get(get_queue_status))
+        .route("/build/:build_id", get(get_build))
+        .route("/build", post(add_build_job))
+        .route("/ws", get(build_websocket))
+        .route("/job/:job_id", get(get_build_job).delete(cancel_build_job))
+        .route("/job/:job_id/events", get(get_build_job_events))
+        .route("/admin/seed", post(seed_programs))
+        .route("/admin/read-only", put(set_read_only))
+        .route("/admin/reload-config", post(reload_config))
+        .route("/admin/compact-db", post(compact_db_now))
+        .route("/admin/archive", post(archive_now))
+        .route("/admin/prune-cargo-cache", post(prune_cargo_cache_now))
+        .route("/admin/disk", get(get_disk_usage))
+        .route("/admin/disk/clean-up", post(clean_up_disk_now))
+        .route("/admin/verify-chain-programs", post(verify_chain_programs))
+        .route(
+            "/admin/maintenance",
+            get(get_maintenance).put(set_maintenance),
+        )
+        .route("/admin/builders", get(list_builders))
+        .route("/builder/register", post(register_builder))
+        .route("/builder/next", get(next_remote_build))
+        .route("/builder/build/:build_id", post(receive_builder_messages))
+        .route(
+            "/builder/build/:build_id/source",
+            get(get_remote_build_source),
+        )
+        .route("/ns/:namespace/programs", get(list_namespace_programs))
+        .route(
+            "/ns/:namespace/program/:program_hash",
+            delete(delete_namespace_program),
+        )
+        .route(
+            "/ns/:namespace/program/:program_hash/channel/:channel",
+            put(set_namespace_program_channel),
+        )
+        .route(
+            "/ns/:namespace/program/:program_hash/visibility",
+            put(set_namespace_program_visibility),
+        )
+        .route("/ns/:namespace/quota", get(get_namespace_quota))
+        .route(
+            "/ns/:namespace/add-program-git",
+            post(add_namespace_program_git),
+        )
+        .route(
+            "/ns/:namespace/add-program-tar",
+            post(add_namespace_program_tar),
+        )
+        .route(
+            "/ns/:namespace/add-program-zip",
+            post(add_namespace_program_zip),
+        )
+        .route(
+            "/ns/:namespace/add-program-source",
+            post(add_namespace_program_source),
+        )
+        .route(
+            "/ns/:namespace/add-program-source-url",
+            post(add_namespace_program_source_url),
+        )
+        .with_state(state.clone())
+        // Replace axum's default limit with the configured one
+        .layer(DefaultBodyLimit::disable())
+        .layer(RequestBodyLimitLayer::new(max_request_body_size))
+        .layer(map_response(move |response: Response| async move {
+            describe_body_too_large(response, max_request_body_size)
+        }))
+        .layer(cors);
+
+    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port)).await?;
+    let local_addr = listener.local_addr()?;
+    println!("Listening on {}", local_addr);
+
+    let interrupted = fail_interrupted_jobs(&db)?;
+    if !interrupted.is_empty() {
+        log::warn!(
+            "Marked {} interrupted build jobs as failed",
+            interrupted.len()
+        );
+    }
+    if state.config().requeue_interrupted_jobs {
+        for (job, request) in interrupted {
+            let Some(request) = request else {
+                log::warn!(
+                    "Job {} cannot be queued again, as its request was not kept",
+                    job.id
+                );
+                continue;
+            };
+            match requeue_job(&state, job.id, request) {
+                Ok(()) => log::info!("Queued interrupted job {} again", job.id),
+                Err(error) => log::error!("Could not queue job {} again: {}", job.id, error),
+            }
+        }
+    }
+
+    // Handle requests to build programs in separate tasks
+    let builder_db = db.clone();
+    let builder_notifier = state.notifier.clone();
+    let builder_config = state.config.clone();
+    let builder_pool = state.builder_pool.clone();
+    tokio::spawn(async move {
+        handle_build_requests(
+            build_requests_rx,
+            builder_db,
+            builder_notifier,
+            builder_config,
+            signer,
+            builder_pool,
+        )
+        .await;
+    });
+
+    // Pull the images builds are likely to need before they are requested, unless no builds
+    // will be made here
+    let config = state.config();
+    if !read_only.load(Ordering::Relaxed)
+        && config.builder_pool.is_none()
+        && config.kubernetes.is_none()
+    {
+        let images = images_to_pull(&db, &config.docker)?;
+        if !images.is_empty() {
+            tokio::spawn(pull_images(config.container_engine, images));
+        }
+    }
+
+    // Compact the db periodically, if configured to
+    if let Some(hours) = state.config().compaction_interval_hours {
+        let compaction_db = db.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(hours.max(1) * 60 * 60));
+            // The first tick completes immediately, but there is no need to compact at startup
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if let Err(error) = compact_db(&compaction_db).await {
+                    log::error!("Could not compact db: {}", error);
+                }
+            }
+        });
+    }
+
+    // Check which programs are on chain periodically, if a chain is configured
+    if let Some(chain) = &state.config().chain {
+        let status_state = state.clone();
+        let minutes = chain.status_interval_minutes().max(1);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(minutes * 60));
+            loop {
+                interval.tick().await;
+                let Some(chain) = status_state.config().chain.clone() else {
+                    continue;
+                };
+                match refresh_deployment_status(&status_state.db, &chain).await {
+                    Ok(deployed) => log::info!("{} stored programs are on chain", deployed),
+                    Err(error) => log::error!("Could not check programs on chain: {}", error),
+                }
+            }
+        });
+    }
+
+    // Import programs registered on chain, reconnecting if the connection is lost
+    if let Some(chain) = &state.config().chain {
+        if chain.index_programs.unwrap_or_default() {
+            let index_state = state.clone();
+            tokio::spawn(async move {
+                while let Some(chain) = index_state.config().chain.clone() {
+                    if let Err(error) = index_chain_programs(&index_state.db, &chain).await {
+                        log::error!("Stopped importing programs from chain: {}", error);
+                    }
+                    tokio::time::sleep(Duration::from_secs(INDEXER_RETRY_SECONDS)).await;
+                }
+            });
+        }
+    }
+
+    // Mirror programs from peers periodically. Peers are read each time, so that they can be
+    // changed by reloading the configuration.
+    let peer_state = state.clone();
+    tokio::spawn(async move {
+        loop {
+            let config = peer_state.config();
+            for peer in &config.peers {
+                match sync_peer(&peer_state.db, peer).await {
+                    Ok(sync) => log::info!(
+                        "Mirrored {} programs from {}, rejecting {}",
+                        sync.mirrored,
+                        peer,
+                        sync.rejected
+                    ),
+                    Err(error) => log::error!("Could not sync with {}: {}", peer, error),
+                }
+            }
+            let minutes = config.peer_sync_interval_minutes();
+            drop(config);
+            tokio::time::sleep(Duration::from_secs(minutes * 60)).await;
+        }
+    });
+
+    // Archive artifacts of programs which are not being used once a day, if configured to
+    let archive_state = state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(24 * 60 * 60));
+        loop {
+            interval.tick().await;
+            if let Some(archive) = &archive_state.config().archive {
+                if let Err(error) = archive_untouched(&archive_state.db, archive).await {
+                    log::error!("Could not archive artifacts: {}", error);
+                }
+            }
+        }
+    });
+
+    // Reload the configuration file on SIGHUP
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            if let Err(error) = state.reload_config() {
+                log::error!("Could not reload configuration: {}", error);
+            }
+        }
+    });
+
+    if read_only.load(Ordering::Relaxed) {
+        log::info!("Starting in read-only mode, so no programs will be built");
+    } else {
+        let import = enqueue_seeds(&db, &build_queue, configured_programs).await?;
+        if import.queued > 0 {
+            log::info!(
+                "Queued {} builds of missing configured programs",
+                import.queued
+            );
+        }
+
+        if let Some(seed_file) = seed_file {
+            let seeds = parse_seed_list(&std::fs::read_to_string(seed_file)?);
+            let import = enqueue_seeds(&db, &build_queue, seeds).await?;
+            log::info!(
+                "Queued {} builds from seed file, skipped {}",
+                import.queued,
+                import.skipped
+            );
+        }
+    }
+
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+/// Query parameters for build requests
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct BuildQuery {
+    /// Email address to notify when the build finishes
+    notify: Option<String>,
+    /// Hex encoded signature request message with which to test run the program once built
+    test_message: Option<String>,
+    /// Hex encoded auxiliary data to give with the test run signature request
+    test_auxiliary_data: Option<String>,
+    /// Whether to measure the fuel used by the test run
+    #[serde(default)]
+    benchmark: bool,
+    /// Whether to run the program's test suite before building it
+    #[serde(default)]
+    run_tests: bool,
+    /// Whether to fail the build if the program's test suite fails
+    #[serde(default)]
+    require_tests_pass: bool,
+    /// A binary to compare with the one built, given in the request body rather than the query
+    #[serde(skip)]
+    compare_with: Option<Vec<u8>>,
+    /// Who can see the program once built. Private programs must be built in a namespace.
+    visibility: Option<Visibility>,
+    /// Directory of the program within the repository or archive, if not the root
+    path: Option<String>,
+    /// Whether to build the program even if the same commit has already been built
+    #[serde(default)]
+    no_cache: bool,
+    /// Whether to give the binary in the success message, as clients written before it could be
+    /// downloaded separately expect
+    #[serde(default)]
+    include_binary: bool,
+    /// The hash of the program being verified by rebuilding it, which is not a query parameter
+    #[serde(skip)]
+    verify: Option<H256>,
+    /// Whether to check the hash of the binary given to compare with
+    #[serde(skip)]
+    verify_given: bool,
+    /// The docker image to build with in place of the one in the program's metadata, pinned by
+    /// digest as recorded for an earlier build, such as `name@sha256:...`
+    docker_image: Option<String>,
+    /// Whether to optimize the binary with `wasm-opt`, even if the program's metadata does not
+    /// ask for it
+    #[serde(default)]
+    optimize: bool,
+    /// Whether to register the program on chain once it is built, which the server must be
+    /// configured for
+    #[serde(default)]
+    deploy: bool,
+    /// Comma separated docker images to build the program with one after another, instead of
+    /// those given in its metadata
+    docker_images: Option<String>,
+    /// Comma separated cargo features to build the program with
+    features: Option<String>,
+    /// Whether to build the program without its default features
+    #[serde(default)]
+    no_default_features: bool,
+    /// Whether to carry on with the build if the client disconnects, recording its progress in a
+    /// job which can be followed instead
+    #[serde(default)]
+    background: bool,
+}
+
+impl BuildQuery {
+    /// Get options for the build from the query parameters
+    fn build_options(&self, config: &Config) -> Result<BuildOptions, AppError> {
+        let test_run = match &self.test_message {
+            Some(message) => Some(TestRun {
+                message: hex::decode(message)?,
+                auxiliary_data: self
+                    .test_auxiliary_data
+                    .as_ref()
+                    .map(hex::decode)
+                    .transpose()?,
+                fuel: config.test_run_fuel,
+            }),
+            None if self.benchmark => return Err(AppError::MissingTestInput),
+            None => None,
+        };
+        if self.deploy
+            && config
+                .chain
+                .as_ref()
+                .and_then(|chain| chain.deployer_key.as_ref())
+                .is_none()
+        {
+            return Err(AppError::DeploymentDisabled);
+        }
+        if let Some(docker_image) = &self.docker_image {
+            if !docker_image.contains("@sha256:") {
+                return Err(AppError::UnpinnedImage);
+            }
+        }
+        let docker_images: Vec<String> = self
+            .docker_images
+            .iter()
+            .flat_map(|docker_images| docker_images.split(','))
+            .map(str::trim)
+            .filter(|docker_image| !docker_image.is_empty())
+            .map(str::to_string)
+            .collect();
+        // Images given in the program's metadata are only checked once the build starts
+        for docker_image in self.docker_image.iter().chain(&docker_images) {
+            check_docker_image(&config.allowed_docker_images, docker_image)?;
+        }
+        Ok(BuildOptions {
+            test_run,
+            benchmark: self.benchmark,
+            run_tests: self.run_tests || self.require_tests_pass,
+            require_tests_pass: self.require_tests_pass,
+            compare_with: self.compare_with.clone(),
+            visibility: self.visibility,
+            path: self
+                .path
+                .as_deref()
+                .map(validate_subdirectory)
+                .transpose()?,
+            no_cache: self.no_cache,
+            include_binary: self.include_binary,
+            verify: self.verify,
+            verify_given: self.verify_given,
+            docker_image: self.docker_image.clone(),
+            docker_images,
+            optimize: self.optimize,
+            deploy: self.deploy,
+            features: FeatureSelection {
+                features: self
+                    .features
+                    .iter()
+                    .flat_map(|features| features.split(','))
+                    .map(str::trim)
+                    .filter(|feature| !feature.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+                no_default_features: self.no_default_features,
+            },
+            ..Default::default()
+        })
+    }
+}
+
+/// Add a program from a git repository
+async fn add_program_git(
+    State(state): State<AppState>,
+    extract::Query(query): extract::Query<BuildQuery>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<(StatusCode, BuildStream), AppError> {
+    let submission = GitSubmission::parse(body)?;
+    queue_build(&state, query, &headers, |responder| {
+        submission.into_build_request(responder)
+    })
+    .await
+}
+
+/// Add a program given as a tar achive
+async fn add_program_tar(
+    State(state): State<AppState>,
+    extract::Query(query): extract::Query<BuildQuery>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<(StatusCode, BuildStream), AppError> {
+    let archive = spool_body(&state, body).await?;
+    check_tar(&archive, state.config().build_limits.max_unpacked_size())?;
+    queue_build(&state, query, &headers, |responder| {
+        BuildRequest::new_tar(archive, responder)
+    })
+    .await
+}
+
+/// Write a request body to a temporary file as it arrives, rather than holding it in memory
+async fn spool_body(state: &AppState, body: Body) -> Result<SpooledArchive, AppError> {
+    let stream = body.into_data_stream().map_err(|error| {
+        // The size limit is enforced while the body is read, when no length is given up front
+        let too_large = std::iter::successors(
+            Some(&error as &(dyn std::error::Error + 'static)),
+            |error| error.source(),
+        )
+        .any(|error| error.is::<LengthLimitError>());
+        if too_large {
+            AppError::BodyTooLarge(state.max_request_body_size)
+        } else {
+            AppError::Body(error)
+        }
+    });
+    SpooledArchive::from_stream(stream).await
+}
+
+/// Add a program given as a zip archive
+async fn add_program_zip(
+    State(state): State<AppState>,
+    extract::Query(query): extract::Query<BuildQuery>,
+    headers: HeaderMap,
+    input: Bytes,
+) -> Result<(StatusCode, BuildStream), AppError> {
+    check_zip(&input, state.config().build_limits.max_unpacked_size())?;
+    queue_build(&state, query, &headers, |responder| {
+        BuildRequest::new_zip(input.to_vec(), responder)
+    })
+    .await
+}
+
+/// A git repository to build a program from. A body which is not a JSON object is taken to be
+/// just the URL, building the default branch.
+#[derive(Deserialize)]
+struct GitSubmission {
+    url: String,
+    /// A branch, tag or commit hash to build
+    #[serde(rename = "ref")]
+    git_ref: Option<String>,
+    /// Access token with which to clone a private repository over https
+    token: Option<String>,
+    /// The cargo features to build with, if not the program's default features
+    #[serde(flatten)]
+    features: FeatureSelection,
+}
+
+impl GitSubmission {
+    fn parse(body: String) -> Result<Self, AppError> {
+        let submission: Self = if body.trim_start().starts_with('{') {
+            serde_json::from_str(&body).map_err(AppError::InvalidJson)?
+        } else {
+            Self {
+                url: body,
+                git_ref: None,
+                token: None,
+                features: FeatureSelection::default(),
+            }
+        };
+        if !is_git_argument(&submission.url)
+            || !submission.git_ref.as_deref().is_none_or(is_git_argument)
+        {
+            return Err(AppError::InvalidGitArgument);
+        }
+        Ok(submission)
+    }
+
+    fn into_build_request(self, responder: BuildResponder) -> BuildRequest {
+        let mut build_request =
+            BuildRequest::new_git(self.url, responder).with_features(self.features);
+        if let Some(git_ref) = self.git_ref {
+            build_request = build_request.with_git_ref(git_ref);
+        }
+        if let Some(token) = self.token {
+            build_request = build_request.with_git_token(token);
+        }
+        build_request
+    }
+}
+
+/// A program given as a single source file, which is built as the `src/lib.rs` of a project
+/// generated from the standard template
+#[derive(Deserialize)]
+struct SourceSubmission {
+    /// The package name, if not the default
+    name: Option<String>,
+    /// Contents of `src/lib.rs`
+    source: String,
+    /// Extra dependencies from crates.io, with their version requirements
+    #[serde(default)]
+    dependencies: BTreeMap<String, String>,
+}
+
+impl SourceSubmission {
+    /// Wrap the source file in a generated cargo project, given as a tar archive
+    fn into_tar(self) -> Result<Vec<u8>, AppError> {
+        let name = self
+            .name
+            .unwrap_or_else(|| DEFAULT_PROGRAM_NAME.to_string());
+        let mut template = ProgramTemplate::new(name)?.with_source(self.source);
+        for (name, version) in self.dependencies {
+            template.add_dependency(name, &version)?;
+        }
+        Ok(template.to_tar()?)
+    }
+}
+
+/// Add a program given as a single source file
+async fn add_program_source(
+    State(state): State<AppState>,
+    extract::Query(query): extract::Query<BuildQuery>,
+    headers: HeaderMap,
+    Json(submission): Json<SourceSubmission>,
+) -> Result<(StatusCode, BuildStream), AppError> {
+    let archive = SpooledArchive::from_bytes(&submission.into_tar()?)?;
+    queue_build(&state, query, &headers, |responder| {
+        BuildRequest::new_tar(archive, responder)
+    })
+    .await
+}
+
+/// Add a program given as the URL of a single source file, such as a raw file or a GitHub gist
+async fn add_program_source_url(
+    State(state): State<AppState>,
+    extract::Query(query): extract::Query<BuildQuery>,
+    headers: HeaderMap,
+    url: String,
+) -> Result<(StatusCode, BuildStream), AppError> {
+    if !is_allowed_source_url(&url) {
+        return Err(AppError::UntrustedSourceUrl);
+    }
+    queue_build(&state, query, &headers, |responder| {
+        BuildRequest::new_source_url(url, responder)
+    })
+    .await
+}
+
+/// Add a program from a tar or zip archive stored on IPFS, given by an `ipfs://` URL or a CID
+async fn add_program_ipfs(
+    State(state): State<AppState>,
+    extract::Query(query): extract::Query<BuildQuery>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<(StatusCode, BuildStream), AppError> {
+    let config = state.config();
+    let ipfs = config.ipfs.as_ref().ok_or(AppError::IpfsDisabled)?;
+    let cid = parse_cid(&body)?;
+    state.check_accepting_builds()?;
+    let input = fetch_file(ipfs, &cid, state.max_request_body_size).await?;
+    queue_archive(&state, query, &headers, input).await
+}
+
+/// An archive to download and build. A body which is not a JSON object is taken to be just the
+/// URL.
+#[derive(Deserialize)]
+struct ArchiveSubmission {
+    url: String,
+    /// Hex encoded SHA-256 checksum the archive must have
+    sha256: Option<String>,
+}
+
+/// Add a program from a tar or zip archive at an https URL, such as a release asset
+async fn add_program_url(
+    State(state): State<AppState>,
+    extract::Query(query): extract::Query<BuildQuery>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<(StatusCode, BuildStream), AppError> {
+    let submission = if body.trim_start().starts_with('{') {
+        serde_json::from_str(&body).map_err(AppError::InvalidJson)?
+    } else {
+        ArchiveSubmission {
+            url: body.trim().to_string(),
+            sha256: None,
+        }
+    };
+    // Checked before fetching, as well as when the build is queued, so as not to download an
+    // archive which cannot be built
+    state.check_accepting_builds()?;
+    let input = fetch_archive(
+        &submission.url,
+        submission.sha256.as_deref(),
+        state.max_request_body_size,
+    )
+    .await?;
+    queue_archive(&state, query, &headers, input).await
+}
+
+/// Check and build an archive which was fetched from elsewhere, which may be a tar or zip archive
+async fn queue_archive(
+    state: &AppState,
+    query: BuildQuery,
+    headers: &HeaderMap,
+    input: Vec<u8>,
+) -> Result<(StatusCode, BuildStream), AppError> {
+    let max_unpacked_size = state.config().build_limits.max_unpacked_size();
+    if input.starts_with(&ZIP_MAGIC) {
+        check_zip(&input, max_unpacked_size)?;
+        return queue_build(state, query, headers, |responder| {
+            BuildRequest::new_zip(input, responder)
+        })
+        .await;
+    }
+    let archive = SpooledArchive::from_bytes(&input)?;
+    check_tar(&archive, max_unpacked_size)?;
+    queue_build(state, query, headers, |responder| {
+        BuildRequest::new_tar(archive, responder)
+    })
+    .await
+}
+
+/// Query parameters giving the metadata of a pre-built program binary
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct BinaryQuery {
+    /// Package name of the program
+    name: String,
+    /// Package version of the program
+    version: String,
+    configuration_schema: Option<String>,
+    auxiliary_data_schema: Option<String>,
+    oracle_data_pointer: Option<String>,
+    version_number: Option<u8>,
+}
+
+/// Register a pre-built program binary, for which there is no source code to build. This needs a
+/// tenant's or the admin API key, as the binary cannot be checked against its name.
+async fn add_program_binary(
+    State(state): State<AppState>,
+    extract::Query(query): extract::Query<BinaryQuery>,
+    headers: HeaderMap,
+    binary: Bytes,
+) -> Result<String, AppError> {
+    let config = state.config();
+    let is_tenant = bearer_token(&headers)
+        .and_then(|token| config.tenant_with_api_key(token))
+        .is_some();
+    let is_admin = config
+        .admin_api_key
+        .as_ref()
+        .is_some_and(|admin_api_key| has_bearer_token(&headers, admin_api_key));
+    if !is_tenant && !is_admin {
+        return Err(AppError::Unauthorized);
+    }
+    state.check_accepting_builds()?;
+    if !is_valid_package_name(&query.name) {
+        return Err(InvalidPackageName.into());
+    }
+    cargo_metadata::semver::Version::parse(&query.version).map_err(AppError::InvalidVersion)?;
+    let entropy_metadata = EntropyProgramMetadata {
+        docker_image: None,
+        docker_images: None,
+        configuration_schema: query.configuration_schema,
+        auxiliary_data_schema: query.auxiliary_data_schema,
+        oracle_data_pointer: query.oracle_data_pointer,
+        version_number: query.version_number,
+        optimize: None,
+        features: FeatureSelection::default(),
+    };
+    let hash = register_binary(
+        &state.db,
+        &binary,
+        &query.name,
+        &query.version,
+        &entropy_metadata,
+    )?;
+    Ok(serde_json::to_string(&hex::encode(hash))?)
+}
+
+/// Query parameters giving the Entropy metadata with which to hash a program binary
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct HashQuery {
+    configuration_schema: Option<String>,
+    auxiliary_data_schema: Option<String>,
+    oracle_data_pointer: Option<String>,
+    version_number: Option<u8>,
+}
+
+/// Compute the hash a program binary would be given, without storing anything, so that a
+/// program built elsewhere can have its hash predicted
+async fn compute_program_hash(
+    extract::Query(query): extract::Query<HashQuery>,
+    binary: Bytes,
+) -> Result<String, AppError> {
+    validate_wasm(&binary)?;
+    let entropy_metadata = EntropyProgramMetadata {
+        docker_image: None,
+        docker_images: None,
+        configuration_schema: query.configuration_schema,
+        auxiliary_data_schema: query.auxiliary_data_schema,
+        oracle_data_pointer: query.oracle_data_pointer,
+        version_number: query.version_number,
+        optimize: None,
+        features: FeatureSelection::default(),
+    };
+    let hash = entropy_metadata.program_hash(&binary);
+    Ok(serde_json::to_string(&hex::encode(hash))?)
+}
+
+/// Query parameters for comparing a binary with a build
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct CompareQuery {
+    /// Url to a git repo containing the source code to build
+    git_url: String,
+}
+
+/// Build a program from source and compare the result with the given binary
+async fn compare_program(
+    State(state): State<AppState>,
+    extract::Query(compare_query): extract::Query<CompareQuery>,
+    extract::Query(mut query): extract::Query<BuildQuery>,
+    headers: HeaderMap,
+    binary: Bytes,
+) -> Result<(StatusCode, BuildStream), AppError> {
+    if !is_git_argument(&compare_query.git_url) {
+        return Err(AppError::InvalidGitArgument);
+    }
+    query.compare_with = Some(binary.to_vec());
+    queue_build(&state, query, &headers, |responder| {
+        BuildRequest::new_git(compare_query.git_url, responder)
+    })
+    .await
+}
+
+/// Build a program from source and check whether the given binary has the same hash, so that
+/// a binary received from elsewhere can be checked against its claimed source
+async fn verify_binary(
+    State(state): State<AppState>,
+    extract::Query(compare_query): extract::Query<CompareQuery>,
+    extract::Query(mut query): extract::Query<BuildQuery>,
+    headers: HeaderMap,
+    binary: Bytes,
+) -> Result<(StatusCode, BuildStream), AppError> {
+    validate_wasm(&binary)?;
+    if !is_git_argument(&compare_query.git_url) {
+        return Err(AppError::InvalidGitArgument);
+    }
+    query.compare_with = Some(binary.to_vec());
+    query.verify_given = true;
+    queue_build(&state, query, &headers, |responder| {
+        BuildRequest::new_git(compare_query.git_url, responder)
+    })
+    .await
+}
+
+/// Rebuild a public program from the git source it was first built from, with the same docker
+/// image, to check that it has the same hash
+async fn verify_program(
+    State(state): State<AppState>,
+    extract::Path(program_hash): extract::Path<String>,
+    extract::Query(mut query): extract::Query<BuildQuery>,
+    headers: HeaderMap,
+) -> Result<(StatusCode, BuildStream), AppError> {
+    let hash = hex::decode(program_hash)?;
+    // Rebuilding adds the program as a public one, so other programs cannot be verified
+    if !state.db.contains_key(&hash)? || !get_visibility(&state.db, &hash)?.is_listed() {
+        return Err(AppError::ProgramNotFound);
+    }
+    let provenance = get_provenance(&state.db, &hash)?.ok_or(AppError::ProvenanceNotFound)?;
+    query.verify = Some(H256::from_slice(&hash));
+    query.docker_image = provenance.docker_image;
+    query.path = provenance.path;
+    // A program optimized on request is only reproduced if it is optimized again
+    query.optimize = get_optimization(&state.db, &hash)?.is_some();
+    let features = get_entropy_metadata(&state.db, &hash)?
+        .unwrap_or_default()
+        .features;
+    query.features = Some(features.features.join(","));
+    query.no_default_features = features.no_default_features;
+    queue_build(&state, query, &headers, |responder| {
+        BuildRequest::new_git(provenance.url, responder).with_git_ref(provenance.commit)
+    })
+    .await
+}
+
+/// Add a program from a git repository to a tenant's namespace
+async fn add_namespace_program_git(
+    State(state): State<AppState>,
+    extract::Path(namespace): extract::Path<String>,
+    extract::Query(query): extract::Query<BuildQuery>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<(StatusCode, HeaderMap, BuildStream), AppError> {
+    let submission = GitSubmission::parse(body)?;
+    let config = state.config();
+    let tenant = authenticate(&config, &headers, &namespace)?;
+    queue_namespace_build(&state, tenant, query, &headers, |responder| {
+        submission
+            .into_build_request(responder)
+            .in_namespace(namespace)
+    })
+    .await
+}
+
+/// Add a program given as a tar archive to a tenant's namespace
+async fn add_namespace_program_tar(
+    State(state): State<AppState>,
+    extract::Path(namespace): extract::Path<String>,
+    extract::Query(query): extract::Query<BuildQuery>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<(StatusCode, HeaderMap, BuildStream), AppError> {
+    let config = state.config();
+    let tenant = authenticate(&config, &headers, &namespace)?;
+    let archive = spool_body(&state, body).await?;
+    check_tar(&archive, config.build_limits.max_unpacked_size())?;
+    queue_namespace_build(&state, tenant, query, &headers, |responder| {
+        BuildRequest::new_tar(archive, responder).in_namespace(namespace)
+    })
+    .await
+}
+
+/// Add a program given as a zip archive to a tenant's namespace
+async fn add_namespace_program_zip(
+    State(state): State<AppState>,
+    extract::Path(namespace): extract::Path<String>,
+    extract::Query(query): extract::Query<BuildQuery>,
+    headers: HeaderMap,
+    input: Bytes,
+) -> Result<(StatusCode, HeaderMap, BuildStream), AppError> {
+    let config = state.config();
+    let tenant = authenticate(&config, &headers, &namespace)?;
+    check_zip(&input, config.build_limits.max_unpacked_size())?;
+    queue_namespace_build(&state, tenant, query, &headers, |responder| {
+        BuildRequest::new_zip(input.to_vec(), responder).in_namespace(namespace)
+    })
+    .await
+}
+
+/// Add a program given as a single source file to a tenant's namespace
+async fn add_namespace_program_source(
+    State(state): State<AppState>,
+    extract::Path(namespace): extract::Path<String>,
+    extract::Query(query): extract::Query<BuildQuery>,
+    headers: HeaderMap,
+    Json(submission): Json<SourceSubmission>,
+) -> Result<(StatusCode, HeaderMap, BuildStream), AppError> {
+    let config = state.config();
+    let tenant = authenticate(&config, &headers, &namespace)?;
+    let archive = SpooledArchive::from_bytes(&submission.into_tar()?)?;
+    queue_namespace_build(&state, tenant, query, &headers, |responder| {
+        BuildRequest::new_tar(archive, responder).in_namespace(namespace)
+    })
+    .await
+}
+
+/// Add a program given as the URL of a single source file to a tenant's namespace
+async fn add_namespace_program_source_url(
+    State(state): State<AppState>,
+    extract::Path(namespace): extract::Path<String>,
+    extract::Query(query): extract::Query<BuildQuery>,
+    headers: HeaderMap,
+    url: String,
+) -> Result<(StatusCode, HeaderMap, BuildStream), AppError> {
+    let config = state.config();
+    let tenant = authenticate(&config, &headers, &namespace)?;
+    if !is_allowed_source_url(&url) {
+        return Err(AppError::UntrustedSourceUrl);
+    }
+    queue_namespace_build(&state, tenant, query, &headers, |responder| {
+        BuildRequest::new_source_url(url, responder).in_namespace(namespace)
+    })
+    .await
+}
+
+/// Send a build request to the build task and stream the responses back to the client. A build
+/// requested in the background is also given a job, so that it can be followed once the client
+/// has gone.
+async fn queue_build(
+    state: &AppState,
+    query: BuildQuery,
+    headers: &HeaderMap,
+    build_request: impl FnOnce(BuildResponder) -> BuildRequest,
+) -> Result<(StatusCode, BuildStream), AppError> {
+    let (response_tx, response_rx) = futures_mpsc::channel(1000);
+    let background = query.background;
+    let events = JobEvents::default();
+    let mut responder = BuildResponder::new(response_tx);
+    if background {
+        responder = responder
+            .with_events(events.clone())
+            .finishing_when_disconnected();
+    }
+    let build_request = prepare_build(state, query, headers, build_request(responder))?;
+    let body = Body::from_stream(response_rx);
+    if !background {
+        send_build(state, build_request)?;
+        return Ok((StatusCode::OK, BuildStream(body, None)));
+    }
+    let job = create_job(
+        &state.db,
+        build_request.source(),
+        build_request.namespace().map(str::to_string),
+    )?;
+    let cancel_token = send_job(state, job.id, build_request, &events)?;
+    Ok((
+        StatusCode::OK,
+        BuildStream(body, Some((job.id, cancel_token))),
+    ))
+}
+
+/// A stream of [BuildResponse](program_metadata_http_service::build::BuildResponse)s, each given
+/// as JSON on its own line, along with the id and cancel token of the job of a build requested in
+/// the background
+struct BuildStream(Body, Option<(u64, String)>);
+
+impl IntoResponse for BuildStream {
+    fn into_response(self) -> Response {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/x-ndjson"),
+        );
+        if let Some((job_id, cancel_token)) = self.1 {
+            headers.insert(
+                HeaderName::from_static("x-job-id"),
+                HeaderValue::from(job_id),
+            );
+            if let Ok(cancel_token) = HeaderValue::from_str(&cancel_token) {
+                headers.insert(HeaderName::from_static("x-job-cancel-token"), cancel_token);
+            }
+        }
+        (headers, self.0).into_response()
+    }
+}
+
+/// Check that a build may be made with the given options, and apply them to the request
+fn prepare_build(
+    state: &AppState,
+    query: BuildQuery,
+    headers: &HeaderMap,
+    build_request: BuildRequest,
+) -> Result<BuildRequest, AppError> {
+    state.check_accepting_builds()?;
+    let config = state.config();
+    // Registering a program is signed with the server's deployer key, whose account pays for it
+    if query.deploy {
+        match build_request.namespace() {
+            Some(namespace) => {
+                authenticate(&config, headers, namespace)?;
+            }
+            None => authenticate_admin(&config, headers)?,
+        }
+    }
+    let options = query.build_options(&config)?;
+    let mut build_request = build_request.with_options(options);
+    // Features may also be chosen in the body of the request
+    let features = build_request.features().clone().normalize()?;
+    build_request = build_request.with_features(features);
+    if query.visibility == Some(Visibility::Private) && build_request.namespace().is_none() {
+        return Err(AppError::PrivateWithoutNamespace);
+    }
+    if let Some(email) = query.notify {
+        if config.smtp.is_none() {
+            return Err(AppError::NotificationsDisabled);
+        }
+        let tenant = build_request
+            .namespace()
+            .and_then(|namespace| config.tenant(namespace));
+        if !tenant.is_some_and(|tenant| tenant.may_notify(&email)) {
+            return Err(AppError::NotifyAddressNotAllowed);
+        }
+        validate_address(&email)?;
+        build_request = build_request.with_notification(email);
+    }
+    Ok(build_request)
+}
+
+/// A message from a client of the websocket build API
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum WebSocketRequest {
+    /// Build a program from a git repository
+    Build(GitSubmission),
+    /// Cancel the build running on this connection
+    Cancel,
+    Ping,
+}
+
+/// A message to a client of the websocket build API, other than a [BuildResponse]
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum WebSocketReply {
+    Pong,
+    Error { error: String },
+}
+
+/// Build programs over a websocket, as an alternative to a streamed response which some proxies
+/// buffer. Query parameters give the options for builds requested on the connection.
+async fn build_websocket(
+    State(state): State<AppState>,
+    extract::Query(query): extract::Query<BuildQuery>,
+    headers: HeaderMap,
+    websocket: WebSocketUpgrade,
+) -> Response {
+    websocket.on_upgrade(move |socket| handle_build_websocket(state, query, headers, socket))
+}
+
+/// A build running on a websocket connection
+struct RunningBuild {
+    responses: futures_mpsc::Receiver<Result<String, BuildError>>,
+    cancelled: Arc<AtomicBool>,
+}
+
+/// Handle requests from a websocket client, sending the responses of each build as JSON text
+/// messages. One build may run at a time on each connection.
+async fn handle_build_websocket(
+    state: AppState,
+    query: BuildQuery,
+    headers: HeaderMap,
+    socket: WebSocket,
+) {
+    let (mut sender, mut receiver) = socket.split();
+    let mut running: Option<RunningBuild> = None;
+    loop {
+        let reply = tokio::select! {
+            message = receiver.next() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    // Protocol level pings are answered by axum
+                    Some(Ok(_)) => continue,
+                };
+                match serde_json::from_str(&text) {
+                    Ok(WebSocketRequest::Build(submission)) if running.is_none() => {
+                        let (response_tx, response_rx) = futures_mpsc::channel(1000);
+                        let build_request = prepare_build(
+                            &state,
+                            query.clone(),
+                            &headers,
+                            submission.into_build_request(BuildResponder::new(response_tx)),
+                        );
+                        match build_request {
+                            Ok(build_request) => {
+                                let cancelled = build_request.cancellation_flag();
+                                match send_build(&state, build_request) {
+                                    Ok(()) => {
+                                        running = Some(RunningBuild {
+                                            responses: response_rx,
+                                            cancelled,
+                                        });
+                                        continue;
+                                    }
+                                    Err(error) => WebSocketReply::Error { error: error.to_string() },
+                                }
+                            }
+                            Err(error) => WebSocketReply::Error { error: error.to_string() },
+                        }
+                    }
+                    Ok(WebSocketRequest::Build(_)) => WebSocketReply::Error {
+                        error: "A build is already running on this connection".to_string(),
+                    },
+                    Ok(WebSocketRequest::Cancel) => match &running {
+                        Some(running) => {
+                            // The build then finishes with a cancelled failure
+                            running.cancelled.store(true, Ordering::Relaxed);
+                            continue;
+                        }
+                        None => WebSocketReply::Error {
+                            error: "No build is running on this connection".to_string(),
+                        },
+                    },
+                    Ok(WebSocketRequest::Ping) => WebSocketReply::Pong,
+                    Err(error) => WebSocketReply::Error { error: error.to_string() },
+                }
+            }
+            response = next_build_response(&mut running) => {
+                let text = match response {
+                    Some(Ok(response)) => response.trim_end().to_string(),
+                    Some(Err(error)) => match serde_json::to_string(&WebSocketReply::Error {
+                        error: error.to_string(),
+                    }) {
+                        Ok(text) => text,
+                        Err(_) => break,
+                    },
+                    None => {
+                        running = None;
+                        continue;
+                    }
+                };
+                if sender.send(Message::Text(text)).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+        };
+        let Ok(text) = serde_json::to_string(&reply) else {
+            break;
+        };
+        if sender.send(Message::Text(text)).await.is_err() {
+            break;
+        }
+    }
+    // Nobody is left to receive the output, so the build is abandoned
+    if let Some(running) = running {
+        running.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Wait for the next response of the build running on a websocket connection, if any
+async fn next_build_response(
+    running: &mut Option<RunningBuild>,
+) -> Option<Result<String, BuildError>> {
+    match running {
+        Some(running) => running.responses.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Add a build request to the build queue
+fn send_build(state: &AppState, build_request: BuildRequest) -> Result<(), AppError> {
+    state
+        .build_queue
+        .try_send(build_request)
+        .map_err(|error| match error {
+            TrySendError::Full(_) => AppError::QueueFull,
+            TrySendError::Closed(_) => AppError::MpscSend,
+        })
+}
+
+/// Queue a build of a program from a git repository without waiting for it, responding with a
+/// job whose progress can be polled, and a token with which it can be cancelled
+async fn add_build_job(
+    State(state): State<AppState>,
+    extract::Query(query): extract::Query<BuildQuery>,
+    extract::RawQuery(raw_query): extract::RawQuery,
+    headers: HeaderMap,
+    body: String,
+) -> Result<(StatusCode, String), AppError> {
+    let submission = GitSubmission::parse(body.clone())?;
+    // A token may also be given in the URL, which the stored request would keep
+    let has_token = submission.token.is_some() || strip_credentials(&submission.url).1.is_some();
+    let deploy = query.deploy;
+    let events = JobEvents::default();
+    let build_request = prepare_build(
+        &state,
+        query,
+        &headers,
+        submission.into_build_request(BuildResponder::detached().with_events(events.clone())),
+    )?;
+    let job = create_job(
+        &state.db,
+        build_request.source(),
+        build_request.namespace().map(str::to_string),
+    )?;
+    // Requests are kept to queue them again after a restart, without the secrets they were made
+    // with, so builds which needed those are not
+    if !has_token && !deploy {
+        let request = JobRequest {
+            query: raw_query.unwrap_or_default(),
+            body,
+        };
+        store_job_request(&state.db, job.id, &request)?;
+    }
+    let cancel_token = send_job(&state, job.id, build_request, &events)?;
+    let mut response = serde_json::to_value(&job)?;
+    response["cancel_token"] = cancel_token.into();
+    Ok((StatusCode::ACCEPTED, serde_json::to_string(&response)?))
+}
+
+/// Queue a job which was interrupted by a restart again, with the request which submitted it.
+/// Its submitter's cancel token is not kept, so only the administrator can cancel it.
+fn requeue_job(state: &AppState, job_id: u64, request: JobRequest) -> Result<(), AppError> {
+    let uri = format!("/?{}", request.query)
+        .parse()
+        .map_err(|_| AppError::InvalidJobRequest)?;
+    let extract::Query(query) =
+        extract::Query::try_from_uri(&uri).map_err(|_| AppError::InvalidJobRequest)?;
+    let submission = GitSubmission::parse(request.body.clone())?;
+    let events = JobEvents::default();
+    let build_request = prepare_build(
+        state,
+        query,
+        &HeaderMap::new(),
+        submission.into_build_request(BuildResponder::detached().with_events(events.clone())),
+    )?;
+    update_job(&state.db, job_id, JobStatus::Queued, None)?;
+    store_job_request(&state.db, job_id, &request)?;
+    send_job(state, job_id, build_request, &events)?;
+    Ok(())
+}
+
+/// Queue the build of a job so that it can be cancelled and followed, giving the token with
+/// which its submitter can cancel it
+fn send_job(
+    state: &AppState,
+    job_id: u64,
+    build_request: BuildRequest,
+    events: &JobEvents,
+) -> Result<String, AppError> {
+    let cancel_token =
+        state
+            .running_jobs
+            .register(job_id, &build_request.cancellation_flag(), events);
+    send_build(state, build_request.with_job(job_id))?;
+    Ok(cancel_token)
+}
+
+/// Cancel a queued or running build job. This requires the job's cancel token, or the
+/// administrator's API key.
+async fn cancel_build_job(
+    State(state): State<AppState>,
+    extract::Path(job_id): extract::Path<u64>,
+    headers: HeaderMap,
+) -> Result<StatusCode, AppError> {
+    let job = get_job(&state.db, job_id)?.ok_or(AppError::JobNotFound)?;
+    let is_submitter = bearer_token(&headers)
+        .is_some_and(|token| state.running_jobs.check_cancel_token(job_id, token));
+    if !is_submitter {
+        authenticate_admin(&state.config(), &headers)?;
+    }
+    if !job.status.is_pending() || !state.running_jobs.cancel(job_id) {
+        return Err(AppError::JobFinished);
+    }
+    // The build may have finished since, in which case its outcome stands
+    if !cancel_pending_job(&state.db, job_id)? {
+        return Err(AppError::JobFinished);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Get the progress of a build job
+async fn get_build_job(
+    State(state): State<AppState>,
+    extract::Path(job_id): extract::Path<u64>,
+    headers: HeaderMap,
+) -> Result<String, AppError> {
+    let job = get_job(&state.db, job_id)?.ok_or(AppError::JobNotFound)?;
+    check_job_visible(&state, &headers, &job)?;
+    Ok(serde_json::to_string(&job)?)
+}
+
+/// Check that a job may be seen by the client. A job for a tenant may only be seen by that
+/// tenant whatever its status, as its source may be of a program meant to be private.
+fn check_job_visible(state: &AppState, headers: &HeaderMap, job: &Job) -> Result<(), AppError> {
+    if let Some(namespace) = &job.namespace {
+        authenticate(&state.config(), headers, namespace).map_err(|_| AppError::JobNotFound)?;
+    }
+    if let JobStatus::Succeeded { hash } = job.status {
+        check_visible(state, headers, hash.as_ref()).map_err(|_| AppError::JobNotFound)?;
+    }
+    Ok(())
+}
+
+/// Follow the output of a build job as server-sent events, each the JSON of a `BuildResponse`.
+/// The output so far is given first, followed by output as it arrives. For a job which has
+/// finished, its stored build log is given instead, followed by a `job` event giving the job.
+async fn get_build_job_events(
+    State(state): State<AppState>,
+    extract::Path(job_id): extract::Path<u64>,
+    headers: HeaderMap,
+) -> Result<Sse<BoxStream<'static, Result<Event, axum::Error>>>, AppError> {
+    let job = get_job(&state.db, job_id)?.ok_or(AppError::JobNotFound)?;
+    check_job_visible(&state, &headers, &job)?;
+
+    let events = match state.running_jobs.subscribe(job_id) {
+        Some((history, receiver)) => {
+            let live = stream::unfold(receiver, |mut receiver| async move {
+                loop {
+                    match receiver.recv().await {
+                        Ok(response) => return Some((response, receiver)),
+                        // A subscriber which falls behind misses some output, rather than
+                        // holding up the build
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            });
+            stream::iter(history)
+                .chain(live)
+                .map(|response| Event::default().json_data(response))
+                .boxed()
+        }
+        None => {
+            let log = match job.status {
+                JobStatus::Succeeded { hash } => {
+                    get_build_log(&state.db, state.config().archive.as_ref(), hash.as_ref()).await?
+                }
+                _ => None,
+            };
+            let lines = match log {
+                Some(log) => log.lines().map(|line| format!("{line}\n")).collect(),
+                None => match job.build_id {
+                    Some(build_id) => get_build_record(&state.db, build_id)?
+                        .map(|record| record.log_tail)
+                        .unwrap_or_default(),
+                    None => Vec::new(),
+                },
+            };
+            let job_event = Event::default().event("job").json_data(&job);
+            stream::iter(lines)
+                .map(|line| Event::default().json_data(BuildResponse::StdOut(line)))
+                .chain(stream::once(async move { job_event }))
+                .boxed()
+        }
+    };
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// Get hashes of all programs in a tenant's namespace. Programs which are not public are only
+/// included when authenticated as the tenant.
+async fn list_namespace_programs(
+    State(state): State<AppState>,
+    extract::Path(namespace): extract::Path<String>,
+    headers: HeaderMap,
+) -> Result<String, AppError> {
+    let config = state.config();
+    config
+        .tenant(&namespace)
+        .ok_or(AppError::NamespaceNotFound)?;
+    let is_owner = authenticate(&config, &headers, &namespace).is_ok();
+    let mut hashes = Vec::new();
+    for hash in namespace_programs(&state.db, &namespace)? {
+        if is_owner || get_visibility(&state.db, hash.as_ref())?.is_listed() {
+            hashes.push(hex::encode(hash));
+        }
+    }
+    Ok(serde_json::to_string(&hashes)?)
+}
+
+/// Remove a program from a tenant's namespace. The program remains in the global view, as
+/// others may depend on it.
+async fn delete_namespace_program(
+    State(state): State<AppState>,
+    extract::Path((namespace, program_hash)): extract::Path<(String, String)>,
+    headers: HeaderMap,
+) -> Result<StatusCode, AppError> {
+    authenticate(&state.config(), &headers, &namespace)?;
+    state.check_writable()?;
+    let hash = hex::decode(program_hash)?;
+    if !remove_from_namespace(&state.db, &namespace, &hash)? {
+        return Err(AppError::ProgramNotFound);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Point a release channel of a program's family at the program
+async fn set_namespace_program_channel(
+    State(state): State<AppState>,
+    extract::Path((namespace, program_hash, channel)): extract::Path<(String, String, String)>,
+    headers: HeaderMap,
+) -> Result<StatusCode, AppError> {
+    authenticate(&state.config(), &headers, &namespace)?;
+    state.check_writable()?;
+    if !is_valid_channel_name(&channel) {
+        return Err(AppError::InvalidChannelName);
+    }
+    let hash = hex::decode(program_hash)?;
+    if !namespace_contains(&state.db, &namespace, &hash)? {
+        return Err(AppError::ProgramNotFound);
+    }
+    let value = state.db.get(&hash)?.ok_or(AppError::ProgramNotFound)?;
+    let package: Package = serde_json::from_slice(&value)?;
+    let set = set_channel(
+        &state.db,
+        &package.name,
+        &channel,
+        H256::from_slice(&hash),
+        &namespace,
+    )?;
+    if !set {
+        return Err(AppError::ChannelOwnedByOtherTenant);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Set who can see a program in a tenant's namespace
+async fn set_namespace_program_visibility(
+    State(state): State<AppState>,
+    extract::Path((namespace, program_hash)): extract::Path<(String, String)>,
+    headers: HeaderMap,
+    Json(visibility): Json<Visibility>,
+) -> Result<StatusCode, AppError> {
+    authenticate(&state.config(), &headers, &namespace)?;
+    state.check_writable()?;
+    let hash = hex::decode(program_hash)?;
+    if !namespace_contains(&state.db, &namespace, &hash)? {
+        return Err(AppError::ProgramNotFound);
+    }
+    if !set_visibility(&state.db, H256::from_slice(&hash), visibility, &namespace)? {
+        return Err(AppError::VisibilityOwnedByOtherTenant);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Get the hash of the program a release channel points at
+async fn resolve_channel(
+    State(state): State<AppState>,
+    extract::Path((name, channel)): extract::Path<(String, String)>,
+) -> Result<String, AppError> {
+    let channel = get_channel(&state.db, &name, &channel)?.ok_or(AppError::ChannelNotFound)?;
+    Ok(serde_json::to_string(&hex::encode(channel.hash))?)
+}
+
+/// Get all release channels of a program family, with the hash each points at
+async fn list_channels(
+    State(state): State<AppState>,
+    extract::Path(name): extract::Path<String>,
+) -> Result<String, AppError> {
+    let channels: std::collections::BTreeMap<String, String> = get_channels(&state.db, &name)?
+        .into_iter()
+        .map(|(channel, hash)| (channel, hex::encode(hash)))
+        .collect();
+    Ok(serde_json::to_string(&channels)?)
+}
+
+/// Check that the request has the bearer token of the tenant who owns the given namespace
+fn authenticate<'a>(
+    config: &'a Config,
+    headers: &HeaderMap,
+    namespace: &str,
+) -> Result<&'a Tenant, AppError> {
+    let tenant = config
+        .tenant(namespace)
+        .ok_or(AppError::NamespaceNotFound)?;
+    if !has_bearer_token(headers, &tenant.api_key) {
+        return Err(AppError::Unauthorized);
+    }
+    Ok(tenant)
+}
+
+/// Get the bearer token given in the authorization header of a request
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// Whether a request has the given key as its bearer token, compared in constant time so that
+/// timing does not reveal how much of a guessed key is right
+fn has_bearer_token(headers: &HeaderMap, key: &str) -> bool {
+    bearer_token(headers).is_some_and(|token| bool::from(token.as_bytes().ct_eq(key.as_bytes())))
+}
+
+/// Check that a program may be seen by the requester. Private programs are reported as not
+/// found unless the request has the bearer token of the tenant who owns them.
+fn check_visible(state: &AppState, headers: &HeaderMap, hash: &[u8]) -> Result<(), AppError> {
+    let config = state.config();
+    let namespace = bearer_token(headers)
+        .and_then(|token| config.tenant_with_api_key(token))
+        .map(|tenant| tenant.namespace.as_str());
+    if !get_visibility(&state.db, hash)?.is_visible_to(namespace) {
+        return Err(AppError::ProgramNotFound);
+    }
+    Ok(())
+}
+
+/// Check that the request has the bearer token of the administrator
+fn authenticate_admin(config: &Config, headers: &HeaderMap) -> Result<(), AppError> {
+    let admin_api_key = config
+        .admin_api_key
+        .as_ref()
+        .ok_or(AppError::AdminDisabled)?;
+    if !has_bearer_token(headers, admin_api_key) {
+        return Err(AppError::Unauthorized);
+    }
+    Ok(())
+}
+
+/// Request builds of each program in a seed list which has not already been built
+async fn seed_programs(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    seed_list: String,
+) -> Result<String, AppError> {
+    authenticate_admin(&state.config(), &headers)?;
+    state.check_accepting_builds()?;
+    let import = enqueue_seeds(&state.db, &state.build_queue, parse_seed_list(&seed_list)).await?;
+    Ok(serde_json::to_string(&import)?)
+}
+
+/// Turn read-only mode on or off, given a JSON boolean
+async fn set_read_only(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(read_only): Json<bool>,
+) -> Result<StatusCode, AppError> {
+    authenticate_admin(&state.config(), &headers)?;
+    state.read_only.store(read_only, Ordering::Relaxed);
+    log::info!("Read-only mode set to {}", read_only);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Body of requests to turn maintenance mode on or off
+#[derive(Deserialize)]
+struct MaintenanceRequest {
+    /// The message given when rejecting builds, or none to turn maintenance mode off
+    message: Option<String>,
+}
+
+/// Whether the service is in maintenance mode, and how many builds are still waiting to start
+#[derive(Serialize)]
+struct MaintenanceStatus {
+    message: Option<String>,
+    queued_builds: usize,
+}
+
+/// Turn maintenance mode on or off. Builds which are already queued carry on, but new builds are
+/// rejected with the given message.
+async fn set_maintenance(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<MaintenanceRequest>,
+) -> Result<StatusCode, AppError> {
+    authenticate_admin(&state.config(), &headers)?;
+    log::info!("Maintenance message set to {:?}", request.message);
+    if let Ok(mut maintenance) = state.maintenance.write() {
+        *maintenance = request.message;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Get the maintenance status, so that operators can tell when the build queue has drained
+async fn get_maintenance(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<String, AppError> {
+    authenticate_admin(&state.config(), &headers)?;
+    let status = MaintenanceStatus {
+        message: state.maintenance_message(),
+        queued_builds: state.build_queue.len(),
+    };
+    Ok(serde_json::to_string(&status)?)
+}
+
+/// How full the build queue is, so that clients can hold back submissions while it is busy
+#[derive(Serialize)]
+struct QueueStatus {
+    /// Builds waiting for a worker, not counting those being built
+    queued_builds: usize,
+    /// Number of builds which may wait before further builds are rejected
+    max_queued_builds: usize,
+}
+
+/// Get how full the build queue is
+async fn get_queue_status(State(state): State<AppState>) -> Result<String, AppError> {
+    let status = QueueStatus {
+        queued_builds: state.build_queue.len(),
+        max_queued_builds: state.build_queue.max_len(),
+    };
+    Ok(serde_json::to_string(&status)?)
+}
+
+/// Re-read the configuration file
+async fn reload_config(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<StatusCode, AppError> {
+    authenticate_admin(&state.config(), &headers)?;
+    state.reload_config()?;
+    Ok(StatusCode::NO_CONTENT)
+}
 
-use program_metadata_http_service::build::{handle_build_requests, BuildRequest, BuildResponder};
+/// Rebuild secondary indexes and flush the db, reporting its size on disk before and after
+async fn compact_db_now(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<String, AppError> {
+    authenticate_admin(&state.config(), &headers)?;
+    state.check_writable()?;
+    let report = compact_db(&state.db).await?;
+    Ok(serde_json::to_string(&report)?)
+}
 
-/// State available to http route handlers
-#[derive(Clone)]
-struct AppState {
-    /// The key value store
-    db: sled::Db,
-    /// Channel for sending build requests
-    build_requests_tx: Sender<BuildRequest>,
+/// Remove old build cache mounts and the test volumes, reporting what was removed. The
+/// defaults are used if the caches are not configured.
+async fn prune_cargo_cache_now(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<String, AppError> {
+    let config = state.config();
+    authenticate_admin(&config, &headers)?;
+    let report = prune_cargo_cache(
+        config.cargo_cache.clone().unwrap_or_default(),
+        config.container_engine,
+    )
+    .await?;
+    Ok(serde_json::to_string(&report)?)
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::init();
+/// Report the free space on the host and the space used by the container engine and builds
+async fn get_disk_usage(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<String, AppError> {
+    let config = state.config();
+    authenticate_admin(&config, &headers)?;
+    let usage = config
+        .disk
+        .clone()
+        .unwrap_or_default()
+        .usage(config.container_engine)?;
+    Ok(serde_json::to_string(&usage)?)
+}
 
-    let port = std::env::args()
-        .nth(1)
-        .unwrap_or_else(|| "3000".to_string());
+/// Remove dangling images and build cache and stale temporary directories, reporting what was
+/// removed
+async fn clean_up_disk_now(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<String, AppError> {
+    let config = state.config();
+    authenticate_admin(&config, &headers)?;
+    let report = clean_up_disk(
+        config.disk.clone().unwrap_or_default(),
+        config.container_engine,
+    )
+    .await?;
+    Ok(serde_json::to_string(&report)?)
+}
 
-    let cors = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST])
-        .allow_origin(Any);
+/// Archive artifacts of programs which have not been accessed recently
+async fn archive_now(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<String, AppError> {
+    let config = state.config();
+    authenticate_admin(&config, &headers)?;
+    state.check_writable()?;
+    let archive = config.archive.as_ref().ok_or(AppError::ArchiveDisabled)?;
+    let report = archive_untouched(&state.db, archive).await?;
+    Ok(serde_json::to_string(&report)?)
+}
 
-    let (build_requests_tx, build_requests_rx) = channel(1000);
+/// Rebuild programs found on chain from the source they claim, to check that their bytecode
+/// matches it. Programs are given as lines of a program hash and git URL, and those whose
+/// metadata gives a repository are also rebuilt.
+async fn verify_chain_programs(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    source_list: String,
+) -> Result<String, AppError> {
+    authenticate_admin(&state.config(), &headers)?;
+    state.check_accepting_builds()?;
+    let mut sources = parse_chain_source_list(&source_list)?;
+    sources.extend(find_chain_sources(&state.db)?);
+    let import = enqueue_chain_verifications(&state.db, &state.build_queue, sources).await?;
+    Ok(serde_json::to_string(&import)?)
+}
 
-    let db = sled::open("./program-metadata-http-service-db")?;
+/// List the builders which have registered with the pool, and what each is building
+async fn list_builders(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<BTreeMap<String, BuilderStatus>>, AppError> {
+    authenticate_admin(&state.config(), &headers)?;
+    Ok(Json(state.builder_pool.builders()))
+}
 
-    let app = Router::new()
-        .route("/", get(front_page))
-        .route("/programs", get(list_programs))
-        .route("/program/:program_hash", get(get_program))
-        .route("/add-program-git", post(add_program_git))
-        .route("/add-program-tar", post(add_program_tar))
-        .with_state(AppState {
-            db: db.clone(),
-            build_requests_tx,
-        })
-        .layer(cors);
+/// Check that the request has the key of the builder pool
+fn authenticate_builder(config: &Config, headers: &HeaderMap) -> Result<(), AppError> {
+    let builder_pool = config
+        .builder_pool
+        .as_ref()
+        .ok_or(AppError::BuilderPoolDisabled)?;
+    if !has_bearer_token(headers, &builder_pool.key) {
+        return Err(AppError::Unauthorized);
+    }
+    Ok(())
+}
 
-    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port)).await?;
-    let local_addr = listener.local_addr()?;
-    println!("Listening on {}", local_addr);
+/// Identifies a builder, when it registers and when it asks for a build
+#[derive(Deserialize)]
+struct BuilderName {
+    name: String,
+}
 
-    // Handle requests to build programs in serial in a separate task
-    tokio::spawn(async move {
-        handle_build_requests(build_requests_rx, db).await;
-    });
+/// Register a builder, which then asks for builds with `/builder/next`
+async fn register_builder(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(builder): Json<BuilderName>,
+) -> Result<StatusCode, AppError> {
+    authenticate_builder(&state.config(), &headers)?;
+    state.builder_pool.register(&builder.name);
+    Ok(StatusCode::NO_CONTENT)
+}
 
-    axum::serve(listener, app).await?;
-    Ok(())
+/// Give a builder the next build to carry out, waiting a while for one to be queued if there
+/// are none. No content is given if none were.
+async fn next_remote_build(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    extract::Query(builder): extract::Query<BuilderName>,
+) -> Result<Response, AppError> {
+    authenticate_builder(&state.config(), &headers)?;
+    Ok(
+        match state.builder_pool.next(&builder.name, MAX_BUILD_WAIT).await {
+            Some(build) => Json(build).into_response(),
+            None => StatusCode::NO_CONTENT.into_response(),
+        },
+    )
 }
 
-/// Add a program from a git repository
-async fn add_program_git(
+/// Get the source of a build sent to a builder, as a gzipped tarball
+async fn get_remote_build_source(
     State(state): State<AppState>,
-    git_url: String,
-) -> Result<(StatusCode, Body), AppError> {
-    let (response_tx, response_rx) = futures_mpsc::channel(1000);
-    state
-        .build_requests_tx
-        .send(BuildRequest::new_git(git_url, BuildResponder(response_tx)))
-        .await?;
+    headers: HeaderMap,
+    extract::Path(build_id): extract::Path<u64>,
+) -> Result<(HeaderMap, Vec<u8>), AppError> {
+    authenticate_builder(&state.config(), &headers)?;
+    let source = state
+        .builder_pool
+        .source(build_id)
+        .ok_or(AppError::RemoteBuildNotFound)?;
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/gzip"));
+    Ok((response_headers, source.to_vec()))
+}
 
-    Ok((StatusCode::OK, Body::from_stream(response_rx)))
+/// Pass on output and the outcome of a build from the builder carrying it out, given as lines
+/// of JSON
+async fn receive_builder_messages(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    extract::Path(build_id): extract::Path<u64>,
+    body: String,
+) -> Result<StatusCode, AppError> {
+    authenticate_builder(&state.config(), &headers)?;
+    let messages = body
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(serde_json::from_str)
+        .collect::<Result<Vec<BuilderMessage>, _>>()
+        .map_err(AppError::InvalidJson)?;
+    if !state.builder_pool.deliver(build_id, messages).await {
+        return Err(AppError::RemoteBuildNotFound);
+    }
+    Ok(StatusCode::NO_CONTENT)
 }
 
-/// Add a program given as a tar achive
-async fn add_program_tar(
+/// Queue a build in a tenant's namespace, counting it against the tenant's quota. The build is
+/// counted before it is queued, so that concurrent requests cannot overshoot the quota, and given
+/// back if it could not be queued. Responds with headers giving the quota which remains.
+async fn queue_namespace_build(
+    state: &AppState,
+    tenant: &Tenant,
+    mut query: BuildQuery,
+    headers: &HeaderMap,
+    build_request: impl FnOnce(BuildResponder) -> BuildRequest,
+) -> Result<(StatusCode, HeaderMap, BuildStream), AppError> {
+    // Builds which would be rejected should not count against the quota
+    state.check_accepting_builds()?;
+    if let Some(quota) = quota_status(&state.db, tenant)?.exceeded() {
+        return Err(AppError::QuotaExceeded(quota));
+    }
+    if !reserve_build(&state.db, tenant)? {
+        let status = quota_status(&state.db, tenant)?;
+        return Err(AppError::QuotaExceeded(
+            status.exceeded().unwrap_or("build quota"),
+        ));
+    }
+    // The tenant's own address is only used when notifications are enabled
+    if query.notify.is_none() && state.config().smtp.is_some() {
+        query.notify = tenant.email.clone();
+    }
+    let queued = queue_build(state, query, headers, build_request).await;
+    if queued.is_err() {
+        release_build(&state.db, &tenant.namespace)?;
+    }
+    let (status, body) = queued?;
+    Ok((
+        status,
+        quota_headers(&quota_status(&state.db, tenant)?),
+        body,
+    ))
+}
+
+/// Headers giving the remaining amount of each quota which has a limit
+fn quota_headers(quota: &QuotaStatus) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    let remaining = [
+        (
+            "x-quota-remaining-builds-today",
+            quota.remaining_builds_today,
+        ),
+        (
+            "x-quota-remaining-builds-total",
+            quota.remaining_builds_total,
+        ),
+        (
+            "x-quota-remaining-programs",
+            quota.remaining_programs.map(|programs| programs as u64),
+        ),
+        (
+            "x-quota-remaining-stored-bytes",
+            quota.remaining_stored_bytes,
+        ),
+    ];
+    for (name, value) in remaining {
+        if let Some(value) = value {
+            headers.insert(HeaderName::from_static(name), HeaderValue::from(value));
+        }
+    }
+    headers
+}
+
+/// Get a tenant's usage and remaining quota
+async fn get_namespace_quota(
     State(state): State<AppState>,
-    input: Bytes,
-) -> Result<(StatusCode, Body), AppError> {
-    let (response_tx, response_rx) = futures_mpsc::channel(1000);
-    state
-        .build_requests_tx
-        .send(BuildRequest::new_tar(
-            input.to_vec(),
-            BuildResponder(response_tx),
-        ))
-        .await?;
-    Ok((StatusCode::OK, Body::from_stream(response_rx)))
+    extract::Path(namespace): extract::Path<String>,
+    headers: HeaderMap,
+) -> Result<(HeaderMap, String), AppError> {
+    let quota = quota_status(
+        &state.db,
+        authenticate(&state.config(), &headers, &namespace)?,
+    )?;
+    Ok((quota_headers(&quota), serde_json::to_string(&quota)?))
 }
 
 /// Get metadata about a program with a given hash
 async fn get_program(
     State(state): State<AppState>,
     extract::Path(program_hash): extract::Path<String>,
+    extract::Query(query): extract::Query<ProgramQuery>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let hash = resolve_digest(&state.db, hex::decode(program_hash)?)?;
+    check_visible(&state, &headers, &hash)?;
+    let value = state.db.get(&hash)?.ok_or(AppError::ProgramNotFound)?;
+    let metadata: serde_json::Value = serde_json::from_slice(&value)?;
+    record_access(&state.db, &hash)?;
+    count_fetch(&state, &headers, &hash, FetchKind::Metadata);
+
+    let mut details = ProgramDetails::new(
+        H256::from_slice(&hash),
+        &metadata,
+        get_entropy_metadata(&state.db, &hash)?.unwrap_or_default(),
+        first_build_of(&state.db, &hash)?,
+        get_reproductions(&state.db, &hash)?.summary(state.config().verified_threshold()),
+        get_usage(&state.db, &hash)?,
+    );
+    // Flag other programs claiming to be the same name and version
+    let collisions =
+        name_version_collisions(&state.db, &details.name, &details.version, details.hash)?;
+    if !collisions.is_empty() {
+        details.collision_warning = Some(CollisionWarning::new(collisions));
+    }
+    details.benchmark = get_benchmark(&state.db, &hash)?;
+    details.audit = get_audit(&state.db, &hash)?;
+    if let Some(status) = get_deployment_status(&state.db, &hash)? {
+        details.deployed = Some(status.deployed);
+        details.deployer = status.deployer;
+    }
+    details.chain_verification = get_chain_verification(&state.db, &hash)?;
+    details.origin = get_origin(&state.db, &hash)?;
+    details.peer_consensus = get_peer_consensus(&state.db, &hash)?;
+    details.ipfs = get_ipfs_pins(&state.db, &hash)?;
+    details.provenance = get_provenance(&state.db, &hash)?;
+    details.digests = get_digests(&state.db, &hash)?;
+    details.optimization = get_optimization(&state.db, &hash)?;
+    // Tooling which expects another hash algorithm can be given that digest as the hash
+    if let Some(digests) = &details.digests {
+        details.hash = digests.get(state.config().primary_hash);
+    }
+    details.changelog = get_changelog(&state.db, &hash)?;
+
+    Ok(match query.format {
+        ProgramFormat::Json => Json(details).into_response(),
+        ProgramFormat::Raw => Json(raw_program_metadata(metadata, details)?).into_response(),
+    })
+}
+
+/// The form in which program metadata was given before it was typed: the package metadata
+/// with additional fields added
+fn raw_program_metadata(
+    mut metadata: serde_json::Value,
+    details: ProgramDetails,
+) -> Result<serde_json::Value, AppError> {
+    if let Some(collision_warning) = details.collision_warning {
+        metadata["collision_warning"] = serde_json::to_value(collision_warning)?;
+    }
+    if let Some(benchmark) = details.benchmark {
+        metadata["benchmark"] = serde_json::to_value(benchmark)?;
+    }
+    if let Some(audit) = details.audit {
+        metadata["audit"] = serde_json::to_value(audit)?;
+    }
+    if let Some(deployed) = details.deployed {
+        metadata["deployed"] = deployed.into();
+        metadata["deployer"] = details.deployer.into();
+    }
+    if let Some(chain_verification) = details.chain_verification {
+        metadata["chain_verification"] = serde_json::to_value(chain_verification)?;
+    }
+    if let Some(origin) = details.origin {
+        metadata["origin"] = serde_json::to_value(origin)?;
+    }
+    if let Some(peer_consensus) = details.peer_consensus {
+        metadata["peer_consensus"] = serde_json::to_value(peer_consensus)?;
+    }
+    if let Some(ipfs) = details.ipfs {
+        metadata["ipfs"] = serde_json::to_value(ipfs)?;
+    }
+    metadata["reproductions"] = serde_json::to_value(details.reproductions)?;
+    metadata["usage"] = serde_json::to_value(details.usage)?;
+    if let Some(changelog) = details.changelog {
+        metadata["changelog"] = serde_json::to_value(changelog)?;
+    }
+    if let Some(provenance) = details.provenance {
+        metadata["provenance"] = serde_json::to_value(provenance)?;
+    }
+    if let Some(digests) = details.digests {
+        metadata["digests"] = serde_json::to_value(digests)?;
+    }
+    if let Some(optimization) = details.optimization {
+        metadata["optimization"] = serde_json::to_value(optimization)?;
+    }
+    Ok(metadata)
+}
+
+/// Forms in which program metadata can be given
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum ProgramFormat {
+    #[default]
+    Json,
+    /// The stored package metadata with additional fields added, as given by earlier versions
+    Raw,
+}
+
+/// Query parameters for getting program metadata
+#[derive(Deserialize)]
+struct ProgramQuery {
+    #[serde(default)]
+    format: ProgramFormat,
+}
+
+/// Get the Entropy specific metadata of a program, which is part of its hash
+async fn get_program_entropy_metadata(
+    State(state): State<AppState>,
+    extract::Path(program_hash): extract::Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<EntropyProgramMetadata>, AppError> {
+    Ok(Json(visible_entropy_metadata(
+        &state,
+        &headers,
+        &program_hash,
+    )?))
+}
+
+/// Get the configuration schema of a program as it was given
+async fn get_program_configuration_schema(
+    State(state): State<AppState>,
+    extract::Path(program_hash): extract::Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let entropy_metadata = visible_entropy_metadata(&state, &headers, &program_hash)?;
+    schema_response(
+        entropy_metadata.configuration_schema,
+        "configuration schema",
+    )
+}
+
+/// Get the auxiliary data schema of a program as it was given
+async fn get_program_auxiliary_data_schema(
+    State(state): State<AppState>,
+    extract::Path(program_hash): extract::Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let entropy_metadata = visible_entropy_metadata(&state, &headers, &program_hash)?;
+    schema_response(
+        entropy_metadata.auxiliary_data_schema,
+        "auxiliary data schema",
+    )
+}
+
+/// Get the Entropy metadata of a program given by its hex encoded hash, if it may be seen
+fn visible_entropy_metadata(
+    state: &AppState,
+    headers: &HeaderMap,
+    program_hash: &str,
+) -> Result<EntropyProgramMetadata, AppError> {
+    let hash = hex::decode(program_hash)?;
+    check_visible(state, headers, &hash)?;
+    get_entropy_metadata(&state.db, &hash)?.ok_or(AppError::ProgramNotFound)
+}
+
+/// Give a schema from a program's metadata as it is, marked as JSON if it is valid JSON
+fn schema_response(schema: Option<String>, kind: &'static str) -> Result<Response, AppError> {
+    let schema = schema.ok_or(AppError::SchemaNotFound(kind))?;
+    let content_type = if serde_json::from_str::<serde_json::Value>(&schema).is_ok() {
+        "application/json"
+    } else {
+        "text/plain; charset=utf-8"
+    };
+    Ok((
+        [(CONTENT_TYPE, HeaderValue::from_static(content_type))],
+        schema,
+    )
+        .into_response())
+}
+
+/// Get the signed statement of how a program was built
+async fn get_program_attestation(
+    State(state): State<AppState>,
+    extract::Path(program_hash): extract::Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<Attestation>, AppError> {
+    let hash = hex::decode(program_hash)?;
+    check_visible(&state, &headers, &hash)?;
+    if !state.db.contains_key(&hash)? {
+        return Err(AppError::ProgramNotFound);
+    }
+    let attestation = get_attestation(&state.db, &hash)?.ok_or(AppError::AttestationNotFound)?;
+    Ok(Json(attestation))
+}
+
+/// Get the crates a program was built from
+async fn get_program_dependencies(
+    State(state): State<AppState>,
+    extract::Path(program_hash): extract::Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<Dependency>>, AppError> {
+    let hash = hex::decode(program_hash)?;
+    check_visible(&state, &headers, &hash)?;
+    if !state.db.contains_key(&hash)? {
+        return Err(AppError::ProgramNotFound);
+    }
+    let dependencies = get_dependencies(&state.db, &hash)?.ok_or(AppError::DependenciesNotFound)?;
+    Ok(Json(dependencies))
+}
+
+/// Get the `Cargo.lock` a program was built with
+async fn get_program_lockfile(
+    State(state): State<AppState>,
+    extract::Path(program_hash): extract::Path<String>,
+    headers: HeaderMap,
 ) -> Result<String, AppError> {
     let hash = hex::decode(program_hash)?;
-    Ok(std::str::from_utf8(&state.db.get(hash)?.ok_or(AppError::ProgramNotFound)?)?.to_string())
+    check_visible(&state, &headers, &hash)?;
+    if !state.db.contains_key(&hash)? {
+        return Err(AppError::ProgramNotFound);
+    }
+    get_lockfile(&state.db, state.config().archive.as_ref(), &hash)
+        .await?
+        .ok_or(AppError::LockfileNotFound)
 }
 
-/// Get hashes of all programs in the db
-async fn list_programs(State(state): State<AppState>) -> Result<String, AppError> {
-    let mut hashes = Vec::new();
-    for res in state.db.iter() {
-        let (key, _value) = res?;
-        hashes.push(hex::encode(key));
+/// Get a CycloneDX software bill of materials of a program
+async fn get_program_sbom(
+    State(state): State<AppState>,
+    extract::Path(program_hash): extract::Path<String>,
+    headers: HeaderMap,
+) -> Result<(HeaderMap, Json<serde_json::Value>), AppError> {
+    let hash = hex::decode(program_hash)?;
+    check_visible(&state, &headers, &hash)?;
+    if !state.db.contains_key(&hash)? {
+        return Err(AppError::ProgramNotFound);
     }
-    Ok(serde_json::to_string(&hashes)?)
+    let sbom = get_sbom(&state.db, &hash)?.ok_or(AppError::SbomNotFound)?;
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/vnd.cyclonedx+json"),
+    );
+    Ok((response_headers, Json(sbom)))
 }
 
-/// The "/" route responds with a web page showing the programs
-async fn front_page(State(state): State<AppState>) -> Html<String> {
-    let mut programs = Vec::new();
-    for res in state.db.iter() {
-        if let Ok((key, value)) = res {
-            if let Ok(package) = serde_json::from_slice::<Package>(&value) {
-                let hash = hex::encode(key);
-                programs.push(format!(
-                    "<li><a href=\"program/{}\">{} <code>{}</code></a></li>",
-                    hash, package.name, hash,
-                ));
+/// Get the commits since the previous build of a program's git repository
+async fn get_program_changelog(
+    State(state): State<AppState>,
+    extract::Path(program_hash): extract::Path<String>,
+    headers: HeaderMap,
+) -> Result<String, AppError> {
+    let hash = hex::decode(program_hash)?;
+    check_visible(&state, &headers, &hash)?;
+    if !state.db.contains_key(&hash)? {
+        return Err(AppError::ProgramNotFound);
+    }
+    let changelog = get_changelog(&state.db, &hash)?.ok_or(AppError::ChangelogNotFound)?;
+    Ok(serde_json::to_string(&changelog)?)
+}
+
+/// Get the output of the build which produced a program
+async fn get_program_build_log(
+    State(state): State<AppState>,
+    extract::Path(program_hash): extract::Path<String>,
+    headers: HeaderMap,
+) -> Result<String, AppError> {
+    let hash = hex::decode(program_hash)?;
+    check_visible(&state, &headers, &hash)?;
+    if !state.db.contains_key(&hash)? {
+        return Err(AppError::ProgramNotFound);
+    }
+    get_build_log(&state.db, state.config().archive.as_ref(), &hash)
+        .await?
+        .ok_or(AppError::BuildLogNotFound)
+}
+
+/// Download the wasm binary of a program
+async fn get_program_binary(
+    State(state): State<AppState>,
+    extract::Path(program_hash): extract::Path<String>,
+    headers: HeaderMap,
+) -> Result<(HeaderMap, Vec<u8>), AppError> {
+    let hash = resolve_digest(&state.db, hex::decode(program_hash)?)?;
+    check_visible(&state, &headers, &hash)?;
+    let binary = get_binary(&state.db, state.config().archive.as_ref(), &hash)
+        .await?
+        .ok_or(AppError::BinaryNotFound)?;
+    record_access(&state.db, &hash)?;
+    count_fetch(&state, &headers, &hash, FetchKind::Binary);
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/wasm"));
+    if let Ok(disposition) = HeaderValue::from_str(&format!(
+        "attachment; filename=\"{}.wasm\"",
+        hex::encode(&hash)
+    )) {
+        response_headers.insert(CONTENT_DISPOSITION, disposition);
+    }
+    Ok((response_headers, binary))
+}
+
+/// Get a gzipped tarball of the source code a program was built from
+async fn get_program_source(
+    State(state): State<AppState>,
+    extract::Path(program_hash): extract::Path<String>,
+    headers: HeaderMap,
+) -> Result<(HeaderMap, Vec<u8>), AppError> {
+    let hash = resolve_digest(&state.db, hex::decode(program_hash)?)?;
+    check_visible(&state, &headers, &hash)?;
+    if !state.db.contains_key(&hash)? {
+        return Err(AppError::ProgramNotFound);
+    }
+    let source = get_source(&state.db, state.config().archive.as_ref(), &hash)
+        .await?
+        .ok_or(AppError::SourceNotFound)?;
+    record_access(&state.db, &hash)?;
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/gzip"));
+    if let Ok(disposition) = HeaderValue::from_str(&format!(
+        "attachment; filename=\"{}.tar.gz\"",
+        hex::encode(&hash)
+    )) {
+        response_headers.insert(CONTENT_DISPOSITION, disposition);
+    }
+    Ok((response_headers, source))
+}
+
+/// Count a fetch of a program towards its usage, unless it appears to come from a bot. Failing
+/// to count it only loses a statistic, so does not fail the request.
+fn count_fetch(state: &AppState, headers: &HeaderMap, hash: &[u8], kind: FetchKind) {
+    let user_agent = headers
+        .get(USER_AGENT)
+        .and_then(|value| value.to_str().ok());
+    if !is_bot(user_agent) {
+        if let Err(error) = record_fetch(&state.db, hash, kind) {
+            log::error!("Could not count fetch of {}: {}", hex::encode(hash), error);
+        }
+    }
+}
+
+/// Encodings in which program info can be given
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum ProgramInfoFormat {
+    #[default]
+    Json,
+    Scale,
+}
+
+/// Query parameters for getting program info
+#[derive(Deserialize)]
+struct ProgramInfoQuery {
+    #[serde(default)]
+    format: ProgramInfoFormat,
+}
+
+/// Get a program's metadata as the arguments to give the chain's `set_program` call
+async fn get_program_info(
+    State(state): State<AppState>,
+    extract::Path(program_hash): extract::Path<String>,
+    extract::Query(query): extract::Query<ProgramInfoQuery>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let hash = hex::decode(program_hash)?;
+    check_visible(&state, &headers, &hash)?;
+    let entropy_metadata =
+        get_entropy_metadata(&state.db, &hash)?.ok_or(AppError::ProgramNotFound)?;
+    record_access(&state.db, &hash)?;
+    count_fetch(&state, &headers, &hash, FetchKind::ProgramInfo);
+    let program_info = ProgramInfo::new(H256::from_slice(&hash), entropy_metadata);
+    Ok(match query.format {
+        ProgramInfoFormat::Json => serde_json::to_string(&program_info)?.into_response(),
+        ProgramInfoFormat::Scale => (
+            [(
+                CONTENT_TYPE,
+                HeaderValue::from_static("application/octet-stream"),
+            )],
+            program_info.to_scale(),
+        )
+            .into_response(),
+    })
+}
+
+/// Get hashes of all listed programs in the db
+async fn list_programs(
+    State(state): State<AppState>,
+    extract::Query(query): extract::Query<ProgramsQuery>,
+) -> Result<(HeaderMap, String), AppError> {
+    let page = listed_programs(&state, &query)?;
+    let mut headers = HeaderMap::new();
+    if let Some(Ok(next)) = page
+        .next
+        .map(|next| HeaderValue::from_str(&hex::encode(next)))
+    {
+        headers.insert(HeaderName::from_static("x-next-cursor"), next);
+    }
+    let hashes: Vec<String> = page.hashes.iter().map(hex::encode).collect();
+    Ok((headers, serde_json::to_string(&hashes)?))
+}
+
+/// Query parameters for listing programs
+#[derive(Deserialize, Default)]
+struct ProgramsQuery {
+    /// Only give programs with this package name
+    name: Option<String>,
+    /// The most programs to give, all if not given
+    limit: Option<usize>,
+    /// Number of programs to skip
+    offset: Option<usize>,
+    /// Hex encoded hash of the last program of the previous page, as given by the
+    /// `x-next-cursor` header. Programs are ordered by hash.
+    after: Option<String>,
+}
+
+/// A page of listed programs
+#[derive(Default)]
+struct ProgramsPage {
+    /// Hashes of the programs, in order
+    hashes: Vec<Vec<u8>>,
+    /// Hash of the last program, if there are more programs after it
+    next: Option<Vec<u8>>,
+}
+
+/// Get a page of the hashes of listed programs
+fn listed_programs(state: &AppState, query: &ProgramsQuery) -> Result<ProgramsPage, AppError> {
+    let after = query.after.as_deref().map(hex::decode).transpose()?;
+    let keys: Box<dyn Iterator<Item = Result<Vec<u8>, AppError>>> = match (&query.name, &after) {
+        (Some(name), _) => Box::new(
+            programs_named(&state.db, name)?
+                .into_iter()
+                .map(|hash| Ok(hash.as_bytes().to_vec())),
+        ),
+        (None, Some(after)) => Box::new(
+            state
+                .db
+                .range::<&[u8], _>((Bound::Excluded(after.as_slice()), Bound::Unbounded))
+                .keys()
+                .map(|key| Ok(key?.to_vec())),
+        ),
+        (None, None) => Box::new(state.db.iter().keys().map(|key| Ok(key?.to_vec()))),
+    };
+
+    let mut listed = Vec::new();
+    let mut skipped = 0;
+    for key in keys {
+        let key = key?;
+        if after.as_ref().is_some_and(|after| key <= *after)
+            || !get_visibility(&state.db, &key)?.is_listed()
+        {
+            continue;
+        }
+        if skipped < query.offset.unwrap_or_default() {
+            skipped += 1;
+            continue;
+        }
+        // One more than the limit is taken to find whether there is a next page
+        if query.limit.is_some_and(|limit| listed.len() > limit) {
+            break;
+        }
+        listed.push(key);
+    }
+    let next = match query.limit {
+        Some(limit) if listed.len() > limit => {
+            listed.truncate(limit);
+            listed.last().cloned()
+        }
+        _ => None,
+    };
+    Ok(ProgramsPage {
+        hashes: listed,
+        next,
+    })
+}
+
+/// Query parameters for searching programs
+#[derive(Deserialize)]
+struct SearchQuery {
+    /// Words to look for in package names, descriptions, keywords and authors
+    q: String,
+}
+
+/// Find listed programs whose metadata contains words starting with each of the given words
+async fn search(
+    State(state): State<AppState>,
+    extract::Query(query): extract::Query<SearchQuery>,
+) -> Result<Json<Vec<SearchResult>>, AppError> {
+    let mut results = Vec::new();
+    for hash in search_programs(&state.db, &query.q)? {
+        if !get_visibility(&state.db, hash.as_ref())?.is_listed() {
+            continue;
+        }
+        if let Some(metadata) = state.db.get(hash)? {
+            let metadata: serde_json::Value = serde_json::from_slice(&metadata)?;
+            results.push(SearchResult::new(hash, &metadata));
+        }
+    }
+    Ok(Json(results))
+}
+
+/// Query parameters for listing builds
+#[derive(Deserialize)]
+struct BuildsQuery {
+    /// Only show builds with this status - either "succeeded" or "failed"
+    status: Option<String>,
+}
+
+/// Get records of previous builds, optionally filtered by status. Builds of programs which are
+/// not listed are left out.
+async fn list_builds(
+    State(state): State<AppState>,
+    extract::Query(query): extract::Query<BuildsQuery>,
+) -> Result<String, AppError> {
+    let mut records = Vec::new();
+    for record in get_build_records(&state.db, query.status.as_deref())? {
+        let listed = match record.status {
+            BuildStatus::Succeeded { hash } => {
+                get_visibility(&state.db, hash.as_ref())?.is_listed()
             }
+            // The source of a failed build for a tenant may be of a program meant to be private
+            BuildStatus::Failed { .. } => record.namespace.is_none(),
+        };
+        if listed {
+            records.push(record);
+        }
+    }
+    Ok(serde_json::to_string(&records)?)
+}
+
+/// Get the record of a single build
+async fn get_build(
+    State(state): State<AppState>,
+    extract::Path(build_id): extract::Path<u64>,
+    headers: HeaderMap,
+) -> Result<String, AppError> {
+    let record = get_build_record(&state.db, build_id)?.ok_or(AppError::BuildNotFound)?;
+    match (&record.status, &record.namespace) {
+        (BuildStatus::Succeeded { hash }, _) => {
+            check_visible(&state, &headers, hash.as_ref()).map_err(|_| AppError::BuildNotFound)?
+        }
+        (BuildStatus::Failed { .. }, Some(namespace)) => {
+            authenticate(&state.config(), &headers, namespace)
+                .map_err(|_| AppError::BuildNotFound)?;
+        }
+        (BuildStatus::Failed { .. }, None) => {}
+    }
+    Ok(serde_json::to_string(&record)?)
+}
+
+/// Query parameters for generating a program skeleton
+#[derive(Deserialize)]
+struct ScaffoldQuery {
+    /// The package name of the program
+    name: String,
+}
+
+/// Generate a skeleton program project which is ready to build, as a tar archive
+async fn scaffold_program(
+    extract::Query(query): extract::Query<ScaffoldQuery>,
+) -> Result<(HeaderMap, Vec<u8>), AppError> {
+    let template = ProgramTemplate::new(query.name)?;
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/x-tar"));
+    if let Ok(disposition) =
+        HeaderValue::from_str(&format!("attachment; filename=\"{}.tar\"", template.name))
+    {
+        headers.insert(CONTENT_DISPOSITION, disposition);
+    }
+    Ok((headers, template.to_tar()?))
+}
+
+/// Get a JSON schema describing the types used in API responses
+async fn get_api_schema() -> Result<String, AppError> {
+    Ok(serde_json::to_string(&api_schema())?)
+}
+
+/// The "/" route responds with a web page showing the programs, a page at a time
+async fn front_page(
+    State(state): State<AppState>,
+    extract::Query(mut query): extract::Query<ProgramsQuery>,
+) -> Html<String> {
+    query.limit = Some(FRONT_PAGE_SIZE);
+    query.offset = None;
+    let page = listed_programs(&state, &query).unwrap_or_default();
+    let mut programs = Vec::new();
+    for key in page.hashes {
+        let Ok(Some(value)) = state.db.get(&key) else {
+            continue;
+        };
+        if let Ok(package) = serde_json::from_slice::<Package>(&value) {
+            let hash = hex::encode(&key);
+            let collisions = name_version_collisions(
+                &state.db,
+                &package.name,
+                &package.version.to_string(),
+                H256::from_slice(&key),
+            )
+            .unwrap_or_default();
+            let warning = if collisions.is_empty() {
+                String::new()
+            } else {
+                format!(
+                    " <strong>Warning: {} other program(s) have the same name and version</strong>",
+                    collisions.len()
+                )
+            };
+            let deployed = match get_deployment_status(&state.db, &key) {
+                Ok(Some(status)) if status.deployed => {
+                    " <span title=\"Registered with the programs pallet\">on chain</span>"
+                }
+                _ => "",
+            };
+            let reproductions = get_reproductions(&state.db, &key).unwrap_or_default();
+            let badge = if reproductions.count() >= state.config().verified_threshold() {
+                format!(
+                    " <span title=\"Reproduced by {} independent builds\">&#10004; verified</span>",
+                    reproductions.count()
+                )
+            } else {
+                String::new()
+            };
+            programs.push(format!(
+                "<li><a href=\"program/{}\">{} {} <code>{}</code></a>{}{}{}</li>",
+                hash,
+                escape_html(&package.name),
+                escape_html(&package.version.to_string()),
+                hash,
+                badge,
+                deployed,
+                warning,
+            ));
         }
     }
 
+    let banner = state
+        .maintenance_message()
+        .map(|message| {
+            format!(
+                "<p><strong>Under maintenance: {}</strong></p>",
+                escape_html(&message)
+            )
+        })
+        .unwrap_or_default();
+
+    let next_page = match page.next {
+        Some(next) => {
+            let name = query
+                .name
+                .as_ref()
+                .map(|name| {
+                    let name: String =
+                        url::form_urlencoded::byte_serialize(name.as_bytes()).collect();
+                    format!("&name={}", escape_html(&name))
+                })
+                .unwrap_or_default();
+            format!(
+                "<p><a href=\"?after={}{}\">Next page</a></p>",
+                hex::encode(next),
+                name
+            )
+        }
+        None => String::new(),
+    };
+
     Html(format!(
         r#"
         <!doctype html>
@@ -137,18 +2886,92 @@ async fn front_page(State(state): State<AppState>) -> Html<String> {
             <head></head>
             <body>
                 <h1>Program metadata http service</h1>
+                {}
+                <p><a href="stats">Usage statistics</a></p>
                 <ul>{}</ul>
+                {}
             </body>
         </html>
         "#,
+        banner,
         programs.join("\n"),
+        next_page,
     ))
 }
 
+/// Escape text for use in the content or a quoted attribute of an html element
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        match character {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            character => escaped.push(character),
+        }
+    }
+    escaped
+}
+
+/// The "/stats" route responds with a web page showing how often each listed program has been
+/// fetched, most used first
+async fn stats_page(State(state): State<AppState>) -> Result<Html<String>, AppError> {
+    let mut rows = Vec::new();
+    for res in state.db.iter() {
+        let (key, value) = res?;
+        if !get_visibility(&state.db, &key)?.is_listed() {
+            continue;
+        }
+        if let Ok(package) = serde_json::from_slice::<Package>(&value) {
+            rows.push((package, hex::encode(&key), get_usage(&state.db, &key)?));
+        }
+    }
+    rows.sort_by(|(_, _, a), (_, _, b)| {
+        (b.last_30_days, b.metadata_fetches).cmp(&(a.last_30_days, a.metadata_fetches))
+    });
+    let rows: Vec<String> = rows
+        .into_iter()
+        .map(|(package, hash, usage)| {
+            format!(
+                "<tr><td><a href=\"program/{}\">{} {}</a></td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                hash,
+                escape_html(&package.name),
+                escape_html(&package.version.to_string()),
+                usage.last_7_days,
+                usage.last_30_days,
+                usage.metadata_fetches,
+                usage.program_info_fetches,
+                usage.binary_fetches,
+            )
+        })
+        .collect();
+
+    Ok(Html(format!(
+        r#"
+        <!doctype html>
+        <html>
+            <head></head>
+            <body>
+                <h1>Program usage</h1>
+                <table>
+                    <tr><th>Program</th><th>Last 7 days</th><th>Last 30 days</th><th>Metadata fetches</th><th>Program info fetches</th><th>Binary downloads</th></tr>
+                    {}
+                </table>
+            </body>
+        </html>
+        "#,
+        rows.join("\n"),
+    )))
+}
+
 #[derive(Debug, Error)]
 pub enum AppError {
     #[error("JSON error: {0}")]
     Json(#[from] serde_json::Error),
+    #[error("Cannot parse request body: {0}")]
+    InvalidJson(serde_json::Error),
     #[error("Utf8Error: {0}")]
     Utf8(#[from] std::str::Utf8Error),
     #[error("Database error {0}")]
@@ -157,13 +2980,184 @@ pub enum AppError {
     Hex(#[from] hex::FromHexError),
     #[error("Program not found")]
     ProgramNotFound,
-    #[error("Queue is full: {0}")]
-    MpscSend(#[from] tokio::sync::mpsc::error::SendError<BuildRequest>),
+    #[error("Benchmarking requires a test-message to evaluate the program with")]
+    MissingTestInput,
+    #[error("Build not found")]
+    BuildNotFound,
+    #[error("Job not found")]
+    JobNotFound,
+    #[error("Job has already finished")]
+    JobFinished,
+    #[error("Email notifications are not enabled on this server")]
+    NotificationsDisabled,
+    #[error("Notifications may only be sent to addresses configured for the namespace")]
+    NotifyAddressNotAllowed,
+    #[error("{0}")]
+    Notify(#[from] program_metadata_http_service::notify::NotifyError),
+    #[error("No binary was stored for this program")]
+    BinaryNotFound,
+    #[error("No build log was recorded for this program")]
+    BuildLogNotFound,
+    #[error("No source snapshot was kept for this program")]
+    SourceNotFound,
+    #[error("No dependencies were recorded for this program, as it was not built here")]
+    DependenciesNotFound,
+    #[error("No lockfile was recorded for this program, as it was not built here")]
+    LockfileNotFound,
+    #[error("No SBOM was made for this program, as it was not built here")]
+    SbomNotFound,
+    #[error("No changelog was recorded for this program")]
+    ChangelogNotFound,
+    #[error("No attestation was made for this program, as it was not built here")]
+    AttestationNotFound,
+    #[error("The program has no {0}")]
+    SchemaNotFound(&'static str),
+    #[error("The program was not built from a git repository, so cannot be rebuilt")]
+    ProvenanceNotFound,
+    #[error("A docker image to build with must be pinned by digest, as `name@sha256:digest`")]
+    UnpinnedImage,
+    #[error("Channel not found")]
+    ChannelNotFound,
+    #[error("{}", INVALID_GIT_ARGUMENT)]
+    InvalidGitArgument,
+    #[error("{}", UNTRUSTED_SOURCE_URL)]
+    UntrustedSourceUrl,
+    #[error("Channel names may only contain lowercase letters, digits and dashes")]
+    InvalidChannelName,
+    #[error("Channels for this program are owned by another tenant")]
+    ChannelOwnedByOtherTenant,
+    #[error("The visibility of this program can only be changed by the tenant who first built it")]
+    VisibilityOwnedByOtherTenant,
+    #[error("{0}")]
+    InvalidPackageName(#[from] InvalidPackageName),
+    #[error("Package versions must be semver versions: {0}")]
+    InvalidVersion(cargo_metadata::semver::Error),
+    #[error("Namespace not found")]
+    NamespaceNotFound,
+    #[error("Missing or incorrect API key")]
+    Unauthorized,
+    #[error("Admin routes are not enabled on this server")]
+    AdminDisabled,
+    #[error("Archiving is not enabled on this server")]
+    ArchiveDisabled,
+    #[error("IPFS is not enabled on this server")]
+    IpfsDisabled,
+    #[error("Registering programs on chain is not enabled on this server")]
+    DeploymentDisabled,
+    #[error("Remote builders are not enabled on this server")]
+    BuilderPoolDisabled,
+    #[error("Remote build not found, or no longer wanted")]
+    RemoteBuildNotFound,
+    #[error("{0}")]
+    Config(#[from] ConfigError),
+    #[error("The service is in read-only mode")]
+    ReadOnly,
+    #[error("The service is under maintenance: {0}")]
+    Maintenance(String),
+    #[error("Private programs must be built in a namespace")]
+    PrivateWithoutNamespace,
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(&'static str),
+    #[error("Build error: {0}")]
+    Build(#[from] BuildError),
+    #[error("Build queue is not accepting requests")]
+    MpscSend,
+    #[error("Build queue is full, try again later")]
+    QueueFull,
+    #[error("The request which submitted the job cannot be read")]
+    InvalidJobRequest,
+    #[error("Request body is larger than the limit of {0} bytes")]
+    BodyTooLarge(usize),
+    #[error("Cannot read request body: {0}")]
+    Body(axum::Error),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
 }
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        let body = format!("{self}").into_bytes();
-        (StatusCode::INTERNAL_SERVER_ERROR, body).into_response()
+        let status = match self {
+            AppError::ProgramNotFound
+            | AppError::NamespaceNotFound
+            | AppError::BuildNotFound
+            | AppError::JobNotFound
+            | AppError::ChannelNotFound
+            | AppError::ChangelogNotFound
+            | AppError::AttestationNotFound
+            | AppError::DependenciesNotFound
+            | AppError::LockfileNotFound
+            | AppError::SbomNotFound
+            | AppError::SchemaNotFound(_)
+            | AppError::ProvenanceNotFound
+            | AppError::BuildLogNotFound
+            | AppError::SourceNotFound
+            | AppError::BinaryNotFound
+            | AppError::RemoteBuildNotFound
+            | AppError::BuilderPoolDisabled
+            | AppError::AdminDisabled => StatusCode::NOT_FOUND,
+            AppError::Build(
+                BuildError::InvalidChainSource(_)
+                | BuildError::InvalidCid(_)
+                | BuildError::InsecureUrl
+                | BuildError::ChecksumMismatch { .. }
+                | BuildError::InvalidWasm(_)
+                | BuildError::InvalidPath
+                | BuildError::InvalidFeature(_)
+                | BuildError::UntrustedImage(_)
+                | BuildError::UnsafeArchive(_)
+                | BuildError::InvalidSchema(..)
+                | BuildError::Zip(_),
+            ) => StatusCode::BAD_REQUEST,
+            AppError::ChannelOwnedByOtherTenant
+            | AppError::VisibilityOwnedByOtherTenant
+            | AppError::NotifyAddressNotAllowed => StatusCode::FORBIDDEN,
+            AppError::NotificationsDisabled
+            | AppError::Notify(_)
+            | AppError::MissingTestInput
+            | AppError::InvalidChannelName
+            | AppError::UntrustedSourceUrl
+            | AppError::InvalidGitArgument
+            | AppError::InvalidPackageName(_)
+            | AppError::InvalidVersion(_)
+            | AppError::ArchiveDisabled
+            | AppError::IpfsDisabled
+            | AppError::DeploymentDisabled
+            | AppError::PrivateWithoutNamespace
+            | AppError::UnpinnedImage
+            | AppError::Body(_)
+            | AppError::Hex(_)
+            | AppError::InvalidJson(_)
+            | AppError::Utf8(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AppError::JobFinished => StatusCode::CONFLICT,
+            AppError::QuotaExceeded(_) | AppError::QueueFull => StatusCode::TOO_MANY_REQUESTS,
+            AppError::BodyTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::ReadOnly | AppError::Maintenance(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Build(BuildError::LowDiskSpace(..)) => StatusCode::INSUFFICIENT_STORAGE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let body = ErrorBody {
+            status: status.as_u16(),
+            error: self.to_string(),
+        };
+        if let AppError::QueueFull = self {
+            return (
+                status,
+                [(RETRY_AFTER, QUEUE_FULL_RETRY_AFTER_SECONDS.to_string())],
+                Json(body),
+            )
+                .into_response();
+        }
+        (status, Json(body)).into_response()
+    }
+}
+
+/// Give a descriptive error for bodies rejected for being over the size limit, which are
+/// otherwise rejected with a generic message
+fn describe_body_too_large(response: Response, max_request_body_size: usize) -> Response {
+    if response.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        AppError::BodyTooLarge(max_request_body_size).into_response()
+    } else {
+        response
     }
 }
@@ -0,0 +1,42 @@
+//! Namespaces group programs belonging to a particular tenant. Programs themselves are always
+//! stored in the global content-addressed db - a namespace only records which of them belong to
+//! the tenant.
+use sp_core::H256;
+
+use crate::build::Error;
+
+/// Get the sled tree listing the programs in a namespace
+fn namespace_tree(db: &sled::Db, namespace: &str) -> Result<sled::Tree, Error> {
+    Ok(db.open_tree(format!("namespace/{}", namespace))?)
+}
+
+/// Add a program to a namespace
+pub fn add_to_namespace(db: &sled::Db, namespace: &str, hash: H256) -> Result<(), Error> {
+    namespace_tree(db, namespace)?.insert(hash, &[])?;
+    Ok(())
+}
+
+/// Remove a program from a namespace, returning false if it was not there
+pub fn remove_from_namespace(db: &sled::Db, namespace: &str, hash: &[u8]) -> Result<bool, Error> {
+    Ok(namespace_tree(db, namespace)?.remove(hash)?.is_some())
+}
+
+/// Check whether a program is in a namespace
+pub fn namespace_contains(db: &sled::Db, namespace: &str, hash: &[u8]) -> Result<bool, Error> {
+    Ok(namespace_tree(db, namespace)?.contains_key(hash)?)
+}
+
+/// Get the hashes of all programs in a namespace
+pub fn namespace_programs(db: &sled::Db, namespace: &str) -> Result<Vec<H256>, Error> {
+    let mut hashes = Vec::new();
+    for res in namespace_tree(db, namespace)?.iter() {
+        let (key, _value) = res?;
+        hashes.push(H256::from_slice(&key));
+    }
+    Ok(hashes)
+}
+
+/// Get the number of programs in a namespace
+pub fn namespace_program_count(db: &sled::Db, namespace: &str) -> Result<usize, Error> {
+    Ok(namespace_tree(db, namespace)?.len())
+}
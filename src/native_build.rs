@@ -0,0 +1,74 @@
+//! Building programs directly with cargo on the host rather than in a container, for
+//! development machines and CI where running containers is awkward. Builds depend on what is
+//! installed on the host, so are not reproducible elsewhere as container builds are, and the
+//! program's build scripts run unconfined.
+use serde::Deserialize;
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::build::FeatureSelection;
+
+/// The target programs are compiled for
+pub(crate) const WASM_TARGET: &str = "wasm32-unknown-unknown";
+
+/// Directory within the program in which cargo puts its output
+const NATIVE_TARGET_DIR: &str = "native_target";
+
+/// Settings for building programs with cargo on the host
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct NativeBuildConfig {
+    /// The rustup toolchain to build with, such as `1.78.0`, which must have the
+    /// `wasm32-unknown-unknown` target installed
+    pub toolchain: String,
+    /// Whether to build with `cargo component`, as the Dockerfile of the program template does.
+    /// Otherwise `cargo build` is used.
+    #[serde(default)]
+    pub component: bool,
+}
+
+impl NativeBuildConfig {
+    /// A cargo command with the configured toolchain, run in the program's directory
+    fn cargo(&self, program_path: &Path) -> Command {
+        let mut command = Command::new("cargo");
+        command
+            .arg(format!("+{}", self.toolchain))
+            .current_dir(program_path);
+        command
+    }
+
+    /// The command which builds a program
+    pub fn build_command(&self, program_path: &Path, features: &FeatureSelection) -> Command {
+        let mut command = self.cargo(program_path);
+        if self.component {
+            command.arg("component");
+        }
+        command
+            .arg("build")
+            .arg("--release")
+            .arg(format!("--target={}", WASM_TARGET))
+            .arg(format!(
+                "--target-dir={}",
+                program_path.join(NATIVE_TARGET_DIR).display()
+            ))
+            .args(features.cargo_args());
+        command
+    }
+
+    /// The command which runs a program's tests
+    pub fn test_command(&self, program_path: &Path, features: &FeatureSelection) -> Command {
+        let mut command = self.cargo(program_path);
+        command.arg("test").args(features.cargo_args());
+        command
+    }
+
+    /// The directory in which a program's binary is found once it is built
+    pub fn binary_dir(&self, program_path: &Path) -> PathBuf {
+        program_path
+            .join(NATIVE_TARGET_DIR)
+            .join(WASM_TARGET)
+            .join("release")
+    }
+}
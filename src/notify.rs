@@ -0,0 +1,76 @@
+//! Email notifications sent to submitters when their build finishes
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, Address, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use thiserror::Error;
+
+use crate::{
+    config::SmtpConfig,
+    record::{BuildRecord, BuildStatus},
+};
+
+/// Sends emails about finished builds over SMTP
+#[derive(Clone)]
+pub struct Notifier {
+    mailer: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    /// Public URL of this service, used to link to the build record
+    public_url: String,
+}
+
+impl Notifier {
+    pub fn new(smtp: &SmtpConfig, public_url: Option<String>) -> Result<Self, NotifyError> {
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::relay(&smtp.host)?;
+        if let Some(port) = smtp.port {
+            builder = builder.port(port);
+        }
+        if let (Some(username), Some(password)) = (&smtp.username, &smtp.password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+        Ok(Self {
+            mailer: builder.build(),
+            from: smtp.from.parse()?,
+            public_url: public_url.unwrap_or_default(),
+        })
+    }
+
+    /// Send an email describing the outcome of a build
+    pub async fn notify(&self, to: &str, record: &BuildRecord) -> Result<(), NotifyError> {
+        let outcome = match &record.status {
+            BuildStatus::Succeeded { hash } => format!("succeeded with hash {}", hex::encode(hash)),
+            BuildStatus::Failed { error, .. } => format!("failed: {}", error),
+        };
+        let email = Message::builder()
+            .from(self.from.clone())
+            .to(to.parse()?)
+            .subject(format!(
+                "Build of {} {}",
+                record.source,
+                record.status.name()
+            ))
+            .body(format!(
+                "The build of {} {}\n\nThe build log is available at {}/build/{}\n",
+                record.source, outcome, self.public_url, record.id
+            ))?;
+        self.mailer.send(email).await?;
+        Ok(())
+    }
+}
+
+/// Check that an email address given by a client is valid
+pub fn validate_address(address: &str) -> Result<(), NotifyError> {
+    address.parse::<Address>()?;
+    Ok(())
+}
+
+/// An error when sending an email notification
+#[derive(Debug, Error)]
+pub enum NotifyError {
+    #[error("Invalid email address: {0}")]
+    Address(#[from] lettre::address::AddressError),
+    #[error("Cannot create email: {0}")]
+    Message(#[from] lettre::error::Error),
+    #[error("SMTP error: {0}")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+}
@@ -0,0 +1,77 @@
+//! Optimization of program binaries with `wasm-opt`, to shrink programs which will be stored on
+//! chain. As this changes the binary and so the program hash, the settings used are recorded so
+//! that the build can be reproduced.
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sp_core::H256;
+use std::{io::ErrorKind, path::Path, process::Command};
+
+use crate::build::Error;
+
+/// Name of the sled tree in which the optimization settings of programs are stored, keyed by
+/// program hash
+const OPTIMIZATION_TREE: &str = "optimizations";
+
+/// Arguments given to `wasm-opt`, optimizing aggressively for size
+const WASM_OPT_FLAGS: [&str; 1] = ["-Oz"];
+
+/// How a program binary was optimized after it was compiled
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Optimization {
+    /// The version of `wasm-opt`, as it reports it
+    pub wasm_opt_version: String,
+    /// The arguments given to `wasm-opt`
+    pub flags: Vec<String>,
+}
+
+/// Optimize a binary in place with `wasm-opt`
+pub fn optimize_binary(binary_path: &Path) -> Result<Optimization, Error> {
+    let version =
+        Command::new("wasm-opt")
+            .arg("--version")
+            .output()
+            .map_err(|error| match error.kind() {
+                ErrorKind::NotFound => {
+                    Error::Optimization("wasm-opt is not installed on this server".to_string())
+                }
+                _ => Error::Io(error),
+            })?;
+    let output = Command::new("wasm-opt")
+        .args(WASM_OPT_FLAGS)
+        .arg(binary_path)
+        .arg("-o")
+        .arg(binary_path)
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::Optimization(
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        ));
+    }
+    Ok(Optimization {
+        wasm_opt_version: String::from_utf8_lossy(&version.stdout).trim().to_string(),
+        flags: WASM_OPT_FLAGS.iter().map(|flag| flag.to_string()).collect(),
+    })
+}
+
+/// Store how a program was optimized, unless this is already known. Only builds optimized in the
+/// same way give the same hash, so there is nothing to replace.
+pub fn store_optimization(
+    db: &sled::Db,
+    hash: H256,
+    optimization: &Optimization,
+) -> Result<(), Error> {
+    let _ = db.open_tree(OPTIMIZATION_TREE)?.compare_and_swap(
+        hash,
+        None as Option<&[u8]>,
+        Some(serde_json::to_vec(optimization)?),
+    )?;
+    Ok(())
+}
+
+/// Get how a program was optimized, if it was
+pub fn get_optimization(db: &sled::Db, hash: &[u8]) -> Result<Option<Optimization>, Error> {
+    match db.open_tree(OPTIMIZATION_TREE)?.get(hash)? {
+        Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,234 @@
+//! Comparison of programs built here with independent builds by peer instances of this service.
+//! Peers which build a program from the same commit and get the same hash count as reproductions
+//! of it, so that a program's hash does not rest on trusting a single builder.
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sp_core::H256;
+
+use crate::{
+    build::{BuildResponse, Error, FeatureSelection},
+    entropy_metadata::get_entropy_metadata,
+    federation::peer_error,
+    optimize::get_optimization,
+    provenance::{get_provenance, GitProvenance},
+    record::unix_time,
+    reproduction::record_peer_reproduction,
+};
+
+/// Name of the sled tree in which the outcomes of comparing programs with peers are stored,
+/// keyed by program hash
+const PEER_COMPARISONS_TREE: &str = "peer_comparisons";
+
+/// Whether a peer got the same hash when it built a program
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerAgreement {
+    /// The peer built the program and got the same hash
+    Agrees,
+    /// The peer built the same commit and got a different hash
+    Disagrees,
+    /// The peer could not be asked, or could not build the program
+    Unavailable,
+}
+
+/// The outcome of comparing a program with a peer's build of it
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PeerComparison {
+    /// Base URL of the peer
+    pub peer: String,
+    pub agreement: PeerAgreement,
+    /// The hash the peer got, if it differs
+    #[schemars(with = "Option<String>")]
+    pub built: Option<H256>,
+    /// Why the peer could not be compared with, if it could not
+    pub error: Option<String>,
+    /// Unix time in seconds when the peer was asked
+    pub checked_at: u64,
+}
+
+/// How many peers agree on a program's hash, as given in its metadata
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PeerConsensus {
+    /// Number of peers which independently built the program with the same hash
+    pub agreeing: usize,
+    /// Number of peers which built the same commit with a different hash
+    pub disagreeing: usize,
+    pub comparisons: Vec<PeerComparison>,
+}
+
+/// The parts of a peer's program metadata which show whether it built the program itself
+#[derive(Deserialize)]
+struct PeerProgram {
+    built_at: Option<u64>,
+    origin: Option<serde_json::Value>,
+}
+
+/// Ask each peer whether it has independently built a program with the same hash, asking it to
+/// build the same commit if it has not, and store the outcomes
+pub async fn compare_with_peers(db: &sled::Db, peers: &[String], hash: H256) -> Result<(), Error> {
+    let provenance = get_provenance(db, hash.as_ref())?;
+    let optimize = get_optimization(db, hash.as_ref())?.is_some();
+    let features = get_entropy_metadata(db, hash.as_ref())?
+        .unwrap_or_default()
+        .features;
+    let client = reqwest::Client::new();
+    for peer in peers {
+        let peer = peer.trim_end_matches('/');
+        let comparison = match compare_with_peer(
+            &client,
+            peer,
+            hash,
+            provenance.as_ref(),
+            optimize,
+            &features,
+        )
+        .await
+        {
+            Ok((agreement, built)) => PeerComparison {
+                peer: peer.to_string(),
+                agreement,
+                built,
+                error: None,
+                checked_at: unix_time(),
+            },
+            Err(error) => PeerComparison {
+                peer: peer.to_string(),
+                agreement: PeerAgreement::Unavailable,
+                built: None,
+                error: Some(error.to_string()),
+                checked_at: unix_time(),
+            },
+        };
+        log::info!(
+            "Compared {:?} with {}: {:?}",
+            hash,
+            peer,
+            comparison.agreement
+        );
+        if comparison.agreement == PeerAgreement::Agrees {
+            record_peer_reproduction(db, hash, peer)?;
+        }
+        store_peer_comparison(db, hash, comparison)?;
+    }
+    Ok(())
+}
+
+/// Compare a program with one peer, giving the hash the peer built if it differs
+async fn compare_with_peer(
+    client: &reqwest::Client,
+    peer: &str,
+    hash: H256,
+    provenance: Option<&GitProvenance>,
+    optimize: bool,
+    features: &FeatureSelection,
+) -> Result<(PeerAgreement, Option<H256>), Error> {
+    // A program the peer mirrored from elsewhere or was given as a binary is not independent
+    let response = client
+        .get(format!("{}/program/{}", peer, hex::encode(hash)))
+        .send()
+        .await
+        .map_err(peer_error)?;
+    if response.status().is_success() {
+        let program: PeerProgram =
+            serde_json::from_slice(&response.bytes().await.map_err(peer_error)?)?;
+        if program.built_at.is_some() && program.origin.is_none() {
+            return Ok((PeerAgreement::Agrees, None));
+        }
+    }
+
+    let provenance = provenance.ok_or_else(|| {
+        Error::Peer("the program was not built from git, so cannot be rebuilt".to_string())
+    })?;
+    let mut query = Vec::new();
+    if let Some(path) = &provenance.path {
+        query.push(("path", path.clone()));
+    }
+    // The peer only accepts an image pinned by digest
+    if let Some(docker_image) = provenance
+        .docker_image
+        .as_ref()
+        .filter(|docker_image| docker_image.contains("@sha256:"))
+    {
+        query.push(("docker-image", docker_image.clone()));
+    }
+    if optimize {
+        query.push(("optimize", "true".to_string()));
+    }
+    let mut response = client
+        .post(format!("{}/add-program-git", peer))
+        .query(&query)
+        .body(serde_json::to_string(&serde_json::json!({
+            "url": provenance.url,
+            "ref": provenance.commit,
+            "features": features.features,
+            "no-default-features": features.no_default_features,
+        }))?)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(peer_error)?;
+
+    // The build is streamed as lines of JSON, ending with its outcome
+    let mut buffer = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(peer_error)? {
+        buffer.extend_from_slice(&chunk);
+        while let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+            match serde_json::from_slice::<BuildResponse>(&line) {
+                Ok(BuildResponse::Success { hash: built, .. }) if built == hash => {
+                    return Ok((PeerAgreement::Agrees, None));
+                }
+                Ok(BuildResponse::Success { hash: built, .. }) => {
+                    return Ok((PeerAgreement::Disagrees, Some(built)));
+                }
+                Ok(BuildResponse::Failure { error, .. }) => return Err(Error::Peer(error)),
+                _ => {}
+            }
+        }
+    }
+    Err(Error::Peer(
+        "the peer's build ended without giving a hash".to_string(),
+    ))
+}
+
+/// Store the outcome of comparing a program with a peer, replacing any earlier comparison with
+/// the same peer
+pub fn store_peer_comparison(
+    db: &sled::Db,
+    hash: H256,
+    comparison: PeerComparison,
+) -> Result<(), Error> {
+    let mut comparisons = get_peer_comparisons(db, hash.as_ref())?;
+    comparisons.retain(|existing| existing.peer != comparison.peer);
+    comparisons.push(comparison);
+    db.open_tree(PEER_COMPARISONS_TREE)?
+        .insert(hash, serde_json::to_vec(&comparisons)?)?;
+    Ok(())
+}
+
+/// Get the outcomes of comparing a program with each peer
+pub fn get_peer_comparisons(db: &sled::Db, hash: &[u8]) -> Result<Vec<PeerComparison>, Error> {
+    Ok(match db.open_tree(PEER_COMPARISONS_TREE)?.get(hash)? {
+        Some(value) => serde_json::from_slice(&value)?,
+        None => Vec::new(),
+    })
+}
+
+/// Get how many peers agree on a program's hash, if it has been compared with any
+pub fn get_peer_consensus(db: &sled::Db, hash: &[u8]) -> Result<Option<PeerConsensus>, Error> {
+    let comparisons = get_peer_comparisons(db, hash)?;
+    if comparisons.is_empty() {
+        return Ok(None);
+    }
+    let count = |agreement| {
+        comparisons
+            .iter()
+            .filter(|comparison| comparison.agreement == agreement)
+            .count()
+    };
+    Ok(Some(PeerConsensus {
+        agreeing: count(PeerAgreement::Agrees),
+        disagreeing: count(PeerAgreement::Disagrees),
+        comparisons,
+    }))
+}
@@ -0,0 +1,110 @@
+//! A program's metadata as a typed document, combining the package metadata given by `cargo
+//! metadata` with what this service knows about the program
+use cargo_metadata::Package;
+use schemars::JsonSchema;
+use serde::Serialize;
+use sp_core::H256;
+
+use crate::{
+    audit::AuditReport, build::EntropyProgramMetadata, chain_verification::ChainVerification,
+    changelog::Changelog, digest::ProgramDigests, federation::Origin, index::CollisionWarning,
+    ipfs::IpfsPins, optimize::Optimization, peer_comparison::PeerConsensus,
+    provenance::GitProvenance, record::BuildRecord, reproduction::ReproductionSummary,
+    test_run::Benchmark, usage::UsageSummary,
+};
+
+/// A program's metadata, as given by `/program/:program_hash`
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ProgramDetails {
+    #[schemars(with = "String")]
+    pub hash: H256,
+    pub name: String,
+    pub version: String,
+    /// Package metadata as given by `cargo metadata`, for programs built from source
+    #[schemars(with = "Option<serde_json::Value>")]
+    pub package: Option<Package>,
+    /// The Entropy specific metadata, which is part of the program hash
+    pub entropy_metadata: EntropyProgramMetadata,
+    /// Only present for programs which were not built from source, such as those registered as
+    /// a binary or found on chain
+    pub verification: Option<String>,
+    /// Unix time in seconds when the program was first built by this service
+    pub built_at: Option<u64>,
+    /// The commit built, for programs built from git
+    pub commit: Option<String>,
+    /// The docker image the program was first built with, pinned by digest if it could be
+    /// resolved
+    pub docker_image: Option<String>,
+    /// The repository, commit and tag the program was built from, for programs built from git
+    pub provenance: Option<GitProvenance>,
+    /// Digests made with each supported hash algorithm, for programs stored since they were added
+    pub digests: Option<ProgramDigests>,
+    /// How the binary was optimized after compiling, if it was
+    pub optimization: Option<Optimization>,
+    pub reproductions: ReproductionSummary,
+    pub usage: UsageSummary,
+    /// Present when other programs have the same name and version
+    pub collision_warning: Option<CollisionWarning>,
+    pub benchmark: Option<Benchmark>,
+    /// Known vulnerabilities in the dependencies, as found when the program was last built
+    pub audit: Option<AuditReport>,
+    /// Whether the program is registered on chain, if the chain has been checked
+    pub deployed: Option<bool>,
+    /// SS58 address of the account which registered the program on chain
+    pub deployer: Option<String>,
+    /// The outcome of rebuilding the program from its claimed source, for programs found on chain
+    pub chain_verification: Option<ChainVerification>,
+    /// The peer the program was mirrored from, if it was not added here
+    pub origin: Option<Origin>,
+    /// Whether peers which built the program independently got the same hash
+    pub peer_consensus: Option<PeerConsensus>,
+    /// CIDs of the binary and source, if they were published to IPFS
+    pub ipfs: Option<IpfsPins>,
+    /// Commits since the previous build of the same repository
+    pub changelog: Option<Changelog>,
+}
+
+impl ProgramDetails {
+    /// Get the details of a program from its stored metadata, and the record of its first build
+    /// if it was built here
+    pub fn new(
+        hash: H256,
+        metadata: &serde_json::Value,
+        entropy_metadata: EntropyProgramMetadata,
+        build: Option<BuildRecord>,
+        reproductions: ReproductionSummary,
+        usage: UsageSummary,
+    ) -> Self {
+        let (built_at, commit, docker_image) = match build {
+            Some(build) => (Some(build.timestamp), build.commit, build.docker_image),
+            None => (None, None, None),
+        };
+        Self {
+            hash,
+            name: metadata["name"].as_str().unwrap_or_default().to_string(),
+            version: metadata["version"].as_str().unwrap_or_default().to_string(),
+            // Programs imported from the chain, or mirrored without it, have no package metadata
+            package: serde_json::from_value(metadata.clone()).ok(),
+            entropy_metadata,
+            verification: metadata["verification"].as_str().map(str::to_string),
+            built_at,
+            commit,
+            docker_image,
+            provenance: None,
+            digests: None,
+            optimization: None,
+            reproductions,
+            usage,
+            collision_warning: None,
+            benchmark: None,
+            audit: None,
+            deployed: None,
+            deployer: None,
+            chain_verification: None,
+            origin: None,
+            peer_consensus: None,
+            ipfs: None,
+            changelog: None,
+        }
+    }
+}
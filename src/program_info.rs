@@ -0,0 +1,64 @@
+//! Program metadata in the form taken by the `set_program` call of the Entropy chain's programs
+//! pallet, so that clients can register a program directly from what this service gives them
+use parity_scale_codec::Encode;
+use schemars::JsonSchema;
+use serde::{Serialize, Serializer};
+use sp_core::H256;
+
+use crate::build::EntropyProgramMetadata;
+
+/// The arguments of `set_program`, with the hash of the program standing in for its bytecode
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ProgramInfo {
+    /// Hash of the program, which refers to the bytecode given when the program was built
+    #[schemars(with = "String")]
+    pub program_hash: H256,
+    #[serde(serialize_with = "serialize_bytes")]
+    #[schemars(with = "String")]
+    pub configuration_schema: Vec<u8>,
+    #[serde(serialize_with = "serialize_bytes")]
+    #[schemars(with = "String")]
+    pub auxiliary_data_schema: Vec<u8>,
+    #[serde(serialize_with = "serialize_bytes")]
+    #[schemars(with = "String")]
+    pub oracle_data_pointer: Vec<u8>,
+    pub version_number: u8,
+}
+
+impl ProgramInfo {
+    pub fn new(program_hash: H256, entropy_metadata: EntropyProgramMetadata) -> Self {
+        Self {
+            program_hash,
+            configuration_schema: entropy_metadata
+                .configuration_schema
+                .unwrap_or_default()
+                .into_bytes(),
+            auxiliary_data_schema: entropy_metadata
+                .auxiliary_data_schema
+                .unwrap_or_default()
+                .into_bytes(),
+            oracle_data_pointer: entropy_metadata
+                .oracle_data_pointer
+                .unwrap_or_default()
+                .into_bytes(),
+            version_number: entropy_metadata.version_number.unwrap_or_default(),
+        }
+    }
+
+    /// SCALE encode the arguments of `set_program` which follow the bytecode, so that a client
+    /// only needs to prepend the SCALE encoded bytecode to get the encoded call arguments
+    pub fn to_scale(&self) -> Vec<u8> {
+        (
+            &self.configuration_schema,
+            &self.auxiliary_data_schema,
+            &self.oracle_data_pointer,
+            self.version_number,
+        )
+            .encode()
+    }
+}
+
+/// Give bytes as a 0x prefixed hex string, as chain clients expect
+fn serialize_bytes<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("0x{}", hex::encode(bytes)))
+}
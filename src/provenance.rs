@@ -0,0 +1,77 @@
+//! Where the source code of programs built from git came from, so that a program hash can be
+//! traced back to the exact source it was built from
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sp_core::H256;
+use std::{path::Path, process::Command};
+
+use crate::build::Error;
+
+/// Name of the sled tree in which the git provenance of programs is stored, keyed by program
+/// hash
+const PROVENANCE_TREE: &str = "provenance";
+
+/// The git source of a program
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct GitProvenance {
+    /// The URL of the repository as it was given
+    pub url: String,
+    /// The commit built
+    pub commit: String,
+    /// A tag pointing at the commit, if there is one
+    pub tag: Option<String>,
+    /// The directory of the program within the repository, if not the root
+    pub path: Option<String>,
+    /// The docker image the program was built with, pinned by digest if it could be resolved
+    #[serde(default)]
+    pub docker_image: Option<String>,
+}
+
+/// Find a tag pointing at the commit checked out in a clone. When a single ref was fetched, git
+/// records whether it was a tag in `FETCH_HEAD`, as tags are not otherwise fetched.
+pub fn head_tag(repo_path: &Path) -> Result<Option<String>, Error> {
+    if let Ok(fetch_head) = std::fs::read_to_string(repo_path.join(".git").join("FETCH_HEAD")) {
+        // Lines look like `<commit>\t\ttag 'v0.1.0' of <url>`
+        return Ok(fetch_head.lines().find_map(|line| {
+            let tag = line.split('\t').nth(2)?.strip_prefix("tag '")?;
+            Some(tag.split_once('\'')?.0.to_string())
+        }));
+    }
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repo_path)
+        .args(["tag", "--points-at", "HEAD"])
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::GitClone(
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()
+        .map(str::to_string))
+}
+
+/// Store the git provenance of a program, unless it was already built from git. The first
+/// source a program was built from is kept, as any of them give the same hash.
+pub fn store_provenance(
+    db: &sled::Db,
+    hash: H256,
+    provenance: &GitProvenance,
+) -> Result<(), Error> {
+    let _ = db.open_tree(PROVENANCE_TREE)?.compare_and_swap(
+        hash,
+        None as Option<&[u8]>,
+        Some(serde_json::to_vec(provenance)?),
+    )?;
+    Ok(())
+}
+
+/// Get the git provenance of a program, if it was built from git
+pub fn get_provenance(db: &sled::Db, hash: &[u8]) -> Result<Option<GitProvenance>, Error> {
+    match db.open_tree(PROVENANCE_TREE)?.get(hash)? {
+        Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+        None => Ok(None),
+    }
+}
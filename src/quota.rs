@@ -0,0 +1,155 @@
+//! Tracking of build and storage usage per tenant, so that configured quotas can be enforced
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    binary::BINARY_TREE,
+    build::Error,
+    config::Tenant,
+    namespace::{namespace_program_count, namespace_programs},
+    record::unix_time,
+};
+
+/// Name of the sled tree in which usage counters are stored, keyed by namespace
+const USAGE_TREE: &str = "usage";
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Builds requested by a tenant
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct BuildCounts {
+    /// Days since the unix epoch when `today` was last reset
+    day: u64,
+    /// Builds requested today
+    today: u64,
+    /// Builds requested in total
+    total: u64,
+}
+
+/// A tenant's current usage together with the remaining amount of each quota. Remaining values
+/// are `None` when there is no limit.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct QuotaStatus {
+    pub builds_today: u64,
+    pub builds_total: u64,
+    pub programs: usize,
+    pub stored_bytes: u64,
+    pub remaining_builds_today: Option<u64>,
+    pub remaining_builds_total: Option<u64>,
+    pub remaining_programs: Option<usize>,
+    pub remaining_stored_bytes: Option<u64>,
+}
+
+impl QuotaStatus {
+    /// Get a description of the first quota which has been used up, if any
+    pub fn exceeded(&self) -> Option<&'static str> {
+        if self.remaining_builds_today == Some(0) {
+            Some("daily build quota")
+        } else if self.remaining_builds_total == Some(0) {
+            Some("total build quota")
+        } else if self.remaining_programs == Some(0) {
+            Some("program count quota")
+        } else if self.remaining_stored_bytes == Some(0) {
+            Some("storage quota")
+        } else {
+            None
+        }
+    }
+}
+
+/// Get a tenant's usage and remaining quota
+pub fn quota_status(db: &sled::Db, tenant: &Tenant) -> Result<QuotaStatus, Error> {
+    let builds = get_build_counts(db, &tenant.namespace)?;
+    let programs = namespace_program_count(db, &tenant.namespace)?;
+    let stored_bytes = namespace_stored_bytes(db, &tenant.namespace)?;
+    Ok(QuotaStatus {
+        builds_today: builds.today,
+        builds_total: builds.total,
+        programs,
+        stored_bytes,
+        remaining_builds_today: tenant
+            .max_builds_per_day
+            .map(|max| max.saturating_sub(builds.today)),
+        remaining_builds_total: tenant
+            .max_builds
+            .map(|max| max.saturating_sub(builds.total)),
+        remaining_programs: tenant.max_programs.map(|max| max.saturating_sub(programs)),
+        remaining_stored_bytes: tenant
+            .max_stored_bytes
+            .map(|max| max.saturating_sub(stored_bytes)),
+    })
+}
+
+/// Count a build requested by a tenant if it is within the tenant's build quotas, giving whether
+/// it was counted. The count is updated atomically, so concurrent requests cannot both take the
+/// last build of a quota.
+pub fn reserve_build(db: &sled::Db, tenant: &Tenant) -> Result<bool, Error> {
+    let today = unix_time() / SECONDS_PER_DAY;
+    let mut reserved = false;
+    db.open_tree(USAGE_TREE)?
+        .update_and_fetch(&tenant.namespace, |value| {
+            let mut builds = parse_build_counts(value, today);
+            reserved = tenant
+                .max_builds_per_day
+                .is_none_or(|max| builds.today < max)
+                && tenant.max_builds.is_none_or(|max| builds.total < max);
+            if !reserved {
+                return value.map(<[u8]>::to_vec);
+            }
+            builds.today += 1;
+            builds.total += 1;
+            serde_json::to_vec(&builds).ok()
+        })?;
+    Ok(reserved)
+}
+
+/// Give back a build counted by [reserve_build] which could not be queued
+pub fn release_build(db: &sled::Db, namespace: &str) -> Result<(), Error> {
+    let today = unix_time() / SECONDS_PER_DAY;
+    db.open_tree(USAGE_TREE)?
+        .update_and_fetch(namespace, |value| {
+            let mut builds = parse_build_counts(value, today);
+            builds.today = builds.today.saturating_sub(1);
+            builds.total = builds.total.saturating_sub(1);
+            serde_json::to_vec(&builds).ok()
+        })?;
+    Ok(())
+}
+
+/// Get build counts for a namespace, resetting the daily count if it is from a previous day
+fn get_build_counts(db: &sled::Db, namespace: &str) -> Result<BuildCounts, Error> {
+    let today = unix_time() / SECONDS_PER_DAY;
+    let value = db.open_tree(USAGE_TREE)?.get(namespace)?;
+    if let Some(value) = &value {
+        // Checked here so that a corrupt record is reported rather than counted from zero
+        serde_json::from_slice::<BuildCounts>(value)?;
+    }
+    Ok(parse_build_counts(value.as_deref(), today))
+}
+
+/// Build counts as stored, with the daily count reset if it is from a previous day
+fn parse_build_counts(value: Option<&[u8]>, today: u64) -> BuildCounts {
+    let mut builds: BuildCounts = value
+        .and_then(|value| serde_json::from_slice(value).ok())
+        .unwrap_or_default();
+    if builds.day != today {
+        builds.day = today;
+        builds.today = 0;
+    }
+    builds
+}
+
+/// The number of bytes of metadata and binaries stored for the programs in a namespace
+fn namespace_stored_bytes(db: &sled::Db, namespace: &str) -> Result<u64, Error> {
+    let binaries = db.open_tree(BINARY_TREE)?;
+    let mut stored_bytes = 0;
+    for hash in namespace_programs(db, namespace)? {
+        if let Some(value) = db.get(hash)? {
+            stored_bytes += value.len() as u64;
+        }
+        if let Some(binary) = binaries.get(hash)? {
+            stored_bytes += binary.len() as u64;
+        }
+    }
+    Ok(stored_bytes)
+}
@@ -0,0 +1,251 @@
+//! Persisted records of build attempts, so that builds can be inspected after the response
+//! stream has gone away
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sp_core::H256;
+use std::{
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    build::{Error, FailureKind},
+    test_suite::TestSummary,
+};
+
+/// Name of the sled tree in which build records are stored
+const BUILDS_TREE: &str = "builds";
+
+/// Name of the sled tree giving the id of the first successful build of each program, keyed by
+/// program hash
+const PROGRAM_BUILDS_TREE: &str = "program_builds";
+
+/// A record of a single build attempt
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct BuildRecord {
+    /// Identifier of this build, which is also its key in the db
+    pub id: u64,
+    /// Where the source code came from
+    pub source: BuildSource,
+    /// The outcome of the build
+    pub status: BuildStatus,
+    /// The last lines of output from the build
+    pub log_tail: Vec<String>,
+    /// Unix time in seconds when the build finished
+    pub timestamp: u64,
+    /// Results of the program's test suite, if it was run
+    #[serde(default)]
+    pub test_summary: Option<TestSummary>,
+    /// The commit built, for programs built from git
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    /// The docker image used, such as `name@sha256:...` when its digest was found
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub docker_image: Option<String>,
+    /// The namespace of the tenant the program was built for, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+}
+
+/// Describes the source code given in a build request
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BuildSource {
+    Git {
+        url: String,
+        /// The branch or tag built, if not the default branch
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        git_ref: Option<String>,
+    },
+    Tar {
+        size: usize,
+    },
+    Zip {
+        size: usize,
+    },
+    SourceUrl {
+        url: String,
+    },
+}
+
+impl fmt::Display for BuildSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildSource::Git { url, git_ref: None } => write!(f, "git repository {}", url),
+            BuildSource::Git {
+                url,
+                git_ref: Some(git_ref),
+            } => write!(f, "git repository {} at {}", url, git_ref),
+            BuildSource::Tar { size } => write!(f, "tar archive of {} bytes", size),
+            BuildSource::Zip { size } => write!(f, "zip archive of {} bytes", size),
+            BuildSource::SourceUrl { url } => write!(f, "source file {}", url),
+        }
+    }
+}
+
+/// The outcome of a build
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum BuildStatus {
+    Succeeded {
+        #[schemars(with = "String")]
+        hash: H256,
+    },
+    Failed {
+        kind: FailureKind,
+        error: String,
+    },
+}
+
+impl BuildStatus {
+    /// The name of this status as used in query strings
+    pub fn name(&self) -> &'static str {
+        match self {
+            BuildStatus::Succeeded { .. } => "succeeded",
+            BuildStatus::Failed { .. } => "failed",
+        }
+    }
+}
+
+/// Store a record of a build which has just finished
+#[allow(clippy::too_many_arguments)]
+pub fn insert_build_record(
+    db: &sled::Db,
+    source: BuildSource,
+    status: BuildStatus,
+    log_tail: Vec<String>,
+    test_summary: Option<TestSummary>,
+    commit: Option<String>,
+    docker_image: Option<String>,
+    namespace: Option<String>,
+) -> Result<BuildRecord, Error> {
+    let id = db.generate_id()?;
+    let record = BuildRecord {
+        id,
+        source,
+        status,
+        log_tail,
+        timestamp: unix_time(),
+        test_summary,
+        commit,
+        docker_image,
+        namespace,
+    };
+    // Big endian keys mean records are iterated in the order they were created
+    db.open_tree(BUILDS_TREE)?
+        .insert(id.to_be_bytes(), serde_json::to_vec(&record)?)?;
+    if let BuildStatus::Succeeded { hash } = &record.status {
+        // Later builds of the same program are reproductions, so the first is kept and the
+        // swap failing because there is already a build is not an error
+        let _ = db.open_tree(PROGRAM_BUILDS_TREE)?.compare_and_swap(
+            hash,
+            None as Option<&[u8]>,
+            Some(&id.to_be_bytes()),
+        )?;
+    }
+    Ok(record)
+}
+
+/// Get the record of the first successful build of a program
+pub fn first_build_of(db: &sled::Db, hash: &[u8]) -> Result<Option<BuildRecord>, Error> {
+    match db.open_tree(PROGRAM_BUILDS_TREE)?.get(hash)? {
+        Some(id) => match id.as_ref().try_into() {
+            Ok(id) => get_build_record(db, u64::from_be_bytes(id)),
+            Err(_) => Ok(None),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Get the record of a build with the given id
+pub fn get_build_record(db: &sled::Db, id: u64) -> Result<Option<BuildRecord>, Error> {
+    match db.open_tree(BUILDS_TREE)?.get(id.to_be_bytes())? {
+        Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+        None => Ok(None),
+    }
+}
+
+/// Get all build records, optionally only those with the given status name
+pub fn get_build_records(db: &sled::Db, status: Option<&str>) -> Result<Vec<BuildRecord>, Error> {
+    let mut records = Vec::new();
+    for res in db.open_tree(BUILDS_TREE)?.iter() {
+        let (_key, value) = res?;
+        let record: BuildRecord = serde_json::from_slice(&value)?;
+        if status.is_none_or(|status| record.status.name() == status) {
+            records.push(record);
+        }
+    }
+    Ok(records)
+}
+
+/// Check whether a program built from the given source is present in the db
+pub fn has_succeeded(db: &sled::Db, source: &BuildSource) -> Result<bool, Error> {
+    for res in db.open_tree(BUILDS_TREE)?.iter() {
+        let (_key, value) = res?;
+        let record: BuildRecord = serde_json::from_slice(&value)?;
+        if let BuildStatus::Succeeded { hash } = record.status {
+            if &record.source == source && db.contains_key(hash)? {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Get the commit of the most recent successful build of the same branch or tag of a git
+/// repository, if its commit was recorded
+pub fn last_built_commit(
+    db: &sled::Db,
+    git_url: &str,
+    git_ref: Option<&str>,
+) -> Result<Option<String>, Error> {
+    for res in db.open_tree(BUILDS_TREE)?.iter().rev() {
+        let (_key, value) = res?;
+        let record: BuildRecord = serde_json::from_slice(&value)?;
+        if let (
+            BuildSource::Git {
+                url,
+                git_ref: record_ref,
+            },
+            BuildStatus::Succeeded { .. },
+            Some(commit),
+        ) = (&record.source, &record.status, record.commit)
+        {
+            if url == git_url && record_ref.as_deref() == git_ref {
+                return Ok(Some(commit));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Get the docker images used by the most recent successful builds, most recent first and
+/// without repeats, looking at up to the given number of builds
+pub fn recent_docker_images(db: &sled::Db, builds: usize) -> Result<Vec<String>, Error> {
+    let mut images: Vec<String> = Vec::new();
+    let mut seen = 0;
+    for res in db.open_tree(BUILDS_TREE)?.iter().rev() {
+        if seen == builds {
+            break;
+        }
+        let (_key, value) = res?;
+        let record: BuildRecord = serde_json::from_slice(&value)?;
+        if let (BuildStatus::Succeeded { .. }, Some(docker_image)) =
+            (&record.status, record.docker_image)
+        {
+            seen += 1;
+            if !images.contains(&docker_image) {
+                images.push(docker_image);
+            }
+        }
+    }
+    Ok(images)
+}
+
+/// The current time in seconds since the unix epoch
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
@@ -0,0 +1,242 @@
+//! Running as a builder for another instance of this service, which sends it programs to build.
+//! Builders ask the service for builds rather than being sent them, so need not be reachable
+//! from it.
+use futures::{channel::mpsc as futures_mpsc, StreamExt};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{
+    build::{build_for_pool, run_blocking, BuildOptions, BuildResponder, BuildResponse, Error},
+    builder_pool::{BuilderMessage, BuilderPoolConfig, RemoteBuild},
+    config::Config,
+    disk::{build_temp_dir, PruneTrigger},
+    unpack::{unpack_tar, SpooledArchive},
+};
+
+/// How long to wait before trying again when the service cannot be reached
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// How often to tell the service that a build giving no output is still running
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Requests to the service this process builds for
+#[derive(Clone)]
+struct PoolClient {
+    client: reqwest::Client,
+    /// Base URL of the service
+    url: String,
+    key: String,
+    /// The name this builder registered with
+    name: String,
+}
+
+impl PoolClient {
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        self.client
+            .request(method, format!("{}{}", self.url, path))
+            .bearer_auth(&self.key)
+    }
+
+    /// Register with the service, which also tells it that anything this builder was building
+    /// before it restarted has been abandoned
+    async fn register(&self) -> Result<(), Error> {
+        self.request(reqwest::Method::POST, "/builder/register")
+            .header("content-type", "application/json")
+            .body(serde_json::to_string(
+                &serde_json::json!({ "name": self.name }),
+            )?)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(pool_error)?;
+        Ok(())
+    }
+
+    /// Ask for a build, which the service waits a while to give if it has none
+    async fn next_build(&self) -> Result<Option<RemoteBuild>, Error> {
+        let response = self
+            .request(reqwest::Method::GET, "/builder/next")
+            .query(&[("name", &self.name)])
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(pool_error)?;
+        if response.status() == reqwest::StatusCode::NO_CONTENT {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_slice(
+            &response.bytes().await.map_err(pool_error)?,
+        )?))
+    }
+
+    /// Get the gzipped tarball of a build's source
+    async fn source(&self, id: u64) -> Result<Vec<u8>, Error> {
+        let response = self
+            .request(
+                reqwest::Method::GET,
+                &format!("/builder/build/{}/source", id),
+            )
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(pool_error)?;
+        Ok(response.bytes().await.map_err(pool_error)?.to_vec())
+    }
+
+    /// Send messages about a build, as lines of JSON. This fails if the service no longer wants
+    /// the build.
+    async fn send(&self, id: u64, messages: &[BuilderMessage]) -> Result<(), Error> {
+        let mut body = String::new();
+        for message in messages {
+            body.push_str(&serde_json::to_string(message)?);
+            body.push('\n');
+        }
+        self.request(reqwest::Method::POST, &format!("/builder/build/{}", id))
+            .body(body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(pool_error)?;
+        Ok(())
+    }
+}
+
+fn pool_error(error: reqwest::Error) -> Error {
+    Error::PoolConnection(error.to_string())
+}
+
+/// Register with the service at the given URL and carry out the builds it gives, one at a time,
+/// until the process is stopped. Builds use this process's own build settings.
+pub async fn run_builder(url: String, pool_config: BuilderPoolConfig, config: Config) {
+    let pool = PoolClient {
+        client: reqwest::Client::new(),
+        url: url.trim_end_matches('/').to_string(),
+        key: pool_config.key,
+        name: pool_config
+            .name
+            .unwrap_or_else(|| format!("builder-{:08x}", rand::random::<u32>())),
+    };
+    while let Err(error) = pool.register().await {
+        log::error!("Could not register with {}: {}", pool.url, error);
+        tokio::time::sleep(RETRY_DELAY).await;
+    }
+    log::info!("Registered with {} as {}", pool.url, pool.name);
+
+    let prune_trigger = PruneTrigger::default();
+
+    loop {
+        let build = match pool.next_build().await {
+            Ok(Some(build)) => build,
+            Ok(None) => continue,
+            Err(error) => {
+                log::error!("Could not ask {} for a build: {}", pool.url, error);
+                tokio::time::sleep(RETRY_DELAY).await;
+                continue;
+            }
+        };
+        let id = build.id;
+        log::info!("Building {} for {}", id, pool.url);
+        if let Err(error) = carry_out_build(&pool, &config, build).await {
+            log::error!("Could not send the outcome of build {}: {}", id, error);
+        }
+        // Builders keep no db, so only the configured images are kept when pruning
+        prune_trigger.build_finished(&config, None);
+    }
+}
+
+/// Fetch the source of a build, build it and send the outcome to the service
+async fn carry_out_build(
+    pool: &PoolClient,
+    config: &Config,
+    build: RemoteBuild,
+) -> Result<(), Error> {
+    let options = BuildOptions {
+        features: build.features.clone(),
+        resource_limits: config.build_limits.clone(),
+        allowed_docker_images: config.allowed_docker_images.clone(),
+        docker: config.docker.clone(),
+        container_engine: config.container_engine,
+        native_build: config.native_build.clone(),
+        kubernetes: config.kubernetes.clone(),
+        cargo_cache: config.cargo_cache.clone(),
+        sccache: config.sccache.clone(),
+        ..Default::default()
+    };
+
+    let source_dir = build_temp_dir()?;
+    let unpacked = match pool.source(build.id).await {
+        Ok(source) => {
+            let destination = source_dir.path().to_path_buf();
+            let max_size = options.resource_limits.max_unpacked_size();
+            run_blocking(move || {
+                unpack_tar(
+                    &SpooledArchive::from_bytes(&source)?,
+                    &destination,
+                    max_size,
+                )
+            })
+            .await
+        }
+        Err(error) => Err(error),
+    };
+    if let Err(error) = unpacked {
+        return pool.send(build.id, &[BuilderMessage::failed(&error)]).await;
+    }
+
+    let (sender, receiver) = futures_mpsc::channel(1000);
+    let responder = BuildResponder::new(sender);
+    let forwarder = tokio::spawn(forward_output(
+        pool.clone(),
+        build.id,
+        receiver,
+        responder.cancellation_flag(),
+    ));
+    let messages = build_for_pool(&build, source_dir.path(), &options, responder).await;
+    // The output is all sent once the responder is dropped, before the outcome is
+    if let Err(error) = forwarder.await {
+        log::error!("Stopped sending output of build {}: {}", build.id, error);
+    }
+    pool.send(build.id, &messages).await
+}
+
+/// Send a build's output to the service as it arrives, with a heartbeat when there has been none
+/// for a while, until the build ends. The build is cancelled if the service no longer wants it.
+async fn forward_output(
+    pool: PoolClient,
+    id: u64,
+    mut receiver: futures_mpsc::Receiver<Result<String, Error>>,
+    cancelled: Arc<AtomicBool>,
+) {
+    loop {
+        let mut messages = Vec::new();
+        match tokio::time::timeout(HEARTBEAT_INTERVAL, receiver.next()).await {
+            Ok(Some(response)) => messages.extend(output_message(response)),
+            Ok(None) => return,
+            Err(_) => messages.push(BuilderMessage::Heartbeat),
+        }
+        // Whatever else has arrived is sent along with it
+        while let Ok(response) = receiver.try_recv() {
+            messages.extend(output_message(response));
+        }
+        if messages.is_empty() || cancelled.load(Ordering::Relaxed) {
+            continue;
+        }
+        if let Err(error) = pool.send(id, &messages).await {
+            log::warn!("Abandoning build {}: {}", id, error);
+            cancelled.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A line of build output as a message to the service
+fn output_message(response: Result<String, Error>) -> Option<BuilderMessage> {
+    let line = response.ok()?;
+    serde_json::from_str::<BuildResponse>(&line)
+        .ok()
+        .map(BuilderMessage::Response)
+}
@@ -0,0 +1,103 @@
+//! Tracking of independent rebuilds which reproduced a program's hash. Each reproduction is
+//! evidence that the binary really does correspond to its source code.
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sp_core::H256;
+
+use crate::build::Error;
+
+/// Name of the sled tree in which reproductions are stored, keyed by program hash
+const REPRODUCTIONS_TREE: &str = "reproductions";
+
+/// Who is recorded as asking for a rebuild outside any namespace. Such rebuilds count as one
+/// reproduction between them, as anyone can ask for them.
+pub const ANONYMOUS_REQUESTER: &str = "anonymous";
+
+/// Rebuilds which gave the same hash as the original build
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Reproductions {
+    /// Who asked this service to verify the program by rebuilding it and got the same hash: the
+    /// namespace of each tenant, or [ANONYMOUS_REQUESTER]. Each is only counted once, so that
+    /// one requester cannot make a program verified by asking again.
+    #[serde(default)]
+    pub rebuilt_by: Vec<String>,
+    /// Other builder instances which attested to getting the same hash
+    pub peers: Vec<String>,
+}
+
+impl Reproductions {
+    /// The total number of independent rebuilds which reproduced the hash
+    pub fn count(&self) -> u64 {
+        (self.rebuilt_by.len() + self.peers.len()) as u64
+    }
+
+    /// Summarise for inclusion in program metadata
+    pub fn summary(self, verified_threshold: u64) -> ReproductionSummary {
+        ReproductionSummary {
+            count: self.count(),
+            local: self.rebuilt_by.len() as u64,
+            verified: self.count() >= verified_threshold,
+            peers: self.peers,
+        }
+    }
+}
+
+/// Reproductions of a program as given in its metadata
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ReproductionSummary {
+    /// Total number of independent rebuilds which reproduced the hash
+    pub count: u64,
+    /// Number of requesters for whom this service rebuilt the program and got the same hash
+    pub local: u64,
+    /// Other builder instances which attested to getting the same hash
+    pub peers: Vec<String>,
+    /// Whether the count has reached the threshold for the program to be considered verified
+    pub verified: bool,
+}
+
+/// Get the reproductions of a program
+pub fn get_reproductions(db: &sled::Db, hash: &[u8]) -> Result<Reproductions, Error> {
+    Ok(match db.open_tree(REPRODUCTIONS_TREE)?.get(hash)? {
+        Some(value) => serde_json::from_slice(&value)?,
+        None => Reproductions::default(),
+    })
+}
+
+/// Record that a rebuild verifying a program, asked for by the given requester, reproduced its
+/// hash. Each requester is only counted once.
+pub fn record_local_reproduction(db: &sled::Db, hash: H256, requester: &str) -> Result<(), Error> {
+    add_reproduction(
+        db,
+        hash,
+        |reproductions| &mut reproductions.rebuilt_by,
+        requester,
+    )
+}
+
+/// Record that another builder instance reproduced a program's hash. Each peer is only counted
+/// once.
+pub fn record_peer_reproduction(db: &sled::Db, hash: H256, peer: &str) -> Result<(), Error> {
+    add_reproduction(db, hash, |reproductions| &mut reproductions.peers, peer)
+}
+
+/// Add a name to a list of the reproductions of a program if it is not already there. This is
+/// done atomically, so that concurrent rebuilds are all counted.
+fn add_reproduction(
+    db: &sled::Db,
+    hash: H256,
+    list: impl Fn(&mut Reproductions) -> &mut Vec<String>,
+    name: &str,
+) -> Result<(), Error> {
+    db.open_tree(REPRODUCTIONS_TREE)?
+        .update_and_fetch(hash, |value| {
+            let mut reproductions: Reproductions = value
+                .and_then(|value| serde_json::from_slice(value).ok())
+                .unwrap_or_default();
+            let names = list(&mut reproductions);
+            if !names.iter().any(|existing| existing == name) {
+                names.push(name.to_string());
+            }
+            serde_json::to_vec(&reproductions).ok()
+        })?;
+    Ok(())
+}
@@ -0,0 +1,173 @@
+//! Watching the memory and CPU a build's containers use while they run. Containers are put in a
+//! cgroup of their own with `--cgroup-parent`, whose usage is sampled from the cgroup v2
+//! filesystem and streamed to the client. A build nearing its memory limit is stopped with a
+//! descriptive error, rather than leaving a small host to thrash until the kernel kills it.
+use std::{
+    path::PathBuf,
+    sync::mpsc::{self, RecvTimeoutError},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crate::build::{BuildResponder, BuildResponse, Error, ResourceLimits};
+
+/// Where the cgroup v2 filesystem is mounted, if not configured
+const DEFAULT_CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Percentage of its memory limit at which a build is stopped, if not configured
+const DEFAULT_STOP_AT_PERCENT: u64 = 95;
+
+/// Samples the usage of a build's cgroup
+pub struct ResourceMonitor {
+    /// Name of the cgroup given as the parent of the build's containers. Without dashes, it is
+    /// at the top of the hierarchy with both the systemd and cgroupfs drivers.
+    cgroup_name: String,
+    cgroup_path: PathBuf,
+    interval: Duration,
+    memory_limit: Option<u64>,
+    /// Memory in bytes at which the build is stopped
+    stop_at: Option<u64>,
+}
+
+/// A running monitor, which stops when finished or dropped
+pub struct MonitorHandle {
+    stop: Option<mpsc::Sender<()>>,
+    /// Gives the memory used when the build was stopped, if it was
+    thread: Option<JoinHandle<Option<u64>>>,
+    cgroup_path: PathBuf,
+    memory_limit: Option<u64>,
+}
+
+impl ResourceMonitor {
+    /// A monitor for one build, if monitoring is configured
+    pub fn new(resource_limits: &ResourceLimits) -> Option<Self> {
+        let interval = Duration::from_secs(resource_limits.monitor_interval_seconds?.max(1));
+        let cgroup_name = format!("programbuild{:016x}.slice", rand::random::<u64>());
+        let memory_limit = resource_limits.memory.as_deref().and_then(memory_bytes);
+        let stop_at_percent = resource_limits
+            .stop_at_percent
+            .unwrap_or(DEFAULT_STOP_AT_PERCENT);
+        Some(Self {
+            cgroup_path: resource_limits
+                .cgroup_root
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_CGROUP_ROOT))
+                .join(&cgroup_name),
+            cgroup_name,
+            interval,
+            memory_limit,
+            stop_at: memory_limit.map(|limit| limit / 100 * stop_at_percent),
+        })
+    }
+
+    /// Argument to `build` or `run` which puts the build's containers in the monitored cgroup
+    pub fn cgroup_parent_arg(&self) -> String {
+        format!("--cgroup-parent={}", self.cgroup_name)
+    }
+
+    /// Start sampling in the background, sending each sample to the client
+    pub fn start(&self, mut responder: BuildResponder) -> MonitorHandle {
+        let (stop, stopped) = mpsc::channel();
+        let cgroup_path = self.cgroup_path.clone();
+        let interval = self.interval;
+        let memory_limit = self.memory_limit;
+        let stop_at = self.stop_at;
+        let thread = std::thread::spawn(move || {
+            let mut last_cpu: Option<(u64, Instant)> = None;
+            loop {
+                match stopped.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => return None,
+                }
+                // The cgroup only exists while a container is running in it
+                let Some(memory) = read_number(&cgroup_path.join("memory.current")) else {
+                    continue;
+                };
+                let cpu_usage = cpu_usage_micros(&cgroup_path);
+                let now = Instant::now();
+                let cpu_percent = match (cpu_usage, last_cpu) {
+                    (Some(usage), Some((last_usage, last_time))) => {
+                        let elapsed = now.duration_since(last_time).as_micros().max(1) as f64;
+                        usage.saturating_sub(last_usage) as f64 / elapsed * 100.0
+                    }
+                    _ => 0.0,
+                };
+                last_cpu = cpu_usage.map(|usage| (usage, now));
+                let _ = responder.try_send(BuildResponse::Resource {
+                    memory,
+                    memory_limit,
+                    cpu_percent,
+                });
+                if stop_at.is_some_and(|stop_at| memory >= stop_at) {
+                    if let Err(error) = std::fs::write(cgroup_path.join("cgroup.kill"), "1") {
+                        log::error!("Could not stop build nearing its memory limit: {}", error);
+                        continue;
+                    }
+                    return Some(memory);
+                }
+            }
+        });
+        MonitorHandle {
+            stop: Some(stop),
+            thread: Some(thread),
+            cgroup_path: self.cgroup_path.clone(),
+            memory_limit: self.memory_limit,
+        }
+    }
+}
+
+impl MonitorHandle {
+    /// Stop sampling, giving an error if the build was stopped for nearing its memory limit
+    pub fn finish(mut self) -> Result<(), Error> {
+        match self.stop_thread() {
+            Some(memory) => Err(Error::MemoryLimitNeared(
+                memory / (1024 * 1024),
+                self.memory_limit.unwrap_or_default() / (1024 * 1024),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn stop_thread(&mut self) -> Option<u64> {
+        self.stop.take();
+        let stopped_at = self.thread.take()?.join().ok().flatten();
+        // The cgroupfs driver leaves the empty cgroup behind, and systemd removes it itself
+        let _ = std::fs::remove_dir(&self.cgroup_path);
+        stopped_at
+    }
+}
+
+impl Drop for MonitorHandle {
+    fn drop(&mut self) {
+        self.stop_thread();
+    }
+}
+
+fn read_number(path: &std::path::Path) -> Option<u64> {
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// CPU time used by the cgroup so far, in microseconds
+fn cpu_usage_micros(cgroup_path: &std::path::Path) -> Option<u64> {
+    std::fs::read_to_string(cgroup_path.join("cpu.stat"))
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("usage_usec "))?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Convert a memory size in docker's notation, such as `900m`, to bytes. Docker's units are
+/// powers of 1024.
+fn memory_bytes(memory: &str) -> Option<u64> {
+    let memory = memory.trim().to_ascii_lowercase();
+    let (number, multiplier) = match memory.chars().last()? {
+        'b' => (&memory[..memory.len() - 1], 1),
+        'k' => (&memory[..memory.len() - 1], 1024),
+        'm' => (&memory[..memory.len() - 1], 1024 * 1024),
+        'g' => (&memory[..memory.len() - 1], 1024 * 1024 * 1024),
+        _ => (&memory[..], 1),
+    };
+    number.parse::<u64>().ok().map(|number| number * multiplier)
+}
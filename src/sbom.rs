@@ -0,0 +1,157 @@
+//! Software bills of materials of programs in the CycloneDX format, so that organizations
+//! deploying programs can feed what went into them into their supply chain tooling
+use cargo_metadata::{Metadata, Package};
+use serde_json::{json, Value};
+use sp_core::H256;
+use std::collections::HashMap;
+
+use crate::{build::Error, dependencies::Dependency, digest::ProgramDigests};
+
+/// Name of the sled tree in which SBOMs are stored as CycloneDX JSON, keyed by program hash
+const SBOM_TREE: &str = "sboms";
+
+/// The version of the CycloneDX specification SBOMs are given in
+const CYCLONEDX_SPEC_VERSION: &str = "1.5";
+
+/// Make a CycloneDX SBOM of a program from its package metadata and resolved dependencies. The
+/// root component is the program itself, with the digests of its binary.
+pub fn generate_sbom(
+    metadata: &Metadata,
+    dependencies: &[Dependency],
+    digests: &ProgramDigests,
+) -> Result<Value, Error> {
+    let root_package = metadata
+        .root_package()
+        .ok_or(Error::MetadataMissingRootPackage)?;
+    let packages: HashMap<(&str, String), &Package> = metadata
+        .packages
+        .iter()
+        .map(|package| {
+            (
+                (package.name.as_str(), package.version.to_string()),
+                package,
+            )
+        })
+        .collect();
+
+    let mut root_component = component(
+        &root_package.name,
+        &root_package.version.to_string(),
+        Some(root_package),
+    );
+    root_component["type"] = json!("application");
+    root_component["hashes"] = json!([
+        { "alg": "BLAKE2b-256", "content": hex::encode(digests.blake2_256) },
+        { "alg": "SHA-256", "content": hex::encode(digests.sha256) },
+    ]);
+    let root_ref = purl(&root_package.name, &root_package.version.to_string());
+
+    let components: Vec<Value> = dependencies
+        .iter()
+        .filter(|dependency| purl(&dependency.name, &dependency.version) != root_ref)
+        .map(|dependency| {
+            let package = packages.get(&(dependency.name.as_str(), dependency.version.clone()));
+            component(&dependency.name, &dependency.version, package.copied())
+        })
+        .collect();
+    let dependency_graph: Vec<Value> = dependencies
+        .iter()
+        .map(|dependency| {
+            let depends_on: Vec<String> = dependency
+                .dependencies
+                .iter()
+                .filter_map(|label| {
+                    let (name, version) = label.split_once(' ')?;
+                    Some(purl(name, version))
+                })
+                .collect();
+            json!({
+                "ref": purl(&dependency.name, &dependency.version),
+                "dependsOn": depends_on,
+            })
+        })
+        .collect();
+
+    Ok(json!({
+        "bomFormat": "CycloneDX",
+        "specVersion": CYCLONEDX_SPEC_VERSION,
+        "serialNumber": serial_number(),
+        "version": 1,
+        "metadata": {
+            "tools": [{
+                "name": env!("CARGO_PKG_NAME"),
+                "version": env!("CARGO_PKG_VERSION"),
+            }],
+            "component": root_component,
+        },
+        "components": components,
+        "dependencies": dependency_graph,
+    }))
+}
+
+/// The package URL of a crate, which is also used to refer to it within the SBOM
+fn purl(name: &str, version: &str) -> String {
+    format!("pkg:cargo/{}@{}", name, version)
+}
+
+/// A CycloneDX component for a crate, with what is known about it from its package metadata
+fn component(name: &str, version: &str, package: Option<&Package>) -> Value {
+    let mut component = json!({
+        "type": "library",
+        "bom-ref": purl(name, version),
+        "name": name,
+        "version": version,
+        "purl": purl(name, version),
+    });
+    let Some(package) = package else {
+        return component;
+    };
+    if let Some(description) = &package.description {
+        component["description"] = json!(description);
+    }
+    if let Some(license) = &package.license {
+        component["licenses"] = json!([{ "expression": license }]);
+    }
+    let mut external_references = Vec::new();
+    if let Some(repository) = &package.repository {
+        external_references.push(json!({ "type": "vcs", "url": repository }));
+    }
+    if let Some(homepage) = &package.homepage {
+        external_references.push(json!({ "type": "website", "url": homepage }));
+    }
+    if !external_references.is_empty() {
+        component["externalReferences"] = json!(external_references);
+    }
+    component
+}
+
+/// A random version 4 UUID identifying an SBOM, as a URN
+fn serial_number() -> String {
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = hex::encode(bytes);
+    format!(
+        "urn:uuid:{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+/// Store the SBOM of a program, replacing that of any earlier build
+pub fn store_sbom(db: &sled::Db, hash: H256, sbom: &Value) -> Result<(), Error> {
+    db.open_tree(SBOM_TREE)?
+        .insert(hash, serde_json::to_vec(sbom)?)?;
+    Ok(())
+}
+
+/// Get the SBOM of a program, if it was built here
+pub fn get_sbom(db: &sled::Db, hash: &[u8]) -> Result<Option<Value>, Error> {
+    match db.open_tree(SBOM_TREE)?.get(hash)? {
+        Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+        None => Ok(None),
+    }
+}
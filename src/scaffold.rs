@@ -0,0 +1,284 @@
+//! Generation of skeleton program projects, so that new program authors have something which
+//! already builds to start from
+use std::collections::BTreeMap;
+use tar::{Builder, Header};
+
+use crate::build::{Error, DEFAULT_DOCKER_IMAGE};
+
+/// The package name given to programs submitted without one
+pub const DEFAULT_PROGRAM_NAME: &str = "program";
+
+/// The largest single source file which will be fetched from a URL
+const MAX_SOURCE_FILE_SIZE: usize = 1024 * 1024;
+
+/// The hosts single source files may be fetched from, including those GitHub redirects gists to,
+/// so that clients cannot have the server make requests within its own network
+const SOURCE_FILE_HOSTS: [&str; 3] = [
+    "gist.github.com",
+    "gist.githubusercontent.com",
+    "raw.githubusercontent.com",
+];
+
+/// The most redirects followed when fetching a single source file
+const MAX_SOURCE_FILE_REDIRECTS: usize = 5;
+
+/// The version of the Entropy programs core library which generated programs depend on
+const TEMPLATE_CORE_VERSION: &str = "v0.10.0";
+
+/// Source code of a program which only allows signing non-empty messages
+const TEMPLATE_LIB_RS: &str = r#"#![no_std]
+
+extern crate alloc;
+
+use alloc::{string::ToString, vec::Vec};
+
+use entropy_programs_core::{bindgen::Error, bindgen::*, export_program, prelude::*};
+
+register_custom_getrandom!(always_fail);
+
+pub struct ExampleProgram;
+
+impl Program for ExampleProgram {
+    fn evaluate(
+        signature_request: SignatureRequest,
+        _config: Option<Vec<u8>>,
+        _oracle_data: Option<Vec<u8>>,
+    ) -> Result<(), Error> {
+        if signature_request.message.is_empty() {
+            return Err(Error::Evaluation("Cannot sign an empty message".to_string()));
+        }
+        Ok(())
+    }
+
+    fn custom_hash(_data: Vec<u8>) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+export_program!(ExampleProgram);
+"#;
+
+/// Builds the program in docker, putting the binary in the output directory
+const TEMPLATE_DOCKERFILE: &str = r#"# To build this program, and put the .wasm binary in the directory 'binary-dir':
+# docker build --output=binary-dir .
+ARG IMAGE=peg997/build-entropy-programs:version0.1
+FROM ${IMAGE} AS base
+# Cargo features chosen for the build, which are given by the build service when requested
+ARG FEATURES
+ARG NO_DEFAULT_FEATURES
+# Settings for compiling with sccache, which are given by the build service if it uses sccache
+ARG RUSTC_WRAPPER
+ARG SCCACHE_BUCKET
+ARG SCCACHE_REGION
+ARG SCCACHE_ENDPOINT
+ARG SCCACHE_S3_KEY_PREFIX
+ARG SCCACHE_CACHE_SIZE
+ARG SCCACHE_DIR
+
+WORKDIR /usr/src/programs
+COPY . .
+
+# The cargo registry and target directory are cache mounts kept between builds. The target
+# directory is locked for the build, so that only this program's binary is copied out of it.
+RUN --mount=type=cache,id=cargo-registry,target=/usr/local/cargo/registry \
+    --mount=type=cache,id=cargo-target,target=/usr/src/programs/target,sharing=locked \
+    --mount=type=cache,id=sccache,target=/var/cache/sccache \
+    --mount=type=secret,id=aws-credentials,target=/root/.aws/credentials \
+    rm -f target/wasm32-unknown-unknown/release/*.wasm && \
+    cargo component build --release --target wasm32-unknown-unknown ${FEATURES:+--features "$FEATURES"} $NO_DEFAULT_FEATURES && \
+    mkdir -p /output && cp target/wasm32-unknown-unknown/release/*.wasm /output/
+
+FROM scratch AS binary
+COPY --from=base /output/*.wasm /
+"#;
+
+const TEMPLATE_GITIGNORE: &str = "/target\n/binary-dir\n";
+
+/// A cargo project for an Entropy program, generated from a template
+#[derive(Debug, Clone)]
+pub struct ProgramTemplate {
+    /// The package name
+    pub name: String,
+    /// Contents of `src/lib.rs`
+    pub source: String,
+    /// Extra dependencies, given as the name and the right hand side of the line in Cargo.toml
+    pub dependencies: BTreeMap<String, String>,
+}
+
+/// A package name which cargo would not accept, and which could add to Cargo.toml
+#[derive(Debug, thiserror::Error)]
+#[error("Package names may only contain letters, digits, dashes and underscores, and must start with a letter")]
+pub struct InvalidPackageName;
+
+impl Default for ProgramTemplate {
+    /// A template with the default package name and program source code
+    fn default() -> Self {
+        Self {
+            name: DEFAULT_PROGRAM_NAME.to_string(),
+            source: TEMPLATE_LIB_RS.to_string(),
+            dependencies: BTreeMap::new(),
+        }
+    }
+}
+
+impl ProgramTemplate {
+    /// A template with the default program source code
+    pub fn new(name: String) -> Result<Self, InvalidPackageName> {
+        if !is_valid_package_name(&name) {
+            return Err(InvalidPackageName);
+        }
+        Ok(Self {
+            name,
+            ..Default::default()
+        })
+    }
+
+    /// Use the given source code for `src/lib.rs` rather than the example program
+    pub fn with_source(mut self, source: String) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Add a dependency from crates.io with the given version requirement
+    pub fn add_dependency(
+        &mut self,
+        name: String,
+        version: &str,
+    ) -> Result<(), InvalidPackageName> {
+        if !is_valid_package_name(&name) {
+            return Err(InvalidPackageName);
+        }
+        // Quoting as a toml string means the requirement cannot add anything else to Cargo.toml
+        let requirement = toml::Value::String(version.to_string()).to_string();
+        self.dependencies.insert(name, requirement);
+        Ok(())
+    }
+
+    /// Generate the files of the project, as paths relative to the project root and contents
+    pub fn files(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("Cargo.toml", self.cargo_toml()),
+            ("Dockerfile", TEMPLATE_DOCKERFILE.to_string()),
+            (".gitignore", TEMPLATE_GITIGNORE.to_string()),
+            ("src/lib.rs", self.source.clone()),
+        ]
+    }
+
+    /// Generate the project as a tar archive, with the files at the root of the archive
+    pub fn to_tar(&self) -> Result<Vec<u8>, Error> {
+        let mut builder = Builder::new(Vec::new());
+        for (path, contents) in self.files() {
+            let mut header = Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, contents.as_bytes())?;
+        }
+        Ok(builder.into_inner()?)
+    }
+
+    fn cargo_toml(&self) -> String {
+        let mut dependencies = format!(
+            "entropy-programs-core = {{ git = \"https://github.com/entropyxyz/programs.git\", tag = \"{}\" }}\n\
+             getrandom = {{ version = \"0.2\", default-features = false, features = [\"custom\"] }}\n",
+            TEMPLATE_CORE_VERSION
+        );
+        for (name, requirement) in &self.dependencies {
+            dependencies.push_str(&format!("{} = {}\n", name, requirement));
+        }
+        format!(
+            r#"[package]
+name = "{name}"
+version = "0.1.0"
+edition = "2021"
+
+[lib]
+crate-type = ["cdylib"]
+
+[dependencies]
+{dependencies}
+[package.metadata.component]
+package = "entropy:{name}"
+
+[package.metadata.entropy-program]
+docker-image = "{DEFAULT_DOCKER_IMAGE}"
+"#,
+            name = self.name,
+        )
+    }
+}
+
+/// Fetch a single source file from a URL. Links to a GitHub gist page are followed to the raw
+/// contents of the gist's file.
+pub async fn fetch_source_file(url: &str) -> Result<String, Error> {
+    let fetch_error = |error: reqwest::Error| Error::Fetch(error.to_string());
+    let mut url = reqwest::Url::parse(url).map_err(|error| Error::Fetch(error.to_string()))?;
+    if !is_allowed_source_host(&url) {
+        return Err(Error::Fetch(UNTRUSTED_SOURCE_URL.to_string()));
+    }
+    if url.host_str() == Some("gist.github.com")
+        && !url
+            .path_segments()
+            .is_some_and(|mut segments| segments.any(|segment| segment == "raw"))
+    {
+        let path = format!("{}/raw", url.path().trim_end_matches('/'));
+        url.set_path(&path);
+    }
+
+    // Redirects are checked too, as an allowed host could otherwise send the request elsewhere
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::custom(|attempt| {
+            if attempt.previous().len() > MAX_SOURCE_FILE_REDIRECTS {
+                attempt.error("too many redirects")
+            } else if is_allowed_source_host(attempt.url()) {
+                attempt.follow()
+            } else {
+                attempt.error(UNTRUSTED_SOURCE_URL)
+            }
+        }))
+        .build()
+        .map_err(fetch_error)?;
+    let mut response = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(fetch_error)?;
+    let mut source = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(fetch_error)? {
+        source.extend_from_slice(&chunk);
+        if source.len() > MAX_SOURCE_FILE_SIZE {
+            return Err(Error::Fetch(format!(
+                "Source file is larger than {} bytes",
+                MAX_SOURCE_FILE_SIZE
+            )));
+        }
+    }
+    String::from_utf8(source).map_err(|_| Error::Fetch("Source file is not UTF-8".to_string()))
+}
+
+/// Why a URL a single source file may not be fetched from was refused
+pub const UNTRUSTED_SOURCE_URL: &str =
+    "Source files can only be fetched over https from GitHub gists or raw.githubusercontent.com";
+
+/// Whether a single source file may be fetched from the given URL
+pub fn is_allowed_source_url(url: &str) -> bool {
+    reqwest::Url::parse(url).is_ok_and(|url| is_allowed_source_host(&url))
+}
+
+/// Only https on the default port is allowed, and only to the hosts listed
+fn is_allowed_source_host(url: &reqwest::Url) -> bool {
+    url.scheme() == "https"
+        && url.port().is_none()
+        && url
+            .host_str()
+            .is_some_and(|host| SOURCE_FILE_HOSTS.contains(&host))
+}
+
+/// Package names are restricted to the characters cargo allows
+pub fn is_valid_package_name(name: &str) -> bool {
+    name.chars().next().is_some_and(|c| c.is_ascii_alphabetic())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
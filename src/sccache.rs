@@ -0,0 +1,137 @@
+//! Caching of compiled crates with sccache, so that builds of different programs share the
+//! artifacts of the dependencies they have in common rather than each compiling them. The cache
+//! is an S3 bucket if one is configured, which builders on several hosts can share, and
+//! otherwise a local directory. Build images must have `sccache` installed.
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{path::PathBuf, process::Command};
+
+/// Where the local cache is kept in build containers
+const CONTAINER_SCCACHE_DIR: &str = "/var/cache/sccache";
+
+/// Named volume holding the local cache of test containers, if not configured
+const DEFAULT_SCCACHE_VOLUME: &str = "program-metadata-sccache";
+
+/// ID of the build secret holding the credentials for the bucket, which the template's
+/// Dockerfile mounts where the AWS SDK looks for them
+const CREDENTIALS_SECRET_ID: &str = "aws-credentials";
+
+/// Where the credentials for the bucket are mounted in test containers
+const CONTAINER_CREDENTIALS_FILE: &str = "/root/.aws/credentials";
+
+/// Settings for caching compiled crates with sccache
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SccacheConfig {
+    /// S3 bucket to keep the cache in. If not given, the cache is local.
+    pub bucket: Option<String>,
+    pub region: Option<String>,
+    /// Endpoint of an S3 compatible service other than AWS, such as MinIO
+    pub endpoint: Option<String>,
+    /// Prefix of the keys of cached artifacts in the bucket
+    pub key_prefix: Option<String>,
+    /// AWS credentials file for the bucket, which is given to builds as a secret rather than a
+    /// build argument, so that it is not kept in the build cache
+    pub credentials_file: Option<PathBuf>,
+    /// Largest size of a local cache, such as `10G`, sccache's default if not given
+    pub cache_size: Option<String>,
+    /// Directory of the local cache for native builds, sccache's default if not given
+    pub dir: Option<PathBuf>,
+    /// Named volume holding the local cache for test containers
+    pub volume: Option<String>,
+}
+
+impl SccacheConfig {
+    /// Environment variables which make cargo compile with sccache and configure the bucket.
+    /// Where a local cache is kept depends on where the build runs, so is not included.
+    fn env(&self) -> Vec<(&'static str, String)> {
+        let mut env = vec![("RUSTC_WRAPPER", "sccache".to_string())];
+        let settings = [
+            ("SCCACHE_BUCKET", &self.bucket),
+            ("SCCACHE_REGION", &self.region),
+            ("SCCACHE_ENDPOINT", &self.endpoint),
+            ("SCCACHE_S3_KEY_PREFIX", &self.key_prefix),
+            ("SCCACHE_CACHE_SIZE", &self.cache_size),
+        ];
+        for (name, value) in settings {
+            if let Some(value) = value {
+                env.push((name, value.clone()));
+            }
+        }
+        env
+    }
+
+    /// Whether the cache is kept where the build runs rather than in a bucket
+    pub fn is_local(&self) -> bool {
+        self.bucket.is_none()
+    }
+
+    /// Arguments to `build`, which the template's Dockerfile passes on to cargo
+    pub fn docker_build_args(&self) -> Vec<String> {
+        let mut env = self.env();
+        if self.is_local() {
+            env.push(("SCCACHE_DIR", CONTAINER_SCCACHE_DIR.to_string()));
+        }
+        let mut args: Vec<String> = env
+            .into_iter()
+            .flat_map(|(name, value)| ["--build-arg".to_string(), format!("{}={}", name, value)])
+            .collect();
+        if let Some(credentials_file) = &self.credentials_file {
+            args.push(format!(
+                "--secret=id={},src={}",
+                CREDENTIALS_SECRET_ID,
+                credentials_file.display()
+            ));
+        }
+        args
+    }
+
+    /// Arguments to `run` for a test container
+    pub fn docker_run_args(&self) -> Vec<String> {
+        let mut args: Vec<String> = self
+            .env()
+            .into_iter()
+            .map(|(name, value)| format!("--env={}={}", name, value))
+            .collect();
+        if self.is_local() {
+            args.push(format!(
+                "--volume={}:{}",
+                self.volume.as_deref().unwrap_or(DEFAULT_SCCACHE_VOLUME),
+                CONTAINER_SCCACHE_DIR
+            ));
+            args.push(format!("--env=SCCACHE_DIR={}", CONTAINER_SCCACHE_DIR));
+        }
+        if let Some(credentials_file) = &self.credentials_file {
+            args.push(format!(
+                "--volume={}:{}:ro",
+                credentials_file.display(),
+                CONTAINER_CREDENTIALS_FILE
+            ));
+        }
+        args
+    }
+
+    /// Set up a cargo command run on the host to use sccache
+    pub fn apply(&self, command: &mut Command) {
+        command.envs(self.env());
+        if let Some(dir) = &self.dir {
+            command.env("SCCACHE_DIR", dir);
+        }
+        if let Some(credentials_file) = &self.credentials_file {
+            command.env("AWS_SHARED_CREDENTIALS_FILE", credentials_file);
+        }
+    }
+
+    /// Environment of a Kubernetes build container, whose local cache is kept in the given
+    /// directory. Pods get credentials for the bucket from the cluster, such as from their
+    /// service account.
+    pub fn kubernetes_env(&self, local_dir: &str) -> Value {
+        let mut env = self.env();
+        if self.is_local() {
+            env.push(("SCCACHE_DIR", local_dir.to_string()));
+        }
+        env.into_iter()
+            .map(|(name, value)| json!({ "name": name, "value": value }))
+            .collect()
+    }
+}
@@ -0,0 +1,236 @@
+//! JSON schema of the types used in API responses, so that clients can generate their own types
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{InstanceType, RootSchema, Schema, SchemaObject, SubschemaValidation},
+    JsonSchema,
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::{
+    attestation::Attestation,
+    audit::AuditReport,
+    build::BuildResponse,
+    chain_verification::ChainVerification,
+    changelog::Changelog,
+    dependencies::Dependency,
+    digest::ProgramDigests,
+    federation::Origin,
+    index::{CollisionWarning, SearchResult},
+    ipfs::IpfsPins,
+    job::Job,
+    optimize::Optimization,
+    peer_comparison::PeerConsensus,
+    program_details::ProgramDetails,
+    program_info::ProgramInfo,
+    provenance::GitProvenance,
+    quota::QuotaStatus,
+    record::BuildRecord,
+    reproduction::ReproductionSummary,
+    test_run::Benchmark,
+    usage::UsageSummary,
+};
+
+/// The JSON body of an error response
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ErrorBody {
+    /// The HTTP status code of the response
+    pub status: u16,
+    /// A description of the error
+    pub error: String,
+}
+
+/// Get a schema whose properties are the responses given by each route, with definitions of
+/// the types they use
+pub fn api_schema() -> RootSchema {
+    let mut gen = SchemaGenerator::default();
+    let mut responses = BTreeMap::new();
+
+    add_response::<BuildResponse>(
+        &mut gen,
+        &mut responses,
+        "build_stream_item",
+        "An item in the response stream of /add-program-git and /add-program-tar",
+    );
+    add_response::<Vec<String>>(
+        &mut gen,
+        &mut responses,
+        "programs",
+        "Hex encoded program hashes, as given by /programs and /ns/:namespace/programs",
+    );
+    add_response::<Vec<SearchResult>>(
+        &mut gen,
+        &mut responses,
+        "search",
+        "Programs matching a search, as given by /search",
+    );
+    add_response::<Vec<BuildRecord>>(
+        &mut gen,
+        &mut responses,
+        "builds",
+        "Build records, as given by /builds",
+    );
+    add_response::<BuildRecord>(
+        &mut gen,
+        &mut responses,
+        "build",
+        "A build record, as given by /build/:build_id",
+    );
+    add_response::<BTreeMap<String, String>>(
+        &mut gen,
+        &mut responses,
+        "channels",
+        "Hex encoded program hashes keyed by channel name, as given by /program/by-name/:name/channels",
+    );
+    add_response::<QuotaStatus>(
+        &mut gen,
+        &mut responses,
+        "quota",
+        "A tenant's usage and remaining quota, as given by /ns/:namespace/quota",
+    );
+    add_response::<ProgramInfo>(
+        &mut gen,
+        &mut responses,
+        "program_info",
+        "Arguments of the chain's set_program call, as given by /program/:program_hash/program-info",
+    );
+    add_response::<Changelog>(
+        &mut gen,
+        &mut responses,
+        "changelog",
+        "Commits since the previous build of the same repository, as given by /program/:program_hash/changelog",
+    );
+    add_response::<Job>(
+        &mut gen,
+        &mut responses,
+        "job",
+        "A build job, as given by /build and /job/:job_id",
+    );
+    add_response::<Attestation>(
+        &mut gen,
+        &mut responses,
+        "attestation",
+        "A signed statement of how a program was built, as given by /program/:program_hash/attestation",
+    );
+    add_response::<Vec<Dependency>>(
+        &mut gen,
+        &mut responses,
+        "dependencies",
+        "The crates a program was built from, as given by /program/:program_hash/dependencies",
+    );
+    add_response::<ProgramDetails>(
+        &mut gen,
+        &mut responses,
+        "program",
+        "A program's metadata, as given by /program/:program_hash",
+    );
+    responses.insert("program_raw".to_string(), program_metadata_schema(&mut gen));
+    add_response::<ErrorBody>(
+        &mut gen,
+        &mut responses,
+        "error",
+        "The body of any response with an error status",
+    );
+
+    let mut root = gen.into_root_schema_for::<()>();
+    root.schema = SchemaObject {
+        instance_type: Some(InstanceType::Object.into()),
+        ..Default::default()
+    };
+    root.schema.metadata().title = Some("program-metadata-http-service API".to_string());
+    root.schema.object().properties = responses;
+    root
+}
+
+/// Add the schema of a response type with a description of where it is used
+fn add_response<T: JsonSchema>(
+    gen: &mut SchemaGenerator,
+    responses: &mut BTreeMap<String, Schema>,
+    name: &str,
+    description: &str,
+) {
+    let mut schema = SchemaObject {
+        subschemas: Some(Box::new(SubschemaValidation {
+            all_of: Some(vec![gen.subschema_for::<T>()]),
+            ..Default::default()
+        })),
+        ..Default::default()
+    };
+    schema.metadata().description = Some(description.to_string());
+    responses.insert(name.to_string(), schema.into());
+}
+
+/// Program metadata as given by /program/:program_hash with `format=raw`, which is the package
+/// metadata from `cargo metadata` together with some additional fields
+fn program_metadata_schema(gen: &mut SchemaGenerator) -> Schema {
+    let mut schema = SchemaObject {
+        instance_type: Some(InstanceType::Object.into()),
+        ..Default::default()
+    };
+    schema.metadata().description = Some(
+        "Package metadata as given by `cargo metadata`, with additional fields added by this service"
+            .to_string(),
+    );
+    let object = schema.object();
+    object.properties.insert(
+        "collision_warning".to_string(),
+        gen.subschema_for::<CollisionWarning>(),
+    );
+    object.properties.insert(
+        "reproductions".to_string(),
+        gen.subschema_for::<ReproductionSummary>(),
+    );
+    object
+        .properties
+        .insert("benchmark".to_string(), gen.subschema_for::<Benchmark>());
+    object
+        .properties
+        .insert("audit".to_string(), gen.subschema_for::<AuditReport>());
+    object
+        .properties
+        .insert("usage".to_string(), gen.subschema_for::<UsageSummary>());
+    object
+        .properties
+        .insert("changelog".to_string(), gen.subschema_for::<Changelog>());
+    object.properties.insert(
+        "provenance".to_string(),
+        gen.subschema_for::<GitProvenance>(),
+    );
+    object
+        .properties
+        .insert("digests".to_string(), gen.subschema_for::<ProgramDigests>());
+    object.properties.insert(
+        "optimization".to_string(),
+        gen.subschema_for::<Optimization>(),
+    );
+    // Only present once the chain has been checked
+    object
+        .properties
+        .insert("deployed".to_string(), gen.subschema_for::<bool>());
+    object.properties.insert(
+        "deployer".to_string(),
+        gen.subschema_for::<Option<String>>(),
+    );
+    object.properties.insert(
+        "chain_verification".to_string(),
+        gen.subschema_for::<ChainVerification>(),
+    );
+    object
+        .properties
+        .insert("origin".to_string(), gen.subschema_for::<Origin>());
+    object.properties.insert(
+        "peer_consensus".to_string(),
+        gen.subschema_for::<PeerConsensus>(),
+    );
+    object
+        .properties
+        .insert("ipfs".to_string(), gen.subschema_for::<IpfsPins>());
+    // Only present for programs registered as a binary without source code
+    object
+        .properties
+        .insert("verification".to_string(), gen.subschema_for::<String>());
+    object.required.insert("reproductions".to_string());
+    object.required.insert("usage".to_string());
+    object.additional_properties = Some(Box::new(Schema::Bool(true)));
+    schema.into()
+}
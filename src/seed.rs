@@ -0,0 +1,82 @@
+//! Bulk import of programs from a list of git repositories, so that a fresh instance can be
+//! populated with known programs
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    build::{BuildRequest, BuildResponder, Error},
+    build_queue::BuildQueue,
+    git_auth::strip_credentials,
+    record::{has_succeeded, BuildSource},
+};
+
+/// A git repository to build a program from
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Seed {
+    pub url: String,
+    /// The branch or tag to build, if not the default branch
+    #[serde(rename = "ref")]
+    pub git_ref: Option<String>,
+}
+
+impl Seed {
+    fn source(&self) -> BuildSource {
+        BuildSource::Git {
+            url: strip_credentials(&self.url).0,
+            git_ref: self.git_ref.clone(),
+        }
+    }
+}
+
+/// The outcome of enqueuing a list of seeds
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SeedImport {
+    /// Number of builds requested
+    pub queued: usize,
+    /// Number of seeds skipped, because they were repeated or their program is already present
+    pub skipped: usize,
+}
+
+/// Parse a seed list, which has a git URL on each line optionally followed by a branch or tag.
+/// Blank lines and lines starting with `#` are ignored.
+pub fn parse_seed_list(input: &str) -> Vec<Seed> {
+    input
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            Some(Seed {
+                url: words.next()?.to_string(),
+                git_ref: words.next().map(|git_ref| git_ref.to_string()),
+            })
+        })
+        .collect()
+}
+
+/// Request builds of each seed which has not already been successfully built
+pub async fn enqueue_seeds(
+    db: &sled::Db,
+    build_queue: &BuildQueue,
+    seeds: Vec<Seed>,
+) -> Result<SeedImport, Error> {
+    let mut import = SeedImport::default();
+    let mut seen = Vec::new();
+    for seed in seeds {
+        if seen.contains(&seed) || has_succeeded(db, &seed.source())? {
+            import.skipped += 1;
+            continue;
+        }
+        let mut build_request = BuildRequest::new_git(seed.url.clone(), BuildResponder::detached());
+        if let Some(git_ref) = seed.git_ref.clone() {
+            build_request = build_request.with_git_ref(git_ref);
+        }
+        build_queue
+            .send(build_request)
+            .await
+            .map_err(|_| Error::Mpsc)?;
+        log::info!("Queued build of {}", seed.source());
+        import.queued += 1;
+        seen.push(seed);
+    }
+    Ok(import)
+}
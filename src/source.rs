@@ -0,0 +1,69 @@
+//! Snapshots of the source code programs were built from, so that auditors can fetch the exact
+//! inputs of a build without relying on the upstream git host staying available
+use flate2::{write::GzEncoder, Compression};
+use sp_core::H256;
+use std::path::Path;
+use tar::Builder;
+
+use crate::{
+    archive::{get_artifact, ArchiveConfig},
+    build::Error,
+};
+
+/// Name of the sled tree in which source snapshots are stored as gzipped tarballs, keyed by
+/// program hash
+pub const SOURCE_TREE: &str = "sources";
+
+/// The largest compressed snapshot which will be kept
+pub const MAX_SOURCE_SNAPSHOT_SIZE: usize = 50 * 1024 * 1024;
+
+/// Make a gzipped tarball of a source tree, leaving out git's own data
+pub fn snapshot_source(source_path: &Path) -> Result<Vec<u8>, Error> {
+    let mut builder = Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    builder.follow_symlinks(false);
+    append_dir(&mut builder, source_path, Path::new(""))?;
+    Ok(builder.into_inner()?.finish()?)
+}
+
+/// Add the contents of a directory to a tarball, under the given path within it
+fn append_dir<W: std::io::Write>(
+    builder: &mut Builder<W>,
+    dir: &Path,
+    archive_path: &Path,
+) -> Result<(), Error> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    // Sorted so that the same source always gives the same tarball
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        if entry.file_name() == ".git" {
+            continue;
+        }
+        let path = entry.path();
+        let entry_archive_path = archive_path.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            builder.append_dir(&entry_archive_path, &path)?;
+            append_dir(builder, &path, &entry_archive_path)?;
+        } else {
+            builder.append_path_with_name(&path, &entry_archive_path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Store the source snapshot of a program, unless it already has one. Any source which builds to
+/// the program gives the same hash, so the snapshot of the first build is kept.
+pub fn store_source(db: &sled::Db, hash: H256, snapshot: &[u8]) -> Result<(), Error> {
+    let _ =
+        db.open_tree(SOURCE_TREE)?
+            .compare_and_swap(hash, None as Option<&[u8]>, Some(snapshot))?;
+    Ok(())
+}
+
+/// Get the source snapshot of a program, if it was built here
+pub async fn get_source(
+    db: &sled::Db,
+    archive: Option<&ArchiveConfig>,
+    hash: &[u8],
+) -> Result<Option<Vec<u8>>, Error> {
+    get_artifact(db, archive, SOURCE_TREE, hash).await
+}
@@ -0,0 +1,109 @@
+//! Test runs of built programs in the Entropy programs runtime, to catch programs which build
+//! but fail as soon as they are evaluated, and to measure how much they cost to evaluate
+use entropy_programs_runtime::{Config as RuntimeConfig, Runtime, RuntimeError, SignatureRequest};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sp_core::H256;
+use std::time::Instant;
+
+use crate::build::Error;
+
+/// Name of the sled tree in which benchmarks are stored, keyed by program hash
+const BENCHMARKS_TREE: &str = "benchmarks";
+
+/// The most fuel a program may use when being benchmarked
+const MAX_BENCHMARK_FUEL: u64 = u32::MAX as u64;
+
+/// Sample input with which to evaluate a program after it is built
+#[derive(Debug, Clone)]
+pub struct TestRun {
+    /// The message of the signature request
+    pub message: Vec<u8>,
+    /// Auxiliary data given with the signature request
+    pub auxiliary_data: Option<Vec<u8>>,
+    /// Fuel given to the runtime, if not the runtime's default
+    pub fuel: Option<u64>,
+}
+
+/// The cost of evaluating a program with some sample input
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Benchmark {
+    /// The least fuel needed to evaluate the program
+    pub fuel: u64,
+    /// How long evaluation took, in microseconds, including instantiating the program
+    pub execution_time_micros: u64,
+    /// Length in bytes of the sample signature request message
+    pub message_length: usize,
+}
+
+impl TestRun {
+    /// Evaluate a program binary with this input, giving an error message if evaluation fails
+    pub async fn run(self, binary: Vec<u8>) -> Result<(), String> {
+        // Evaluation is CPU bound, so it should not block the async runtime
+        tokio::task::spawn_blocking(move || {
+            let fuel = self.fuel;
+            self.evaluate(&binary, fuel)
+                .map_err(|error| error.to_string())
+        })
+        .await
+        .map_err(|error| format!("Test run panicked: {}", error))?
+    }
+
+    /// Find the least fuel with which a program can be evaluated with this input, by searching
+    /// for the point at which the runtime runs out of fuel
+    pub async fn benchmark(self, binary: Vec<u8>) -> Result<Benchmark, String> {
+        tokio::task::spawn_blocking(move || {
+            let start = Instant::now();
+            self.evaluate(&binary, Some(MAX_BENCHMARK_FUEL))
+                .map_err(|error| error.to_string())?;
+            let execution_time_micros = start.elapsed().as_micros() as u64;
+
+            // Evaluation runs out of fuel with `not_enough` and succeeds with `enough`
+            let (mut not_enough, mut enough) = (0, MAX_BENCHMARK_FUEL);
+            while enough - not_enough > 1 {
+                let fuel = not_enough + (enough - not_enough) / 2;
+                match self.evaluate(&binary, Some(fuel)) {
+                    Ok(()) => enough = fuel,
+                    Err(RuntimeError::OutOfFuel) => not_enough = fuel,
+                    Err(error) => return Err(error.to_string()),
+                }
+            }
+
+            Ok(Benchmark {
+                fuel: enough,
+                execution_time_micros,
+                message_length: self.message.len(),
+            })
+        })
+        .await
+        .map_err(|error| format!("Benchmark panicked: {}", error))?
+    }
+
+    /// Evaluate a program with the given amount of fuel, or the runtime's default
+    fn evaluate(&self, binary: &[u8], fuel: Option<u64>) -> Result<(), RuntimeError> {
+        let mut runtime = match fuel {
+            Some(fuel) => Runtime::new(RuntimeConfig { fuel }),
+            None => Runtime::default(),
+        };
+        let signature_request = SignatureRequest {
+            message: self.message.clone(),
+            auxilary_data: self.auxiliary_data.clone(),
+        };
+        runtime.evaluate(binary, &signature_request, None, None)
+    }
+}
+
+/// Store the benchmark of a program
+pub fn store_benchmark(db: &sled::Db, hash: H256, benchmark: &Benchmark) -> Result<(), Error> {
+    db.open_tree(BENCHMARKS_TREE)?
+        .insert(hash, serde_json::to_vec(benchmark)?)?;
+    Ok(())
+}
+
+/// Get the benchmark of a program, if it has been benchmarked
+pub fn get_benchmark(db: &sled::Db, hash: &[u8]) -> Result<Option<Benchmark>, Error> {
+    match db.open_tree(BENCHMARKS_TREE)?.get(hash)? {
+        Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+        None => Ok(None),
+    }
+}
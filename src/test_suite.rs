@@ -0,0 +1,43 @@
+//! Summaries of a program's own test suite, run with `cargo test` before building
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Totals of the `test result` lines output by `cargo test`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct TestSummary {
+    pub passed: u64,
+    pub failed: u64,
+    pub ignored: u64,
+    /// Whether `cargo test` exited successfully
+    pub success: bool,
+}
+
+impl TestSummary {
+    /// Add up the results from each `test result` line in the output of `cargo test`, which look
+    /// like `test result: ok. 3 passed; 0 failed; 1 ignored; 0 measured; 0 filtered out`
+    pub fn from_output(output: &str, success: bool) -> Self {
+        let mut summary = Self {
+            success,
+            ..Default::default()
+        };
+        for line in output.lines() {
+            let Some((_, results)) = line.split_once("test result:") else {
+                continue;
+            };
+            for result in results.split(['.', ';']) {
+                let mut words = result.split_whitespace();
+                if let (Some(count), Some(kind)) = (words.next(), words.next()) {
+                    if let Ok(count) = count.parse::<u64>() {
+                        match kind {
+                            "passed" => summary.passed += count,
+                            "failed" => summary.failed += count,
+                            "ignored" => summary.ignored += count,
+                            _ => {}
+                        }
+                    }
+                }
+            }
+        }
+        summary
+    }
+}
@@ -0,0 +1,641 @@
+//! Unpacking of uploaded archives, which are untrusted. Archives are checked before anything is
+//! written, so that entries cannot be written outside of the directory they are unpacked into
+//! and a small upload cannot fill the disk.
+use flate2::read::GzDecoder;
+use futures::{Stream, StreamExt};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Cursor, Read, Write},
+    path::{Component, Path, PathBuf},
+};
+use tar::{Archive, EntryType};
+use temp_dir::TempDir;
+use tokio::io::AsyncWriteExt;
+use zip::ZipArchive;
+
+use crate::{build::Error, disk::build_temp_dir};
+
+/// Magic numbers at the start of compressed archives
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Magic number at the start of a zip archive, which is what tells one apart from a tar archive
+pub const ZIP_MAGIC: [u8; 4] = [0x50, 0x4b, 0x03, 0x04];
+
+/// The most bytes an archive may unpack to, if not configured
+pub const DEFAULT_MAX_UNPACKED_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// The most files and directories an archive may contain
+const MAX_ENTRIES: usize = 100_000;
+
+/// File type bits of a unix mode, and their value for a symbolic link
+const UNIX_FILE_TYPE_MASK: u32 = 0o170000;
+const UNIX_SYMLINK: u32 = 0o120000;
+
+/// The most redirects followed when fetching an archive
+const MAX_ARCHIVE_REDIRECTS: usize = 10;
+
+/// An uploaded archive written to a temporary file, so that it need not be held in memory. The
+/// file is removed when this is dropped.
+pub struct SpooledArchive {
+    // Held so that the directory is removed along with the archive
+    _dir: TempDir,
+    path: PathBuf,
+    size: u64,
+    sha256: [u8; 32],
+}
+
+impl SpooledArchive {
+    /// Write an archive to a temporary file as its chunks arrive
+    pub async fn from_stream<S, B, E>(mut stream: S) -> Result<Self, E>
+    where
+        S: Stream<Item = Result<B, E>> + Unpin,
+        B: AsRef<[u8]>,
+        E: From<std::io::Error>,
+    {
+        let dir = build_temp_dir()?;
+        let path = dir.child("archive");
+        let mut file = tokio::fs::File::create(&path).await?;
+        let mut size = 0;
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            file.write_all(chunk.as_ref()).await?;
+            hasher.update(chunk.as_ref());
+            size += chunk.as_ref().len() as u64;
+        }
+        file.flush().await?;
+        Ok(Self {
+            _dir: dir,
+            path,
+            size,
+            sha256: hasher.finalize().into(),
+        })
+    }
+
+    /// Write an archive which is already in memory to a temporary file
+    pub fn from_bytes(archive: &[u8]) -> std::io::Result<Self> {
+        let dir = build_temp_dir()?;
+        let path = dir.child("archive");
+        std::fs::write(&path, archive)?;
+        Ok(Self {
+            _dir: dir,
+            path,
+            size: archive.len() as u64,
+            sha256: Sha256::digest(archive).into(),
+        })
+    }
+
+    /// The size of the archive in bytes, before it is decompressed
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The SHA-256 hash of the archive as uploaded, by which identical uploads are recognised
+    pub fn sha256(&self) -> [u8; 32] {
+        self.sha256
+    }
+}
+
+/// Check that a tar archive, which may be compressed with gzip or zstd, is safe to unpack
+pub fn check_tar(archive: &SpooledArchive, max_size: u64) -> Result<(), Error> {
+    let file = File::open(&archive.path)?;
+    // Nothing is written while checking, so any other error reading is due to the archive
+    visit_tar(file, max_size, None).map_err(|error| match error {
+        Error::Io(error) => Error::UnsafeArchive(error.to_string()),
+        error => error,
+    })
+}
+
+/// Unpack a tar archive, which may be compressed with gzip or zstd, into a directory
+pub fn unpack_tar(
+    archive: &SpooledArchive,
+    destination: &Path,
+    max_size: u64,
+) -> Result<(), Error> {
+    check_tar(archive, max_size)?;
+    visit_tar(File::open(&archive.path)?, max_size, Some(destination))
+}
+
+/// Check that a zip archive is safe to unpack
+pub fn check_zip(input: &[u8], max_size: u64) -> Result<(), Error> {
+    // Nothing is written while checking, so any error reading is due to the archive
+    visit_zip(input, max_size, None).map_err(|error| match error {
+        Error::Io(error) => Error::UnsafeArchive(error.to_string()),
+        error => error,
+    })
+}
+
+/// Download an archive from an https URL, failing if it is larger than the given size or, if a
+/// hex encoded SHA-256 checksum is given, if it does not match
+pub async fn fetch_archive(
+    url: &str,
+    expected_sha256: Option<&str>,
+    max_size: usize,
+) -> Result<Vec<u8>, Error> {
+    let fetch_error = |error: reqwest::Error| Error::Fetch(error.to_string());
+    let url = reqwest::Url::parse(url).map_err(|error| Error::Fetch(error.to_string()))?;
+    if url.scheme() != "https" {
+        return Err(Error::InsecureUrl);
+    }
+    // Redirects are checked too, as an https URL could otherwise send the request elsewhere
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::custom(|attempt| {
+            if attempt.previous().len() > MAX_ARCHIVE_REDIRECTS {
+                attempt.error("too many redirects")
+            } else if attempt.url().scheme() == "https" {
+                attempt.follow()
+            } else {
+                attempt.error(Error::InsecureUrl.to_string())
+            }
+        }))
+        .build()
+        .map_err(fetch_error)?;
+    let mut response = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(fetch_error)?;
+    let mut archive = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(fetch_error)? {
+        archive.extend_from_slice(&chunk);
+        if archive.len() > max_size {
+            return Err(Error::Fetch(format!(
+                "Archive is larger than {} bytes",
+                max_size
+            )));
+        }
+    }
+    if let Some(expected) = expected_sha256 {
+        let actual = hex::encode(Sha256::digest(&archive));
+        if !actual.eq_ignore_ascii_case(expected.trim()) {
+            return Err(Error::ChecksumMismatch {
+                expected: expected.to_string(),
+                actual,
+            });
+        }
+    }
+    Ok(archive)
+}
+
+/// Unpack a zip archive into a directory
+pub fn unpack_zip(input: &[u8], destination: &Path, max_size: u64) -> Result<(), Error> {
+    check_zip(input, max_size)?;
+    visit_zip(input, max_size, Some(destination))
+}
+
+/// Check each entry of a tar archive, and unpack it if a destination is given
+fn visit_tar(input: File, max_size: u64, destination: Option<&Path>) -> Result<(), Error> {
+    // Reading is stopped just beyond the limit, so that a small compressed archive cannot
+    // be decompressed without end
+    let mut archive = Archive::new(decompress(input)?.take(max_size.saturating_add(1)));
+    let mut size: u64 = 0;
+    // Links and the paths that are resolved through them, checked once all links are known as
+    // an entry may come before the link it would be written through
+    let mut links = HashSet::new();
+    let mut resolved_paths = Vec::new();
+    for (index, entry) in archive.entries()?.enumerate() {
+        if index >= MAX_ENTRIES {
+            return Err(Error::UnsafeArchive(format!(
+                "more than {} entries",
+                MAX_ENTRIES
+            )));
+        }
+        let mut entry = entry?;
+        let path = entry.path()?.to_path_buf();
+        let is_plain = path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        if !is_plain {
+            return Err(Error::UnsafeArchive(format!(
+                "entry {} is outside of the archive",
+                path.display()
+            )));
+        }
+        match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Directory | EntryType::GNUSparse => {}
+            entry_type @ (EntryType::Symlink | EntryType::Link) => {
+                let target = entry.link_name()?.unwrap_or_default().to_path_buf();
+                // Symbolic link targets are relative to the directory containing the link, and
+                // hard link targets to the root of the archive
+                let resolved = match (entry_type, path.parent()) {
+                    (EntryType::Symlink, Some(parent)) => parent.join(&target),
+                    _ => target,
+                };
+                if !is_enclosed(&resolved) {
+                    return Err(Error::UnsafeArchive(format!(
+                        "link {} points outside of the archive",
+                        path.display()
+                    )));
+                }
+                // A hard link to a symbolic link copies it, so may not point at one, while a
+                // symbolic link may point at another link but not through one
+                if entry_type == EntryType::Symlink {
+                    links.insert(normalize(&path).unwrap_or_default());
+                }
+                resolved_paths.push((path.clone(), resolved, entry_type == EntryType::Symlink));
+            }
+            // Extended headers give metadata of the following entry
+            EntryType::XGlobalHeader | EntryType::XHeader => {}
+            EntryType::GNULongName | EntryType::GNULongLink => {}
+            other => {
+                return Err(Error::UnsafeArchive(format!(
+                    "entry {} has unsupported type {:?}",
+                    path.display(),
+                    other
+                )));
+            }
+        }
+        size = size.saturating_add(entry.header().size()?);
+        if size > max_size {
+            return Err(too_large(max_size));
+        }
+        resolved_paths.push((path.clone(), path.clone(), true));
+        if let Some(destination) = destination {
+            entry.unpack_in(destination)?;
+        }
+    }
+    // Where a path is resolved through a symbolic link it is no longer enclosed by its
+    // components alone, as the link may point anywhere above itself
+    for (path, resolved, may_be_link) in resolved_paths {
+        if passes_through_link(&resolved, &links, may_be_link) {
+            return Err(Error::UnsafeArchive(format!(
+                "entry {} is reached through a link",
+                path.display()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Check each entry of a zip archive, and unpack it if a destination is given
+fn visit_zip(input: &[u8], max_size: u64, destination: Option<&Path>) -> Result<(), Error> {
+    let mut archive = ZipArchive::new(Cursor::new(input))?;
+    if archive.len() > MAX_ENTRIES {
+        return Err(Error::UnsafeArchive(format!(
+            "more than {} entries",
+            MAX_ENTRIES
+        )));
+    }
+    let mut size: u64 = 0;
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index)?;
+        let Some(path) = file.enclosed_name() else {
+            return Err(Error::UnsafeArchive(format!(
+                "entry {} is outside of the archive",
+                file.name()
+            )));
+        };
+        let mode = file.unix_mode();
+        if mode.is_some_and(|mode| mode & UNIX_FILE_TYPE_MASK == UNIX_SYMLINK) {
+            return Err(Error::UnsafeArchive(format!(
+                "entry {} is a link",
+                file.name()
+            )));
+        }
+        let output = destination.map(|destination| destination.join(&path));
+        if file.is_dir() {
+            if let Some(output) = output {
+                fs::create_dir_all(output)?;
+            }
+            continue;
+        }
+        let mut writer: Box<dyn Write> = match &output {
+            Some(output) => {
+                if let Some(parent) = output.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                Box::new(File::create(output)?)
+            }
+            None => Box::new(io::sink()),
+        };
+        // The size in the header is given by the archive, so the entry is measured by what it
+        // decompresses to, reading no further than just beyond the limit
+        let remaining = max_size - size;
+        size += io::copy(&mut (&mut file).take(remaining + 1), &mut writer)?;
+        if size > max_size {
+            return Err(too_large(max_size));
+        }
+        #[cfg(unix)]
+        if let (Some(output), Some(mode)) = (&output, mode) {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(output, fs::Permissions::from_mode(mode & 0o777))?;
+        }
+    }
+    Ok(())
+}
+
+/// Wrap an archive in a decompressor if it is compressed with gzip or zstd, detected by the magic
+/// number it starts with
+fn decompress(input: File) -> Result<Box<dyn Read>, Error> {
+    let mut input = BufReader::new(input);
+    let start = input.fill_buf()?;
+    Ok(if start.starts_with(&GZIP_MAGIC) {
+        Box::new(GzDecoder::new(input))
+    } else if start.starts_with(&ZSTD_MAGIC) {
+        Box::new(zstd::Decoder::with_buffer(input)?)
+    } else {
+        Box::new(input)
+    })
+}
+
+/// Whether a relative path, which may contain `..`, stays within the directory it is relative to
+fn is_enclosed(path: &Path) -> bool {
+    normalize(path).is_some()
+}
+
+/// Whether an enclosed relative path is resolved through one of the given symbolic links, or is
+/// one if it may not be
+fn passes_through_link(path: &Path, links: &HashSet<PathBuf>, may_be_link: bool) -> bool {
+    let mut resolved = PathBuf::new();
+    let mut components = path.components().peekable();
+    while let Some(component) = components.next() {
+        match component {
+            Component::Normal(name) => resolved.push(name),
+            Component::ParentDir => {
+                resolved.pop();
+                continue;
+            }
+            _ => continue,
+        }
+        if links.contains(&resolved) && (components.peek().is_some() || !may_be_link) {
+            return true;
+        }
+    }
+    false
+}
+
+/// A relative path with `.` and `..` removed, if it stays within the directory it is relative to
+fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return None;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(normalized)
+}
+
+/// The error given when an archive unpacks to more than the limit
+fn too_large(max_size: u64) -> Error {
+    Error::UnsafeArchive(format!("unpacks to more than {} bytes", max_size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+    use tar::{Builder, Header};
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    const MAX_SIZE: u64 = 1024 * 1024;
+
+    /// A tar entry header with its path written directly, as the tar builder refuses unsafe paths
+    fn tar_header(path: &str, entry_type: EntryType, size: u64, link: Option<&str>) -> Header {
+        let mut header = Header::new_gnu();
+        let name = &mut header.as_gnu_mut().unwrap().name;
+        name[..path.len()].copy_from_slice(path.as_bytes());
+        header.set_entry_type(entry_type);
+        header.set_size(size);
+        header.set_mode(0o644);
+        if let Some(link) = link {
+            let link_name = &mut header.as_gnu_mut().unwrap().linkname;
+            link_name[..link.len()].copy_from_slice(link.as_bytes());
+        }
+        header.set_cksum();
+        header
+    }
+
+    /// A tar archive of files and symbolic links, given as a path and either contents or a target
+    fn tar_archive(entries: &[(&str, Result<&str, &str>)]) -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+        for (path, entry) in entries {
+            match entry {
+                Ok(contents) => {
+                    let header = tar_header(path, EntryType::Regular, contents.len() as u64, None);
+                    builder.append(&header, contents.as_bytes()).unwrap();
+                }
+                Err(target) => {
+                    let header = tar_header(path, EntryType::Symlink, 0, Some(target));
+                    builder.append(&header, std::io::empty()).unwrap();
+                }
+            }
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn check_tar_bytes(archive: &[u8]) -> Result<(), Error> {
+        check_tar(&SpooledArchive::from_bytes(archive).unwrap(), MAX_SIZE)
+    }
+
+    fn zip_archive(add: impl FnOnce(&mut ZipWriter<Cursor<Vec<u8>>>)) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        add(&mut writer);
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn enclosed_paths() {
+        assert!(is_enclosed(Path::new("src/lib.rs")));
+        assert!(is_enclosed(Path::new("./src/../Cargo.toml")));
+        assert!(is_enclosed(Path::new("src/..")));
+        assert!(!is_enclosed(Path::new("..")));
+        assert!(!is_enclosed(Path::new("src/../../Cargo.toml")));
+        assert!(!is_enclosed(Path::new("/etc/passwd")));
+    }
+
+    #[test]
+    fn tar_with_plain_entries_and_links_is_accepted() {
+        let archive = tar_archive(&[
+            ("src/lib.rs", Ok("fn main() {}")),
+            ("src/link.rs", Err("lib.rs")),
+            ("latest", Err("src")),
+        ]);
+        check_tar_bytes(&archive).unwrap();
+    }
+
+    #[test]
+    fn tar_entry_outside_of_archive_is_rejected() {
+        let archive = tar_archive(&[("../escaped", Ok("contents"))]);
+        assert!(matches!(
+            check_tar_bytes(&archive),
+            Err(Error::UnsafeArchive(_))
+        ));
+    }
+
+    #[test]
+    fn tar_entry_with_absolute_path_is_rejected() {
+        let archive = tar_archive(&[("/tmp/escaped", Ok("contents"))]);
+        assert!(matches!(
+            check_tar_bytes(&archive),
+            Err(Error::UnsafeArchive(_))
+        ));
+    }
+
+    #[test]
+    fn tar_link_outside_of_archive_is_rejected() {
+        let archive = tar_archive(&[("src/link", Err("../../etc/passwd"))]);
+        assert!(matches!(
+            check_tar_bytes(&archive),
+            Err(Error::UnsafeArchive(_))
+        ));
+    }
+
+    #[test]
+    fn tar_link_through_another_link_is_rejected() {
+        // Each link stays within the archive by its path alone, but `up/up` is resolved from the
+        // top of the archive, to outside of it
+        let archive = tar_archive(&[("up", Err(".")), ("up/up", Err(".."))]);
+        assert!(matches!(
+            check_tar_bytes(&archive),
+            Err(Error::UnsafeArchive(_))
+        ));
+        // The same, with the link written through coming after the entry
+        let archive = tar_archive(&[("escape", Err("up/..")), ("up", Err("."))]);
+        assert!(matches!(
+            check_tar_bytes(&archive),
+            Err(Error::UnsafeArchive(_))
+        ));
+    }
+
+    #[test]
+    fn tar_entry_written_through_a_link_is_rejected() {
+        let archive = tar_archive(&[("src", Err(".")), ("src/lib.rs", Ok("fn main() {}"))]);
+        assert!(matches!(
+            check_tar_bytes(&archive),
+            Err(Error::UnsafeArchive(_))
+        ));
+    }
+
+    #[test]
+    fn tar_hard_link_to_a_link_is_rejected() {
+        let mut builder = Builder::new(Vec::new());
+        let header = tar_header("dir/up", EntryType::Symlink, 0, Some(".."));
+        builder.append(&header, std::io::empty()).unwrap();
+        let header = tar_header("up", EntryType::Link, 0, Some("dir/up"));
+        builder.append(&header, std::io::empty()).unwrap();
+        let archive = builder.into_inner().unwrap();
+        assert!(matches!(
+            check_tar_bytes(&archive),
+            Err(Error::UnsafeArchive(_))
+        ));
+    }
+
+    #[test]
+    fn gzip_bomb_is_rejected() {
+        let size = MAX_SIZE * 2;
+        let mut builder = Builder::new(Vec::new());
+        let header = tar_header("zeros", EntryType::Regular, size, None);
+        builder
+            .append(&header, std::io::repeat(0).take(size))
+            .unwrap();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&builder.into_inner().unwrap()).unwrap();
+        let archive = encoder.finish().unwrap();
+        assert!((archive.len() as u64) < MAX_SIZE / 100);
+        assert!(matches!(
+            check_tar_bytes(&archive),
+            Err(Error::UnsafeArchive(_))
+        ));
+    }
+
+    #[test]
+    fn zip_with_plain_entries_is_accepted() {
+        let archive = zip_archive(|writer| {
+            writer
+                .start_file("src/lib.rs", SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(b"fn main() {}").unwrap();
+        });
+        check_zip(&archive, MAX_SIZE).unwrap();
+    }
+
+    #[test]
+    fn zip_entry_outside_of_archive_is_rejected() {
+        for path in ["../escaped", "/tmp/escaped"] {
+            let archive = zip_archive(|writer| {
+                writer
+                    .start_file(path, SimpleFileOptions::default())
+                    .unwrap();
+                writer.write_all(b"contents").unwrap();
+            });
+            assert!(matches!(
+                check_zip(&archive, MAX_SIZE),
+                Err(Error::UnsafeArchive(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn zip_link_is_rejected() {
+        let archive = zip_archive(|writer| {
+            writer
+                .add_symlink("link", "../../etc/passwd", SimpleFileOptions::default())
+                .unwrap();
+        });
+        assert!(matches!(
+            check_zip(&archive, MAX_SIZE),
+            Err(Error::UnsafeArchive(_))
+        ));
+    }
+
+    #[test]
+    fn zip_bomb_is_rejected() {
+        let archive = zip_archive(|writer| {
+            writer
+                .start_file("zeros", SimpleFileOptions::default())
+                .unwrap();
+            std::io::copy(&mut std::io::repeat(0).take(MAX_SIZE * 2), writer).unwrap();
+        });
+        assert!((archive.len() as u64) < MAX_SIZE / 100);
+        assert!(matches!(
+            check_zip(&archive, MAX_SIZE),
+            Err(Error::UnsafeArchive(_))
+        ));
+    }
+
+    #[test]
+    fn zip_bomb_with_forged_size_is_rejected() {
+        let mut archive = zip_archive(|writer| {
+            writer
+                .start_file("zeros", SimpleFileOptions::default())
+                .unwrap();
+            std::io::copy(&mut std::io::repeat(0).take(MAX_SIZE * 2), writer).unwrap();
+        });
+        // Declare the entry as a single byte in both the local and the central header
+        let forge = |archive: &mut Vec<u8>, signature: &[u8], offset: usize| {
+            let start = archive
+                .windows(signature.len())
+                .position(|window| window == signature)
+                .unwrap();
+            archive[start + offset..start + offset + 4].copy_from_slice(&1u32.to_le_bytes());
+        };
+        forge(&mut archive, b"PK\x03\x04", 22);
+        forge(&mut archive, b"PK\x01\x02", 24);
+        assert_eq!(
+            ZipArchive::new(Cursor::new(&archive[..]))
+                .unwrap()
+                .by_index(0)
+                .unwrap()
+                .size(),
+            1
+        );
+        let destination = TempDir::new().unwrap();
+        assert!(matches!(
+            unpack_zip(&archive, destination.path(), MAX_SIZE),
+            Err(Error::UnsafeArchive(_))
+        ));
+        assert!(!destination.path().join("zeros").exists());
+    }
+}
@@ -0,0 +1,119 @@
+//! Counts of how often each program is fetched, so that authors can see whether their program
+//! is being used. Fetches are counted per day and only recent days are kept.
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::{build::Error, record::unix_time};
+
+/// Name of the sled tree in which usage is stored, keyed by program hash
+const USAGE_TREE: &str = "program_usage";
+
+/// Number of days for which daily counts are kept
+const DAYS_KEPT: u64 = 30;
+
+/// Number of days counted in the weekly figure
+const DAYS_PER_WEEK: u64 = 7;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Substrings of user agents of crawlers and monitoring tools, whose fetches are not counted
+const BOT_USER_AGENTS: &[&str] = &[
+    "bot", "crawl", "spider", "slurp", "monitor", "uptime", "pingdom", "preview",
+];
+
+/// What was fetched
+#[derive(Debug, Clone, Copy)]
+pub enum FetchKind {
+    /// Program metadata from `/program/:program_hash`
+    Metadata,
+    /// Arguments for the chain from `/program/:program_hash/program-info`
+    ProgramInfo,
+    /// The program binary from `/binary/:program_hash`
+    Binary,
+}
+
+/// Stored fetch counts of a program
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Usage {
+    metadata_fetches: u64,
+    program_info_fetches: u64,
+    #[serde(default)]
+    binary_fetches: u64,
+    /// Total fetches of either kind, keyed by the number of days since the unix epoch
+    daily: BTreeMap<u64, u64>,
+}
+
+/// Usage of a program as given in its metadata
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct UsageSummary {
+    /// Total number of times the program's metadata has been fetched
+    pub metadata_fetches: u64,
+    /// Total number of times the program's info for the chain has been fetched
+    pub program_info_fetches: u64,
+    /// Total number of times the program's binary has been downloaded
+    pub binary_fetches: u64,
+    /// Fetches of either kind in the last 7 days
+    pub last_7_days: u64,
+    /// Fetches of either kind in the last 30 days
+    pub last_30_days: u64,
+}
+
+impl Usage {
+    fn summary(&self, today: u64) -> UsageSummary {
+        let since = |days: u64| {
+            self.daily
+                .range(today.saturating_sub(days - 1)..)
+                .map(|(_day, count)| count)
+                .sum()
+        };
+        UsageSummary {
+            metadata_fetches: self.metadata_fetches,
+            program_info_fetches: self.program_info_fetches,
+            binary_fetches: self.binary_fetches,
+            last_7_days: since(DAYS_PER_WEEK),
+            last_30_days: since(DAYS_KEPT),
+        }
+    }
+}
+
+/// Whether a request appears to come from a crawler or other automated tool rather than a
+/// user. Requests without a user agent are counted as automated.
+pub fn is_bot(user_agent: Option<&str>) -> bool {
+    match user_agent {
+        Some(user_agent) => {
+            let user_agent = user_agent.to_lowercase();
+            BOT_USER_AGENTS.iter().any(|bot| user_agent.contains(bot))
+        }
+        None => true,
+    }
+}
+
+/// Count a fetch of a program. The count is updated atomically, so that concurrent fetches are
+/// all counted.
+pub fn record_fetch(db: &sled::Db, hash: &[u8], kind: FetchKind) -> Result<(), Error> {
+    let today = unix_time() / SECONDS_PER_DAY;
+    db.open_tree(USAGE_TREE)?.update_and_fetch(hash, |value| {
+        let mut usage: Usage = value
+            .and_then(|value| serde_json::from_slice(value).ok())
+            .unwrap_or_default();
+        match kind {
+            FetchKind::Metadata => usage.metadata_fetches += 1,
+            FetchKind::ProgramInfo => usage.program_info_fetches += 1,
+            FetchKind::Binary => usage.binary_fetches += 1,
+        }
+        *usage.daily.entry(today).or_default() += 1;
+        usage.daily = usage.daily.split_off(&today.saturating_sub(DAYS_KEPT - 1));
+        serde_json::to_vec(&usage).ok()
+    })?;
+    Ok(())
+}
+
+/// Get the usage of a program
+pub fn get_usage(db: &sled::Db, hash: &[u8]) -> Result<UsageSummary, Error> {
+    let usage: Usage = match db.open_tree(USAGE_TREE)?.get(hash)? {
+        Some(value) => serde_json::from_slice(&value)?,
+        None => Usage::default(),
+    };
+    Ok(usage.summary(unix_time() / SECONDS_PER_DAY))
+}
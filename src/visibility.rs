@@ -0,0 +1,100 @@
+//! Programs which are hidden from listings, so that tenants can stage programs before releasing
+//! them. Unlisted programs can still be looked up by anyone who knows their hash, but private
+//! programs can only be looked up by the tenant who owns them.
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sp_core::H256;
+
+use crate::build::Error;
+
+/// Name of the sled tree in which the visibility of programs which are not public is stored,
+/// keyed by program hash
+const VISIBILITY_TREE: &str = "visibility";
+
+/// Who can see a program
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    /// Listed, and visible to anyone
+    #[default]
+    Public,
+    /// Not listed, but visible to anyone who knows the hash
+    Unlisted,
+    /// Not listed, and only visible to the tenant who owns it
+    Private,
+}
+
+/// The visibility of a program together with the tenant who set it
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProgramVisibility {
+    pub visibility: Visibility,
+    /// Namespace of the tenant who owns the program, who alone may see it if it is private
+    pub namespace: Option<String>,
+}
+
+impl ProgramVisibility {
+    /// Whether the program should be included in listings
+    pub fn is_listed(&self) -> bool {
+        self.visibility == Visibility::Public
+    }
+
+    /// Whether the program may be seen by someone authenticated as the given tenant, if any
+    pub fn is_visible_to(&self, namespace: Option<&str>) -> bool {
+        self.visibility != Visibility::Private || self.namespace.as_deref() == namespace
+    }
+}
+
+/// Get the visibility of a program
+pub fn get_visibility(db: &sled::Db, hash: &[u8]) -> Result<ProgramVisibility, Error> {
+    Ok(match db.open_tree(VISIBILITY_TREE)?.get(hash)? {
+        Some(value) => serde_json::from_slice(&value)?,
+        None => ProgramVisibility::default(),
+    })
+}
+
+/// Set the visibility of a program which a build has just stored for the first time. The tenant
+/// the program was built for, if any, becomes its owner. This gives false without changing
+/// anything if the visibility was set meanwhile by another build of the same program.
+pub fn set_initial_visibility(
+    db: &sled::Db,
+    hash: H256,
+    visibility: Visibility,
+    namespace: Option<&str>,
+) -> Result<bool, Error> {
+    // Public programs without an owner are the default, so need no record
+    if visibility == Visibility::Public && namespace.is_none() {
+        return Ok(true);
+    }
+    let record = ProgramVisibility {
+        visibility,
+        namespace: namespace.map(|namespace| namespace.to_string()),
+    };
+    let swap = db.open_tree(VISIBILITY_TREE)?.compare_and_swap(
+        hash,
+        None as Option<&[u8]>,
+        Some(serde_json::to_vec(&record)?),
+    )?;
+    Ok(swap.is_ok())
+}
+
+/// Change the visibility of a program owned by the given tenant. Only the tenant who first
+/// stored a program owns it, so this gives false without changing anything for other tenants
+/// and for programs first stored outside any namespace.
+pub fn set_visibility(
+    db: &sled::Db,
+    hash: H256,
+    visibility: Visibility,
+    namespace: &str,
+) -> Result<bool, Error> {
+    let existing = get_visibility(db, hash.as_ref())?;
+    if existing.namespace.as_deref() != Some(namespace) {
+        return Ok(false);
+    }
+    let record = ProgramVisibility {
+        visibility,
+        namespace: existing.namespace,
+    };
+    db.open_tree(VISIBILITY_TREE)?
+        .insert(hash, serde_json::to_vec(&record)?)?;
+    Ok(true)
+}